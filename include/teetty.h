@@ -0,0 +1,43 @@
+/* C API of teetty, available when the crate is built with the `ffi` feature:
+ *
+ *   cargo build --release --lib --features ffi
+ *
+ * which builds target/release/libteetty.so (.dylib on macOS) and
+ * libteetty.a.  Functions taking a session fail with EINVAL if it's NULL.
+ */
+#ifndef TEETTY_H
+#define TEETTY_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* A program running in a pty. */
+typedef struct teetty_session teetty_session;
+
+/* Starts the program in `argv` (terminated by NULL) in a pty.  The output is
+ * written to `out_path` unless it's NULL.  Returns NULL on errors. */
+teetty_session *teetty_spawn(const char *const *argv, const char *out_path);
+
+/* Sends `len` bytes of input to the program.  Returns 0 or -1 on errors. */
+int teetty_write_stdin(teetty_session *session, const uint8_t *data, size_t len);
+
+/* Changes the size of the pty.  Returns 0 or -1 on errors. */
+int teetty_resize(teetty_session *session, uint16_t cols, uint16_t rows);
+
+/* Waits for the session to end, frees it and returns the exit code of the
+ * program or -1 on errors. */
+int teetty_wait(teetty_session *session);
+
+/* Returns the message of the last error on this thread or NULL.  It stays
+ * valid until the next call into teetty on this thread. */
+const char *teetty_last_error(void);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
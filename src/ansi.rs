@@ -0,0 +1,266 @@
+//! A minimal streaming parser for the escape sequences in the output.
+//!
+//! This is not a terminal emulator.  It only picks the escape sequences out of
+//! the output so that they can be recognized even if they are split across
+//! reads.
+
+/// Escape sequences larger than this are given up on.
+const MAX_SEQUENCE_LEN: usize = 64 * 1024;
+
+/// The kind of an escape sequence.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    /// A control sequence (`ESC [ ... final`).
+    Csi,
+    /// An operating system command (`ESC ] ... BEL` or `ESC ] ... ESC \`).
+    Osc,
+    /// A string sequence (DCS, SOS, PM or APC) terminated by `ESC \`.
+    Str,
+    /// Any other escape sequence (eg: `ESC ( B`).
+    Esc,
+}
+
+/// A complete escape sequence.
+#[derive(Debug)]
+pub struct Sequence<'a> {
+    pub kind: Kind,
+    /// The raw bytes of the sequence including the introducer and terminator.
+    pub raw: &'a [u8],
+}
+
+impl<'a> Sequence<'a> {
+    /// For control sequences returns the parameter bytes and the final byte.
+    pub fn csi(&self) -> Option<(&'a [u8], u8)> {
+        match self.kind {
+            Kind::Csi => Some((
+                &self.raw[2..self.raw.len() - 1],
+                self.raw[self.raw.len() - 1],
+            )),
+            _ => None,
+        }
+    }
+
+    /// For operating system commands returns the command and its data (eg: `0`
+    /// and the title for `ESC ] 0 ; title BEL`).
+    pub fn osc(&self) -> Option<(&'a [u8], &'a [u8])> {
+        if self.kind != Kind::Osc {
+            return None;
+        }
+        let body = &self.raw[2..];
+        let body = body
+            .strip_suffix(b"\x07")
+            .or_else(|| body.strip_suffix(b"\x1b\\"))?;
+        Some(match body.iter().position(|&c| c == b';') {
+            Some(idx) => (&body[..idx], &body[idx + 1..]),
+            None => (body, &[]),
+        })
+    }
+
+    /// For private mode changes (`ESC [ ? ... h` or `l`) returns the modes and
+    /// whether they are set or reset.
+    pub fn private_modes(&self) -> Option<(impl Iterator<Item = u32> + 'a, bool)> {
+        let (params, final_byte) = self.csi()?;
+        let set = match final_byte {
+            b'h' => true,
+            b'l' => false,
+            _ => return None,
+        };
+        let modes = params
+            .strip_prefix(b"?")?
+            .split(|&c| c == b';')
+            .filter_map(|x| std::str::from_utf8(x).ok()?.parse().ok());
+        Some((modes, set))
+    }
+}
+
+/// A piece of output: a byte outside of escape sequences or a complete escape
+/// sequence.
+#[derive(Debug)]
+pub enum Piece<'a> {
+    Byte(u8),
+    Sequence(Sequence<'a>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Ground,
+    Escape,
+    EscIntermediate,
+    Csi,
+    Str,
+    StrEscape,
+}
+
+/// Streaming parser that finds escape sequences in the output.
+pub struct Parser {
+    state: State,
+    kind: Kind,
+    buf: Vec<u8>,
+}
+
+impl Default for Parser {
+    fn default() -> Parser {
+        Parser {
+            state: State::Ground,
+            kind: Kind::Esc,
+            buf: Vec::new(),
+        }
+    }
+}
+
+impl Parser {
+    /// Feeds a chunk of output to the parser.
+    ///
+    /// The callback is invoked for every completed escape sequence.  Incomplete
+    /// sequences are held back until the rest arrives with a later chunk.
+    pub fn feed<F: FnMut(Sequence)>(&mut self, data: &[u8], mut f: F) {
+        for &c in data {
+            if self.state != State::Ground {
+                self.advance(c, &mut f);
+            } else if c == 0x1b {
+                self.buf.clear();
+                self.buf.push(c);
+                self.state = State::Escape;
+            }
+        }
+    }
+
+    /// Feeds a chunk of output to the parser and appends everything that is not
+    /// part of an escape sequence to `out`.
+    pub fn strip(&mut self, data: &[u8], out: &mut Vec<u8>) {
+        for &c in data {
+            if self.state != State::Ground {
+                self.advance(c, &mut |_| {});
+            } else if c == 0x1b {
+                self.buf.clear();
+                self.buf.push(c);
+                self.state = State::Escape;
+            } else {
+                out.push(c);
+            }
+        }
+    }
+
+    /// Feeds a chunk of output to the parser and invokes the callback for every
+    /// byte outside of escape sequences and every completed sequence in the
+    /// order they appear.
+    pub fn feed_pieces<F: FnMut(Piece)>(&mut self, data: &[u8], mut f: F) {
+        for &c in data {
+            if self.state != State::Ground {
+                self.advance(c, &mut |seq| f(Piece::Sequence(seq)));
+            } else if c == 0x1b {
+                self.buf.clear();
+                self.buf.push(c);
+                self.state = State::Escape;
+            } else {
+                f(Piece::Byte(c));
+            }
+        }
+    }
+
+    /// Feeds a chunk of output to the parser and appends it to `out` without
+    /// the escape sequences for which `keep` returns `false`.
+    ///
+    /// Incomplete sequences are held back until the rest arrives.
+    pub fn filter<F: FnMut(&Sequence) -> bool>(
+        &mut self,
+        data: &[u8],
+        out: &mut Vec<u8>,
+        mut keep: F,
+    ) {
+        for &c in data {
+            if self.state != State::Ground {
+                self.advance(c, &mut |seq: Sequence| {
+                    if keep(&seq) {
+                        out.extend_from_slice(seq.raw);
+                    }
+                });
+            } else if c == 0x1b {
+                self.buf.clear();
+                self.buf.push(c);
+                self.state = State::Escape;
+            } else {
+                out.push(c);
+            }
+        }
+    }
+
+    /// Returns `true` if the data fed so far ends in an incomplete sequence.
+    pub fn in_sequence(&self) -> bool {
+        self.state != State::Ground
+    }
+
+    fn advance<F: FnMut(Sequence)>(&mut self, c: u8, f: &mut F) {
+        match self.state {
+            State::Ground => unreachable!(),
+            State::Str => {
+                self.buf.push(c);
+                if c == 0x1b {
+                    self.state = State::StrEscape;
+                } else if c == 0x07 && self.kind == Kind::Osc {
+                    self.emit(Kind::Osc, f);
+                }
+            }
+            State::StrEscape => {
+                if c == b'\\' {
+                    self.buf.push(c);
+                    self.emit(self.kind, f);
+                } else {
+                    // not a string terminator.  Give up on the sequence and
+                    // start over with the escape character.
+                    self.buf.clear();
+                    self.buf.push(0x1b);
+                    self.state = State::Escape;
+                    self.advance(c, f);
+                }
+            }
+            // an escape character aborts all other sequences
+            _ if c == 0x1b => {
+                self.buf.clear();
+                self.buf.push(c);
+                self.state = State::Escape;
+            }
+            State::Escape => {
+                self.buf.push(c);
+                match c {
+                    b'[' => self.state = State::Csi,
+                    b']' => {
+                        self.state = State::Str;
+                        self.kind = Kind::Osc;
+                    }
+                    b'P' | b'X' | b'^' | b'_' => {
+                        self.state = State::Str;
+                        self.kind = Kind::Str;
+                    }
+                    0x20..=0x2f => self.state = State::EscIntermediate,
+                    _ => self.emit(Kind::Esc, f),
+                }
+            }
+            State::EscIntermediate => {
+                self.buf.push(c);
+                if !(0x20..=0x2f).contains(&c) {
+                    self.emit(Kind::Esc, f);
+                }
+            }
+            State::Csi => {
+                self.buf.push(c);
+                if (0x40..=0x7e).contains(&c) {
+                    self.emit(Kind::Csi, f);
+                }
+            }
+        }
+        if self.buf.len() > MAX_SEQUENCE_LEN {
+            self.buf.clear();
+            self.state = State::Ground;
+        }
+    }
+
+    fn emit<F: FnMut(Sequence)>(&mut self, kind: Kind, f: &mut F) {
+        f(Sequence {
+            kind,
+            raw: &self.buf,
+        });
+        self.buf.clear();
+        self.state = State::Ground;
+    }
+}
@@ -0,0 +1,120 @@
+//! Answering terminal queries when there is no terminal.
+//!
+//! Programs ask the terminal for its capabilities (DA), its status (DSR) or
+//! the cursor position and frequently wait for the reply.  Without a terminal
+//! nobody answers, so with `--answerback` teetty replies with what a basic
+//! xterm compatible terminal would.  The cursor is tracked roughly which is
+//! enough for the common trick of moving it to the bottom right corner to find
+//! out the size of the terminal.
+use crate::ansi::{Parser, Piece, Sequence};
+
+/// Replies to the queries in the output of the program.
+#[derive(Default)]
+pub struct Answerback {
+    parser: Parser,
+    /// The cursor position (1-based).
+    row: u16,
+    col: u16,
+}
+
+impl Answerback {
+    pub fn new() -> Answerback {
+        Answerback {
+            row: 1,
+            col: 1,
+            ..Default::default()
+        }
+    }
+
+    /// Feeds output of the program.  Replies for the terminal of the given size
+    /// are appended to `reply`.
+    pub fn feed(&mut self, data: &[u8], (rows, cols): (u16, u16), reply: &mut Vec<u8>) {
+        let (rows, cols) = (rows.max(1), cols.max(1));
+        self.parser.feed_pieces(data, |piece| match piece {
+            Piece::Byte(b'\r') => self.col = 1,
+            Piece::Byte(b'\n' | 0x0b | 0x0c) => self.row = (self.row + 1).min(rows),
+            Piece::Byte(0x08) => self.col = self.col.saturating_sub(1).max(1),
+            Piece::Byte(b'\t') => self.col = (self.col.div_ceil(8) * 8 + 1).min(cols),
+            // only the first byte of a UTF-8 sequence moves the cursor
+            Piece::Byte(c) if c >= 0x20 && !(0x80..0xc0).contains(&c) => {
+                self.col = (self.col + 1).min(cols)
+            }
+            Piece::Byte(_) => {}
+            Piece::Sequence(seq) => {
+                answer(&seq, (rows, cols), (&mut self.row, &mut self.col), reply)
+            }
+        });
+    }
+}
+
+/// Handles an escape sequence: cursor movements update the position and
+/// queries are answered.
+fn answer(
+    seq: &Sequence,
+    (rows, cols): (u16, u16),
+    pos: (&mut u16, &mut u16),
+    reply: &mut Vec<u8>,
+) {
+    let (row, col) = pos;
+    // white on black
+    match seq.osc() {
+        Some((b"10", b"?")) => return reply.extend_from_slice(b"\x1b]10;rgb:ffff/ffff/ffff\x1b\\"),
+        Some((b"11", b"?")) => return reply.extend_from_slice(b"\x1b]11;rgb:0000/0000/0000\x1b\\"),
+        _ => {}
+    }
+    let Some((params, final_byte)) = seq.csi() else {
+        return;
+    };
+    let args = std::str::from_utf8(params)
+        .unwrap_or_default()
+        .trim_start_matches(['?', '>'])
+        .split(';')
+        .map(|x| x.parse::<u16>().unwrap_or(0))
+        .collect::<Vec<_>>();
+    let arg = |idx: usize| args.get(idx).copied().filter(|&x| x > 0).unwrap_or(1);
+    match (params.first(), final_byte) {
+        (Some(b'>'), b'c') => reply.extend_from_slice(b"\x1b[>0;10;1c"),
+        (_, b'c') if args[0] == 0 => reply.extend_from_slice(b"\x1b[?1;2c"),
+        (None | Some(b'0'..=b'9'), b'n') if args[0] == 5 => reply.extend_from_slice(b"\x1b[0n"),
+        (None | Some(b'0'..=b'9'), b'n') if args[0] == 6 => {
+            reply.extend_from_slice(format!("\x1b[{};{}R", row, col).as_bytes())
+        }
+        (Some(b'?'), b'n') if args[0] == 6 => {
+            reply.extend_from_slice(format!("\x1b[?{};{}R", row, col).as_bytes())
+        }
+        (None | Some(b'0'..=b'9'), b't') if args[0] == 18 => {
+            reply.extend_from_slice(format!("\x1b[8;{};{}t", rows, cols).as_bytes())
+        }
+        (None | Some(b'0'..=b'9'), b'H' | b'f') => {
+            *row = arg(0).min(rows);
+            *col = arg(1).min(cols);
+        }
+        (None | Some(b'0'..=b'9'), b'A') => *row = row.saturating_sub(arg(0)).max(1),
+        (None | Some(b'0'..=b'9'), b'B') => *row = row.saturating_add(arg(0)).min(rows),
+        (None | Some(b'0'..=b'9'), b'C') => *col = col.saturating_add(arg(0)).min(cols),
+        (None | Some(b'0'..=b'9'), b'D') => *col = col.saturating_sub(arg(0)).max(1),
+        (None | Some(b'0'..=b'9'), b'G') => *col = arg(0).min(cols),
+        (None | Some(b'0'..=b'9'), b'd') => *row = arg(0).min(rows),
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_answerback() {
+        let mut answerback = Answerback::new();
+        let mut reply = Vec::new();
+        answerback.feed(b"\x1b[c\x1b[>c\x1b[5n", (24, 80), &mut reply);
+        assert_eq!(reply, b"\x1b[?1;2c\x1b[>0;10;1c\x1b[0n");
+        reply.clear();
+        answerback.feed(b"ab\r\ncd\x1b[6", (24, 80), &mut reply);
+        answerback.feed(b"n\x1b[999;999H\x1b[6n\x1b[18t", (24, 80), &mut reply);
+        assert_eq!(reply, b"\x1b[2;3R\x1b[24;80R\x1b[8;24;80t");
+        reply.clear();
+        answerback.feed(b"\x1b[1;1m\x1b]11;?\x07", (24, 80), &mut reply);
+        assert_eq!(reply, b"\x1b]11;rgb:0000/0000/0000\x1b\\");
+    }
+}
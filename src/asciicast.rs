@@ -0,0 +1,488 @@
+//! Recordings in the [asciicast v2](https://docs.asciinema.org/manual/asciicast/v2/)
+//! format.
+//!
+//! A recording is a header line with a JSON object describing the terminal
+//! followed by one JSON array per event: `[<seconds>, "o", "<output>"]`.
+//! Input is recorded as `i` events if requested and changes of the size of the
+//! terminal as `r` events (`"<cols>x<rows>"`).
+//! Recordings can be played back with `teetty play` or asciinema.
+//!
+//! Next to the recording an index (`<recording>.idx`) is written with a line
+//! `<seconds> <byte offset> <line number> <cols>x<rows>` every few seconds of
+//! the recording, so that playing back or exporting a part of a long recording
+//! can jump to it without reading everything before.
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+
+use anyhow::{bail, Context, Error};
+use nix::pty::Winsize;
+
+use crate::json::{self, Value};
+use crate::sink::{Event as SinkEvent, Sink};
+use crate::ttyrec;
+
+/// How many seconds of a recording are between the entries of its index.
+const INDEX_INTERVAL: f64 = 10.0;
+
+/// Returns the path of the index of a recording.
+pub fn index_path(path: &Path) -> PathBuf {
+    let mut index = path.as_os_str().to_owned();
+    index.push(".idx");
+    index.into()
+}
+
+/// Writes a recording of the output.
+pub struct Recorder {
+    file: File,
+    index: File,
+    started: Instant,
+    pending: Vec<u8>,
+    flush: bool,
+    input: bool,
+    /// The current size of the terminal as rows and columns.
+    size: (u16, u16),
+    /// The size of the recording so far and the number of lines in it.
+    offset: u64,
+    lines: usize,
+    next_index: f64,
+}
+
+impl Recorder {
+    /// Creates a recording and writes the header.  With `input` the input of
+    /// the program is recorded too.
+    pub fn create(
+        path: &Path,
+        winsize: Option<Winsize>,
+        args: &[OsString],
+        flush: bool,
+        input: bool,
+    ) -> Result<Recorder, Error> {
+        let mut file = File::create(path)
+            .with_context(|| format!("could not create recording {}", path.display()))?;
+        let (rows, cols) = winsize
+            .filter(|x| x.ws_row > 0 && x.ws_col > 0)
+            .map_or((24, 80), |x| (x.ws_row, x.ws_col));
+        let command = args
+            .iter()
+            .map(|x| x.to_string_lossy())
+            .collect::<Vec<_>>()
+            .join(" ");
+        let header = format!(
+            "{{\"version\":2,\"width\":{},\"height\":{},\"timestamp\":{},\"command\":{}}}\n",
+            cols,
+            rows,
+            SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map_or(0, |x| x.as_secs()),
+            json::quote(&command),
+        );
+        file.write_all(header.as_bytes())?;
+        let index_path = index_path(path);
+        let index = File::create(&index_path)
+            .with_context(|| format!("could not create index {}", index_path.display()))?;
+        Ok(Recorder {
+            file,
+            index,
+            started: Instant::now(),
+            pending: Vec::new(),
+            flush,
+            input,
+            size: (rows, cols),
+            offset: header.len() as u64,
+            lines: 1,
+            next_index: 0.0,
+        })
+    }
+
+    fn write_event(&mut self, kind: &str, data: &str) -> io::Result<()> {
+        let time = self.started.elapsed().as_secs_f64();
+        if time >= self.next_index {
+            writeln!(
+                self.index,
+                "{:.6} {} {} {}x{}",
+                time,
+                self.offset,
+                self.lines + 1,
+                self.size.1,
+                self.size.0
+            )?;
+            self.next_index = time + INDEX_INTERVAL;
+        }
+        let line = format!(
+            "[{:.6},{},{}]\n",
+            time,
+            json::quote(kind),
+            json::quote(data)
+        );
+        self.file.write_all(line.as_bytes())?;
+        self.offset += line.len() as u64;
+        self.lines += 1;
+        if self.flush {
+            self.file.flush()?;
+        }
+        Ok(())
+    }
+}
+
+impl Sink for Recorder {
+    /// Records a chunk of output.
+    ///
+    /// Events have to be valid UTF-8 so incomplete characters at the end of the
+    /// chunk are held back until the rest arrives.
+    fn write_chunk(&mut self, _ts: Duration, data: &[u8]) -> io::Result<()> {
+        self.pending.extend_from_slice(data);
+        let text = decode_utf8(&mut self.pending);
+        if text.is_empty() {
+            return Ok(());
+        }
+        self.write_event("o", &text)
+    }
+
+    /// Records input if requested and changes of the size.
+    fn event(&mut self, event: &SinkEvent) -> io::Result<()> {
+        match *event {
+            SinkEvent::InputInjected(ref data) | SinkEvent::InputTyped(ref data) if self.input => {
+                self.write_event("i", &String::from_utf8_lossy(data))
+            }
+            // the session reports the size it starts with as well
+            SinkEvent::Resized { rows, cols } if (rows, cols) != self.size => {
+                self.size = (rows, cols);
+                self.write_event("r", &format!("{}x{}", cols, rows))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Writes what's still held back.
+    fn finish(&mut self) -> io::Result<()> {
+        if !self.pending.is_empty() {
+            let text = String::from_utf8_lossy(&std::mem::take(&mut self.pending)).into_owned();
+            self.write_event("o", &text)?;
+        }
+        self.file.flush()
+    }
+}
+
+/// An event in a recording.
+#[derive(Debug, Clone)]
+pub struct Event {
+    /// Seconds since the start of the recording.
+    pub time: f64,
+    /// The type of the event (`o` for output, `i` for input, `r` for a resize).
+    pub kind: String,
+    pub data: String,
+}
+
+impl Event {
+    /// Returns the new size of the terminal as rows and columns for a resize.
+    pub fn size(&self) -> Option<(u16, u16)> {
+        if self.kind != "r" {
+            return None;
+        }
+        parse_size(&self.data)
+    }
+}
+
+/// A recording loaded from a file.
+#[derive(Debug, Clone)]
+pub struct Recording {
+    pub width: u16,
+    pub height: u16,
+    pub events: Vec<Event>,
+}
+
+impl Recording {
+    /// Loads a recording from a file.
+    pub fn load(path: &Path) -> Result<Recording, Error> {
+        let source = fs::read_to_string(path)
+            .with_context(|| format!("could not read recording {}", path.display()))?;
+        Recording::parse(&source).with_context(|| format!("invalid recording {}", path.display()))
+    }
+
+    /// Loads the events of a recording between `from` and `to` seconds.
+    ///
+    /// If the recording has an index, reading starts at the last entry before
+    /// `from` and stops after `to`.  The size of the recording is the size of
+    /// the terminal at `from`.  ttyrec recordings are read as well.
+    pub fn load_range(path: &Path, from: f64, to: Option<f64>) -> Result<Recording, Error> {
+        let context = || format!("invalid recording {}", path.display());
+        let file = File::open(path)
+            .with_context(|| format!("could not read recording {}", path.display()))?;
+        let mut reader = BufReader::new(file);
+        if reader.fill_buf()?.first().is_some_and(|&c| c != b'{') {
+            let mut data = Vec::new();
+            reader.read_to_end(&mut data)?;
+            return Recording::from_ttyrec(&data, from, to).with_context(context);
+        }
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Err(Error::msg("recording is empty")).with_context(context);
+        }
+        let mut recording = Recording::parse_header(&line).with_context(context)?;
+        let mut lineno = 1;
+        if let Some(entry) = seek_index(&index_path(path), from) {
+            reader.seek(SeekFrom::Start(entry.offset))?;
+            // a recording which was replaced without its index would be read
+            // from the wrong place
+            if reader.fill_buf()?.first() == Some(&b'[') {
+                lineno = entry.lineno - 1;
+                if let Some((rows, cols)) = entry.size {
+                    (recording.height, recording.width) = (rows, cols);
+                }
+            } else {
+                reader.seek(SeekFrom::Start(line.len() as u64))?;
+            }
+        }
+        loop {
+            line.clear();
+            if reader.read_line(&mut line)? == 0 {
+                break;
+            }
+            lineno += 1;
+            let Some(event) = parse_event(&line, lineno).with_context(context)? else {
+                continue;
+            };
+            if to.is_some_and(|to| event.time > to) {
+                break;
+            }
+            if event.time >= from {
+                recording.events.push(event);
+            } else if let Some((rows, cols)) = event.size() {
+                (recording.height, recording.width) = (rows, cols);
+            }
+        }
+        Ok(recording)
+    }
+
+    /// Parses a recording.
+    pub fn parse(source: &str) -> Result<Recording, Error> {
+        let mut lines = source.lines().enumerate();
+        let mut recording = match lines.next() {
+            Some((_, line)) => Recording::parse_header(line)?,
+            None => bail!("recording is empty"),
+        };
+        for (idx, line) in lines {
+            if let Some(event) = parse_event(line, idx + 1)? {
+                recording.events.push(event);
+            }
+        }
+        Ok(recording)
+    }
+
+    /// Reads a ttyrec recording.  It doesn't know the size of the terminal.
+    fn from_ttyrec(data: &[u8], from: f64, to: Option<f64>) -> Result<Recording, Error> {
+        let mut recording = Recording {
+            width: 0,
+            height: 0,
+            events: Vec::new(),
+        };
+        let mut pending = Vec::new();
+        for (time, chunk) in ttyrec::parse(data)? {
+            if to.is_some_and(|to| time > to) {
+                break;
+            }
+            pending.extend_from_slice(chunk);
+            let data = decode_utf8(&mut pending);
+            if time >= from && !data.is_empty() {
+                recording.events.push(Event {
+                    time,
+                    kind: "o".into(),
+                    data,
+                });
+            }
+        }
+        Ok(recording)
+    }
+
+    fn parse_header(line: &str) -> Result<Recording, Error> {
+        let header = json::parse(line).context("line 1")?;
+        if header.get("version").and_then(Value::as_f64) != Some(2.0) {
+            bail!("only asciicast v2 recordings are supported");
+        }
+        let dimension = |key| {
+            header
+                .get(key)
+                .and_then(Value::as_f64)
+                .map_or(0, |x| x as u16)
+        };
+        Ok(Recording {
+            width: dimension("width"),
+            height: dimension("height"),
+            events: Vec::new(),
+        })
+    }
+
+    /// Returns the output events.
+    pub fn output(&self) -> impl Iterator<Item = &Event> {
+        self.events.iter().filter(|x| x.kind == "o")
+    }
+
+    /// Returns the input events.
+    pub fn input(&self) -> impl Iterator<Item = &Event> {
+        self.events.iter().filter(|x| x.kind == "i")
+    }
+}
+
+/// Decodes the bytes as UTF-8 with invalid ones replaced.  An incomplete
+/// character at the end is left in `pending`.
+fn decode_utf8(pending: &mut Vec<u8>) -> String {
+    let mut text = String::new();
+    let mut rest = &pending[..];
+    while !rest.is_empty() {
+        match std::str::from_utf8(rest) {
+            Ok(s) => {
+                text.push_str(s);
+                rest = &[];
+            }
+            Err(err) => {
+                let (valid, invalid) = rest.split_at(err.valid_up_to());
+                text.push_str(std::str::from_utf8(valid).unwrap());
+                match err.error_len() {
+                    Some(len) => {
+                        text.push(char::REPLACEMENT_CHARACTER);
+                        rest = &invalid[len..];
+                    }
+                    None => {
+                        rest = invalid;
+                        break;
+                    }
+                }
+            }
+        }
+    }
+    *pending = rest.to_vec();
+    text
+}
+
+/// Parses an event line, blank lines are skipped.
+fn parse_event(line: &str, lineno: usize) -> Result<Option<Event>, Error> {
+    if line.trim().is_empty() {
+        return Ok(None);
+    }
+    let value = json::parse(line).with_context(|| format!("line {lineno}"))?;
+    match value.as_array() {
+        Some([time, kind, data]) => Ok(Some(Event {
+            time: time
+                .as_f64()
+                .ok_or_else(|| Error::msg(format!("line {lineno}: invalid time")))?,
+            kind: kind.as_str().unwrap_or_default().to_string(),
+            data: data.as_str().unwrap_or_default().to_string(),
+        })),
+        _ => bail!("line {}: invalid event", lineno),
+    }
+}
+
+/// Parses the size of a resize event, `<cols>x<rows>`.
+fn parse_size(data: &str) -> Option<(u16, u16)> {
+    let (cols, rows) = data.split_once('x')?;
+    Some((rows.parse().ok()?, cols.parse().ok()?))
+}
+
+/// An entry of the index of a recording.
+struct IndexEntry {
+    offset: u64,
+    lineno: usize,
+    /// The size of the terminal as rows and columns.  Older indexes don't have
+    /// it.
+    size: Option<(u16, u16)>,
+}
+
+/// Looks up the entry to start reading at for events from `from` seconds on.
+fn seek_index(path: &Path, from: f64) -> Option<IndexEntry> {
+    if from <= 0.0 {
+        return None;
+    }
+    let index = fs::read_to_string(path).ok()?;
+    let mut found = None;
+    for entry in index.lines() {
+        let mut fields = entry.split(' ');
+        let (Some(Ok(time)), Some(Ok(offset)), Some(Ok(lineno))) = (
+            fields.next().map(str::parse::<f64>),
+            fields.next().map(str::parse),
+            fields.next().map(str::parse),
+        ) else {
+            break;
+        };
+        if time > from {
+            break;
+        }
+        found = Some(IndexEntry {
+            offset,
+            lineno,
+            size: fields.next().and_then(parse_size),
+        });
+    }
+    found
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_recorder_roundtrip() {
+        let path = std::env::temp_dir().join(format!("teetty-test-{}.cast", std::process::id()));
+        let mut recorder = Recorder::create(&path, None, &["echo".into()], false, true).unwrap();
+        recorder
+            .write_chunk(Duration::ZERO, b"hello \xe2\x82")
+            .unwrap();
+        recorder
+            .write_chunk(Duration::ZERO, b"\xac\r\n\xff")
+            .unwrap();
+        recorder
+            .event(&SinkEvent::InputTyped(b"q".to_vec()))
+            .unwrap();
+        for (rows, cols) in [(24, 80), (30, 100)] {
+            recorder.event(&SinkEvent::Resized { rows, cols }).unwrap();
+        }
+        recorder.finish().unwrap();
+        let recording = Recording::load(&path).unwrap();
+        fs::remove_file(&path).ok();
+        fs::remove_file(index_path(&path)).ok();
+        assert_eq!((recording.width, recording.height), (80, 24));
+        let output = recording.output().map(|x| &x.data[..]).collect::<Vec<_>>();
+        assert_eq!(output, ["hello ", "\u{20ac}\r\n\u{fffd}"]);
+        assert_eq!(
+            recording.input().map(|x| &x.data[..]).collect::<Vec<_>>(),
+            ["q"]
+        );
+        let sizes = recording.events.iter().filter_map(Event::size);
+        assert_eq!(sizes.collect::<Vec<_>>(), [(30, 100)]);
+    }
+
+    #[test]
+    fn test_load_range() {
+        let path = std::env::temp_dir().join(format!("teetty-range-{}.cast", std::process::id()));
+        let header = "{\"version\":2,\"width\":80,\"height\":24}\n";
+        let events = [
+            "[1.0,\"o\",\"a\"]\n",
+            "[11.0,\"o\",\"b\"]\n",
+            "[21.0,\"o\",\"c\"]\n",
+        ];
+        fs::write(
+            &path,
+            [header].iter().chain(&events).copied().collect::<String>(),
+        )
+        .unwrap();
+        // the index points at the second event for the first one to see that
+        // reading starts there
+        let offset = header.len() + events[0].len();
+        fs::write(index_path(&path), format!("1.0 {} 2 100x30\n", offset)).unwrap();
+        let recording = Recording::load_range(&path, 5.0, Some(15.0)).unwrap();
+        assert_eq!((recording.width, recording.height), (100, 30));
+        let output = recording.output().map(|x| &x.data[..]).collect::<Vec<_>>();
+        assert_eq!(output, ["b"]);
+        let recording = Recording::load_range(&path, 1.0, None).unwrap();
+        let output = recording.output().map(|x| &x.data[..]).collect::<Vec<_>>();
+        assert_eq!(output, ["b", "c"]);
+        // an index which doesn't match the recording is ignored
+        fs::write(index_path(&path), "1.0 3 2\n").unwrap();
+        let recording = Recording::load_range(&path, 1.0, None).unwrap();
+        fs::remove_file(&path).ok();
+        fs::remove_file(index_path(&path)).ok();
+        assert_eq!(recording.events.len(), 3);
+    }
+}
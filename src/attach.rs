@@ -0,0 +1,106 @@
+//! Watching a named session from another terminal.
+use std::io::Write;
+use std::os::fd::AsRawFd;
+use std::os::unix::net::UnixStream;
+
+use anyhow::{bail, Error};
+use nix::libc::{STDIN_FILENO, STDOUT_FILENO};
+use nix::sys::select::{select, FdSet};
+use nix::sys::termios::tcgetattr;
+use nix::unistd::read;
+use teetty::control::{read_frame, FRAME_OUTPUT, FRAME_RESIZE};
+use teetty::escape::{EscapeAction, EscapeDetector};
+use teetty::registry::{lookup, socket_path};
+use teetty::spawn::{get_winsize, write_all_fd};
+use teetty::term::RestoreTerm;
+
+/// Watches the output of a named session without being able to send input.
+///
+/// Viewing ends with the escape sequence `~.`, with `^C` or when the session
+/// ends.
+pub fn attach_read_only(name: &str, escape_char: Option<u8>) -> Result<i32, Error> {
+    let info = lookup(name)?;
+    let mut stream = match UnixStream::connect(socket_path(name)?) {
+        Ok(stream) => stream,
+        Err(err) => bail!("could not connect to session '{}': {}", info.name, err),
+    };
+    stream.write_all(b"attach read-only\n")?;
+
+    let term_attrs = tcgetattr(STDIN_FILENO).ok();
+    let _restore_term = term_attrs.as_ref().map(RestoreTerm::raw);
+    let newline = if term_attrs.is_some() { "\r\n" } else { "\n" };
+    let mut escape = escape_char.map(EscapeDetector::new);
+    let mut discarded = Vec::new();
+    let mut read_stdin = term_attrs.is_some();
+    let mut buf = [0; 1024];
+
+    eprint!(
+        "[watching session '{}' read-only, {} to stop]{}",
+        info.name,
+        match escape_char {
+            Some(c) => format!("{}. or ^C", c as char),
+            None => "^C".into(),
+        },
+        newline
+    );
+
+    'outer: loop {
+        let mut read_fds = FdSet::new();
+        read_fds.insert(stream.as_raw_fd());
+        if read_stdin {
+            read_fds.insert(STDIN_FILENO);
+        }
+        match select(None, Some(&mut read_fds), None, None, None) {
+            Ok(_) => {}
+            Err(nix::errno::Errno::EINTR) => continue,
+            Err(err) => return Err(err.into()),
+        }
+
+        // keyboard input is discarded except for the ways to stop viewing
+        if read_fds.contains(STDIN_FILENO) {
+            let n = read(STDIN_FILENO, &mut buf)?;
+            if n == 0 {
+                read_stdin = false;
+            }
+            for &c in &buf[..n] {
+                if c == 0x03 {
+                    break 'outer;
+                }
+                if let Some(ref mut escape) = escape {
+                    discarded.clear();
+                    if let Some(EscapeAction::Detach) = escape.feed(c, &mut discarded) {
+                        break 'outer;
+                    }
+                }
+            }
+        }
+
+        if read_fds.contains(stream.as_raw_fd()) {
+            match read_frame(&mut stream)? {
+                Some((FRAME_OUTPUT, data)) => write_all_fd(STDOUT_FILENO, &data)?,
+                Some((FRAME_RESIZE, size)) => {
+                    // ask the terminal to match the size of the session.  Terminals
+                    // that don't support this ignore the request.
+                    let size = String::from_utf8_lossy(&size);
+                    let Some((rows, cols)) = size.split_once(' ') else {
+                        continue;
+                    };
+                    if term_attrs.is_some()
+                        && get_winsize(STDIN_FILENO).is_some_and(|ws| {
+                            ws.ws_row.to_string() != rows || ws.ws_col.to_string() != cols
+                        })
+                    {
+                        write_all_fd(STDOUT_FILENO, format!("\x1b[8;{rows};{cols}t").as_bytes())?;
+                    }
+                }
+                Some(_) => {}
+                None => {
+                    eprint!("[session '{}' ended]{}", info.name, newline);
+                    break;
+                }
+            }
+        }
+    }
+
+    Ok(0)
+}
@@ -0,0 +1,239 @@
+//! Tamper-evident logs of sessions.
+//!
+//! With `--audit <path>` every chunk of output, all input, changes of the size,
+//! idle periods and the exit of the program are appended to the file as JSON records, one
+//! per line.  Every record holds the SHA-256 digest of the line before it in
+//! `prev`, so changing, removing or reordering records breaks the chain from
+//! there on.  [`verify`] (`teetty audit-verify`) checks the chain.  Appending to
+//! an existing log continues its chain.
+//!
+//! The chain alone can't tell if records were removed from the end or if the
+//! whole log was written anew.  The [`Head`] of the chain, the number and the
+//! digest of the last record, is appended to a separate file when a session
+//! ends and has to be checked against the log to catch that.  The head file
+//! should live where the writer of the log can't change it.
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::os::fd::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{Duration, Instant, SystemTime};
+
+use anyhow::{bail, Context, Error};
+use nix::fcntl::{flock, FlockArg};
+
+use crate::base64;
+use crate::hash::{hex, sha256};
+use crate::json::{self, Value};
+use crate::sink::{Event, Sink};
+use crate::time::format_utc;
+
+/// The `prev` of the first record of a chain.
+const GENESIS: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// The end of a chain: the sequence number and the digest of the last record.
+/// It's written as `SEQ:DIGEST`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Head {
+    pub seq: u64,
+    pub digest: String,
+}
+
+impl fmt::Display for Head {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.seq, self.digest)
+    }
+}
+
+impl FromStr for Head {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Head, String> {
+        let (seq, digest) = s.trim().split_once(':').ok_or("expected SEQ:DIGEST")?;
+        let seq = seq.parse().map_err(|_| "invalid sequence number")?;
+        if digest.len() != GENESIS.len() || !digest.bytes().all(|c| c.is_ascii_hexdigit()) {
+            return Err("invalid digest".into());
+        }
+        Ok(Head {
+            seq,
+            digest: digest.to_ascii_lowercase(),
+        })
+    }
+}
+
+/// Appends the records of a session to an audit log.
+pub struct AuditLog {
+    file: File,
+    started: Instant,
+    seq: u64,
+    /// The digest of the last record.
+    prev: String,
+    head_path: Option<PathBuf>,
+}
+
+impl AuditLog {
+    /// Opens the audit log for appending.  The log is locked so that only one
+    /// session at a time writes to it.  If `head_path` is given the [`Head`]
+    /// is appended to it when the session ends.
+    pub fn open(path: &Path, head_path: Option<&Path>) -> Result<AuditLog, Error> {
+        let file = File::options()
+            .append(true)
+            .create(true)
+            .open(path)
+            .with_context(|| format!("could not open audit log {}", path.display()))?;
+        if flock(file.as_raw_fd(), FlockArg::LockExclusiveNonblock).is_err() {
+            bail!("audit log {} is used by another session", path.display());
+        }
+        let existing = fs::read_to_string(path)
+            .with_context(|| format!("could not read audit log {}", path.display()))?;
+        let (seq, prev) = match existing.lines().rev().find(|x| !x.is_empty()) {
+            Some(line) => {
+                let seq = json::parse(line)
+                    .ok()
+                    .and_then(|x| x.get("seq").and_then(Value::as_f64))
+                    .with_context(|| format!("invalid last record in {}", path.display()))?;
+                (seq as u64, hex(&sha256(line.as_bytes())))
+            }
+            None => (0, GENESIS.to_string()),
+        };
+        Ok(AuditLog {
+            file,
+            started: Instant::now(),
+            seq,
+            prev,
+            head_path: head_path.map(Path::to_path_buf),
+        })
+    }
+
+    /// Returns the end of the chain so far.
+    pub fn head(&self) -> Head {
+        Head {
+            seq: self.seq,
+            digest: self.prev.clone(),
+        }
+    }
+
+    fn append(&mut self, kind: &str, data: &[u8]) -> io::Result<()> {
+        self.seq += 1;
+        let line = format!(
+            "{{\"seq\":{},\"time\":{},\"elapsed\":{:.6},\"type\":{},\"data\":{},\"prev\":{}}}",
+            self.seq,
+            json::quote(&format_utc(SystemTime::now())),
+            self.started.elapsed().as_secs_f64(),
+            json::quote(kind),
+            json::quote(&base64::encode(data)),
+            json::quote(&self.prev),
+        );
+        self.file.write_all(format!("{}\n", line).as_bytes())?;
+        self.prev = hex(&sha256(line.as_bytes()));
+        Ok(())
+    }
+}
+
+impl Sink for AuditLog {
+    fn write_chunk(&mut self, _ts: Duration, bytes: &[u8]) -> io::Result<()> {
+        self.append("o", bytes)
+    }
+
+    fn event(&mut self, event: &Event) -> io::Result<()> {
+        match *event {
+            Event::InputInjected(ref data) | Event::InputTyped(ref data) => self.append("i", data),
+            Event::Resized { rows, cols } => {
+                self.append("r", format!("{}x{}", cols, rows).as_bytes())
+            }
+            Event::ChildExited(code) => self.append("x", code.to_string().as_bytes()),
+            Event::Idle => self.append("idle", b""),
+            Event::Active => self.append("active", b""),
+            _ => Ok(()),
+        }
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.file.sync_all()?;
+        if let Some(ref path) = self.head_path {
+            let mut file = File::options().append(true).create(true).open(path)?;
+            writeln!(file, "{}", self.head())?;
+            file.sync_all()?;
+        }
+        Ok(())
+    }
+}
+
+/// Checks the chain of an audit log and returns its head.  With `expected`
+/// the log has to end there, which catches removed trailing records and logs
+/// that were written anew.
+pub fn verify(path: &Path, expected: Option<&Head>) -> Result<Head, Error> {
+    let log = fs::read_to_string(path)
+        .with_context(|| format!("could not read audit log {}", path.display()))?;
+    let mut prev = GENESIS.to_string();
+    let mut last_seq = None;
+    for (idx, line) in log.lines().enumerate() {
+        if line.is_empty() {
+            continue;
+        }
+        let lineno = idx + 1;
+        let record = json::parse(line).with_context(|| format!("line {}", lineno))?;
+        let seq = record.get("seq").and_then(Value::as_f64).map(|x| x as u64);
+        if record.get("prev").and_then(Value::as_str) != Some(&prev) {
+            bail!(
+                "line {}: the chain is broken, the record before was changed or removed",
+                lineno
+            );
+        }
+        if seq.is_none() || last_seq.is_some_and(|x: u64| seq != Some(x + 1)) {
+            bail!("line {}: unexpected sequence number", lineno);
+        }
+        last_seq = seq;
+        prev = hex(&sha256(line.as_bytes()));
+    }
+    let head = Head {
+        seq: last_seq.unwrap_or(0),
+        digest: prev,
+    };
+    if expected.is_some_and(|x| *x != head) {
+        bail!(
+            "the log ends at {} instead of the expected head, records were removed or it was rewritten",
+            head
+        );
+    }
+    Ok(head)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chain() {
+        let path = std::env::temp_dir().join(format!("teetty-audit-{}.jsonl", std::process::id()));
+        let head_path = path.with_extension("head");
+        fs::remove_file(&path).ok();
+        fs::remove_file(&head_path).ok();
+        for _ in 0..2 {
+            let mut log = AuditLog::open(&path, Some(&head_path)).unwrap();
+            log.write_chunk(Duration::ZERO, b"$ ").unwrap();
+            log.event(&Event::InputTyped(b"ls\r".to_vec())).unwrap();
+            log.finish().unwrap();
+        }
+        let heads = fs::read_to_string(&head_path).unwrap();
+        let head: Head = heads.lines().last().unwrap().parse().unwrap();
+        assert_eq!(head.seq, 4);
+        assert_eq!(verify(&path, Some(&head)).unwrap(), head);
+        let log = fs::read_to_string(&path).unwrap();
+        // removing the last record keeps the chain intact but not the head
+        let truncated = log
+            .lines()
+            .take(3)
+            .map(|x| format!("{}\n", x))
+            .collect::<String>();
+        fs::write(&path, truncated).unwrap();
+        assert_eq!(verify(&path, None).unwrap().seq, 3);
+        assert!(verify(&path, Some(&head)).is_err());
+        fs::write(&path, log.replacen("\"type\":\"i\"", "\"type\":\"o\"", 1)).unwrap();
+        let err = verify(&path, None).unwrap_err();
+        fs::remove_file(&path).ok();
+        fs::remove_file(&head_path).ok();
+        assert!(err.to_string().starts_with("line 3:"));
+    }
+}
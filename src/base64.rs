@@ -0,0 +1,69 @@
+//! Base64 encoding (RFC 4648 with padding).
+
+const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Encodes the data as base64.
+pub fn encode(data: &[u8]) -> String {
+    let mut rv = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let b = [
+            chunk[0],
+            chunk.get(1).copied().unwrap_or(0),
+            chunk.get(2).copied().unwrap_or(0),
+        ];
+        let n = (u32::from(b[0]) << 16) | (u32::from(b[1]) << 8) | u32::from(b[2]);
+        for i in 0..4 {
+            if i <= chunk.len() {
+                rv.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                rv.push('=');
+            }
+        }
+    }
+    rv
+}
+
+/// Decodes base64.  Padding is optional.  Returns `None` for invalid input.
+pub fn decode(data: &[u8]) -> Option<Vec<u8>> {
+    let data = data
+        .strip_suffix(b"==")
+        .or(data.strip_suffix(b"="))
+        .unwrap_or(data);
+    let mut rv = Vec::with_capacity(data.len() / 4 * 3 + 2);
+    for chunk in data.chunks(4) {
+        if chunk.len() == 1 {
+            return None;
+        }
+        let mut n = 0;
+        for (i, &c) in chunk.iter().enumerate() {
+            let value = ALPHABET.iter().position(|&x| x == c)? as u32;
+            n |= value << (18 - 6 * i);
+        }
+        rv.extend_from_slice(&n.to_be_bytes()[1..chunk.len()]);
+    }
+    Some(rv)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode() {
+        assert_eq!(encode(b""), "");
+        assert_eq!(encode(b"f"), "Zg==");
+        assert_eq!(encode(b"fo"), "Zm8=");
+        assert_eq!(encode(b"foo"), "Zm9v");
+        assert_eq!(encode(b"foobar"), "Zm9vYmFy");
+    }
+
+    #[test]
+    fn test_decode() {
+        for data in [&b""[..], b"f", b"fo", b"foo", b"foobar", b"\xff\x00\x80"] {
+            assert_eq!(decode(encode(data).as_bytes()).as_deref(), Some(data));
+        }
+        assert_eq!(decode(b"Zm8").as_deref(), Some(&b"fo"[..]));
+        assert_eq!(decode(b"Z"), None);
+        assert_eq!(decode(b"Zm9v!"), None);
+    }
+}
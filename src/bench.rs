@@ -0,0 +1,68 @@
+//! Measuring the throughput of sessions.
+use std::io;
+use std::time::{Duration, Instant};
+
+use anyhow::Error;
+use teetty::sink::Sink;
+use teetty::spawn::{spawn, SpawnOptions};
+use teetty::transform::{StripAnsi, Timestamps};
+
+/// A line of output with a bit of color so that the transformations have
+/// something to do.
+const LINE: &str = "teetty bench \x1b[32m0123456789\x1b[0m abcdefghijklmnopqrstuvwxyz";
+
+/// Changes the options of a run for one of the setups.
+type Setup = fn(&mut SpawnOptions);
+
+/// Counts the output.
+struct CountingSink(usize);
+
+impl Sink for CountingSink {
+    fn write_chunk(&mut self, _ts: Duration, bytes: &[u8]) -> io::Result<()> {
+        self.0 += bytes.len();
+        Ok(())
+    }
+}
+
+/// Runs a program which writes `size` bytes as fast as it can through
+/// sessions with different setups and prints the throughput of each.
+pub fn bench(size: usize) -> Result<i32, Error> {
+    let out_path = std::env::temp_dir().join(format!("teetty-bench-{}.log", std::process::id()));
+    // everything but the plain run also writes the output file
+    let scenarios: [(&str, Setup); 5] = [
+        ("plain", |opts| opts.out_path = None),
+        ("output file", |_| {}),
+        ("out filter", |opts| opts.out_filter = Some("cat".into())),
+        ("sinks", |opts| {
+            opts.sinks.push(Box::new(CountingSink(0)));
+            opts.sinks.push(Box::new(CountingSink(0)));
+        }),
+        ("transforms", |opts| {
+            opts.transforms.push(Box::new(StripAnsi::new()));
+            opts.transforms.push(Box::new(Timestamps::new()));
+        }),
+    ];
+    println!("{:<12} {:>9} {:>9}", "setup", "seconds", "MB/s");
+    for (name, setup) in scenarios {
+        let script = format!("yes '{}' | head -c {}", LINE, size);
+        let mut opts = SpawnOptions::new(["sh", "-c", &script]);
+        opts.headless = true;
+        opts.out_path = Some(out_path.clone());
+        opts.truncate_out = true;
+        setup(&mut opts);
+        let started = Instant::now();
+        let code = spawn(opts)?;
+        let elapsed = started.elapsed().as_secs_f64();
+        if code != 0 {
+            eprintln!("teetty: the benchmark program failed ({})", code);
+        }
+        println!(
+            "{:<12} {:>9.3} {:>9.1}",
+            name,
+            elapsed,
+            size as f64 / elapsed / 1_000_000.0
+        );
+    }
+    std::fs::remove_file(&out_path).ok();
+    Ok(0)
+}
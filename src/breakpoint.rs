@@ -0,0 +1,96 @@
+//! Stopping the program when its output matches a pattern.
+//!
+//! With `--break-on <regex>` the program is stopped with `SIGSTOP` as soon as
+//! its output matches and teetty asks on the terminal how to go on: `c`
+//! continues until the next match, `s` steps to the next chunk of output and
+//! `k` kills the program.
+use crate::pattern::Pattern;
+
+/// How much of the most recent output is matched against the pattern.
+const WINDOW_SIZE: usize = 4096;
+
+/// Shown on the terminal when the program was stopped.
+pub const PROMPT: &[u8] = b"\r\n\x1b[7m[teetty: stopped, (c)ontinue, (s)tep or (k)ill?]\x1b[0m\r\n";
+
+/// What the user chose to do with the stopped program.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resume {
+    Continue,
+    Step,
+    Kill,
+}
+
+/// Watches the output for the breakpoint.
+pub struct Breakpoint<'a> {
+    pattern: &'a Pattern,
+    window: Vec<u8>,
+    stopped: bool,
+    step: bool,
+}
+
+impl<'a> Breakpoint<'a> {
+    pub fn new(pattern: &'a Pattern) -> Breakpoint<'a> {
+        Breakpoint {
+            pattern,
+            window: Vec::new(),
+            stopped: false,
+            step: false,
+        }
+    }
+
+    /// Returns `true` while the program is stopped.
+    pub fn is_stopped(&self) -> bool {
+        self.stopped
+    }
+
+    /// Feeds output of the program.  Returns `true` if the program has to be
+    /// stopped.
+    pub fn feed(&mut self, data: &[u8]) -> bool {
+        // output the program wrote before it was stopped is still coming in
+        if self.stopped || data.is_empty() {
+            return false;
+        }
+        self.window.extend_from_slice(data);
+        if self.window.len() > WINDOW_SIZE {
+            self.window.drain(..self.window.len() - WINDOW_SIZE);
+        }
+        if self.step || self.pattern.is_match(&self.window) {
+            self.window.clear();
+            self.stopped = true;
+            self.step = false;
+        }
+        self.stopped
+    }
+
+    /// Handles a key typed while the program is stopped.
+    pub fn key(&mut self, c: u8) -> Option<Resume> {
+        let resume = match c.to_ascii_lowercase() {
+            b'c' => Resume::Continue,
+            b's' => Resume::Step,
+            b'k' => Resume::Kill,
+            _ => return None,
+        };
+        self.stopped = false;
+        self.step = resume == Resume::Step;
+        Some(resume)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_breakpoint() {
+        let pattern = Pattern::new("ERR").unwrap();
+        let mut breakpoint = Breakpoint::new(&pattern);
+        assert!(!breakpoint.feed(b"ok\r\nE"));
+        assert!(breakpoint.feed(b"RR\r\n"));
+        assert!(!breakpoint.feed(b"more"));
+        assert_eq!(breakpoint.key(b'x'), None);
+        assert_eq!(breakpoint.key(b's'), Some(Resume::Step));
+        assert!(breakpoint.feed(b"next"));
+        assert_eq!(breakpoint.key(b'c'), Some(Resume::Continue));
+        assert!(!breakpoint.feed(b"next"));
+    }
+}
@@ -0,0 +1,288 @@
+//! Fans the output of a session out to any number of followers.
+//!
+//! Followers are written to without blocking.  What a follower can't take right
+//! away is queued for it so that a slow follower never holds up the session or
+//! the other followers.  A follower that falls too far behind is disconnected.
+use std::io::{self, Read, Write};
+use std::os::fd::AsRawFd;
+
+use nix::fcntl::{fcntl, FcntlArg, OFlag};
+
+use crate::control::{encode_frame, FRAME_EVENT, FRAME_OUTPUT, FRAME_RESIZE};
+use crate::json;
+use crate::poll::PollSet;
+use crate::websocket::{self, OPCODE_BINARY, OPCODE_TEXT};
+
+/// How much output is queued for a follower before it's disconnected.
+const MAX_PENDING: usize = 4 * 1024 * 1024;
+
+/// How followers want to receive the output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// The raw output.
+    Raw,
+    /// Frames with the output and the terminal size.
+    Framed,
+    /// WebSocket messages with the output and the terminal size.
+    WebSocket,
+}
+
+impl Format {
+    fn encode_output(self, data: &[u8]) -> Vec<u8> {
+        match self {
+            Format::Raw => data.to_vec(),
+            Format::Framed => encode_frame(FRAME_OUTPUT, data),
+            Format::WebSocket => websocket::encode_frame(OPCODE_BINARY, data),
+        }
+    }
+
+    fn encode_resize(self, rows: u16, cols: u16) -> Option<Vec<u8>> {
+        match self {
+            Format::Raw => None,
+            Format::Framed => Some(encode_frame(
+                FRAME_RESIZE,
+                format!("{} {}", rows, cols).as_bytes(),
+            )),
+            Format::WebSocket => Some(websocket::encode_frame(
+                OPCODE_TEXT,
+                format!(r#"{{"type":"resize","rows":{},"cols":{}}}"#, rows, cols).as_bytes(),
+            )),
+        }
+    }
+
+    fn encode_event(self, name: &str) -> Option<Vec<u8>> {
+        match self {
+            Format::Raw => None,
+            Format::Framed => Some(encode_frame(FRAME_EVENT, name.as_bytes())),
+            Format::WebSocket => Some(websocket::encode_frame(
+                OPCODE_TEXT,
+                format!(r#"{{"type":{}}}"#, json::quote(name)).as_bytes(),
+            )),
+        }
+    }
+}
+
+/// Something followers can be connected through.
+pub trait Stream: Read + Write + AsRawFd {}
+
+impl<T: Read + Write + AsRawFd> Stream for T {}
+
+struct Follower {
+    stream: Box<dyn Stream>,
+    format: Format,
+    pending: Vec<u8>,
+    /// Set once the follower closed its side of the connection.  It might
+    /// still want the output though.
+    closed: bool,
+}
+
+impl Follower {
+    /// Sends data to the follower.  Returns `false` if the follower is gone or
+    /// fell behind too far.
+    fn send(&mut self, data: &[u8]) -> bool {
+        if self.pending.is_empty() {
+            match write_some(&mut *self.stream, data) {
+                Some(n) => self.pending.extend_from_slice(&data[n..]),
+                None => return false,
+            }
+        } else {
+            self.pending.extend_from_slice(data);
+        }
+        self.pending.len() <= MAX_PENDING
+    }
+
+    /// Writes as much of the queued data as possible.
+    fn flush(&mut self) -> bool {
+        match write_some(&mut *self.stream, &self.pending) {
+            Some(n) => {
+                self.pending.drain(..n);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// Writes as much as possible without blocking.  Returns `None` on errors.
+fn write_some(stream: &mut dyn Stream, mut data: &[u8]) -> Option<usize> {
+    let mut written = 0;
+    while !data.is_empty() {
+        match stream.write(data) {
+            Ok(0) => return None,
+            Ok(n) => {
+                written += n;
+                data = &data[n..];
+            }
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+            Err(_) => return None,
+        }
+    }
+    Some(written)
+}
+
+/// The followers of a session.
+#[derive(Default)]
+pub struct Broadcaster {
+    followers: Vec<Follower>,
+    winsize: Option<(u16, u16)>,
+}
+
+impl Broadcaster {
+    /// Adds a follower.
+    pub fn add<S: Stream + 'static>(&mut self, stream: S, format: Format) -> io::Result<()> {
+        self.add_with_head(stream, format, &[])
+    }
+
+    /// Adds a follower which is sent `head` before anything else, such as the
+    /// response to its WebSocket handshake.
+    pub fn add_with_head<S: Stream + 'static>(
+        &mut self,
+        stream: S,
+        format: Format,
+        head: &[u8],
+    ) -> io::Result<()> {
+        let flags = OFlag::from_bits_truncate(fcntl(stream.as_raw_fd(), FcntlArg::F_GETFL)?);
+        fcntl(
+            stream.as_raw_fd(),
+            FcntlArg::F_SETFL(flags | OFlag::O_NONBLOCK),
+        )?;
+        let mut follower = Follower {
+            stream: Box::new(stream),
+            format,
+            pending: Vec::new(),
+            closed: false,
+        };
+        if !head.is_empty() && !follower.send(head) {
+            return Ok(());
+        }
+        if let Some(frame) = self
+            .winsize
+            .and_then(|(rows, cols)| format.encode_resize(rows, cols))
+        {
+            if !follower.send(&frame) {
+                return Ok(());
+            }
+        }
+        self.followers.push(follower);
+        Ok(())
+    }
+
+    /// Adds the fds of the followers that need watching to the set.
+    pub fn watch(&self, fds: &mut PollSet) {
+        for follower in &self.followers {
+            if !follower.closed {
+                fds.insert_read(follower.stream.as_raw_fd());
+            }
+            if !follower.pending.is_empty() {
+                fds.insert_write(follower.stream.as_raw_fd());
+            }
+        }
+    }
+
+    /// Writes queued output to the followers that are ready for it and forgets
+    /// about the followers that hung up.
+    pub fn handle(&mut self, fds: &PollSet) {
+        self.followers.retain_mut(|follower| {
+            let fd = follower.stream.as_raw_fd();
+            // followers have nothing to say.  Writes fail once they hung up.
+            if fds.readable(fd) {
+                let mut buf = [0; 1024];
+                match follower.stream.read(&mut buf) {
+                    Ok(0) => follower.closed = true,
+                    Ok(_) => {}
+                    Err(err) if err.kind() == io::ErrorKind::WouldBlock => {}
+                    Err(_) => return false,
+                }
+            }
+            !fds.writable(fd) || follower.flush()
+        });
+    }
+
+    /// Sends output of the session to all followers.
+    pub fn send_output(&mut self, data: &[u8]) {
+        let mut encoded: Vec<(Format, Vec<u8>)> = Vec::new();
+        self.followers.retain_mut(|follower| {
+            let idx = match encoded.iter().position(|(x, _)| *x == follower.format) {
+                Some(idx) => idx,
+                None => {
+                    encoded.push((follower.format, follower.format.encode_output(data)));
+                    encoded.len() - 1
+                }
+            };
+            follower.send(&encoded[idx].1)
+        });
+    }
+
+    /// Records the terminal size of the session and tells the followers about
+    /// it if it changed.
+    pub fn set_winsize(&mut self, rows: u16, cols: u16) {
+        if self.winsize == Some((rows, cols)) {
+            return;
+        }
+        self.winsize = Some((rows, cols));
+        self.followers
+            .retain_mut(|follower| match follower.format.encode_resize(rows, cols) {
+                Some(frame) => follower.send(&frame),
+                None => true,
+            });
+    }
+
+    /// Tells the followers which receive events about one, such as `idle`.
+    pub fn send_event(&mut self, name: &str) {
+        self.followers
+            .retain_mut(|follower| match follower.format.encode_event(name) {
+                Some(frame) => follower.send(&frame),
+                None => true,
+            });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::os::unix::net::UnixStream;
+    use std::time::Duration;
+
+    #[test]
+    fn test_slow_follower() {
+        let mut broadcaster = Broadcaster::default();
+        let (fast, mut fast_peer) = UnixStream::pair().unwrap();
+        let (slow, _slow_peer) = UnixStream::pair().unwrap();
+        broadcaster.add(fast, Format::Raw).unwrap();
+        broadcaster.add(slow, Format::Raw).unwrap();
+
+        let chunk = vec![b'x'; 64 * 1024];
+        let mut buf = vec![0; chunk.len()];
+        // the slow follower is dropped once the socket buffer and its queue are full
+        for _ in 0..(2 * MAX_PENDING / chunk.len()) {
+            broadcaster.send_output(&chunk);
+            // the fast follower keeps up while the slow one never reads
+            let mut received = 0;
+            while received < chunk.len() {
+                received += fast_peer.read(&mut buf[received..]).unwrap();
+                let mut fds = PollSet::new();
+                broadcaster.watch(&mut fds);
+                fds.poll(Duration::ZERO).unwrap();
+                broadcaster.handle(&fds);
+            }
+        }
+        assert_eq!(broadcaster.followers.len(), 1);
+    }
+
+    #[test]
+    fn test_send_event() {
+        let mut broadcaster = Broadcaster::default();
+        let (raw, mut raw_peer) = UnixStream::pair().unwrap();
+        let (framed, mut framed_peer) = UnixStream::pair().unwrap();
+        broadcaster.add(raw, Format::Raw).unwrap();
+        broadcaster.add(framed, Format::Framed).unwrap();
+        broadcaster.send_event("idle");
+        broadcaster.send_output(b"x");
+        let frame = crate::control::read_frame(&mut framed_peer).unwrap();
+        assert_eq!(frame, Some((FRAME_EVENT, b"idle".to_vec())));
+        let mut buf = [0; 2];
+        assert_eq!(raw_peer.read(&mut buf).unwrap(), 1);
+    }
+}
@@ -0,0 +1,150 @@
+//! Placing the program in a cgroup.
+//!
+//! With `--cgroup <path>` the program joins a cgroup (v2) between forking and
+//! executing, so that everything it spawns is accounted to it.  The cgroup is
+//! created if it doesn't exist and removed again at the end if teetty created
+//! it and nothing runs in it anymore.  With `--metadata` the peak memory usage
+//! and the CPU time of the cgroup are added to the metadata when the session
+//! ends.
+use std::fs::{self, File, OpenOptions};
+use std::os::fd::{AsRawFd, RawFd};
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Error};
+
+use crate::json;
+
+/// Where cgroup2 is usually mounted.
+const DEFAULT_MOUNT: &str = "/sys/fs/cgroup";
+
+/// A cgroup the program joins.
+pub struct Cgroup {
+    path: PathBuf,
+    /// `cgroup.procs`, opened before forking so the child only has to write.
+    procs: File,
+    /// The directories teetty created, innermost first.
+    created: Vec<PathBuf>,
+}
+
+impl Cgroup {
+    /// Opens the cgroup at `path` or creates it.  Relative paths are relative
+    /// to the root of the cgroup2 hierarchy.
+    pub fn open(path: &Path) -> Result<Cgroup, Error> {
+        if !cfg!(any(target_os = "linux", target_os = "android")) {
+            bail!("cgroups are only supported on Linux");
+        }
+        let path = mount_point().join(path);
+        let created = create(&path)?;
+        let procs = OpenOptions::new()
+            .write(true)
+            .open(path.join("cgroup.procs"))
+            .with_context(|| format!("could not open the cgroup {}", path.display()))?;
+        Ok(Cgroup {
+            path,
+            procs,
+            created,
+        })
+    }
+
+    /// The fd of `cgroup.procs`.  Writing `0` to it moves the writing process
+    /// into the cgroup.
+    pub fn procs_fd(&self) -> RawFd {
+        self.procs.as_raw_fd()
+    }
+
+    /// Returns the path, the peak memory usage in bytes and the CPU time in
+    /// microseconds as JSON.  Values the enabled controllers don't provide are
+    /// left out.
+    pub fn stats(&self) -> String {
+        let mut fields = vec![format!(
+            "\"path\":{}",
+            json::quote(&self.path.to_string_lossy())
+        )];
+        let read = |name: &str| fs::read_to_string(self.path.join(name)).ok();
+        if let Some(bytes) = read("memory.peak").and_then(|x| x.trim().parse::<u64>().ok()) {
+            fields.push(format!("\"memory_peak\":{}", bytes));
+        }
+        for line in read("cpu.stat").unwrap_or_default().lines() {
+            if let Some((key, value)) = line.split_once(' ') {
+                if ["usage_usec", "user_usec", "system_usec"].contains(&key) {
+                    fields.push(format!("\"{}\":{}", key, value.trim()));
+                }
+            }
+        }
+        format!("{{{}}}", fields.join(","))
+    }
+}
+
+impl Drop for Cgroup {
+    fn drop(&mut self) {
+        // fails if something the program started keeps running in it
+        for dir in &self.created {
+            fs::remove_dir(dir).ok();
+        }
+    }
+}
+
+/// Creates the cgroup and its parents if needed and enables the memory and cpu
+/// controllers for them where possible.  Returns the created directories,
+/// innermost first.
+fn create(path: &Path) -> Result<Vec<PathBuf>, Error> {
+    let existing = path.ancestors().find(|x| x.is_dir());
+    if !existing.is_some_and(|x| x.join("cgroup.procs").is_file()) {
+        bail!("{} is not in a cgroup2 hierarchy", path.display());
+    }
+    let mut created = Vec::new();
+    for dir in path.ancestors().collect::<Vec<_>>().into_iter().rev() {
+        if dir.is_dir() {
+            continue;
+        }
+        if let Some(parent) = dir.parent() {
+            // a controller may be unavailable, or the parent has processes of
+            // its own and can't have controllers for children
+            for controller in ["+memory", "+cpu"] {
+                fs::write(parent.join("cgroup.subtree_control"), controller).ok();
+            }
+        }
+        fs::create_dir(dir)
+            .with_context(|| format!("could not create the cgroup {}", dir.display()))?;
+        created.insert(0, dir.to_path_buf());
+    }
+    Ok(created)
+}
+
+/// Returns where the cgroup2 hierarchy is mounted.
+fn mount_point() -> PathBuf {
+    fs::read_to_string("/proc/self/mountinfo")
+        .ok()
+        .and_then(|x| parse_mount_point(&x))
+        .unwrap_or_else(|| PathBuf::from(DEFAULT_MOUNT))
+}
+
+fn parse_mount_point(mountinfo: &str) -> Option<PathBuf> {
+    mountinfo.lines().find_map(|line| {
+        let (mount, fs) = line.split_once(" - ")?;
+        if fs.split(' ').next()? != "cgroup2" {
+            return None;
+        }
+        mount.split(' ').nth(4).map(PathBuf::from)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_mount_point() {
+        let mountinfo = "\
+            26 1 0:24 / / rw - ext4 /dev/sda1 rw\n\
+            42 32 0:38 / /sys/fs/cgroup/unified rw,relatime - cgroup2 cgroup2 rw\n";
+        assert_eq!(
+            parse_mount_point(mountinfo),
+            Some(PathBuf::from("/sys/fs/cgroup/unified"))
+        );
+        assert_eq!(
+            parse_mount_point("26 1 0:24 / / rw - ext4 /dev/sda1 rw"),
+            None
+        );
+    }
+}
@@ -0,0 +1,144 @@
+//! Running several commands one after another in the same session.
+//!
+//! `teetty --chain -- make ::: make install` runs the commands with a small shell
+//! script in the pty.  A `==> <command>` line shows where every command starts
+//! and the commands are marked with OSC 133 sequences like a shell with shell
+//! integration would, so that `--segment-per-command` and `--ci` treat them as
+//! separate commands.
+use std::ffi::{OsStr, OsString};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+
+use anyhow::{bail, Error};
+
+/// Separates the commands on the command line.
+pub const SEPARATOR: &str = ":::";
+
+/// A command of the chain.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Step {
+    /// A program and its arguments.
+    Args(Vec<OsString>),
+    /// A shell command given with `--then`.
+    Shell(String),
+}
+
+/// Splits the command line at `:::` if `chain` is set and appends the
+/// `--then` commands.  Otherwise `:::` is passed on to the program like any
+/// other argument (eg: for GNU parallel).
+pub fn split(command: Vec<OsString>, then: Vec<String>, chain: bool) -> Result<Vec<Step>, Error> {
+    let mut steps = Vec::new();
+    let mut args = Vec::new();
+    for arg in command {
+        if chain && arg == SEPARATOR {
+            steps.push(Step::Args(std::mem::take(&mut args)));
+        } else {
+            args.push(arg);
+        }
+    }
+    steps.push(Step::Args(args));
+    if steps.iter().any(|x| *x == Step::Args(Vec::new())) {
+        bail!("empty command before or after {}", SEPARATOR);
+    }
+    steps.extend(then.into_iter().map(Step::Shell));
+    Ok(steps)
+}
+
+/// Returns the command to run.  A single command is run as it is, a chain
+/// with `sh -c`.
+///
+/// With `keep_going` the commands after a failed one still run and the exit
+/// code is the one of the first failure, otherwise the chain stops at the
+/// first failure.
+pub fn command(mut steps: Vec<Step>, keep_going: bool) -> Vec<OsString> {
+    if let [Step::Args(_)] = steps[..] {
+        if let Some(Step::Args(args)) = steps.pop() {
+            return args;
+        }
+    }
+    let mut script = b"failed=0\n".to_vec();
+    for step in &steps {
+        let (title, code) = match step {
+            Step::Args(args) => {
+                let title = args
+                    .iter()
+                    .map(|x| display(x))
+                    .collect::<Vec<_>>()
+                    .join(" ");
+                let code = args
+                    .iter()
+                    .map(|x| quote(x))
+                    .collect::<Vec<_>>()
+                    .join(&b' ');
+                (title, code)
+            }
+            Step::Shell(command) => {
+                let mut code = b"eval ".to_vec();
+                code.extend(quote(OsStr::new(command)));
+                (command.clone(), code)
+            }
+        };
+        script.extend_from_slice(
+            b"printf '\\033]133;A\\007==> \\033]133;B\\007%s\\n\\033]133;C\\007' ",
+        );
+        script.extend(quote(OsStr::new(&title)));
+        script.push(b'\n');
+        script.extend(code);
+        script.extend_from_slice(b"\nstatus=$?\nprintf '\\033]133;D;%d\\007' \"$status\"\n");
+        script.extend_from_slice(b"if [ \"$status\" -ne 0 ]; then\n");
+        script.extend_from_slice(b"  printf '==> failed with %d\\n' \"$status\"\n");
+        if keep_going {
+            script.extend_from_slice(b"  [ \"$failed\" -ne 0 ] || failed=$status\n");
+        } else {
+            script.extend_from_slice(b"  exit \"$status\"\n");
+        }
+        script.extend_from_slice(b"fi\n");
+    }
+    script.extend_from_slice(b"exit \"$failed\"\n");
+    vec!["sh".into(), "-c".into(), OsString::from_vec(script)]
+}
+
+/// Returns an argument as it would be typed in a shell.
+fn display(arg: &OsStr) -> String {
+    let safe = |c: &u8| c.is_ascii_alphanumeric() || b"-_./=:@%+,".contains(c);
+    if !arg.is_empty() && arg.as_bytes().iter().all(safe) {
+        arg.to_string_lossy().into_owned()
+    } else {
+        String::from_utf8_lossy(&quote(arg)).into_owned()
+    }
+}
+
+/// Quotes an argument for the shell.
+pub fn quote(arg: &OsStr) -> Vec<u8> {
+    let mut quoted = vec![b'\''];
+    for &c in arg.as_bytes() {
+        if c == b'\'' {
+            quoted.extend_from_slice(b"'\\''");
+        } else {
+            quoted.push(c);
+        }
+    }
+    quoted.push(b'\'');
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split() {
+        let args = ["make", ":::", "make", "it's"].map(OsString::from).to_vec();
+        let steps = split(args.clone(), vec!["echo done".into()], true).unwrap();
+        assert_eq!(steps.len(), 3);
+        assert_eq!(
+            split(args.clone(), Vec::new(), false).unwrap(),
+            [Step::Args(args)]
+        );
+        assert_eq!(steps[1], Step::Args(vec!["make".into(), "it's".into()]));
+        assert_eq!(quote(OsStr::new("it's")), b"'it'\\''s'");
+        assert_eq!(display(OsStr::new("exit 3")), "'exit 3'");
+        assert!(split(["ls", ":::"].map(OsString::from).to_vec(), Vec::new(), true).is_err());
+        let single = split(vec!["ls".into()], Vec::new(), true).unwrap();
+        assert_eq!(command(single, false), ["ls"]);
+    }
+}
@@ -0,0 +1,208 @@
+//! Making the output readable in the logs of CI systems.
+//!
+//! With `--ci github` or `--ci gitlab` every command marked by shell
+//! integration (see [`crate::segment`]) is wrapped in a collapsible group of
+//! the CI system, and lines matching one of the `--ci-error` patterns are
+//! followed by an error annotation.  Only the output written to stdout is
+//! annotated, the output file stays as it is.
+use std::mem::take;
+use std::time::SystemTime;
+
+use crate::ansi::{Parser, Piece};
+use crate::pattern::Pattern;
+use crate::spawn::CiSystem;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Idle,
+    /// The command is typed after the prompt.
+    Input,
+    /// The command runs.
+    Output,
+}
+
+/// Adds the markers of the CI system to the output.
+pub struct CiAnnotator<'a> {
+    system: CiSystem,
+    errors: &'a [Pattern],
+    parser: Parser,
+    state: State,
+    command: Vec<u8>,
+    /// The current line without escape sequences.
+    line: Vec<u8>,
+    at_line_start: bool,
+    /// The number of the open section if a command is running.
+    section: Option<usize>,
+    sections: usize,
+}
+
+impl<'a> CiAnnotator<'a> {
+    pub fn new(system: CiSystem, errors: &'a [Pattern]) -> CiAnnotator<'a> {
+        CiAnnotator {
+            system,
+            errors,
+            parser: Parser::default(),
+            state: State::Idle,
+            command: Vec::new(),
+            line: Vec::new(),
+            at_line_start: true,
+            section: None,
+            sections: 0,
+        }
+    }
+
+    /// Annotates a chunk of output and appends the result to `out`.
+    pub fn filter(&mut self, data: &[u8], out: &mut Vec<u8>) {
+        let mut pieces = Vec::new();
+        let mut bytes = Vec::new();
+        self.parser.feed_pieces(data, |piece| match piece {
+            Piece::Byte(c) => bytes.push(c),
+            Piece::Sequence(seq) => {
+                let marker = match seq.osc() {
+                    Some((b"133", arg)) => arg.first().copied(),
+                    _ => None,
+                };
+                pieces.push((take(&mut bytes), seq.raw.to_vec(), marker));
+            }
+        });
+        pieces.push((bytes, Vec::new(), None));
+        for (bytes, raw, marker) in pieces {
+            for c in bytes {
+                self.byte(c, out);
+            }
+            // the markers go before the sequence so that they don't have to
+            // start a new line in most cases
+            match marker {
+                Some(b'A' | b'D') => {
+                    self.end_section(out);
+                    self.state = State::Idle;
+                }
+                Some(b'B') => {
+                    self.command.clear();
+                    self.state = State::Input;
+                }
+                Some(b'C') => {
+                    self.end_section(out);
+                    self.start_section(out);
+                    self.state = State::Output;
+                }
+                _ => {}
+            }
+            if !raw.is_empty() {
+                out.extend_from_slice(&raw);
+                self.at_line_start = false;
+            }
+        }
+    }
+
+    /// Closes the group of a command which was still running when the session
+    /// ended.
+    pub fn finish(&mut self, out: &mut Vec<u8>) {
+        self.end_section(out);
+    }
+
+    fn byte(&mut self, c: u8, out: &mut Vec<u8>) {
+        out.push(c);
+        self.at_line_start = c == b'\n';
+        if self.state == State::Input {
+            self.command.push(c);
+        }
+        if c != b'\n' {
+            self.line.push(c);
+            return;
+        }
+        let line = String::from_utf8_lossy(&take(&mut self.line)).into_owned();
+        let line = line.trim_end_matches('\r');
+        if self.errors.iter().any(|x| x.is_match(line.as_bytes())) {
+            match self.system {
+                CiSystem::Github => {
+                    out.extend_from_slice(format!("::error::{}\n", escape_data(line)).as_bytes())
+                }
+                CiSystem::Gitlab => {
+                    out.extend_from_slice(format!("\x1b[31;1mERROR: {}\x1b[0m\n", line).as_bytes())
+                }
+            }
+        }
+    }
+
+    fn start_section(&mut self, out: &mut Vec<u8>) {
+        self.start_line(out);
+        self.sections += 1;
+        self.section = Some(self.sections);
+        let command = String::from_utf8_lossy(&self.command).trim().to_string();
+        let title = format!("$ {}", command);
+        match self.system {
+            CiSystem::Github => {
+                out.extend_from_slice(format!("::group::{}\n", escape_data(&title)).as_bytes())
+            }
+            CiSystem::Gitlab => out.extend_from_slice(
+                format!(
+                    "\x1b[0Ksection_start:{}:teetty_{}[collapsed=true]\r\x1b[0K{}\n",
+                    unix_time(),
+                    self.sections,
+                    title
+                )
+                .as_bytes(),
+            ),
+        }
+    }
+
+    fn end_section(&mut self, out: &mut Vec<u8>) {
+        let Some(section) = self.section.take() else {
+            return;
+        };
+        self.start_line(out);
+        match self.system {
+            CiSystem::Github => out.extend_from_slice(b"::endgroup::\n"),
+            CiSystem::Gitlab => out.extend_from_slice(
+                format!(
+                    "\x1b[0Ksection_end:{}:teetty_{}\r\x1b[0K\n",
+                    unix_time(),
+                    section
+                )
+                .as_bytes(),
+            ),
+        }
+    }
+
+    /// Markers have to be at the start of a line.
+    fn start_line(&mut self, out: &mut Vec<u8>) {
+        if !self.at_line_start {
+            self.line.clear();
+            self.at_line_start = true;
+            out.push(b'\n');
+        }
+    }
+}
+
+/// Escapes the message of a GitHub workflow command.
+fn escape_data(data: &str) -> String {
+    data.replace('%', "%25")
+        .replace('\r', "%0D")
+        .replace('\n', "%0A")
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |x| x.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_github() {
+        let errors = [Pattern::new("^error:").unwrap()];
+        let mut annotator = CiAnnotator::new(CiSystem::Github, &errors);
+        let mut out = Vec::new();
+        annotator.filter(b"\x1b]133;B\x07make\r\n\x1b]133;C\x07cc\r\nerr", &mut out);
+        annotator.filter(b"or: 100%\r\n\x1b]133;D;2\x07", &mut out);
+        assert_eq!(
+            String::from_utf8_lossy(&out),
+            "\x1b]133;B\x07make\r\n::group::$ make\n\x1b]133;C\x07cc\r\nerror: 100%\r\n\
+             ::error::error: 100%25\n::endgroup::\n\x1b]133;D;2\x07"
+        );
+    }
+}
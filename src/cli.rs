@@ -1,42 +1,1426 @@
 use std::ffi::OsString;
-use std::path::PathBuf;
+use std::fs;
+use std::os::fd::RawFd;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
 
-use anyhow::Error;
-use clap::Parser;
+use anyhow::{bail, Error};
+use clap::{Args, CommandFactory, Parser, Subcommand};
+use nix::errno::Errno;
+use nix::sys::signal::{kill, Signal};
+use teetty::asciicast::Recording;
+use teetty::audit;
+use teetty::control::request;
+use teetty::debug;
+use teetty::pattern::Pattern;
+use teetty::process::process_tree;
+use teetty::registry::{list, lookup};
+use teetty::script::Script;
+use teetty::spawn::{
+    parse_signal, spawn, Bell, ChildStdin, CiSystem, Clipboard, ControllingTerminal, CorePolicy,
+    ExitMode, IoPriority, Namespace, RecordFormat, SpawnOptions, StdinEof,
+};
+use teetty::template;
+use teetty::term;
+use teetty::time::format_utc;
+use teetty::transform::{Redact, StripAnsi, Timestamps, Transform};
 
-use crate::spawn::{spawn, SpawnOptions};
+use crate::attach::attach_read_only;
+use crate::bench::bench;
+use crate::chain;
+use crate::completions;
+use crate::config;
+use crate::golden::test;
+use crate::multi::{multi, parse_program};
+use crate::play::{export, play};
+use crate::remote::Remote;
+use crate::tmux::{capture_command, pane_size, PaneFifo};
 
 /// teetty is a wrapper binary to execute a command in a pty with remote control
 /// facilities.
 #[derive(Debug, Parser)]
-#[command(version, about, arg_required_else_help = true, max_term_width = 92)]
+#[command(
+    version,
+    about,
+    arg_required_else_help = true,
+    args_conflicts_with_subcommands = true,
+    args_override_self = true,
+    max_term_width = 92
+)]
 pub struct Cli {
+    #[command(subcommand)]
+    subcommand: Option<Commands>,
+    /// Restores a sane terminal state in case a crashed program left the
+    /// terminal in raw mode.
+    #[arg(long = "reset")]
+    reset: bool,
+    #[command(flatten)]
+    run: RunArgs,
+}
+
+/// The options for running a command.  `teetty -- cmd` is the same as `teetty
+/// run -- cmd`.
+#[derive(Debug, Args)]
+struct RunArgs {
+    /// Reads defaults for these options from a TOML file.  Options given on the
+    /// command line take precedence.  Defaults to `$TEETTY_CONFIG`.
+    #[arg(long = "config", value_name = "PATH", env = "TEETTY_CONFIG")]
+    #[allow(dead_code)] // applied before parsing by `config::apply`
+    config: Option<PathBuf>,
+    /// Registers the session under this name so that it can be managed with the
+    /// other teetty commands.
+    #[arg(short = 'n', long = "session", value_name = "NAME")]
+    session: Option<String>,
     /// A path to a FIFO or file.  When provided it's contents are monitored and
-    /// sent to the terminal as input.
+    /// sent to the terminal as input.  Can be provided multiple times in which
+    /// case earlier paths take priority over later ones.
     #[arg(short, long = "in", value_name = "PATH")]
-    in_path: Option<PathBuf>,
+    in_paths: Vec<PathBuf>,
+    /// Translates newlines in the input from the FIFOs into carriage returns
+    /// which is what pressing enter on a terminal sends.
+    #[arg(long = "in-crlf", alias = "in-translate")]
+    in_crlf: bool,
+    /// Feeds the input from the FIFOs byte by byte with the given delay in
+    /// milliseconds between bytes.
+    #[arg(long = "in-delay", value_name = "MS")]
+    in_delay: Option<u64>,
+    /// Feeds the input from the FIFOs line by line with the given delay in
+    /// milliseconds between lines.
+    #[arg(long = "in-line-delay", value_name = "MS")]
+    in_line_delay: Option<u64>,
+    /// Wraps the input from the FIFOs in bracketed paste markers if the program
+    /// enabled bracketed paste mode so that it's treated as pasted text.  End
+    /// markers in the input are removed.
+    #[arg(long = "in-bracketed-paste")]
+    in_bracketed_paste: bool,
+    /// Shows the input sent through the FIFOs, scripts and control commands on
+    /// the terminal as `[input: ...]` markers.
+    #[arg(long = "echo-input")]
+    echo_input: bool,
+    /// Also writes the `[input: ...]` markers to the output file.
+    #[arg(long = "echo-input-log", requires = "out_path")]
+    echo_input_log: bool,
+    /// Only forwards the next line of input from the FIFOs once the output
+    /// matched this regular expression since the last line was sent.
+    #[arg(long = "prompt", value_name = "REGEX")]
+    prompt: Option<Pattern>,
+    /// Stops the program with SIGSTOP when its output matches this regular
+    /// expression and asks whether to continue, step to the next output or
+    /// kill it.
+    #[arg(long = "break-on", value_name = "REGEX")]
+    break_on: Option<Pattern>,
+    /// Groups the commands marked by shell integration in the log of the CI
+    /// system (`github` or `gitlab`) on stdout.
+    #[arg(long = "ci", value_name = "SYSTEM", value_parser = ["github", "gitlab"])]
+    ci: Option<String>,
+    /// Annotates lines matching this regular expression as errors in the log
+    /// of the CI system.  Can be given multiple times.
+    #[arg(long = "ci-error", value_name = "REGEX", requires = "ci")]
+    ci_errors: Vec<Pattern>,
+    /// Runs an expect script against the session.  The script is a list of
+    /// `expect <regex>`, `send <text>`, `sleep <secs>`, `signal <SIG>` and
+    /// `timeout <secs>` commands.
+    #[arg(long = "script", value_name = "PATH")]
+    script: Option<PathBuf>,
+    /// Sends the input of a recording made with `teetty record --record-input`
+    /// to the program with the original timing.
+    #[arg(long = "replay-input", value_name = "FILE", conflicts_with = "script")]
+    replay_input: Option<PathBuf>,
+    /// Replays the input faster (eg: 2) or slower (eg: 0.5).
+    #[arg(
+        long,
+        value_name = "FACTOR",
+        default_value = "1",
+        value_parser = parse_speed,
+        requires = "replay_input"
+    )]
+    speed: f64,
+    /// Runs this shell command in the same session once the command finished.
+    /// Can be given multiple times.
+    #[arg(long = "then", value_name = "COMMAND")]
+    then: Vec<String>,
+    /// Splits the command at `:::` into several commands which run one after
+    /// another (eg: `teetty --chain -- cmd1 ::: cmd2`).  Without it `:::` is
+    /// passed on to the program.
+    #[arg(long = "chain", conflicts_with = "master_fd")]
+    chain: bool,
+    /// Runs the rest of a chain of commands after one of them failed instead
+    /// of stopping.  The exit code is the one of the first failure.
+    #[arg(long = "keep-going")]
+    keep_going: bool,
+    /// The escape character for ssh style escape sequences typed at the start of
+    /// a line (`~.` to detach, `~B` to send a break, `~?` for help).  Set to
+    /// `none` to disable.
+    #[arg(
+        short = 'e',
+        long = "escape-char",
+        value_name = "CHAR",
+        default_value = "~",
+        value_parser = parse_escape_char
+    )]
+    escape_char: EscapeChar,
+    /// Allows detaching from the session with the escape sequence `~.` instead of
+    /// ending it.  The program keeps running in the background.
+    #[arg(long = "detachable")]
+    detachable: bool,
+    /// Runs the session in the background as a daemon, detached from the
+    /// terminal.  Input only comes from `--in` and the control socket.
+    #[arg(long = "daemon", conflicts_with = "detachable")]
+    daemon: bool,
+    /// Adopts the master of a pty opened by another program from this fd
+    /// instead of starting a command.  The output is captured and input is
+    /// injected until the pty is closed.
+    #[arg(
+        long = "master-fd",
+        value_name = "FD",
+        conflicts_with_all = ["then", "utmp"]
+    )]
+    master_fd: Option<RawFd>,
+    /// Keeps the terminal in cooked mode.  Input is edited locally and sent a
+    /// line at a time and `^C` interrupts teetty instead of the program.
+    #[arg(long = "no-raw")]
+    no_raw: bool,
+    /// Only connects the program's stdin, stdout and stderr to the pty instead
+    /// of making it the controlling terminal of the program, which stays in
+    /// the session of teetty.
+    #[arg(long = "no-ctty", conflicts_with = "setsid_only")]
+    no_ctty: bool,
+    /// Like `--no-ctty` but the program runs in a new session of its own.
+    #[arg(long = "setsid-only")]
+    setsid_only: bool,
+    /// What the program gets as stdin: `inherit` connects it to the pty like
+    /// stdout, `null` to `/dev/null` and `none` closes it.  For commands which
+    /// must never wait for input.
+    #[arg(
+        long = "stdin",
+        value_name = "MODE",
+        default_value = "inherit",
+        value_parser = ["none", "null", "inherit"]
+    )]
+    stdin: String,
+    /// Adds N to the nice value of the program, like nice(1) but without
+    /// slowing down teetty itself.
+    #[arg(
+        long = "nice",
+        value_name = "N",
+        allow_negative_numbers = true,
+        value_parser = clap::value_parser!(i32).range(-39..=39)
+    )]
+    nice: Option<i32>,
+    /// Sets the I/O priority of the program to `idle`, `best-effort[:LEVEL]`
+    /// or `realtime[:LEVEL]` with levels from 0 (highest) to 7, like
+    /// ionice(1).  Linux only.
+    #[arg(long = "ionice", value_name = "CLASS[:LEVEL]", value_parser = parse_ionice)]
+    ionice: Option<IoPriority>,
+    /// Runs the program and everything it starts in this cgroup (v2), which is
+    /// created if needed.  Relative paths are relative to the root of the
+    /// cgroup hierarchy.  With `--metadata` the peak memory usage and the CPU
+    /// time of the cgroup are added to the metadata.  Linux only.
+    #[arg(long = "cgroup", value_name = "PATH")]
+    cgroup: Option<PathBuf>,
+    /// Restricts the system calls of the program with a seccomp profile in the
+    /// JSON format of Docker, with argument conditions.  Linux only.
+    #[arg(long = "seccomp", value_name = "PROFILE")]
+    seccomp: Option<PathBuf>,
+    /// Runs the program in new namespaces, a comma separated list of `user`,
+    /// `pid`, `mount`, `net`, `uts` and `ipc`.  With `net` the program only
+    /// has the loopback interface and `user` lets unprivileged users create
+    /// the others.  Linux only.
+    #[arg(
+        long = "unshare",
+        value_name = "KINDS",
+        value_delimiter = ',',
+        value_parser = ["user", "pid", "mount", "net", "uts", "ipc"]
+    )]
+    unshare: Vec<String>,
+    /// Whether the program dumps core: `disable` turns core dumps off and
+    /// `dir=PATH` allows them up to the hard limit and moves the dumps the
+    /// kernel wrote during the session to PATH when it ends.
+    #[arg(long = "core", value_name = "disable|dir=PATH", value_parser = parse_core)]
+    core: Option<CorePolicy>,
+    /// What happens when stdin reaches EOF: `veof` sends EOF to the program,
+    /// `close` hangs up on it, `ignore` does nothing and `exit-after:SECS` sends
+    /// EOF and kills the program if it's still running after SECS.
+    #[arg(
+        long = "on-stdin-eof",
+        value_name = "MODE",
+        default_value = "veof",
+        value_parser = parse_stdin_eof
+    )]
+    on_stdin_eof: StdinEof,
+    /// Sets the title of the terminal while the program runs.  The previous
+    /// title is restored at exit.
+    #[arg(long = "title", value_name = "TITLE")]
+    title: Option<String>,
+    /// Removes the sequences with which the program changes the title of the
+    /// terminal from what's shown.
+    #[arg(long = "strip-title")]
+    strip_title: bool,
+    /// What happens when the program copies to the clipboard of the terminal:
+    /// `allow` passes it on, `block` drops it and `capture` drops it and
+    /// appends what was copied to `--clipboard-file`.
+    #[arg(
+        long = "clipboard",
+        value_name = "POLICY",
+        default_value = "allow",
+        value_parser = ["allow", "block", "capture"]
+    )]
+    clipboard: String,
+    /// The file for `--clipboard capture`.
+    #[arg(
+        long = "clipboard-file",
+        value_name = "PATH",
+        required_if_eq("clipboard", "capture")
+    )]
+    clipboard_file: Option<PathBuf>,
+    /// Answers queries for the capabilities of the terminal and the cursor
+    /// position when stdin isn't a terminal so that programs waiting for a reply
+    /// don't hang.
+    #[arg(long = "answerback")]
+    answerback: bool,
+    /// Splits the output into commands with the OSC 133 markers of shell
+    /// integration and appends a JSON record for every command to this file.
+    /// If the path contains `{n}`, every command gets a file of its own with
+    /// its output instead.
+    #[arg(
+        long = "segment-per-command",
+        value_name = "PATH",
+        conflicts_with = "strip_ansi"
+    )]
+    segment_path: Option<PathBuf>,
+    /// Records the session in utmp and wtmp like a login so that `who` and
+    /// `last` show it.  This usually requires root.
+    #[arg(long = "utmp")]
+    utmp: bool,
     /// Path to an optional output file.  stdout and stderr are captured and streamed
-    /// into this file in addition to the terminal output.
+    /// into this file in addition to the terminal output.  The path can contain
+    /// `strftime` formats and the placeholders `{cmd}`, `{pid}` and `{session}`.
     #[arg(short, long = "out", value_name = "PATH")]
     out_path: Option<PathBuf>,
     /// When this flag is set the output file is truncated first.
     #[arg(long = "truncate")]
     truncate_out: bool,
+    /// Fails if the output file already exists instead of appending to it.
+    #[arg(
+        long = "no-clobber",
+        requires = "out_path",
+        conflicts_with = "truncate_out"
+    )]
+    no_clobber: bool,
+    /// Writes the output to a temporary file first which replaces the output
+    /// file once the session ended.  Readers never see a partial capture.
+    #[arg(long = "atomic", requires = "out_path")]
+    atomic: bool,
+    /// Locks the output file and fails if another session is already writing
+    /// to it.
+    #[arg(long = "lock", requires = "out_path", conflicts_with = "atomic")]
+    lock: bool,
+    /// Like `--lock` but waits for the other session to finish.
+    #[arg(long = "lock-wait", requires = "out_path", conflicts_with = "atomic")]
+    lock_wait: bool,
+    /// Pipes the output through this shell command before it's written to the
+    /// output file (eg: `gzip`).
+    #[arg(long = "out-filter", value_name = "CMD", requires = "out_path")]
+    out_filter: Option<String>,
+    /// Encrypts the output file with age as it's written, for this recipient
+    /// (an age or ssh public key) or the recipients in this file.
+    #[arg(
+        long = "encrypt",
+        value_name = "RECIPIENT",
+        requires = "out_path",
+        conflicts_with = "out_filter"
+    )]
+    encrypt: Option<String>,
+    /// Writes the environment of the program, the host, the user, the terminal
+    /// and the git commit of the working directory to `<output>.meta.json`
+    /// next to the output file or the recording.  Variables which look like
+    /// secrets are redacted.
+    #[arg(long = "metadata")]
+    metadata: bool,
+    /// Also redacts the variables whose names match this regular expression
+    /// in the metadata.  Can be given multiple times.
+    #[arg(
+        long = "metadata-deny-env",
+        value_name = "REGEX",
+        requires = "metadata"
+    )]
+    metadata_deny_env: Vec<Pattern>,
+    /// Computes the SHA-256 digest of everything written to the output file
+    /// and stores it in `<output>.meta.json` when the session ends, so that
+    /// archived logs can be verified.
+    #[arg(
+        long = "checksum",
+        requires = "out_path",
+        conflicts_with_all = ["out_filter", "echo_input_log"]
+    )]
+    checksum: bool,
+    /// Appends every chunk of output and all input to this file as JSON
+    /// records which hold the digest of the record before them, a tamper
+    /// evident log that `teetty audit-verify` checks.
+    #[arg(long = "audit", value_name = "PATH")]
+    audit_path: Option<PathBuf>,
+    /// Appends the sequence number and the digest of the last record of the
+    /// audit log to this file when the session ends.  `teetty audit-verify
+    /// --head` checks that the log still ends there, keep it out of reach of
+    /// whoever can write the log.
+    #[arg(long = "audit-head", value_name = "PATH", requires = "audit_path")]
+    audit_head: Option<PathBuf>,
+    /// Appends when keys were read and how many to this file as JSON records,
+    /// without what was typed, followed by a summary of the intervals between
+    /// the reads.  Nothing is recorded while the program reads a password.
+    #[arg(long = "input-timing", value_name = "PATH")]
+    input_timing_path: Option<PathBuf>,
+    /// Appends a hex dump of everything read from and written to the pty, stdin
+    /// and the inputs to this file, for debugging.
+    #[arg(long = "dump-traffic", value_name = "PATH")]
+    traffic_path: Option<PathBuf>,
+    /// Removes escape sequences such as colors from the captured output.  The
+    /// terminal still shows them.
+    #[arg(long = "strip-ansi")]
+    strip_ansi: bool,
+    /// Replaces everything in the captured output matching this regular
+    /// expression with `[REDACTED]`.  Can be provided multiple times.  The last
+    /// bytes of the output are held back until more arrives so that secrets
+    /// split across reads are found.
+    #[arg(long = "redact", value_name = "REGEX")]
+    redact: Vec<Pattern>,
+    /// Prefixes every line of the captured output with the time it started.
+    #[arg(long = "timestamps")]
+    timestamps: bool,
+    /// Writes a timestamped `[still running]` marker to the output file whenever
+    /// the program produced no output for this many seconds.
+    #[arg(long = "heartbeat", value_name = "SECS", requires = "out_path")]
+    heartbeat: Option<u64>,
+    /// Reports the session as idle once the program produced no output and
+    /// got no input for this many seconds, and as active again when that
+    /// changes.  The events go to followers of the control socket and the
+    /// WebSocket server and to the audit log.
+    #[arg(long = "idle-after", value_name = "SECS", value_parser = parse_secs)]
+    idle_after: Option<Duration>,
+    /// Prints how long the session ran, how the program exited and how many
+    /// bytes of output were captured and of stdin were forwarded to stderr
+    /// when the session ends.
+    #[arg(long = "stats")]
+    stats: bool,
+    /// Stops writing to the output file and the recordings this many seconds
+    /// after the start, with a `[recording stopped]` marker.  The program
+    /// keeps running and its output is still shown.
+    #[arg(long = "record-for", value_name = "SECS", value_parser = parse_secs)]
+    record_for: Option<Duration>,
+    /// Continues the output in a new file this often.  The path of the output
+    /// file is expanded again for every file, so it needs a time format fine
+    /// enough to change in between, eg: `build-%Y%m%d-%H%M.log`.
+    #[arg(
+        long = "segment-every",
+        value_name = "SECS",
+        value_parser = parse_secs,
+        requires = "out_path",
+        conflicts_with = "echo_input_log"
+    )]
+    segment_every: Option<Duration>,
+    /// Stops reading the output this many seconds after the program exited,
+    /// even if processes it left behind still have the terminal open.  By
+    /// default the output is read until all of them are done.
+    #[arg(long = "drain-timeout", value_name = "SECS", value_parser = parse_secs)]
+    drain_timeout: Option<Duration>,
+    /// POSTs a JSON payload with the command, exit code, duration and output
+    /// path to this URL when the session ends.
+    #[arg(long = "notify-url", value_name = "URL")]
+    notify_url: Option<String>,
+    /// Writes how the program ended to this file as JSON when the session
+    /// ends: its exit code or the signal which killed it.
+    #[arg(long = "exit-file", value_name = "PATH")]
+    exit_file: Option<PathBuf>,
+    /// Writes the pid of teetty and the pid of the program on two lines to
+    /// this file, which is removed again when the session ends.
+    #[arg(long = "pid-file", value_name = "PATH")]
+    pid_file: Option<PathBuf>,
+    /// Writes a JUnit XML report with the run as a single test case to this
+    /// file when the session ends, with the end of the output if it failed.
+    #[arg(long = "junit", value_name = "PATH")]
+    junit_path: Option<PathBuf>,
+    /// Like `--junit` but writes a TAP report.
+    #[arg(long = "tap", value_name = "PATH")]
+    tap_path: Option<PathBuf>,
+    /// How teetty exits when the program was killed by a signal: `shell` exits
+    /// with `128 + signal`, `raw` with the number of the signal and
+    /// `propagate-signal` kills teetty with the same signal.
+    #[arg(
+        long = "exit-mode",
+        value_name = "MODE",
+        default_value = "shell",
+        value_parser = ["raw", "shell", "propagate-signal"]
+    )]
+    exit_mode: String,
+    /// A shell command to run once the program started.  Details about the
+    /// session are passed in `TEETTY_*` environment variables.
+    #[arg(long = "on-start", value_name = "CMD")]
+    on_start: Option<String>,
+    /// A shell command to run when the session ended.  The exit code is passed
+    /// in `TEETTY_EXIT_CODE`.
+    #[arg(long = "on-exit", value_name = "CMD")]
+    on_exit: Option<String>,
+    /// A shell command to run when the program exited with a non zero exit code.
+    #[arg(long = "on-failure", value_name = "CMD")]
+    on_failure: Option<String>,
+    /// What happens when the program rings the bell, a comma separated list of
+    /// `count` (reports the number of bells at the end), `notify` (runs
+    /// `--on-bell` or shows a desktop notification) and `strip` (keeps the
+    /// bells from the terminal).  Bells within a second of the last
+    /// notification don't notify again, so only the first bell of a burst is
+    /// reported.
+    #[arg(
+        long = "bell",
+        value_name = "MODES",
+        value_delimiter = ',',
+        value_parser = ["count", "notify", "strip"]
+    )]
+    bell: Vec<String>,
+    /// A shell command to run when the program rings the bell.  Implies
+    /// `--bell notify`.
+    #[arg(long = "on-bell", value_name = "CMD")]
+    on_bell: Option<String>,
+    /// Serves Prometheus metrics about the session on this address (eg:
+    /// `127.0.0.1:9100`) at `/metrics`.
+    #[arg(long = "metrics-addr", value_name = "ADDR")]
+    metrics_addr: Option<String>,
+    /// Sends the output line by line to the systemd journal.
+    #[arg(long = "journald")]
+    journald: bool,
+    /// Streams the output to this URL with a chunked PUT.  If the upload fails
+    /// the output is kept in a local file.
+    #[arg(long = "out-url", value_name = "URL")]
+    out_url: Option<String>,
+    /// An extra header for the upload to `--out-url` (eg: `Authorization: Bearer
+    /// TOKEN`).  Can be provided multiple times.
+    #[arg(long = "out-url-header", value_name = "HEADER", requires = "out_url")]
+    out_url_headers: Vec<String>,
+    /// Serves the live output over WebSocket on this address (eg:
+    /// `127.0.0.1:8080`) at `/ws` together with a viewer page at `/`.
+    #[arg(long = "serve-ws", value_name = "ADDR")]
+    serve_ws: Option<String>,
+    /// Serves xterm.js for the viewer page from this installed `xterm`
+    /// package (eg: `node_modules/@xterm/xterm`).  Without it the page shows
+    /// plain text.
+    #[arg(long = "serve-ws-assets", value_name = "DIR", requires = "serve_ws")]
+    viewer_assets: Option<PathBuf>,
+    /// Serves an HTTP API to control the session on this address (eg:
+    /// `127.0.0.1:8081`): `GET /status`, `POST /signal`, `POST /input` and
+    /// `POST /resize`.
+    #[arg(long = "serve-http", value_name = "ADDR")]
+    serve_http: Option<String>,
+    /// The token clients of the HTTP API and the WebSocket have to send as
+    /// `Authorization: Bearer <TOKEN>` (or `?token=<TOKEN>` for WebSockets).
+    /// Defaults to `$TEETTY_HTTP_TOKEN`, otherwise one is generated and printed
+    /// to stderr.
+    #[arg(long = "http-token", value_name = "TOKEN", env = "TEETTY_HTTP_TOKEN")]
+    http_token: Option<String>,
     /// Disables the default output flushing after all writes.
     #[arg(short = 'F', long = "no-flush")]
     no_flush: bool,
+    /// Disables the zero-copy splice fast path for the output on Linux.
+    #[arg(long = "no-splice")]
+    no_splice: bool,
+    /// The size of the I/O buffer (eg: 64k or 1m).  By default the buffer grows
+    /// to match the amount of output the pty has queued up.
+    #[arg(long = "buffer-size", value_name = "BYTES", value_parser = parse_byte_size)]
+    buffer_size: Option<usize>,
+    /// Checks the size of the terminal every this many seconds in addition to
+    /// listening for SIGWINCH, for environments which swallow the signal.
+    #[arg(long = "poll-winsize", value_name = "SECS", value_parser = parse_interval)]
+    poll_winsize: Option<Duration>,
+    /// The size of the terminal (eg: 120x40) when stdin isn't a terminal.
+    /// Defaults to `$COLUMNS` and `$LINES` or 80x24.
+    #[arg(long = "default-size", value_name = "COLSxROWS", value_parser = parse_size)]
+    default_size: Option<(u16, u16)>,
+    /// Passes this name to the program as `argv[0]`.
+    #[arg(long = "argv0", value_name = "NAME")]
+    argv0: Option<OsString>,
+    /// Starts the program as a login shell, `-` is prepended to `argv[0]`.
+    #[arg(short, long = "login")]
+    login: bool,
+    /// Sets `TERM` for the program.  By default it's inherited, or
+    /// `xterm-256color` if it isn't set.
+    #[arg(long = "term", value_name = "TERM")]
+    term: Option<String>,
+    /// Logs signals and changes of the state of the session to stderr.
+    /// `--debug=trace` also logs every wakeup of the loop and the bytes read.
+    #[arg(
+        long = "debug",
+        value_name = "LEVEL",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "debug",
+        value_parser = ["debug", "trace"]
+    )]
+    debug: Option<String>,
+    /// Writes the debug log to this file instead of stderr.
+    #[arg(long = "debug-file", value_name = "PATH")]
+    debug_file: Option<PathBuf>,
     /// The command and the arguments to run
     #[arg(last = true)]
     command: Vec<OsString>,
 }
 
+#[derive(Debug, Subcommand)]
+enum Commands {
+    /// Runs a command in a pty (the default).
+    Run(RunArgs),
+    /// Runs a command and records the output with its timing.
+    Record(RecordArgs),
+    /// Plays back a recording.
+    Play(PlayArgs),
+    /// Writes the output of a recording as plain text.
+    Export(ExportArgs),
+    /// Watches the output of a named session.
+    Attach(AttachArgs),
+    /// Sends input to a named session.
+    Send(SendArgs),
+    /// Lists the running named sessions.
+    #[command(alias = "ls")]
+    List,
+    /// Sends a signal to the program running in a named session.
+    Kill(KillArgs),
+    /// Measures how fast output goes through sessions with and without
+    /// output files, filters, sinks and transformations.
+    Bench(BenchArgs),
+    /// Runs a command and compares its output with a golden file.
+    Test(TestArgs),
+    /// Runs several programs at once and shows their merged output.
+    Multi(MultiArgs),
+    /// Checks that an audit log written with `--audit` wasn't tampered with.
+    AuditVerify(AuditVerifyArgs),
+    /// Runs a command in a running container with `docker exec -it`.
+    Docker(DockerArgs),
+    /// Runs a command on another host with `ssh -tt`.
+    Ssh(SshArgs),
+    /// Captures the output of a tmux pane which runs already.
+    FromTmux(FromTmuxArgs),
+    /// Prints a completion script for bash, zsh or fish.
+    #[command(hide = true)]
+    Completions(CompletionsArgs),
+}
+
+#[derive(Debug, Args)]
+struct RecordArgs {
+    /// The path of the recording.
+    #[arg(value_name = "FILE")]
+    path: PathBuf,
+    /// The format of the recording: `asciicast` (v2) or `ttyrec`.
+    #[arg(
+        long,
+        value_name = "FORMAT",
+        default_value = "asciicast",
+        value_parser = ["asciicast", "ttyrec"]
+    )]
+    format: String,
+    /// Also records the input so that it can be replayed with
+    /// `--replay-input`.  Only asciicast recordings can hold input.
+    #[arg(long)]
+    record_input: bool,
+    #[command(flatten)]
+    run: RunArgs,
+}
+
+#[derive(Debug, Args)]
+struct PlayArgs {
+    /// The path of the recording.
+    #[arg(value_name = "FILE")]
+    path: PathBuf,
+    /// Plays the recording faster (eg: 2) or slower (eg: 0.5).
+    #[arg(long, value_name = "FACTOR", default_value = "1", value_parser = parse_speed)]
+    speed: f64,
+    /// Shortens pauses in the recording to at most this many seconds.
+    #[arg(long, value_name = "SECS")]
+    idle_limit: Option<f64>,
+    /// Starts playing this far into the recording (eg: 90, 12m or 1h2m3s).
+    #[arg(long, value_name = "TIME", value_parser = parse_position)]
+    seek: Option<Duration>,
+}
+
+#[derive(Debug, Args)]
+struct ExportArgs {
+    /// The path of the recording.
+    #[arg(value_name = "FILE")]
+    path: PathBuf,
+    /// Writes to this file instead of stdout.
+    #[arg(short, long = "out", value_name = "PATH")]
+    out_path: Option<PathBuf>,
+    /// Keeps escape sequences and carriage returns.
+    #[arg(long)]
+    raw: bool,
+    /// Only writes the output from this far into the recording on (eg: 90,
+    /// 12m or 1h2m3s).
+    #[arg(long, value_name = "TIME", value_parser = parse_position)]
+    from: Option<Duration>,
+    /// Only writes the output up to this far into the recording.
+    #[arg(long, value_name = "TIME", value_parser = parse_position)]
+    to: Option<Duration>,
+}
+
+#[derive(Debug, Args)]
+struct SendArgs {
+    /// The name of the session.
+    session: String,
+    /// The input to send.  Escapes such as `\r` and `\x04` are resolved.
+    text: String,
+}
+
+#[derive(Debug, Args)]
+struct AttachArgs {
+    /// The name of the session.
+    session: String,
+    /// Only watches the output without sending any input.
+    #[arg(long)]
+    read_only: bool,
+    /// The escape character to stop watching with (`~.`).  Set to `none` to
+    /// disable.
+    #[arg(
+        short = 'e',
+        long = "escape-char",
+        value_name = "CHAR",
+        default_value = "~",
+        value_parser = parse_escape_char
+    )]
+    escape_char: EscapeChar,
+}
+
+#[derive(Debug, Args)]
+struct BenchArgs {
+    /// How much output the program writes for every run (eg: 64M).
+    #[arg(long, value_name = "BYTES", default_value = "64M", value_parser = parse_byte_size)]
+    size: usize,
+}
+
+#[derive(Debug, Args)]
+struct TestArgs {
+    /// The golden file with the expected output.
+    #[arg(long, value_name = "FILE")]
+    expect_output: PathBuf,
+    /// Replaces matches of this regular expression with `[MASKED]` before
+    /// comparing, eg: for timestamps.  Can be given multiple times.
+    #[arg(long = "mask", value_name = "REGEX")]
+    masks: Vec<Pattern>,
+    /// Writes the output to the golden file instead of comparing it.
+    #[arg(long)]
+    update: bool,
+    /// The command and the arguments to run
+    #[arg(last = true, required = true)]
+    command: Vec<OsString>,
+}
+
+#[derive(Debug, Args)]
+struct MultiArgs {
+    /// The programs to run as `name=command`.  The commands are run with
+    /// `sh -c`.  The name can't contain `/`.
+    #[arg(value_name = "NAME=COMMAND", value_parser = parse_program, required = true)]
+    programs: Vec<(String, String)>,
+    /// The directory to write the `<name>.log` files to.
+    #[arg(long, value_name = "PATH", default_value = ".")]
+    log_dir: PathBuf,
+    /// The prefix of the lines of every program.  `{name}` is replaced with
+    /// the name of the program.  On a terminal the prefix is colored.
+    #[arg(long, value_name = "TEMPLATE", default_value = "{name} | ")]
+    prefix: String,
+    /// Also writes the merged output with plain prefixes to this file.
+    #[arg(long, value_name = "PATH")]
+    log: Option<PathBuf>,
+}
+
+#[derive(Debug, Args)]
+struct DockerArgs {
+    /// The name or id of the container.
+    #[arg(value_name = "CONTAINER")]
+    container: String,
+    /// The container engine to use.
+    #[arg(
+        long,
+        value_name = "ENGINE",
+        default_value = "docker",
+        value_parser = ["docker", "podman"]
+    )]
+    engine: String,
+    /// Passes an extra argument to `exec`, eg: `--exec-arg=--user=root`.  Can
+    /// be given multiple times.
+    #[arg(long = "exec-arg", value_name = "ARG", allow_hyphen_values = true)]
+    exec_args: Vec<String>,
+    #[command(flatten)]
+    run: RunArgs,
+}
+
+#[derive(Debug, Args)]
+struct SshArgs {
+    /// The host to connect to as `[user@]host`.
+    #[arg(value_name = "DESTINATION")]
+    destination: String,
+    /// Passes an extra argument to ssh, eg: `--ssh-arg=-p2222`.  Can be given
+    /// multiple times.
+    #[arg(long = "ssh-arg", value_name = "ARG", allow_hyphen_values = true)]
+    ssh_args: Vec<String>,
+    #[command(flatten)]
+    run: RunArgs,
+}
+
+#[derive(Debug, Args)]
+struct FromTmuxArgs {
+    /// The pane to capture, eg: `%3` or `work:1.0`.
+    #[arg(value_name = "PANE")]
+    pane: String,
+    /// Also records the output with its timing to this file.
+    #[arg(long = "record", value_name = "FILE")]
+    record_path: Option<PathBuf>,
+    /// The format of the recording: `asciicast` (v2) or `ttyrec`.
+    #[arg(
+        long,
+        value_name = "FORMAT",
+        default_value = "asciicast",
+        value_parser = ["asciicast", "ttyrec"]
+    )]
+    format: String,
+    #[command(flatten)]
+    run: RunArgs,
+}
+
+#[derive(Debug, Args)]
+struct CompletionsArgs {
+    /// The shell to complete for.
+    #[arg(value_parser = completions::SHELLS)]
+    shell: String,
+}
+
+#[derive(Debug, Args)]
+struct AuditVerifyArgs {
+    /// The path of the audit log.
+    #[arg(value_name = "FILE")]
+    path: PathBuf,
+    /// Checks that the log ends at this head (`SEQ:DIGEST`, the last line of
+    /// the `--audit-head` file).
+    #[arg(long = "head", value_name = "SEQ:DIGEST")]
+    head: Option<audit::Head>,
+}
+
+#[derive(Debug, Args)]
+struct KillArgs {
+    /// The name of the session.
+    session: String,
+    /// The signal to send (eg: INT, SIGKILL or 9).
+    #[arg(short, long, value_name = "SIG", default_value = "TERM", value_parser = parse_signal_arg)]
+    signal: Signal,
+    /// Signals all processes started by the program as well.
+    #[arg(long)]
+    tree: bool,
+}
+
 pub fn execute() -> Result<i32, Error> {
-    let args = Cli::parse();
-    spawn(&SpawnOptions {
-        args: &args.command[..],
-        out_path: args.out_path.as_deref(),
+    let args = Cli::parse_from(config::apply(
+        std::env::args_os().collect(),
+        &Cli::command(),
+    )?);
+    if args.reset {
+        term::reset()?;
+        return Ok(0);
+    }
+    match args.subcommand {
+        None => run(args.run, None, None),
+        Some(Commands::Run(run_args)) => run(run_args, None, None),
+        Some(Commands::Record(record_args)) => {
+            let format = match record_args.format.as_str() {
+                "ttyrec" => RecordFormat::Ttyrec,
+                _ => RecordFormat::Asciicast,
+            };
+            if record_args.record_input && format == RecordFormat::Ttyrec {
+                bail!("ttyrec recordings cannot hold input");
+            }
+            run(
+                record_args.run,
+                Some((&record_args.path, format, record_args.record_input)),
+                None,
+            )
+        }
+        Some(Commands::Play(ref play_args)) => {
+            let seek = play_args.seek.unwrap_or_default().as_secs_f64();
+            play(&play_args.path, play_args.speed, play_args.idle_limit, seek)
+        }
+        Some(Commands::Export(ref export_args)) => export(
+            &export_args.path,
+            export_args.out_path.as_deref(),
+            export_args.raw,
+            (
+                export_args.from.unwrap_or_default().as_secs_f64(),
+                export_args.to.map(|x| x.as_secs_f64()),
+            ),
+        ),
+        Some(Commands::Attach(ref attach_args)) => {
+            if !attach_args.read_only {
+                bail!("attaching is only supported with --read-only");
+            }
+            attach_read_only(&attach_args.session, attach_args.escape_char.0)
+        }
+        Some(Commands::Send(ref send_args)) => {
+            // the control protocol is line based
+            let text = send_args.text.replace('\n', "\\n");
+            request(&send_args.session, &format!("input {}", text))?;
+            Ok(0)
+        }
+        Some(Commands::List) => list_sessions(),
+        Some(Commands::Kill(ref kill_args)) => kill_session(kill_args),
+        Some(Commands::Bench(ref bench_args)) => bench(bench_args.size),
+        Some(Commands::Test(test_args)) => test(
+            test_args.command,
+            &test_args.expect_output,
+            test_args.masks,
+            test_args.update,
+        ),
+        Some(Commands::Multi(multi_args)) => multi(
+            multi_args.programs,
+            &multi_args.log_dir,
+            &multi_args.prefix,
+            multi_args.log.as_deref(),
+        ),
+        Some(Commands::AuditVerify(ref verify_args)) => {
+            let head = audit::verify(&verify_args.path, verify_args.head.as_ref())?;
+            println!(
+                "{}: {} records, the chain is intact and ends at {}",
+                verify_args.path.display(),
+                head.seq,
+                head
+            );
+            Ok(0)
+        }
+        Some(Commands::Docker(docker_args)) => {
+            let remote = Remote::Container {
+                engine: docker_args.engine,
+                container: docker_args.container,
+                options: docker_args.exec_args,
+            };
+            run(docker_args.run, None, Some(remote))
+        }
+        Some(Commands::Ssh(ssh_args)) => {
+            let remote = Remote::Ssh {
+                destination: ssh_args.destination,
+                options: ssh_args.ssh_args,
+            };
+            run(ssh_args.run, None, Some(remote))
+        }
+        Some(Commands::FromTmux(mut tmux_args)) => {
+            if !tmux_args.run.command.is_empty() {
+                bail!("from-tmux captures a pane and doesn't run a command");
+            }
+            let size = pane_size(&tmux_args.pane)?;
+            let fifo = PaneFifo::create()?;
+            tmux_args.run.command = capture_command(&tmux_args.pane, fifo.path(), size);
+            tmux_args.run.default_size.get_or_insert(size);
+            let format = match tmux_args.format.as_str() {
+                "ttyrec" => RecordFormat::Ttyrec,
+                _ => RecordFormat::Asciicast,
+            };
+            let record = tmux_args
+                .record_path
+                .as_deref()
+                .map(|path| (path, format, false));
+            run(tmux_args.run, record, None)
+        }
+        Some(Commands::Completions(ref completions_args)) => {
+            print!(
+                "{}",
+                completions::generate(&completions_args.shell, Cli::command())
+            );
+            Ok(0)
+        }
+    }
+}
+
+/// Runs the command of the arguments, remotely if `remote` is given.
+fn run(
+    args: RunArgs,
+    record: Option<(&Path, RecordFormat, bool)>,
+    remote: Option<Remote>,
+) -> Result<i32, Error> {
+    if args.command.is_empty() && args.master_fd.is_none() {
+        bail!("no command given to run");
+    }
+    if remote.is_some() && args.master_fd.is_some() {
+        bail!("--master-fd can't be combined with running a command remotely");
+    }
+    if !args.command.is_empty() && args.master_fd.is_some() {
+        bail!("--master-fd doesn't run a command, the program already runs on the pty");
+    }
+    let debug_level = match args.debug.as_deref() {
+        Some("trace") => debug::Level::Trace,
+        Some(_) => debug::Level::Debug,
+        None if args.debug_file.is_some() => debug::Level::Debug,
+        None => debug::Level::Off,
+    };
+    if debug_level != debug::Level::Off {
+        debug::init(debug_level, args.debug_file.as_deref())?;
+    }
+    let mut script = args.script.as_deref().map(Script::load).transpose()?;
+    if let Some(ref path) = args.replay_input {
+        let recording = Recording::load_range(path, 0.0, None)?;
+        if recording.input().next().is_none() {
+            bail!(
+                "{} has no input, record it with --record-input",
+                path.display()
+            );
+        }
+        let input = recording.input().map(|x| (x.time, x.data.as_bytes()));
+        script = Some(Script::replay(input, args.speed));
+    }
+    let out_path = args
+        .out_path
+        .as_deref()
+        .map(|path| expand_path(path, &args))
+        .transpose()?;
+    // placeholders alone expand to the same path every time
+    if args.segment_every.is_some()
+        && !args
+            .out_path
+            .as_deref()
+            .is_some_and(template::has_time_format)
+    {
+        bail!("--segment-every needs an output path with a time format such as %H%M");
+    }
+    let record_path = record
+        .map(|(path, _, _)| expand_path(path, &args))
+        .transpose()?;
+    let mut transforms: Vec<Box<dyn Transform>> = Vec::new();
+    if args.strip_ansi {
+        transforms.push(Box::new(StripAnsi::new()));
+    }
+    if !args.redact.is_empty() {
+        transforms.push(Box::new(Redact::new(args.redact)));
+    }
+    if args.timestamps {
+        transforms.push(Box::new(Timestamps::new()));
+    }
+    let out_filter = match args.encrypt {
+        Some(ref recipient) => Some(encrypt_filter(
+            recipient,
+            out_path
+                .as_deref()
+                .filter(|_| !args.truncate_out && !args.atomic),
+        )?),
+        None => args.out_filter,
+    };
+    let steps = match args.master_fd {
+        Some(_) => Vec::new(),
+        None => chain::split(args.command, args.then, args.chain)?,
+    };
+    let notify_bell = args.on_bell.is_some() || args.bell.iter().any(|x| x == "notify");
+    let opts = SpawnOptions {
+        args: match args.master_fd {
+            Some(_) => Vec::new(),
+            None => {
+                let command = chain::command(steps, args.keep_going);
+                match remote {
+                    Some(ref remote) => remote.wrap(command),
+                    None => command,
+                }
+            }
+        },
+        argv0: args.argv0,
+        login: args.login,
+        term: args.term,
+        out_path,
         truncate_out: args.truncate_out,
+        no_clobber: args.no_clobber,
+        atomic: args.atomic,
+        lock: args.lock || args.lock_wait,
+        lock_wait: args.lock_wait,
+        out_filter,
+        record_path,
+        record_format: record.map(|(_, format, _)| format).unwrap_or_default(),
+        record_input: record.is_some_and(|(_, _, input)| input),
+        metadata: args.metadata,
+        metadata_deny_env: args.metadata_deny_env,
+        checksum: args.checksum,
+        audit_path: args.audit_path,
+        audit_head: args.audit_head,
+        input_timing_path: args.input_timing_path,
+        traffic_path: args.traffic_path,
+        heartbeat: args.heartbeat.map(Duration::from_secs),
+        idle_after: args.idle_after,
+        stats: args.stats,
+        record_for: args.record_for,
+        segment_every: args.segment_every,
+        out_template: args
+            .out_path
+            .clone()
+            .filter(|_| args.segment_every.is_some()),
+        drain_timeout: args.drain_timeout,
+        notify_url: args.notify_url,
+        exit_file: args.exit_file,
+        pid_file: args.pid_file,
+        junit_path: args.junit_path,
+        tap_path: args.tap_path,
+        exit_mode: match &args.exit_mode[..] {
+            "raw" => ExitMode::Raw,
+            "propagate-signal" => ExitMode::PropagateSignal,
+            _ => ExitMode::Shell,
+        },
+        on_start: args.on_start,
+        on_exit: args.on_exit,
+        on_bell: args.on_bell,
+        bell: Bell {
+            count: args.bell.iter().any(|x| x == "count"),
+            notify: notify_bell,
+            strip: args.bell.iter().any(|x| x == "strip"),
+        },
+        on_failure: args.on_failure,
+        metrics_addr: args.metrics_addr,
+        serve_ws: args.serve_ws,
+        viewer_assets: args.viewer_assets,
+        serve_http: args.serve_http,
+        http_token: args.http_token,
+        journald: args.journald,
+        out_url: args.out_url,
+        out_url_headers: args.out_url_headers,
         no_flush: args.no_flush,
-        in_path: args.in_path.as_deref(),
-    })
+        no_splice: args.no_splice,
+        buffer_size: args.buffer_size,
+        poll_winsize: args.poll_winsize,
+        default_size: args.default_size.or_else(size_from_env),
+        in_paths: args.in_paths,
+        in_crlf: args.in_crlf,
+        in_delay: args.in_delay,
+        in_line_delay: args.in_line_delay,
+        in_bracketed_paste: args.in_bracketed_paste,
+        echo_input: args.echo_input,
+        echo_input_log: args.echo_input_log,
+        prompt: args.prompt,
+        break_on: args.break_on,
+        ci: args.ci.as_deref().map(|system| match system {
+            "gitlab" => CiSystem::Gitlab,
+            _ => CiSystem::Github,
+        }),
+        ci_errors: args.ci_errors,
+        script,
+        escape_char: args.escape_char.0,
+        detachable: args.detachable,
+        daemon: args.daemon,
+        master_fd: args.master_fd,
+        no_raw: args.no_raw,
+        on_stdin_eof: args.on_stdin_eof,
+        stdin: match &args.stdin[..] {
+            "none" => ChildStdin::Closed,
+            "null" => ChildStdin::Null,
+            _ => ChildStdin::Pty,
+        },
+        nice: args.nice,
+        ionice: args.ionice,
+        cgroup: args.cgroup,
+        seccomp: args.seccomp,
+        unshare: args
+            .unshare
+            .iter()
+            .map(|x| match &x[..] {
+                "user" => Namespace::User,
+                "pid" => Namespace::Pid,
+                "mount" => Namespace::Mount,
+                "net" => Namespace::Net,
+                "uts" => Namespace::Uts,
+                _ => Namespace::Ipc,
+            })
+            .collect(),
+        core: args.core,
+        ctty: if args.no_ctty {
+            ControllingTerminal::Unchanged
+        } else if args.setsid_only {
+            ControllingTerminal::NewSession
+        } else {
+            ControllingTerminal::Pty
+        },
+        title: args.title,
+        strip_title: args.strip_title,
+        clipboard: match (&args.clipboard[..], args.clipboard_file) {
+            ("block", _) => Clipboard::Block,
+            ("capture", Some(path)) => Clipboard::Capture(path),
+            _ => Clipboard::Allow,
+        },
+        answerback: args.answerback,
+        segment_path: args.segment_path,
+        utmp: args.utmp,
+        headless: false,
+        session: args.session,
+        transforms,
+        transform_terminal: false,
+        sinks: Vec::new(),
+    };
+    Ok(spawn(opts)?)
+}
+
+/// Returns the output filter which encrypts the output for the recipient with
+/// age.  `append_to` is the output file unless it's replaced.
+fn encrypt_filter(recipient: &str, append_to: Option<&Path>) -> Result<String, Error> {
+    let found = std::process::Command::new("age")
+        .arg("--version")
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .status()
+        .is_ok();
+    if !found {
+        bail!("--encrypt needs age (https://age-encryption.org) on the PATH");
+    }
+    // age files can't be concatenated
+    if append_to.is_some_and(|x| fs::metadata(x).is_ok_and(|x| x.len() > 0)) {
+        bail!("encrypted output can't be appended to an existing file, use --truncate");
+    }
+    let flag = if Path::new(recipient).is_file() {
+        "-R"
+    } else {
+        "-r"
+    };
+    let recipient = chain::quote(recipient.as_ref());
+    Ok(format!(
+        "age {} {}",
+        flag,
+        String::from_utf8_lossy(&recipient)
+    ))
+}
+
+/// Expands the placeholders in an output path and creates the directories
+/// leading up to it.
+fn expand_path(path: &Path, args: &RunArgs) -> Result<PathBuf, Error> {
+    template::expand_output_path(
+        path,
+        // an adopted pty has no command
+        args.command
+            .first()
+            .map_or("teetty".as_ref(), |x| x.as_os_str()),
+        args.session.as_deref(),
+        SystemTime::now(),
+    )
+}
+
+fn list_sessions() -> Result<i32, Error> {
+    let sessions = list()?;
+    if !sessions.is_empty() {
+        println!("{:<16} {:>8}  {:<20}  COMMAND", "NAME", "PID", "STARTED");
+    }
+    for info in sessions {
+        println!(
+            "{:<16} {:>8}  {:<20}  {}",
+            info.name,
+            info.pid,
+            format_utc(SystemTime::UNIX_EPOCH + Duration::from_secs(info.started)),
+            info.command
+        );
+    }
+    Ok(0)
+}
+
+fn kill_session(args: &KillArgs) -> Result<i32, Error> {
+    let info = lookup(&args.session)?;
+    let Some(child) = info.child else {
+        bail!("session '{}' has not started its program yet", info.name);
+    };
+    let pids = if args.tree {
+        process_tree(child)?
+    } else {
+        vec![child]
+    };
+    for pid in pids {
+        match kill(pid, args.signal) {
+            Ok(()) => {}
+            // descendants may have exited since the tree was listed
+            Err(Errno::ESRCH) if pid != child => {}
+            Err(errno) => {
+                return Err(Error::new(errno)
+                    .context(format!("could not send {} to process {}", args.signal, pid)));
+            }
+        }
+    }
+    Ok(0)
+}
+
+fn parse_speed(value: &str) -> Result<f64, String> {
+    match value.parse::<f64>() {
+        Ok(speed) if speed > 0.0 && speed.is_finite() => Ok(speed),
+        Ok(_) => Err("speed must be positive".into()),
+        Err(err) => Err(err.to_string()),
+    }
+}
+
+fn parse_secs(value: &str) -> Result<Duration, String> {
+    match value.parse::<f64>() {
+        Ok(secs) => Duration::try_from_secs_f64(secs).map_err(|err| err.to_string()),
+        Err(err) => Err(err.to_string()),
+    }
+}
+
+/// Parses a position in a recording: seconds or a duration such as `12m` or
+/// `1h2m3.5s`.
+fn parse_position(value: &str) -> Result<Duration, String> {
+    if let Ok(secs) = value.parse::<f64>() {
+        return Duration::try_from_secs_f64(secs).map_err(|err| err.to_string());
+    }
+    let mut secs = 0.0;
+    let mut rest = value;
+    while !rest.is_empty() {
+        let end = rest
+            .find(|c: char| !c.is_ascii_digit() && c != '.')
+            .ok_or("expected a unit (h, m or s) after the number")?;
+        let number = rest[..end]
+            .parse::<f64>()
+            .map_err(|_| format!("invalid duration '{}'", value))?;
+        let mut chars = rest[end..].chars();
+        secs += number
+            * match chars.next() {
+                Some('h') => 3600.0,
+                Some('m') => 60.0,
+                Some('s') => 1.0,
+                _ => return Err(format!("invalid duration '{}'", value)),
+            };
+        rest = chars.as_str();
+    }
+    Duration::try_from_secs_f64(secs).map_err(|err| err.to_string())
+}
+
+fn parse_stdin_eof(value: &str) -> Result<StdinEof, String> {
+    match value.split_once(':') {
+        None if value == "veof" => Ok(StdinEof::Veof),
+        None if value == "close" => Ok(StdinEof::Close),
+        None if value == "ignore" => Ok(StdinEof::Ignore),
+        Some(("exit-after", secs)) => parse_secs(secs)
+            .map(StdinEof::ExitAfter)
+            .map_err(|err| format!("invalid timeout: {}", err)),
+        _ => Err("expected veof, close, ignore or exit-after:SECS".into()),
+    }
+}
+
+fn parse_interval(value: &str) -> Result<Duration, String> {
+    match parse_secs(value)? {
+        Duration::ZERO => Err("interval must not be zero".into()),
+        interval => Ok(interval),
+    }
+}
+
+/// Parses a terminal size such as `80x24`.
+fn parse_size(value: &str) -> Result<(u16, u16), String> {
+    let Some((cols, rows)) = value.split_once('x') else {
+        return Err("expected a size such as 80x24".into());
+    };
+    match (cols.parse::<u16>(), rows.parse::<u16>()) {
+        (Ok(0), _) | (_, Ok(0)) => Err("size must not be zero".into()),
+        (Ok(cols), Ok(rows)) => Ok((cols, rows)),
+        (Err(err), _) | (_, Err(err)) => Err(err.to_string()),
+    }
+}
+
+/// Returns the size in `$COLUMNS` and `$LINES` if both are set.
+fn size_from_env() -> Option<(u16, u16)> {
+    let var = |name| {
+        std::env::var(name)
+            .ok()?
+            .parse::<u16>()
+            .ok()
+            .filter(|&x| x > 0)
+    };
+    Some((var("COLUMNS")?, var("LINES")?))
+}
+
+/// Parses an I/O priority such as `best-effort:7`.  The level defaults to 4.
+fn parse_ionice(value: &str) -> Result<IoPriority, String> {
+    let (class, level) = match value.split_once(':') {
+        Some((class, level)) => match level.parse::<u8>() {
+            Ok(level @ 0..=7) => (class, Some(level)),
+            _ => return Err("the level must be from 0 to 7".into()),
+        },
+        None => (value, None),
+    };
+    match class {
+        "realtime" | "1" => Ok(IoPriority::Realtime(level.unwrap_or(4))),
+        "best-effort" | "2" => Ok(IoPriority::BestEffort(level.unwrap_or(4))),
+        "idle" | "3" if level.is_none() => Ok(IoPriority::Idle),
+        "idle" | "3" => Err("the idle class has no levels".into()),
+        _ => Err("expected idle, best-effort or realtime".into()),
+    }
+}
+
+fn parse_core(value: &str) -> Result<CorePolicy, String> {
+    match value.split_once('=') {
+        None if value == "disable" => Ok(CorePolicy::Disable),
+        Some(("dir", path)) if !path.is_empty() => Ok(CorePolicy::Dir(PathBuf::from(path))),
+        _ => Err("expected disable or dir=PATH".into()),
+    }
+}
+
+fn parse_signal_arg(value: &str) -> Result<Signal, String> {
+    parse_signal(value).ok_or_else(|| format!("unknown signal '{}'", value))
+}
+
+/// Parses a byte size with an optional `k` or `m` suffix.
+fn parse_byte_size(value: &str) -> Result<usize, String> {
+    let (num, factor) = match value.as_bytes().last() {
+        Some(b'k' | b'K') => (&value[..value.len() - 1], 1024),
+        Some(b'm' | b'M') => (&value[..value.len() - 1], 1024 * 1024),
+        _ => (value, 1),
+    };
+    match num.parse::<usize>() {
+        Ok(0) => Err("size must not be zero".into()),
+        Ok(n) => n
+            .checked_mul(factor)
+            .ok_or_else(|| "size is too large".into()),
+        Err(err) => Err(err.to_string()),
+    }
+}
+
+/// The escape character or `None` if disabled.
+#[derive(Debug, Clone, Copy)]
+struct EscapeChar(Option<u8>);
+
+fn parse_escape_char(value: &str) -> Result<EscapeChar, String> {
+    match value.as_bytes() {
+        b"none" => Ok(EscapeChar(None)),
+        [c] if c.is_ascii() => Ok(EscapeChar(Some(*c))),
+        [b'^', c] if c.is_ascii_alphabetic() => Ok(EscapeChar(Some(c.to_ascii_uppercase() - b'@'))),
+        _ => Err("expected a single character, ^X or none".into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cli() {
+        Cli::command().debug_assert();
+        let cli = Cli::try_parse_from(["teetty", "-o", "log", "--", "ls", "-l"]).unwrap();
+        assert!(cli.subcommand.is_none());
+        assert_eq!(cli.run.command, ["ls", "-l"]);
+        let cli = Cli::try_parse_from(["teetty", "record", "x.cast", "--", "ls"]).unwrap();
+        assert!(matches!(cli.subcommand, Some(Commands::Record(ref x)) if x.run.command == ["ls"]));
+        let cli = Cli::try_parse_from(["teetty", "--default-size", "120x40", "--", "ls"]).unwrap();
+        assert_eq!(cli.run.default_size, Some((120, 40)));
+        assert!(Cli::try_parse_from(["teetty", "--default-size", "0x40", "--", "ls"]).is_err());
+        let cli = Cli::try_parse_from(["teetty", "--on-stdin-eof", "exit-after:2", "--", "ls"]);
+        assert_eq!(
+            cli.unwrap().run.on_stdin_eof,
+            StdinEof::ExitAfter(Duration::from_secs(2))
+        );
+        // the mode takes exactly one value and leaves the next argument alone
+        let cli = Cli::try_parse_from(["teetty", "--on-stdin-eof", "exit-after", "2", "--", "ls"]);
+        assert!(cli.is_err());
+        let cli = Cli::try_parse_from(["teetty", "--debug", "--", "ls"]).unwrap();
+        assert_eq!(cli.run.debug.as_deref(), Some("debug"));
+        assert!(Cli::try_parse_from(["teetty", "--debug=verbose", "--", "ls"]).is_err());
+        assert_eq!(
+            parse_position("1h2m3.5s"),
+            Ok(Duration::from_secs_f64(3723.5))
+        );
+        assert_eq!(parse_position("90"), Ok(Duration::from_secs(90)));
+        assert!(parse_position("12x").is_err());
+        assert_eq!(parse_ionice("best-effort:7"), Ok(IoPriority::BestEffort(7)));
+        assert_eq!(parse_ionice("idle"), Ok(IoPriority::Idle));
+        assert!(parse_ionice("idle:3").is_err());
+        assert_eq!(
+            parse_core("dir=cores"),
+            Ok(CorePolicy::Dir(PathBuf::from("cores")))
+        );
+        assert!(parse_core("dir=").is_err());
+        let cli = Cli::try_parse_from(["teetty", "--nice", "-5", "--", "ls"]).unwrap();
+        assert_eq!(cli.run.nice, Some(-5));
+        let cli = Cli::try_parse_from(["teetty", "--unshare", "net,pid", "--", "ls"]).unwrap();
+        assert_eq!(cli.run.unshare, ["net", "pid"]);
+    }
 }
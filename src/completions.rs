@@ -0,0 +1,352 @@
+//! Shell completions.
+//!
+//! `teetty completions <shell>` prints a completion script for bash, zsh or
+//! fish which is generated from the command line definition.  The names of the
+//! running sessions are completed for the commands which take a session by
+//! asking `teetty list` when completing, and the program after `--` is
+//! completed like a command.
+use std::fmt::Write;
+
+use clap::{ArgAction, Command, ValueHint};
+
+/// The shells completion scripts can be generated for.
+pub const SHELLS: [&str; 3] = ["bash", "zsh", "fish"];
+
+/// Prints the names of the running sessions, one per line.
+const LIST_SESSIONS: &str = "teetty list 2>/dev/null | awk 'NR > 1 { print $1 }'";
+
+/// An option as the completion scripts need it.
+struct Opt {
+    long: Option<String>,
+    short: Option<char>,
+    help: String,
+    takes_value: bool,
+    values: Vec<String>,
+    path: bool,
+}
+
+impl Opt {
+    fn names(&self) -> Vec<String> {
+        let mut rv = Vec::new();
+        rv.extend(self.long.iter().map(|x| format!("--{}", x)));
+        rv.extend(self.short.iter().map(|x| format!("-{}", x)));
+        rv
+    }
+}
+
+/// The top level of teetty or one of its commands.
+struct Spec {
+    /// The names the command can be given as, empty for the top level.
+    names: Vec<String>,
+    help: String,
+    opts: Vec<Opt>,
+    /// True if the first argument is the name of a session.
+    session: bool,
+}
+
+impl Spec {
+    fn new(cmd: &Command, names: Vec<String>) -> Spec {
+        let mut opts = Vec::new();
+        let mut session = false;
+        for arg in cmd.get_arguments().filter(|x| !x.is_hide_set()) {
+            if arg.is_positional() {
+                session |= arg.get_id() == "session";
+                continue;
+            }
+            let takes_value = !matches!(
+                arg.get_action(),
+                ArgAction::SetTrue
+                    | ArgAction::SetFalse
+                    | ArgAction::Count
+                    | ArgAction::Help
+                    | ArgAction::Version
+            );
+            opts.push(Opt {
+                long: arg.get_long().map(str::to_string),
+                short: arg.get_short(),
+                help: summary(arg.get_help().map(|x| x.to_string())),
+                takes_value,
+                values: arg
+                    .get_possible_values()
+                    .iter()
+                    .map(|x| x.get_name().to_string())
+                    .collect(),
+                path: matches!(
+                    arg.get_value_hint(),
+                    ValueHint::AnyPath | ValueHint::FilePath | ValueHint::DirPath
+                ),
+            });
+        }
+        Spec {
+            names,
+            help: summary(cmd.get_about().map(|x| x.to_string())),
+            opts,
+            session,
+        }
+    }
+
+    fn value_opts(&self) -> String {
+        let names: Vec<_> = self
+            .opts
+            .iter()
+            .filter(|x| x.takes_value)
+            .flat_map(Opt::names)
+            .collect();
+        names.join(" ")
+    }
+
+    fn all_opts(&self) -> String {
+        let names: Vec<_> = self.opts.iter().flat_map(Opt::names).collect();
+        names.join(" ")
+    }
+}
+
+/// Returns the first sentence of a help text.
+fn summary(help: Option<String>) -> String {
+    let help = help.unwrap_or_default().replace('\n', " ");
+    match help.find(". ") {
+        Some(end) => help[..end].to_string(),
+        None => help.trim_end_matches('.').to_string(),
+    }
+}
+
+fn specs(mut cmd: Command) -> Vec<Spec> {
+    cmd.build();
+    let mut specs = vec![Spec::new(&cmd, Vec::new())];
+    for sub in cmd.get_subcommands().filter(|x| !x.is_hide_set()) {
+        let mut names = vec![sub.get_name().to_string()];
+        names.extend(sub.get_visible_aliases().map(str::to_string));
+        specs.push(Spec::new(sub, names));
+    }
+    specs
+}
+
+/// Returns the completion script for the shell.
+pub fn generate(shell: &str, cmd: Command) -> String {
+    let specs = specs(cmd);
+    match shell {
+        "bash" => bash(&specs),
+        "zsh" => zsh(&specs),
+        "fish" => fish(&specs),
+        _ => unreachable!("unknown shell {}", shell),
+    }
+}
+
+/// Writes the part of the bash and zsh scripts which completes the values of
+/// options, then the options and finally the session names.  `words` and
+/// `files` are the shell's ways to offer words and to fall back to paths.
+fn write_cases(out: &mut String, specs: &[Spec], words: &dyn Fn(&str) -> String, files: &str) {
+    out.push_str("    case $cmd in\n");
+    for spec in specs {
+        let pattern = if spec.names.is_empty() {
+            "''".to_string()
+        } else {
+            spec.names.join("|")
+        };
+        writeln!(out, "        {})", pattern).unwrap();
+        out.push_str("            case $prev in\n");
+        for opt in spec.opts.iter().filter(|x| x.takes_value) {
+            let action = if !opt.values.is_empty() {
+                words(&opt.values.join(" "))
+            } else if opt.path {
+                files.to_string()
+            } else {
+                String::new()
+            };
+            writeln!(
+                out,
+                "                {}) {}return ;;",
+                opt.names().join("|"),
+                action
+            )
+            .unwrap();
+        }
+        out.push_str("            esac\n");
+        writeln!(out, "            opts='{}'", spec.all_opts()).unwrap();
+        writeln!(out, "            vals='{}'", spec.value_opts()).unwrap();
+        writeln!(out, "            session={}", spec.session as u8).unwrap();
+        out.push_str("            ;;\n");
+    }
+    out.push_str("    esac\n");
+}
+
+fn bash(specs: &[Spec]) -> String {
+    let commands: Vec<_> = specs.iter().flat_map(|x| x.names.clone()).collect();
+    let words = |x: &str| format!("COMPREPLY=($(compgen -W '{}' -- \"$cur\")); ", x);
+    let mut out = String::new();
+    out.push_str("_teetty() {\n");
+    out.push_str("    local cur=${COMP_WORDS[COMP_CWORD]} prev=${COMP_WORDS[COMP_CWORD-1]}\n");
+    out.push_str("    local cmd= opts= vals= session=0 i\n");
+    out.push_str("    for ((i = 1; i < COMP_CWORD; i++)); do\n");
+    out.push_str("        if [[ ${COMP_WORDS[i]} == -- ]]; then\n");
+    out.push_str("            ((i == COMP_CWORD - 1)) && COMPREPLY=($(compgen -c -- \"$cur\"))\n");
+    out.push_str("            return\n");
+    out.push_str("        fi\n");
+    out.push_str("    done\n");
+    writeln!(
+        out,
+        "    case $((COMP_CWORD > 1)):${{COMP_WORDS[1]}} in\n        1:{}) cmd=${{COMP_WORDS[1]}} ;;\n    esac",
+        commands.join("|1:")
+    )
+    .unwrap();
+    write_cases(&mut out, specs, &words, "");
+    out.push_str("    if [[ $cur == -* ]]; then\n");
+    out.push_str("        COMPREPLY=($(compgen -W \"$opts\" -- \"$cur\"))\n");
+    out.push_str("    elif ((COMP_CWORD == 1)); then\n");
+    writeln!(
+        out,
+        "        COMPREPLY=($(compgen -W '{}' -- \"$cur\"))",
+        commands.join(" ")
+    )
+    .unwrap();
+    out.push_str("    elif ((session)); then\n");
+    out.push_str("        for ((i = 2; i < COMP_CWORD; i++)); do\n");
+    out.push_str("            case \" $vals \" in\n");
+    out.push_str("                *\" ${COMP_WORDS[i]} \"*) ((i++)) ;;\n");
+    out.push_str("                *) [[ ${COMP_WORDS[i]} == -* ]] || return ;;\n");
+    out.push_str("            esac\n");
+    out.push_str("        done\n");
+    writeln!(
+        out,
+        "        COMPREPLY=($(compgen -W \"$({})\" -- \"$cur\"))",
+        LIST_SESSIONS
+    )
+    .unwrap();
+    out.push_str("    fi\n");
+    out.push_str("}\n");
+    out.push_str("complete -o bashdefault -o default -F _teetty teetty\n");
+    out
+}
+
+fn zsh(specs: &[Spec]) -> String {
+    let commands: Vec<_> = specs.iter().flat_map(|x| x.names.clone()).collect();
+    let words = |x: &str| format!("compadd -- {}; ", x);
+    let mut out = String::new();
+    out.push_str("#compdef teetty\n\n");
+    out.push_str("_teetty() {\n");
+    out.push_str("    local cur=${words[CURRENT]} prev=${words[CURRENT-1]}\n");
+    out.push_str("    local cmd= opts= vals= session=0 i\n");
+    out.push_str("    for ((i = 2; i < CURRENT; i++)); do\n");
+    out.push_str("        if [[ ${words[i]} == -- ]]; then\n");
+    out.push_str("            if ((i == CURRENT - 1)); then _command_names -e; else _files; fi\n");
+    out.push_str("            return\n");
+    out.push_str("        fi\n");
+    out.push_str("    done\n");
+    writeln!(
+        out,
+        "    case $((CURRENT > 2)):${{words[2]}} in\n        1:{}) cmd=${{words[2]}} ;;\n    esac",
+        commands.join("|1:")
+    )
+    .unwrap();
+    write_cases(&mut out, specs, &words, "_files; ");
+    out.push_str("    if [[ $cur == -* ]]; then\n");
+    out.push_str("        compadd -- ${=opts}\n");
+    out.push_str("    elif ((CURRENT == 2)); then\n");
+    writeln!(out, "        compadd -- {}", commands.join(" ")).unwrap();
+    out.push_str("    elif ((session)); then\n");
+    out.push_str("        for ((i = 3; i < CURRENT; i++)); do\n");
+    out.push_str("            if [[ \" $vals \" == *\" ${words[i]} \"* ]]; then\n");
+    out.push_str("                ((i++))\n");
+    out.push_str("            elif [[ ${words[i]} != -* ]]; then\n");
+    out.push_str("                _files\n");
+    out.push_str("                return\n");
+    out.push_str("            fi\n");
+    out.push_str("        done\n");
+    writeln!(out, "        compadd -- ${{(f)\"$({})\"}}", LIST_SESSIONS).unwrap();
+    out.push_str("    else\n");
+    out.push_str("        _files\n");
+    out.push_str("    fi\n");
+    out.push_str("}\n\n");
+    out.push_str("if [[ $funcstack[1] == _teetty ]]; then\n");
+    out.push_str("    _teetty \"$@\"\n");
+    out.push_str("else\n");
+    out.push_str("    compdef _teetty teetty\n");
+    out.push_str("fi\n");
+    out
+}
+
+/// Quotes a string for fish.
+fn fish_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\\', "\\\\").replace('\'', "\\'"))
+}
+
+fn fish(specs: &[Spec]) -> String {
+    let mut out = String::new();
+    out.push_str("function __teetty_sessions\n");
+    writeln!(out, "    {}", LIST_SESSIONS).unwrap();
+    out.push_str("end\n\n");
+    for spec in specs {
+        let condition = if spec.names.is_empty() {
+            "__fish_use_subcommand".to_string()
+        } else {
+            format!("__fish_seen_subcommand_from {}", spec.names.join(" "))
+        };
+        if let Some(name) = spec.names.first() {
+            writeln!(
+                out,
+                "complete -c teetty -n __fish_use_subcommand -f -a {} -d {}",
+                name,
+                fish_quote(&spec.help)
+            )
+            .unwrap();
+        }
+        for opt in &spec.opts {
+            write!(out, "complete -c teetty -n {}", fish_quote(&condition)).unwrap();
+            if let Some(ref long) = opt.long {
+                write!(out, " -l {}", long).unwrap();
+            }
+            if let Some(short) = opt.short {
+                write!(out, " -s {}", short).unwrap();
+            }
+            if !opt.values.is_empty() {
+                write!(out, " -x -a {}", fish_quote(&opt.values.join(" "))).unwrap();
+            } else if opt.path {
+                out.push_str(" -r -F");
+            } else if opt.takes_value {
+                out.push_str(" -x");
+            }
+            writeln!(out, " -d {}", fish_quote(&opt.help)).unwrap();
+        }
+        if spec.session {
+            writeln!(
+                out,
+                "complete -c teetty -n {} -f -a '(__teetty_sessions)' -d session",
+                fish_quote(&condition)
+            )
+            .unwrap();
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_generate() {
+        let cmd = || {
+            Command::new("teetty")
+                .arg(
+                    clap::Arg::new("out")
+                        .long("out")
+                        .value_hint(ValueHint::FilePath),
+                )
+                .subcommand(
+                    Command::new("attach")
+                        .about("Watches a session.")
+                        .arg(clap::Arg::new("session"))
+                        .arg(clap::Arg::new("raw").long("raw").action(ArgAction::SetTrue)),
+                )
+        };
+        let bash = generate("bash", cmd());
+        assert!(bash.contains("        attach)\n            case $prev in\n"));
+        assert!(bash.contains("opts='--raw --help -h'"));
+        assert!(bash.contains("vals='--out'"));
+        let fish = generate("fish", cmd());
+        assert!(fish.contains("-a attach -d 'Watches a session'"));
+        assert!(
+            fish.contains("-n '__fish_seen_subcommand_from attach' -f -a '(__teetty_sessions)'")
+        );
+    }
+}
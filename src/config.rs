@@ -0,0 +1,457 @@
+//! Support for configuration files.
+//!
+//! A configuration file provides defaults for the options of `teetty run` in
+//! TOML.  The keys are the names of the long options:
+//!
+//! ```toml
+//! out = "build.log"
+//! no-flush = true
+//! in = ["./stdin", "./automation"]
+//! on-failure = "notify-send 'build failed'"
+//! ```
+//!
+//! The settings are turned into command line arguments which are placed in
+//! front of the ones actually given so that these override the configuration.
+//! Options that can be given multiple times are extended instead.
+use std::ffi::OsString;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Error};
+use clap::Command;
+
+/// A value in a configuration file.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    String(String),
+    Integer(i64),
+    Float(f64),
+    Boolean(bool),
+    Array(Vec<Value>),
+}
+
+/// Inserts the settings of the configuration file into the command line
+/// arguments.
+///
+/// The configuration file is given with `--config` or `$TEETTY_CONFIG`.  The
+/// arguments are returned unchanged if there is none or if the arguments are
+/// for a command which does not run anything.  The commands which run
+/// something are those with the options of `teetty run`, which include
+/// `--config`.
+pub fn apply(args: Vec<OsString>, cmd: &Command) -> Result<Vec<OsString>, Error> {
+    let Some(path) = find_config_path(&args, cmd) else {
+        return Ok(args);
+    };
+    let (pos, run) = match args
+        .get(1)
+        .and_then(|x| x.to_str())
+        .and_then(|x| cmd.find_subcommand(x))
+    {
+        Some(sub) if sub.get_arguments().any(|x| x.get_id() == "config") => (2, sub),
+        Some(_) => return Ok(args),
+        None => (1, cmd.find_subcommand("run").unwrap()),
+    };
+    let settings = load(&path)?;
+    let extra =
+        to_args(&settings, run).with_context(|| format!("invalid config {}", path.display()))?;
+    let mut rv = args;
+    rv.splice(pos.min(rv.len())..pos.min(rv.len()), extra);
+    Ok(rv)
+}
+
+/// Looks for `--config` on the command line and falls back to `$TEETTY_CONFIG`.
+///
+/// The options of teetty end at `--` or at the first argument which isn't an
+/// option, the value of one or a positional argument of the subcommand.  What
+/// follows belongs to the program.
+fn find_config_path(args: &[OsString], cmd: &Command) -> Option<PathBuf> {
+    let mut iter = args.iter().skip(1).peekable();
+    let mut cmd = cmd;
+    let mut positionals = 0;
+    if let Some(sub) = iter
+        .peek()
+        .and_then(|x| x.to_str())
+        .and_then(|x| cmd.find_subcommand(x))
+    {
+        iter.next();
+        cmd = sub;
+        positionals = sub.get_positionals().filter(|x| !x.is_last_set()).count();
+    }
+    let takes_value = |matches: &dyn Fn(&clap::Arg) -> bool| {
+        cmd.get_arguments()
+            .any(|x| matches(x) && x.get_action().takes_values() && !x.is_require_equals_set())
+    };
+    while let Some(arg) = iter.next() {
+        let arg = arg.to_str().unwrap_or("");
+        if arg == "--" {
+            break;
+        } else if arg == "--config" {
+            return iter.next().map(PathBuf::from);
+        } else if let Some(path) = arg.strip_prefix("--config=") {
+            return Some(PathBuf::from(path));
+        } else if let Some(long) = arg.strip_prefix("--") {
+            if !long.contains('=') && takes_value(&|x| x.get_long() == Some(long)) {
+                iter.next();
+            }
+        } else if let Some(shorts) = arg.strip_prefix('-').filter(|x| !x.is_empty()) {
+            // the first short option which takes a value takes the rest or the
+            // next argument
+            for (idx, c) in shorts.char_indices() {
+                if takes_value(&|x| x.get_short() == Some(c)) {
+                    if idx + c.len_utf8() == shorts.len() {
+                        iter.next();
+                    }
+                    break;
+                }
+            }
+        } else if positionals > 0 {
+            positionals -= 1;
+        } else {
+            break;
+        }
+    }
+    std::env::var_os("TEETTY_CONFIG")
+        .filter(|x| !x.is_empty())
+        .map(PathBuf::from)
+}
+
+/// Loads the settings from a configuration file.
+pub fn load(path: &Path) -> Result<Vec<(String, Value)>, Error> {
+    let source = fs::read_to_string(path)
+        .with_context(|| format!("could not read config {}", path.display()))?;
+    parse(&source).with_context(|| format!("invalid config {}", path.display()))
+}
+
+/// Converts settings into command line arguments for the given command.
+fn to_args(settings: &[(String, Value)], cmd: &Command) -> Result<Vec<OsString>, Error> {
+    let mut rv = Vec::new();
+    for (key, value) in settings {
+        let arg = cmd
+            .get_arguments()
+            .find(|x| x.get_long() == Some(key) && key != "config")
+            .ok_or_else(|| Error::msg(format!("unknown setting '{}'", key)))?;
+        if arg.get_action().takes_values() {
+            let values = match value {
+                Value::Array(values) => &values[..],
+                value => std::slice::from_ref(value),
+            };
+            for value in values {
+                let value = match value {
+                    Value::String(s) => s.clone(),
+                    Value::Integer(n) => n.to_string(),
+                    Value::Float(n) => n.to_string(),
+                    _ => bail!("setting '{}' expects a string or number", key),
+                };
+                rv.push(format!("--{}={}", key, value).into());
+            }
+        } else {
+            match value {
+                Value::Boolean(true) => rv.push(format!("--{}", key).into()),
+                Value::Boolean(false) => {}
+                _ => bail!("setting '{}' expects true or false", key),
+            }
+        }
+    }
+    Ok(rv)
+}
+
+/// Parses the subset of TOML needed for configuration files: keys with
+/// strings, numbers, booleans and arrays of them.
+pub fn parse(source: &str) -> Result<Vec<(String, Value)>, Error> {
+    let mut parser = TomlParser {
+        s: source.as_bytes(),
+        pos: 0,
+        line: 1,
+    };
+    let mut rv = Vec::new();
+    loop {
+        parser.skip_blank(true);
+        let Some(&c) = parser.s.get(parser.pos) else {
+            break;
+        };
+        let line = parser.line;
+        if c == b'[' {
+            bail!("line {}: tables are not supported", line);
+        }
+        let key = parser.key().with_context(|| format!("line {line}"))?;
+        parser.skip_blank(false);
+        if !parser.eat(b'=') {
+            bail!("line {}: expected '=' after key", line);
+        }
+        parser.skip_blank(false);
+        let value = parser.value().with_context(|| format!("line {line}"))?;
+        parser.skip_blank(false);
+        if !matches!(parser.s.get(parser.pos), None | Some(b'\n')) {
+            bail!("line {}: expected end of line", line);
+        }
+        if rv.iter().any(|x: &(String, Value)| x.0 == key) {
+            bail!("line {}: duplicate key '{}'", line, key);
+        }
+        rv.push((key, value));
+    }
+    Ok(rv)
+}
+
+struct TomlParser<'a> {
+    s: &'a [u8],
+    pos: usize,
+    line: usize,
+}
+
+impl<'a> TomlParser<'a> {
+    /// Skips whitespace and comments, optionally including newlines.
+    fn skip_blank(&mut self, newlines: bool) {
+        while let Some(&c) = self.s.get(self.pos) {
+            match c {
+                b' ' | b'\t' | b'\r' => self.pos += 1,
+                b'\n' if newlines => {
+                    self.pos += 1;
+                    self.line += 1;
+                }
+                b'#' => {
+                    while self.s.get(self.pos).is_some_and(|&c| c != b'\n') {
+                        self.pos += 1;
+                    }
+                }
+                _ => break,
+            }
+        }
+    }
+
+    fn eat(&mut self, c: u8) -> bool {
+        if self.s.get(self.pos) == Some(&c) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn key(&mut self) -> Result<String, Error> {
+        match self.s.get(self.pos) {
+            Some(b'"') | Some(b'\'') => return self.string(),
+            _ => {}
+        }
+        let start = self.pos;
+        while self
+            .s
+            .get(self.pos)
+            .is_some_and(|&c| c.is_ascii_alphanumeric() || c == b'-' || c == b'_')
+        {
+            self.pos += 1;
+        }
+        if start == self.pos {
+            bail!("expected a key");
+        }
+        Ok(String::from_utf8_lossy(&self.s[start..self.pos]).into_owned())
+    }
+
+    fn value(&mut self) -> Result<Value, Error> {
+        let rest = &self.s[self.pos..];
+        if rest.starts_with(b"true") {
+            self.pos += 4;
+            return Ok(Value::Boolean(true));
+        } else if rest.starts_with(b"false") {
+            self.pos += 5;
+            return Ok(Value::Boolean(false));
+        }
+        match rest.first() {
+            Some(b'"') | Some(b'\'') => Ok(Value::String(self.string()?)),
+            Some(b'[') => {
+                self.pos += 1;
+                let mut items = Vec::new();
+                loop {
+                    self.skip_blank(true);
+                    if self.eat(b']') {
+                        break;
+                    }
+                    items.push(self.value()?);
+                    self.skip_blank(true);
+                    if self.eat(b']') {
+                        break;
+                    } else if !self.eat(b',') {
+                        bail!("expected ',' or ']' in array");
+                    }
+                }
+                Ok(Value::Array(items))
+            }
+            Some(b'+' | b'-' | b'0'..=b'9') => {
+                let start = self.pos;
+                while self.s.get(self.pos).is_some_and(|&c| {
+                    c.is_ascii_alphanumeric() || matches!(c, b'+' | b'-' | b'.' | b'_')
+                }) {
+                    self.pos += 1;
+                }
+                let num = String::from_utf8_lossy(&self.s[start..self.pos]).replace('_', "");
+                if let Ok(n) = num.parse() {
+                    Ok(Value::Integer(n))
+                } else if let Ok(n) = num.parse() {
+                    Ok(Value::Float(n))
+                } else {
+                    bail!("invalid number '{}'", num);
+                }
+            }
+            _ => bail!("expected a value"),
+        }
+    }
+
+    fn string(&mut self) -> Result<String, Error> {
+        let quote = self.s[self.pos];
+        if self.s[self.pos..].starts_with(&[quote; 3]) {
+            bail!("multi-line strings are not supported");
+        }
+        self.pos += 1;
+        let mut rv = Vec::new();
+        loop {
+            let c = match self.s.get(self.pos) {
+                Some(b'\n') | None => bail!("unterminated string"),
+                Some(&c) => c,
+            };
+            self.pos += 1;
+            if c == quote {
+                break;
+            } else if c == b'\\' && quote == b'"' {
+                let Some(&c) = self.s.get(self.pos) else {
+                    bail!("unterminated string");
+                };
+                self.pos += 1;
+                match c {
+                    b'n' => rv.push(b'\n'),
+                    b'r' => rv.push(b'\r'),
+                    b't' => rv.push(b'\t'),
+                    b'e' => rv.push(0x1b),
+                    b'"' | b'\\' => rv.push(c),
+                    b'u' | b'U' => {
+                        let len = if c == b'u' { 4 } else { 8 };
+                        let c = self
+                            .s
+                            .get(self.pos..self.pos + len)
+                            .and_then(|x| std::str::from_utf8(x).ok())
+                            .and_then(|x| u32::from_str_radix(x, 16).ok())
+                            .and_then(char::from_u32)
+                            .ok_or_else(|| Error::msg("invalid unicode escape"))?;
+                        self.pos += len;
+                        rv.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+                    }
+                    c => bail!("invalid escape '\\{}'", c as char),
+                }
+            } else {
+                rv.push(c);
+            }
+        }
+        String::from_utf8(rv).map_err(|_| Error::msg("invalid UTF-8 in string"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let settings = parse(
+            "# defaults for CI\n\
+             out = \"logs/build.log\"  # comment\n\
+             no-flush = true\n\
+             buffer-size = 65_536\n\
+             in = [\n  './a',\n  \"b\\tc\", # second\n]\n",
+        )
+        .unwrap();
+        assert_eq!(
+            settings,
+            vec![
+                ("out".into(), Value::String("logs/build.log".into())),
+                ("no-flush".into(), Value::Boolean(true)),
+                ("buffer-size".into(), Value::Integer(65536)),
+                (
+                    "in".into(),
+                    Value::Array(vec![
+                        Value::String("./a".into()),
+                        Value::String("b\tc".into())
+                    ])
+                ),
+            ]
+        );
+        assert!(parse("out = 'x' y").is_err());
+        assert!(parse("[run]\nout = 'x'").is_err());
+        assert!(parse("out = 'x'\nout = 'y'").is_err());
+    }
+
+    #[test]
+    fn test_find_config_path() {
+        use clap::Arg;
+
+        let run = Command::new("run")
+            .arg(Arg::new("config").long("config"))
+            .arg(Arg::new("out").short('o').long("out"));
+        let cmd = Command::new("teetty")
+            .arg(Arg::new("config").long("config"))
+            .arg(Arg::new("out").short('o').long("out"))
+            .subcommand(run.clone())
+            .subcommand(run.name("record").arg(Arg::new("path")));
+        // ignores the fallback to the environment
+        let env = std::env::var_os("TEETTY_CONFIG").map(PathBuf::from);
+        let find = |args: &[&str]| {
+            let args = args.iter().map(OsString::from).collect::<Vec<_>>();
+            find_config_path(&args, &cmd).filter(|x| Some(x) != env.as_ref())
+        };
+        assert_eq!(
+            find(&["teetty", "--config", "a", "--", "ls"]),
+            Some("a".into())
+        );
+        assert_eq!(find(&["teetty", "-o", "x", "--config=b"]), Some("b".into()));
+        assert_eq!(
+            find(&["teetty", "record", "x.cast", "--config", "c"]),
+            Some("c".into())
+        );
+        // the options of the program
+        assert_eq!(find(&["teetty", "--", "prog", "--config", "x"]), None);
+        assert_eq!(
+            find(&["teetty", "-o", "out", "prog", "--config", "x"]),
+            None
+        );
+        assert_eq!(find(&["teetty", "--out", "--config", "prog"]), None);
+    }
+
+    #[test]
+    fn test_apply() {
+        use clap::Arg;
+
+        let run = Command::new("run")
+            .arg(Arg::new("config").long("config"))
+            .arg(Arg::new("out").short('o').long("out"));
+        let cmd = Command::new("teetty")
+            .arg(Arg::new("config").long("config"))
+            .subcommand(run.clone())
+            .subcommand(run.name("docker").arg(Arg::new("container")))
+            .subcommand(Command::new("list").arg(Arg::new("all").long("all")));
+        let path = std::env::temp_dir().join(format!("teetty-config-{}.toml", std::process::id()));
+        fs::write(&path, "out = \"x.log\"\n").unwrap();
+        let config = path.to_str().unwrap();
+        let apply = |args: &[&str]| {
+            let args = args.iter().map(OsString::from).collect();
+            apply(args, &cmd).unwrap()
+        };
+        // subcommands with the options of `teetty run` get the settings
+        assert_eq!(
+            apply(&["teetty", "docker", "--config", config, "c1", "--", "true"]),
+            [
+                "teetty",
+                "docker",
+                "--out=x.log",
+                "--config",
+                config,
+                "c1",
+                "--",
+                "true"
+            ]
+        );
+        assert_eq!(
+            apply(&["teetty", "--config", config, "--", "true"]),
+            ["teetty", "--out=x.log", "--config", config, "--", "true"]
+        );
+        let args = ["teetty", "list", "--config", config];
+        assert_eq!(apply(&args), args);
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,292 @@
+//! The control socket of named sessions.
+//!
+//! Clients connect to the unix socket of a session and send a command line:
+//!
+//! * `attach read-only` turns the connection into a stream of frames carrying
+//!   the output of the session (`o`) and the size of its terminal (`r`, as
+//!   `rows cols`).  Every frame is a kind byte followed by the length of the
+//!   payload as big endian u32 and the payload itself.  With `--idle-after`
+//!   event frames (`e`) carry `idle` and `active` when the session becomes
+//!   idle or active again.
+//! * `tail` turns the connection into a stream of the raw output.
+//! * `status` responds with a JSON object describing the session.
+//! * `signal <SIG>` sends a signal to the foreground process of the session.
+//! * `input <text>` sends input after resolving escapes such as `\r` and `\x04`.
+//! * `resize <rows> <cols>` changes the size of the terminal.
+//! * `shutdown [eof|kill|<SIG>] [<secs>]` ends the session (see
+//!   [`ShutdownMode`]).  By default `SIGTERM` is sent and the program gets five
+//!   seconds to exit.
+//!
+//! Commands other than `attach` and `tail` respond with a single line (`ok` or
+//! `error: <message>`) and close the connection.
+use std::io::{self, Read, Write};
+use std::os::fd::AsRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::{bail, Error};
+use nix::sys::signal::Signal;
+
+use crate::broadcast::{Broadcaster, Format};
+use crate::poll::PollSet;
+use crate::registry::{lookup, socket_path};
+use crate::script::unescape;
+use crate::spawn::parse_signal;
+
+/// A frame with output of the session.
+pub const FRAME_OUTPUT: u8 = b'o';
+
+/// A frame with the terminal size of the session.
+pub const FRAME_RESIZE: u8 = b'r';
+
+/// A frame with an event of the session.
+pub const FRAME_EVENT: u8 = b'e';
+
+/// How long the program gets to exit after `shutdown` by default.
+const DEFAULT_SHUTDOWN_GRACE: Duration = Duration::from_secs(5);
+
+/// Commands are not allowed to be longer than this.
+const MAX_COMMAND_LEN: usize = 4096;
+
+/// Encodes a single frame.
+pub fn encode_frame(kind: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(payload.len() + 5);
+    frame.push(kind);
+    frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    frame.extend_from_slice(payload);
+    frame
+}
+
+/// Reads a single frame.  Returns `None` once the other side hung up.
+pub fn read_frame<R: Read>(r: &mut R) -> io::Result<Option<(u8, Vec<u8>)>> {
+    let mut header = [0u8; 5];
+    match r.read_exact(&mut header) {
+        Ok(()) => {}
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err),
+    }
+    let len = u32::from_be_bytes([header[1], header[2], header[3], header[4]]);
+    let mut payload = vec![0; len as usize];
+    r.read_exact(&mut payload)?;
+    Ok(Some((header[0], payload)))
+}
+
+/// Sends a command to a named session and returns the response.
+pub fn request(name: &str, command: &str) -> Result<String, Error> {
+    let info = lookup(name)?;
+    let mut stream = match UnixStream::connect(socket_path(name)?) {
+        Ok(stream) => stream,
+        Err(err) => bail!("could not connect to session '{}': {}", info.name, err),
+    };
+    stream.write_all(command.as_bytes())?;
+    stream.write_all(b"\n")?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    let response = response.trim_end();
+    match response.strip_prefix("error: ") {
+        Some(err) => bail!("session '{}': {}", info.name, err),
+        None => Ok(response.to_string()),
+    }
+}
+
+/// How a session is shut down.
+///
+/// The output is still captured while the program exits.  If it's still
+/// running once the grace period is over it's killed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShutdownMode {
+    /// Sends EOF as if `^D` was typed and waits for the program to exit.
+    Eof(Duration),
+    /// Sends a signal to the program and waits for it to exit.
+    Signal(Signal, Duration),
+    /// Kills the program right away.
+    Kill,
+}
+
+/// Something a client asked the session to do.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ControlCommand {
+    Signal(Signal),
+    Input(Vec<u8>),
+    Resize(u16, u16),
+    Shutdown(ShutdownMode),
+}
+
+impl ControlCommand {
+    /// Parses the argument of a `signal` command.
+    pub fn signal(arg: &str) -> Result<ControlCommand, String> {
+        parse_signal(arg.trim())
+            .map(ControlCommand::Signal)
+            .ok_or_else(|| format!("unknown signal '{}'", arg.trim()))
+    }
+
+    /// Parses the argument of a `resize` command (`rows cols`).
+    pub fn resize(arg: &str) -> Result<ControlCommand, String> {
+        let mut parts = arg.split_whitespace().map(|x| x.parse::<u16>());
+        match (parts.next(), parts.next(), parts.next()) {
+            (Some(Ok(rows)), Some(Ok(cols)), None) => Ok(ControlCommand::Resize(rows, cols)),
+            _ => Err("expected rows and columns".into()),
+        }
+    }
+
+    /// Parses the argument of a `shutdown` command (`[eof|kill|<SIG>]
+    /// [<secs>]`).
+    pub fn shutdown(arg: &str) -> Result<ControlCommand, String> {
+        let mut parts = arg.split_whitespace();
+        let how = parts.next().unwrap_or("TERM");
+        let grace = match parts.next() {
+            Some(secs) => secs
+                .parse::<f64>()
+                .ok()
+                .and_then(|x| Duration::try_from_secs_f64(x).ok())
+                .ok_or_else(|| format!("invalid grace period '{}'", secs))?,
+            None => DEFAULT_SHUTDOWN_GRACE,
+        };
+        if parts.next().is_some() {
+            return Err("expected a mode and a grace period".into());
+        }
+        let mode = match how {
+            "eof" => ShutdownMode::Eof(grace),
+            "kill" => ShutdownMode::Kill,
+            _ => match parse_signal(how) {
+                Some(signal) => ShutdownMode::Signal(signal, grace),
+                None => return Err(format!("unknown shutdown mode '{}'", how)),
+            },
+        };
+        Ok(ControlCommand::Shutdown(mode))
+    }
+}
+
+/// A client that didn't send its command yet.
+struct Client {
+    stream: UnixStream,
+    command: Vec<u8>,
+}
+
+/// Accepts connections on the control socket and serves them.
+pub struct ControlServer {
+    listener: UnixListener,
+    clients: Vec<Client>,
+}
+
+impl ControlServer {
+    pub fn bind(path: &Path) -> io::Result<ControlServer> {
+        // the registration guarantees that no running session uses this socket
+        // so whatever is left over can be removed.
+        std::fs::remove_file(path).ok();
+        let listener = UnixListener::bind(path)?;
+        listener.set_nonblocking(true)?;
+        Ok(ControlServer {
+            listener,
+            clients: Vec::new(),
+        })
+    }
+
+    /// Adds the fds that need watching to the set.
+    pub fn watch(&self, fds: &mut PollSet) {
+        fds.insert_read(self.listener.as_raw_fd());
+        for client in &self.clients {
+            fds.insert_read(client.stream.as_raw_fd());
+        }
+    }
+
+    /// Accepts new clients and reads their commands.  Commands that need to be
+    /// carried out by the session are added to `commands`.
+    ///
+    /// Returns `true` if a new follower attached.
+    pub fn handle(
+        &mut self,
+        fds: &PollSet,
+        followers: &mut Broadcaster,
+        status: &dyn Fn() -> String,
+        commands: &mut Vec<ControlCommand>,
+    ) -> bool {
+        if fds.readable(self.listener.as_raw_fd()) {
+            while let Ok((stream, _)) = self.listener.accept() {
+                if stream.set_nonblocking(true).is_ok() {
+                    self.clients.push(Client {
+                        stream,
+                        command: Vec::new(),
+                    });
+                }
+            }
+        }
+
+        let mut attached = false;
+        let mut idx = 0;
+        while idx < self.clients.len() {
+            let client = &mut self.clients[idx];
+            if !fds.readable(client.stream.as_raw_fd()) {
+                idx += 1;
+                continue;
+            }
+            let mut buf = [0; 1024];
+            match client.stream.read(&mut buf) {
+                Ok(0) => {}
+                Ok(n) => {
+                    client.command.extend_from_slice(&buf[..n]);
+                    match client.command.iter().position(|&c| c == b'\n') {
+                        Some(end) => {
+                            let client = self.clients.remove(idx);
+                            attached |= execute(client, end, followers, status, commands);
+                            continue;
+                        }
+                        None if client.command.len() <= MAX_COMMAND_LEN => {
+                            idx += 1;
+                            continue;
+                        }
+                        None => {}
+                    }
+                }
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    idx += 1;
+                    continue;
+                }
+                Err(_) => {}
+            }
+            self.clients.remove(idx);
+        }
+        attached
+    }
+}
+
+/// Executes the command of a client.
+fn execute(
+    mut client: Client,
+    end: usize,
+    followers: &mut Broadcaster,
+    status: &dyn Fn() -> String,
+    commands: &mut Vec<ControlCommand>,
+) -> bool {
+    let line = String::from_utf8_lossy(&client.command[..end]).into_owned();
+    let (cmd, arg) = line.split_once(' ').unwrap_or((&line, ""));
+    let rv = match cmd {
+        "attach" if arg == "read-only" => {
+            return followers.add(client.stream, Format::Framed).is_ok()
+        }
+        "tail" => return followers.add(client.stream, Format::Raw).is_ok(),
+        "status" => Ok(status()),
+        "signal" => ControlCommand::signal(arg)
+            .map(|x| commands.push(x))
+            .map(|_| "ok".into()),
+        "input" => {
+            commands.push(ControlCommand::Input(unescape(arg)));
+            Ok("ok".into())
+        }
+        "resize" => ControlCommand::resize(arg)
+            .map(|x| commands.push(x))
+            .map(|_| "ok".into()),
+        "shutdown" => ControlCommand::shutdown(arg)
+            .map(|x| commands.push(x))
+            .map(|_| "ok".into()),
+        _ => Err(format!("unknown command '{}'", cmd)),
+    };
+    let response = match rv {
+        Ok(response) => format!("{}\n", response),
+        Err(err) => format!("error: {}\n", err),
+    };
+    client.stream.set_nonblocking(false).ok();
+    client.stream.write_all(response.as_bytes()).ok();
+    false
+}
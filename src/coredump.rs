@@ -0,0 +1,168 @@
+//! Collecting the core dumps of the program.
+//!
+//! With `--core dir=<path>` the program may dump cores up to the hard limit
+//! and when the session ends, the core dumps written since it started are moved
+//! to the directory.  The kernel decides where cores go for all processes, so
+//! teetty looks where the core pattern puts them (`core` in the working
+//! directory by default) and only picks up files which look like core dumps.
+//! If the pattern pipes them to a program such as systemd-coredump, teetty
+//! can't get them and says so.
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use anyhow::{anyhow, bail, Context, Error};
+
+/// Returns where the kernel writes core dumps, with `%` specifiers.
+fn core_pattern() -> String {
+    if let Ok(pattern) = fs::read_to_string("/proc/sys/kernel/core_pattern") {
+        let pattern = pattern.trim_end().to_string();
+        let uses_pid =
+            fs::read_to_string("/proc/sys/kernel/core_uses_pid").is_ok_and(|x| x.trim() == "1");
+        return if uses_pid && !pattern.contains("%p") {
+            pattern + ".%p"
+        } else {
+            pattern
+        };
+    }
+    if cfg!(target_os = "macos") {
+        "/cores/core.%P".into()
+    } else {
+        "%N.core".into()
+    }
+}
+
+/// Moves the core dumps written since `since` to `dir` and returns their new
+/// paths.
+pub fn collect(dir: &Path, since: SystemTime) -> Result<Vec<PathBuf>, Error> {
+    let pattern = core_pattern();
+    if let Some(handler) = pattern.strip_prefix('|') {
+        let handler = handler.split_whitespace().next().unwrap_or_default();
+        bail!(
+            "core dumps are passed to {} and not written to {}",
+            handler,
+            dir.display()
+        );
+    }
+    let (source, name) = match pattern.rsplit_once('/') {
+        Some(("", name)) => (Path::new("/"), name),
+        Some((source, name)) => (Path::new(source), name),
+        None => (Path::new("."), &pattern[..]),
+    };
+    if source.to_str().is_some_and(|x| x.contains('%')) {
+        bail!("can't find core dumps for the core pattern {}", pattern);
+    }
+    let name = wildcard(name);
+    // file times come from a coarser clock and may be a bit behind
+    let since = since - Duration::from_secs(1);
+    let mut moved = Vec::new();
+    let Ok(entries) = fs::read_dir(source) else {
+        return Ok(moved);
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let recent = entry
+            .metadata()
+            .and_then(|x| x.modified())
+            .is_ok_and(|x| x >= since);
+        if !recent || !glob(&name, entry.file_name().as_encoded_bytes()) || !is_core(&path) {
+            continue;
+        }
+        fs::create_dir_all(dir).with_context(|| format!("could not create {}", dir.display()))?;
+        let target = dir.join(entry.file_name());
+        if fs::rename(&path, &target).is_err() {
+            // the directory is on another file system
+            fs::copy(&path, &target)
+                .and_then(|_| fs::remove_file(&path))
+                .map_err(|err| anyhow!(err))
+                .with_context(|| format!("could not move {}", path.display()))?;
+        }
+        moved.push(target);
+    }
+    Ok(moved)
+}
+
+/// Replaces the specifiers of a core pattern with `*`.
+fn wildcard(pattern: &str) -> Vec<u8> {
+    let mut rv = Vec::new();
+    let mut chars = pattern.bytes();
+    while let Some(c) = chars.next() {
+        if c != b'%' {
+            rv.push(c);
+        } else if chars.next() == Some(b'%') {
+            rv.push(b'%');
+        } else {
+            rv.push(b'*');
+        }
+    }
+    rv
+}
+
+/// Matches a name against a pattern where `*` matches anything.
+fn glob(pattern: &[u8], name: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => name.is_empty(),
+        Some((b'*', rest)) => (0..=name.len()).any(|i| glob(rest, &name[i..])),
+        Some((c, rest)) => name.first() == Some(c) && glob(rest, &name[1..]),
+    }
+}
+
+/// Checks for the headers of ELF and Mach-O core files.
+fn is_core(path: &Path) -> bool {
+    let mut header = [0; 18];
+    File::open(path)
+        .and_then(|mut x| x.read_exact(&mut header))
+        .is_ok()
+        && is_core_header(&header)
+}
+
+fn is_core_header(header: &[u8; 18]) -> bool {
+    const ET_CORE: u16 = 4;
+    const MH_CORE: u32 = 4;
+    match header[..4] {
+        // `e_type` follows the 16 bytes of `e_ident`, which say if it's big endian
+        [0x7f, b'E', b'L', b'F'] => {
+            let e_type = [header[16], header[17]];
+            let e_type = match header[5] {
+                2 => u16::from_be_bytes(e_type),
+                _ => u16::from_le_bytes(e_type),
+            };
+            e_type == ET_CORE
+        }
+        // 64-bit little endian, `filetype` follows `magic`, `cputype` and
+        // `cpusubtype`
+        [0xcf, 0xfa, 0xed, 0xfe] => {
+            u32::from_le_bytes([header[12], header[13], header[14], header[15]]) == MH_CORE
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_glob() {
+        assert!(glob(&wildcard("core.%e.%p"), b"core.python3.1234"));
+        assert!(glob(&wildcard("core"), b"core"));
+        assert!(!glob(&wildcard("core"), b"core.rs"));
+        assert!(glob(&wildcard("100%%.%p"), b"100%.7"));
+    }
+
+    #[test]
+    fn test_is_core_header() {
+        let mut elf = *b"\x7fELF\x02\x01\x01\0\0\0\0\0\0\0\0\0\x04\0";
+        assert!(is_core_header(&elf));
+        // an executable called core
+        elf[16] = 2;
+        assert!(!is_core_header(&elf));
+        let mut macho = [0; 18];
+        macho[..4].copy_from_slice(&[0xcf, 0xfa, 0xed, 0xfe]);
+        macho[12] = 4;
+        assert!(is_core_header(&macho));
+        macho[12] = 2;
+        assert!(!is_core_header(&macho));
+    }
+}
@@ -0,0 +1,95 @@
+//! Debug logging for diagnosing sessions.
+//!
+//! With `--debug` teetty logs what happens in the session such as signals,
+//! the exit of the program and other changes of its state, `--debug=trace`
+//! also logs every wakeup of the loop and the bytes it moves.  The log goes to
+//! stderr or to the file given with `--debug-file`.
+use std::fs::File;
+use std::io::{self, IsTerminal, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::Instant;
+
+use anyhow::{Context, Error};
+
+/// How much is logged.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
+pub enum Level {
+    Off,
+    /// Signals and changes of the state of the session.
+    Debug,
+    /// Additionally every wakeup of the loop and the bytes read.
+    Trace,
+}
+
+static LEVEL: AtomicU8 = AtomicU8::new(Level::Off as u8);
+static LOG: OnceLock<(Instant, Mutex<Option<File>>)> = OnceLock::new();
+
+/// Turns on logging to stderr, or to the file if given.
+pub fn init(level: Level, path: Option<&Path>) -> Result<(), Error> {
+    let file = path
+        .map(|path| {
+            File::options()
+                .append(true)
+                .create(true)
+                .open(path)
+                .with_context(|| format!("could not open {}", path.display()))
+        })
+        .transpose()?;
+    LOG.set((Instant::now(), Mutex::new(file))).ok();
+    LEVEL.store(level as u8, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Checks if messages of the level are logged.
+pub fn enabled(level: Level) -> bool {
+    level != Level::Off && LEVEL.load(Ordering::Relaxed) >= level as u8
+}
+
+/// Writes a message with the time since logging was turned on.  Use the
+/// `debug!` and `trace!` macros instead.
+pub(crate) fn log(level: Level, args: std::fmt::Arguments) {
+    let Some((started, file)) = LOG.get() else {
+        return;
+    };
+    let tag = if level == Level::Trace {
+        "trace"
+    } else {
+        "debug"
+    };
+    let line = format!(
+        "[{:10.6}] {}: {}",
+        started.elapsed().as_secs_f64(),
+        tag,
+        args
+    );
+    let mut file = file.lock().unwrap_or_else(|err| err.into_inner());
+    match *file {
+        Some(ref mut file) => writeln!(file, "{}", line).ok(),
+        // the terminal might be in raw mode
+        None if io::stderr().is_terminal() => write!(io::stderr(), "{}\r\n", line).ok(),
+        None => writeln!(io::stderr(), "{}", line).ok(),
+    };
+}
+
+/// Logs a message about the state of the session.
+macro_rules! debug {
+    ($($arg:tt)*) => {
+        if $crate::debug::enabled($crate::debug::Level::Debug) {
+            $crate::debug::log($crate::debug::Level::Debug, format_args!($($arg)*));
+        }
+    };
+}
+
+/// Logs a message about the details of the loop.
+macro_rules! trace {
+    ($($arg:tt)*) => {
+        if $crate::debug::enabled($crate::debug::Level::Trace) {
+            $crate::debug::log($crate::debug::Level::Trace, format_args!($($arg)*));
+        }
+    };
+}
+
+pub(crate) use {debug, trace};
@@ -0,0 +1,77 @@
+//! Showing injected input on the terminal and in the log.
+use std::fs::File;
+use std::io::{self, Write};
+use std::time::Duration;
+
+use nix::libc::STDOUT_FILENO;
+
+use crate::sink::{Event, Sink};
+use crate::spawn::write_all_fd;
+
+/// Mirrors the input sent through the FIFOs, scripts and control commands.
+///
+/// Every injection is shown as an `[input: ...]` marker on a line of its own
+/// with control characters escaped.
+pub struct InputEcho {
+    terminal: bool,
+    log: Option<File>,
+    at_line_start: bool,
+}
+
+impl InputEcho {
+    /// Creates the echo.  `log` is the output file if the input should also
+    /// be written there.
+    pub fn new(terminal: bool, log: Option<File>) -> InputEcho {
+        InputEcho {
+            terminal,
+            log,
+            at_line_start: true,
+        }
+    }
+}
+
+impl Sink for InputEcho {
+    fn write_chunk(&mut self, _ts: Duration, bytes: &[u8]) -> io::Result<()> {
+        if let Some(&last) = bytes.last() {
+            self.at_line_start = last == b'\n';
+        }
+        Ok(())
+    }
+
+    fn event(&mut self, event: &Event) -> io::Result<()> {
+        let Event::InputInjected(data) = event else {
+            return Ok(());
+        };
+        let marker = format!(
+            "{}[input: {}]\r\n",
+            if self.at_line_start { "" } else { "\r\n" },
+            data.escape_ascii()
+        );
+        if self.terminal {
+            write_all_fd(STDOUT_FILENO, marker.as_bytes())?;
+        }
+        if let Some(ref mut log) = self.log {
+            log.write_all(marker.as_bytes())?;
+        }
+        self.at_line_start = true;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_markers() {
+        let path = std::env::temp_dir().join(format!("teetty-echo-{}.log", std::process::id()));
+        let mut echo = InputEcho::new(false, Some(File::create(&path).unwrap()));
+        echo.write_chunk(Duration::ZERO, b"name? ").unwrap();
+        echo.event(&Event::InputInjected(b"bob\r".to_vec()))
+            .unwrap();
+        echo.event(&Event::InputInjected(b"\x1b".to_vec())).unwrap();
+        let log = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).ok();
+        assert_eq!(log, b"\r\n[input: bob\\r]\r\n[input: \\x1b]\r\n");
+    }
+}
@@ -0,0 +1,82 @@
+//! ssh style escape sequences on the keyboard input.
+//!
+//! The escape character is only recognized at the beginning of a line, that is
+//! right after enter was pressed or at the very start of the session.
+
+/// What the user asked for with an escape sequence.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EscapeAction {
+    /// `~.` detaches from (or ends) the session.
+    Detach,
+    /// `~B` sends a break.
+    Break,
+    /// `~?` shows the help.
+    Help,
+}
+
+/// Looks for escape sequences in the keyboard input.
+pub struct EscapeDetector {
+    escape_char: u8,
+    at_line_start: bool,
+    pending: bool,
+}
+
+impl EscapeDetector {
+    pub fn new(escape_char: u8) -> EscapeDetector {
+        EscapeDetector {
+            escape_char,
+            at_line_start: true,
+            pending: false,
+        }
+    }
+
+    /// Feeds a byte of keyboard input.
+    ///
+    /// Bytes that should be passed on to the program are appended to `out`.
+    pub fn feed(&mut self, c: u8, out: &mut Vec<u8>) -> Option<EscapeAction> {
+        if self.pending {
+            self.pending = false;
+            let action = match c {
+                b'.' => Some(EscapeAction::Detach),
+                b'B' => Some(EscapeAction::Break),
+                b'?' => Some(EscapeAction::Help),
+                c if c == self.escape_char => {
+                    out.push(c);
+                    None
+                }
+                c => {
+                    out.push(self.escape_char);
+                    out.push(c);
+                    None
+                }
+            };
+            self.at_line_start = action.is_some() || c == b'\r' || c == b'\n';
+            return action;
+        }
+        if self.at_line_start && c == self.escape_char {
+            self.pending = true;
+            return None;
+        }
+        self.at_line_start = c == b'\r' || c == b'\n';
+        out.push(c);
+        None
+    }
+
+    /// Returns the help text for the escape sequences.
+    pub fn help(&self, detachable: bool) -> String {
+        let c = self.escape_char as char;
+        format!(
+            "Supported escape sequences:\r\n \
+             {c}.  - {}\r\n \
+             {c}B  - send a BREAK\r\n \
+             {c}?  - this message\r\n \
+             {c}{c}  - send the escape character\r\n\
+             (Note that escapes are only recognized immediately after newline.)\r\n",
+            if detachable {
+                "detach from the session"
+            } else {
+                "terminate the session"
+            }
+        )
+    }
+}
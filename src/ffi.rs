@@ -0,0 +1,187 @@
+//! C bindings for running sessions.
+//!
+//! With the `ffi` feature the crate exports a small C API around
+//! [`Session`](crate::session::Session), declared in `include/teetty.h`.  A
+//! session is started with `teetty_spawn`, controlled with
+//! `teetty_write_stdin` and `teetty_resize` and ended with `teetty_wait`,
+//! which also frees it.  Functions which fail return `NULL` or `-1` and
+//! `teetty_last_error` describes the problem.  Passing a `NULL` session fails
+//! with `EINVAL`.
+use std::cell::RefCell;
+use std::ffi::{CStr, CString, OsStr};
+use std::fmt;
+use std::os::raw::{c_char, c_int};
+use std::os::unix::ffi::OsStrExt;
+use std::path::PathBuf;
+use std::ptr;
+
+use nix::errno::Errno;
+
+use crate::session::Session;
+use crate::spawn::{SpawnError, SpawnOptions};
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+fn set_last_error(err: &dyn fmt::Display) {
+    let message = CString::new(err.to_string().replace('\0', " ")).ok();
+    LAST_ERROR.with(|x| *x.borrow_mut() = message);
+}
+
+fn status(rv: Result<(), SpawnError>) -> c_int {
+    match rv {
+        Ok(()) => 0,
+        Err(err) => {
+            set_last_error(&err);
+            -1
+        }
+    }
+}
+
+/// Fails with `EINVAL` if `session` is `NULL`.
+fn check_session(session: *mut Session) -> Result<(), ()> {
+    if session.is_null() {
+        set_last_error(&"the session is NULL");
+        set_errno(Errno::EINVAL);
+        return Err(());
+    }
+    Ok(())
+}
+
+fn set_errno(errno: Errno) {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    use nix::libc::__errno_location as errno_location;
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    use nix::libc::__error as errno_location;
+
+    unsafe { *errno_location() = errno as c_int };
+}
+
+/// Returns the message of the last error on this thread or `NULL`.  The
+/// message stays valid until the next call into teetty on this thread.
+#[no_mangle]
+pub extern "C" fn teetty_last_error() -> *const c_char {
+    LAST_ERROR.with(|x| x.borrow().as_ref().map_or(ptr::null(), |x| x.as_ptr()))
+}
+
+/// Starts the program in `argv` (terminated by `NULL`) in a pty.  The output
+/// is written to the file at `out_path` unless it's `NULL`.  Returns `NULL` if
+/// the session could not be started.
+///
+/// # Safety
+///
+/// `argv` has to point to an array of strings terminated by `NULL` and
+/// `out_path` has to be `NULL` or a string.
+#[no_mangle]
+pub unsafe extern "C" fn teetty_spawn(
+    argv: *const *const c_char,
+    out_path: *const c_char,
+) -> *mut Session {
+    let mut args = Vec::new();
+    while !argv.is_null() && !(*argv.add(args.len())).is_null() {
+        let arg = CStr::from_ptr(*argv.add(args.len()));
+        args.push(OsStr::from_bytes(arg.to_bytes()).to_owned());
+    }
+    let mut opts = SpawnOptions::new(args);
+    if !out_path.is_null() {
+        opts.out_path = Some(PathBuf::from(OsStr::from_bytes(
+            CStr::from_ptr(out_path).to_bytes(),
+        )));
+    }
+    match Session::start(opts) {
+        Ok(session) => Box::into_raw(Box::new(session)),
+        Err(err) => {
+            set_last_error(&err);
+            ptr::null_mut()
+        }
+    }
+}
+
+/// Sends `len` bytes of input to the program.  Returns `0` or `-1` on errors.
+///
+/// # Safety
+///
+/// `session` has to come from `teetty_spawn` and `data` has to point to `len`
+/// bytes.
+#[no_mangle]
+pub unsafe extern "C" fn teetty_write_stdin(
+    session: *mut Session,
+    data: *const u8,
+    len: usize,
+) -> c_int {
+    if check_session(session).is_err() {
+        return -1;
+    }
+    let data = if len == 0 {
+        &[][..]
+    } else {
+        std::slice::from_raw_parts(data, len)
+    };
+    status((*session).write_stdin(data))
+}
+
+/// Changes the size of the pty.  Returns `0` or `-1` on errors.
+///
+/// # Safety
+///
+/// `session` has to come from `teetty_spawn`.
+#[no_mangle]
+pub unsafe extern "C" fn teetty_resize(session: *mut Session, cols: u16, rows: u16) -> c_int {
+    if check_session(session).is_err() {
+        return -1;
+    }
+    status((*session).resize(cols, rows))
+}
+
+/// Waits for the session to end, frees it and returns the exit code of the
+/// program or `-1` on errors.
+///
+/// # Safety
+///
+/// `session` has to come from `teetty_spawn` and must not be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn teetty_wait(session: *mut Session) -> c_int {
+    if check_session(session).is_err() {
+        return -1;
+    }
+    match Box::from_raw(session).wait() {
+        Ok(code) => code,
+        Err(err) => {
+            set_last_error(&err);
+            -1
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ffi() {
+        let argv = [
+            c"sh".as_ptr(),
+            c"-c".as_ptr(),
+            c"read x; exit $x".as_ptr(),
+            ptr::null(),
+        ];
+        unsafe {
+            let session = teetty_spawn(argv.as_ptr(), ptr::null());
+            assert!(!session.is_null());
+            assert_eq!(teetty_resize(session, 100, 30), 0);
+            assert_eq!(teetty_write_stdin(session, b"7\r".as_ptr(), 2), 0);
+            assert_eq!(teetty_wait(session), 7);
+            // the program fails to start either right away or when it's waited for
+            let session = teetty_spawn(
+                [c"/nonexistent".as_ptr(), ptr::null()].as_ptr(),
+                ptr::null(),
+            );
+            assert_eq!(teetty_wait(session), -1);
+            assert!(!teetty_last_error().is_null());
+            assert_eq!(teetty_resize(ptr::null_mut(), 80, 24), -1);
+            assert_eq!(Errno::last(), Errno::EINVAL);
+            assert_eq!(teetty_wait(ptr::null_mut()), -1);
+        }
+    }
+}
@@ -0,0 +1,196 @@
+//! Comparing the output of a program with a golden file.
+use std::ffi::OsString;
+use std::fs;
+use std::path::Path;
+
+use anyhow::{Context, Error};
+use teetty::ansi::Parser;
+use teetty::pattern::Pattern;
+use teetty::sink::MemorySink;
+use teetty::spawn::{spawn, SpawnOptions};
+use teetty::transform::{Redact, Transform};
+
+/// Outputs which would need a bigger table than this are not diffed line by
+/// line, only the first difference is shown.
+const MAX_DIFF_CELLS: usize = 4_000_000;
+
+/// How many unchanged lines are shown around the changes of a diff.
+const CONTEXT_LINES: usize = 3;
+
+/// Runs the program and compares its normalized output with the golden file.
+///
+/// Returns 0 if they match and 1 with a diff on stdout if they don't.  With
+/// `update` the golden file is written instead.
+pub fn test(
+    command: Vec<OsString>,
+    golden: &Path,
+    masks: Vec<Pattern>,
+    update: bool,
+) -> Result<i32, Error> {
+    let output = MemorySink::new();
+    let mut opts = SpawnOptions::new(command);
+    opts.headless = true;
+    opts.sinks.push(Box::new(output.clone()));
+    let code = spawn(opts)?;
+    let actual = normalize(&output.contents(), masks);
+    if update {
+        fs::write(golden, &actual)
+            .with_context(|| format!("could not write {}", golden.display()))?;
+        return Ok(0);
+    }
+    let expected = fs::read_to_string(golden).with_context(|| {
+        format!(
+            "could not read {}, create it with --update",
+            golden.display()
+        )
+    })?;
+    if expected == actual {
+        return Ok(0);
+    }
+    println!("--- {}", golden.display());
+    println!("+++ output (the program exited with {})", code);
+    print!("{}", diff(&expected, &actual));
+    Ok(1)
+}
+
+/// Removes escape sequences, carriage returns and everything matching one of
+/// the masks from the output.
+///
+/// Like on a terminal, text after a carriage return overwrites the start of
+/// the line.  Matches of the masks are replaced with `[MASKED]`.
+fn normalize(output: &[u8], masks: Vec<Pattern>) -> String {
+    let mut text = Vec::new();
+    Parser::default().strip(output, &mut text);
+    let mut collapsed = String::new();
+    for line in String::from_utf8_lossy(&text).split_inclusive('\n') {
+        let (line, newline) = match line.strip_suffix('\n') {
+            Some(line) => (line.strip_suffix('\r').unwrap_or(line), "\n"),
+            None => (line, ""),
+        };
+        let mut chars = Vec::new();
+        for part in line.split('\r') {
+            for (idx, c) in part.chars().enumerate() {
+                match chars.get_mut(idx) {
+                    Some(x) => *x = c,
+                    None => chars.push(c),
+                }
+            }
+        }
+        collapsed.extend(chars);
+        collapsed.push_str(newline);
+    }
+    let mut masked = Vec::new();
+    let mut redact = Redact::with_replacement(masks, b"[MASKED]");
+    redact.apply(collapsed.as_bytes(), &mut masked);
+    redact.finish(&mut masked);
+    String::from_utf8_lossy(&masked).into_owned()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Op {
+    Same,
+    Removed,
+    Added,
+}
+
+/// Returns a unified diff of the lines.
+fn diff(expected: &str, actual: &str) -> String {
+    let old = expected.lines().collect::<Vec<_>>();
+    let new = actual.lines().collect::<Vec<_>>();
+    if old.len().saturating_mul(new.len()) > MAX_DIFF_CELLS {
+        let idx = old
+            .iter()
+            .zip(&new)
+            .position(|(a, b)| a != b)
+            .unwrap_or(old.len().min(new.len()));
+        return format!(
+            "@@ first difference in line {} @@\n-{}\n+{}\n",
+            idx + 1,
+            old.get(idx).unwrap_or(&""),
+            new.get(idx).unwrap_or(&"")
+        );
+    }
+    // the length of the longest common subsequence of the rest of the lines
+    let width = new.len() + 1;
+    let mut lcs = vec![0u32; (old.len() + 1) * width];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            lcs[i * width + j] = if old[i] == new[j] {
+                lcs[(i + 1) * width + j + 1] + 1
+            } else {
+                lcs[(i + 1) * width + j].max(lcs[i * width + j + 1])
+            };
+        }
+    }
+    let mut ops = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            ops.push((Op::Same, old[i]));
+            (i, j) = (i + 1, j + 1);
+        } else if j < new.len()
+            && (i == old.len() || lcs[i * width + j + 1] > lcs[(i + 1) * width + j])
+        {
+            ops.push((Op::Added, new[j]));
+            j += 1;
+        } else {
+            ops.push((Op::Removed, old[i]));
+            i += 1;
+        }
+    }
+    let mut out = String::new();
+    let mut idx = 0;
+    let (mut old_line, mut new_line) = (1, 1);
+    while idx < ops.len() {
+        let Some(change) = ops[idx..].iter().position(|x| x.0 != Op::Same) else {
+            break;
+        };
+        let start = (idx + change).saturating_sub(CONTEXT_LINES).max(idx);
+        old_line += start - idx;
+        new_line += start - idx;
+        // the hunk ends once there are more unchanged lines than fit into the
+        // context of two hunks
+        let mut end = idx + change;
+        let mut same = 0;
+        while end < ops.len() && same <= 2 * CONTEXT_LINES {
+            same = if ops[end].0 == Op::Same { same + 1 } else { 0 };
+            end += 1;
+        }
+        let end = end - same.saturating_sub(CONTEXT_LINES);
+        let hunk = &ops[start..end];
+        let count = |op| hunk.iter().filter(|x| x.0 == Op::Same || x.0 == op).count();
+        let (old_count, new_count) = (count(Op::Removed), count(Op::Added));
+        out.push_str(&format!(
+            "@@ -{},{} +{},{} @@\n",
+            old_line, old_count, new_line, new_count
+        ));
+        for (op, line) in hunk {
+            let prefix = match op {
+                Op::Same => ' ',
+                Op::Removed => '-',
+                Op::Added => '+',
+            };
+            out.push_str(&format!("{}{}\n", prefix, line));
+        }
+        old_line += old_count;
+        new_line += new_count;
+        idx = end;
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize_and_diff() {
+        let masks = vec![Pattern::new(r"\d+ms").unwrap()];
+        let output = b"\x1b[1mbuild\x1b[0m\r\n10%\r50%\rdone\r\ntook 12ms\r\n";
+        assert_eq!(normalize(output, masks), "build\ndone\ntook [MASKED]\n");
+        assert_eq!(
+            diff("a\nb\nc\nd\ne\nf\ng\nh\n", "a\nb\nc\nd\nx\nf\ng\nh\n"),
+            "@@ -2,7 +2,7 @@\n b\n c\n d\n-e\n+x\n f\n g\n h\n"
+        );
+    }
+}
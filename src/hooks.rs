@@ -0,0 +1,141 @@
+//! Commands that run when a session starts and ends or the bell rings.
+//!
+//! Hooks are run with `sh -c` and learn about the session from environment
+//! variables:
+//!
+//! * `TEETTY_PID`: the pid of the program
+//! * `TEETTY_COMMAND`: the command line of the program
+//! * `TEETTY_OUT_PATH`: the path of the output file (if any)
+//! * `TEETTY_SESSION`: the name of the session (if any)
+//! * `TEETTY_EXIT_CODE`: the exit code of the program (exit hooks only)
+//! * `TEETTY_DURATION`: how long the session ran in seconds (exit hooks only)
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::Duration;
+
+use nix::unistd::Pid;
+
+use crate::spawn::SpawnOptions;
+
+/// The hooks of a session.
+pub struct Hooks<'a> {
+    opts: &'a SpawnOptions,
+    child: Option<Pid>,
+}
+
+impl<'a> Hooks<'a> {
+    /// Returns the hooks of the session if any are configured.
+    pub fn new(opts: &'a SpawnOptions, child: Option<Pid>) -> Option<Hooks<'a>> {
+        if opts.on_start.is_none()
+            && opts.on_exit.is_none()
+            && opts.on_failure.is_none()
+            && opts.on_bell.is_none()
+        {
+            return None;
+        }
+        Some(Hooks { opts, child })
+    }
+
+    /// Starts the start hook.  The session doesn't wait for it.
+    pub fn run_start_hook(&self) {
+        if let Some(cmd) = self.opts.on_start.as_deref() {
+            if let Err(err) = start(self.command(cmd)) {
+                report("on-start", &err);
+            }
+        }
+    }
+
+    /// Runs the exit hook and on failure the failure hook and waits for them.
+    pub fn run_exit_hooks(&self, code: i32, duration: Duration) {
+        let hooks = [
+            ("on-exit", self.opts.on_exit.as_deref()),
+            (
+                "on-failure",
+                self.opts.on_failure.as_deref().filter(|_| code != 0),
+            ),
+        ];
+        for (name, cmd) in hooks {
+            if let Some(cmd) = cmd {
+                let status = self
+                    .command(cmd)
+                    .env("TEETTY_EXIT_CODE", code.to_string())
+                    .env("TEETTY_DURATION", format!("{:.3}", duration.as_secs_f64()))
+                    .status();
+                if let Err(err) = status {
+                    report(name, &err);
+                }
+            }
+        }
+    }
+
+    fn command(&self, cmd: &str) -> Command {
+        let mut rv = Command::new("sh");
+        rv.arg("-c").arg(cmd).stdin(Stdio::null()).env(
+            "TEETTY_COMMAND",
+            self.opts
+                .args
+                .iter()
+                .map(|x| x.to_string_lossy())
+                .collect::<Vec<_>>()
+                .join(" "),
+        );
+        if let Some(child) = self.child {
+            rv.env("TEETTY_PID", child.to_string());
+        }
+        if let Some(path) = self.opts.out_path.as_deref() {
+            rv.env("TEETTY_OUT_PATH", path);
+        }
+        if let Some(session) = self.opts.session.as_deref() {
+            rv.env("TEETTY_SESSION", session);
+        }
+        rv
+    }
+}
+
+/// Starts the bell hook or shows a desktop notification about the bell if there
+/// is none.  The session doesn't wait for either.
+pub fn notify_bell(opts: &SpawnOptions, child: Option<Pid>) {
+    if let Some(cmd) = opts.on_bell.as_deref() {
+        if let Err(err) = start(Hooks { opts, child }.command(cmd)) {
+            report("on-bell", &err);
+        }
+        return;
+    }
+    let name = opts
+        .args
+        .first()
+        .and_then(|x| Path::new(x).file_name())
+        .map_or_else(|| "the program".into(), |x| x.to_string_lossy());
+    // the name ends up in an AppleScript string on macOS
+    let message = format!("{} rang the bell", name).replace(['"', '\\'], "");
+    let mut cmd = if cfg!(target_os = "macos") {
+        let mut cmd = Command::new("osascript");
+        cmd.arg("-e").arg(format!(
+            "display notification \"{}\" with title \"teetty\"",
+            message
+        ));
+        cmd
+    } else {
+        let mut cmd = Command::new("notify-send");
+        cmd.arg("teetty").arg(message);
+        cmd
+    };
+    cmd.stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null());
+    if let Err(err) = start(cmd) {
+        eprintln!("teetty: could not show a desktop notification: {}", err);
+    }
+}
+
+/// Starts a command without waiting for it.
+fn start(mut cmd: Command) -> std::io::Result<()> {
+    let mut child = cmd.spawn()?;
+    thread::spawn(move || child.wait());
+    Ok(())
+}
+
+fn report(name: &str, err: &std::io::Error) {
+    eprintln!("teetty: could not run {} hook: {}", name, err);
+}
@@ -0,0 +1,489 @@
+//! A minimal HTTP/1.1 server for the session's network endpoints.
+//!
+//! Connections are read and written without blocking from the communication
+//! loop.  Once a complete request arrived it's handed to the caller together
+//! with a [`Response`] to fill in.  Every connection serves a single request.
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::os::fd::AsRawFd;
+use std::time::{Duration, Instant};
+
+use crate::poll::PollSet;
+
+/// Requests (headers and body) are not allowed to be larger than this.
+const MAX_REQUEST_SIZE: usize = 1024 * 1024;
+
+/// How many connections are open at a time.
+const MAX_CONNECTIONS: usize = 64;
+
+/// How long a client has to send its request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long a client has to take the response.
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A parsed request.
+#[derive(Debug)]
+pub struct Request {
+    pub method: String,
+    /// The path without the query string.
+    pub path: String,
+    pub query: Option<String>,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    /// Returns the value of a header (case insensitive).
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Returns the decoded value of a parameter of the query string.
+    pub fn query_param(&self, name: &str) -> Option<String> {
+        self.query
+            .as_deref()?
+            .split('&')
+            .filter_map(|x| x.split_once('='))
+            .find(|(key, _)| *key == name)
+            .map(|(_, value)| percent_decode(value))
+    }
+}
+
+/// Decodes `%XX` escapes and `+`.  Invalid escapes are kept as they are.
+fn percent_decode(s: &str) -> String {
+    let mut rv = Vec::with_capacity(s.len());
+    let mut bytes = s.bytes();
+    while let Some(c) = bytes.next() {
+        match c {
+            b'+' => rv.push(b' '),
+            b'%' => {
+                let hex = bytes.clone().take(2).collect::<Vec<_>>();
+                match std::str::from_utf8(&hex).map(|x| u8::from_str_radix(x, 16)) {
+                    Ok(Ok(byte)) if hex.len() == 2 => {
+                        rv.push(byte);
+                        bytes.nth(1);
+                    }
+                    _ => rv.push(b'%'),
+                }
+            }
+            c => rv.push(c),
+        }
+    }
+    String::from_utf8_lossy(&rv).into_owned()
+}
+
+/// What a connection is waiting for.
+enum State {
+    /// The request, of which this much arrived so far.
+    Reading(Vec<u8>),
+    /// The rest of the response to be written.
+    Writing(Vec<u8>),
+}
+
+struct Conn {
+    stream: TcpStream,
+    state: State,
+    /// The connection is closed if it isn't done by then.
+    deadline: Instant,
+}
+
+/// A response to a request.  What's written to it is sent once it's handed
+/// back with [`HttpServer::send`].
+pub struct Response {
+    stream: TcpStream,
+    buf: Vec<u8>,
+}
+
+impl Response {
+    /// Takes over the connection, e.g. after a WebSocket handshake.  Returns
+    /// it together with what was written to the response so far.
+    pub fn into_inner(self) -> (TcpStream, Vec<u8>) {
+        (self.stream, self.buf)
+    }
+}
+
+impl Write for Response {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Accepts HTTP connections and reads requests from them.
+///
+/// Nothing blocks: requests are read and responses are written as the
+/// connections become ready.  At most [`MAX_CONNECTIONS`] are open at a time
+/// and those which take too long are closed, so clients can't hold up the
+/// session or use up its fds.  Once the limit is reached a new connection
+/// replaces the oldest one that is still waiting for its request.
+pub struct HttpServer {
+    listener: TcpListener,
+    conns: Vec<Conn>,
+}
+
+impl HttpServer {
+    pub fn bind(addr: &str) -> io::Result<HttpServer> {
+        let listener = TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        Ok(HttpServer {
+            listener,
+            conns: Vec::new(),
+        })
+    }
+
+    /// Returns the address the server is bound to.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Closes the connections which ran out of time and adds the fds that need
+    /// watching to the set.
+    pub fn watch(&mut self, fds: &mut PollSet) {
+        let now = Instant::now();
+        self.conns.retain(|conn| conn.deadline > now);
+        fds.insert_read(self.listener.as_raw_fd());
+        for conn in &self.conns {
+            match conn.state {
+                State::Reading(_) => fds.insert_read(conn.stream.as_raw_fd()),
+                State::Writing(_) => fds.insert_write(conn.stream.as_raw_fd()),
+            }
+        }
+    }
+
+    /// Returns how long until the next connection runs out of time.
+    pub fn timeout(&self) -> Option<Duration> {
+        self.conns
+            .iter()
+            .map(|conn| conn.deadline.saturating_duration_since(Instant::now()))
+            .min()
+    }
+
+    /// Accepts new connections, writes pending responses and returns the
+    /// requests that are complete.  Every response has to be handed back with
+    /// [`HttpServer::send`].
+    pub fn handle(&mut self, fds: &PollSet) -> Vec<(Request, Response)> {
+        if fds.readable(self.listener.as_raw_fd()) {
+            while let Ok((stream, _)) = self.listener.accept() {
+                if self.conns.len() >= MAX_CONNECTIONS {
+                    // the oldest client that didn't send its request yet has
+                    // to make room, so idle connections can't lock others out
+                    match self
+                        .conns
+                        .iter()
+                        .position(|conn| matches!(conn.state, State::Reading(_)))
+                    {
+                        Some(idx) => drop(self.conns.remove(idx)),
+                        None => continue,
+                    }
+                }
+                if stream.set_nonblocking(true).is_ok() {
+                    self.conns.push(Conn {
+                        stream,
+                        state: State::Reading(Vec::new()),
+                        deadline: Instant::now() + REQUEST_TIMEOUT,
+                    });
+                }
+            }
+        }
+
+        let mut rv = Vec::new();
+        let mut idx = 0;
+        while idx < self.conns.len() {
+            let conn = &mut self.conns[idx];
+            let keep = match conn.state {
+                State::Reading(ref mut buf) if fds.readable(conn.stream.as_raw_fd()) => {
+                    let mut chunk = [0; 4096];
+                    match conn.stream.read(&mut chunk) {
+                        Ok(0) => false,
+                        Ok(n) => {
+                            buf.extend_from_slice(&chunk[..n]);
+                            match parse_request(buf) {
+                                Some(Ok(req)) => {
+                                    let conn = self.conns.remove(idx);
+                                    let response = Response {
+                                        stream: conn.stream,
+                                        buf: Vec::new(),
+                                    };
+                                    rv.push((req, response));
+                                    continue;
+                                }
+                                Some(Err(())) => {
+                                    let mut response = Vec::new();
+                                    respond(&mut response, 400, "text/plain", b"bad request\n")
+                                        .ok();
+                                    conn.state = State::Writing(response);
+                                    conn.deadline = Instant::now() + RESPONSE_TIMEOUT;
+                                    write_pending(conn)
+                                }
+                                None => buf.len() <= MAX_REQUEST_SIZE,
+                            }
+                        }
+                        Err(err) => err.kind() == io::ErrorKind::WouldBlock,
+                    }
+                }
+                State::Writing(_) if fds.writable(conn.stream.as_raw_fd()) => write_pending(conn),
+                _ => true,
+            };
+            if keep {
+                idx += 1;
+            } else {
+                self.conns.remove(idx);
+            }
+        }
+        rv
+    }
+
+    /// Sends the response.  The connection is closed once it's written.
+    pub fn send(&mut self, response: Response) {
+        let mut conn = Conn {
+            stream: response.stream,
+            state: State::Writing(response.buf),
+            deadline: Instant::now() + RESPONSE_TIMEOUT,
+        };
+        if write_pending(&mut conn) {
+            self.conns.push(conn);
+        }
+    }
+}
+
+/// Writes as much of the response as possible without blocking.  Returns
+/// `false` once the connection is done, because everything was written or it
+/// failed.
+fn write_pending(conn: &mut Conn) -> bool {
+    let State::Writing(ref mut buf) = conn.state else {
+        return true;
+    };
+    while !buf.is_empty() {
+        match conn.stream.write(buf) {
+            Ok(0) => return false,
+            Ok(n) => {
+                buf.drain(..n);
+            }
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return err.kind() == io::ErrorKind::WouldBlock,
+        }
+    }
+    false
+}
+
+/// Parses a request.  Returns `None` if it's not complete yet.
+pub(crate) fn parse_request(buf: &[u8]) -> Option<Result<Request, ()>> {
+    let end = buf.windows(4).position(|x| x == b"\r\n\r\n")?;
+    let Ok(head) = std::str::from_utf8(&buf[..end]) else {
+        return Some(Err(()));
+    };
+    let mut lines = head.split("\r\n");
+    let mut request_line = lines.next().unwrap_or("").split(' ');
+    let (Some(method), Some(path)) = (request_line.next(), request_line.next()) else {
+        return Some(Err(()));
+    };
+    let mut headers = Vec::new();
+    for line in lines {
+        match line.split_once(':') {
+            Some((key, value)) => headers.push((key.trim().to_string(), value.trim().to_string())),
+            None => return Some(Err(())),
+        }
+    }
+    let (path, query) = match path.split_once('?') {
+        Some((path, query)) => (path, Some(query.to_string())),
+        None => (path, None),
+    };
+    let mut req = Request {
+        method: method.to_string(),
+        path: path.to_string(),
+        query,
+        headers,
+        body: Vec::new(),
+    };
+    let len = match req.header("content-length").map(|x| x.parse::<usize>()) {
+        Some(Ok(len)) => len,
+        Some(Err(_)) => return Some(Err(())),
+        None => 0,
+    };
+    let body = &buf[end + 4..];
+    if body.len() < len {
+        return None;
+    }
+    req.body = body[..len].to_vec();
+    Some(Ok(req))
+}
+
+/// Checks that the body of a request, if it has one, is declared as JSON.
+/// Browsers can send other types across origins without asking first.
+pub fn is_json(req: &Request) -> bool {
+    match req.header("content-type") {
+        Some(value) => value
+            .split(';')
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .eq_ignore_ascii_case("application/json"),
+        None => req.body.is_empty(),
+    }
+}
+
+/// Checks that the request carries `Authorization: Bearer <token>`.
+pub fn has_bearer_token(req: &Request, token: &str) -> bool {
+    req.header("authorization")
+        .and_then(|x| x.strip_prefix("Bearer "))
+        .is_some_and(|x| constant_time_eq(x.as_bytes(), token.as_bytes()))
+}
+
+/// Compares secrets without returning early at the first difference.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Generates a random token for clients to authenticate with.
+pub fn generate_token() -> io::Result<String> {
+    let mut bytes = [0; 16];
+    File::open("/dev/urandom")?.read_exact(&mut bytes)?;
+    Ok(bytes.iter().map(|x| format!("{:02x}", x)).collect())
+}
+
+/// Writes a complete response and closes the connection.
+pub fn respond<W: Write>(
+    w: &mut W,
+    status: u16,
+    content_type: &str,
+    body: &[u8],
+) -> io::Result<()> {
+    let reason = match status {
+        200 => "OK",
+        204 => "No Content",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        415 => "Unsupported Media Type",
+        _ => "Error",
+    };
+    write!(
+        w,
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        reason,
+        content_type,
+        body.len()
+    )?;
+    w.write_all(body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_request() {
+        assert!(parse_request(b"GET / HTTP/1.1\r\nHost: x\r\n").is_none());
+        let req = parse_request(b"GET /metrics HTTP/1.1\r\nHost: x\r\n\r\n")
+            .unwrap()
+            .unwrap();
+        assert_eq!(req.method, "GET");
+        assert_eq!(req.path, "/metrics");
+        assert_eq!(req.header("HOST"), Some("x"));
+
+        let req = parse_request(b"GET /ws?a=1&token=x%2By+z HTTP/1.1\r\n\r\n")
+            .unwrap()
+            .unwrap();
+        assert_eq!(req.path, "/ws");
+        assert_eq!(req.query_param("token").as_deref(), Some("x+y z"));
+        assert_eq!(req.query_param("b"), None);
+
+        let req = b"POST /input HTTP/1.1\r\nContent-Length: 5\r\n\r\nhel";
+        assert!(parse_request(req).is_none());
+        let req = parse_request(b"POST /input HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello")
+            .unwrap()
+            .unwrap();
+        assert_eq!(req.body, b"hello");
+
+        assert!(parse_request(b"nonsense\r\n\r\n").unwrap().is_err());
+    }
+
+    /// Polls the server until the condition holds.
+    fn serve_until(server: &mut HttpServer, mut cond: impl FnMut(&mut HttpServer) -> bool) {
+        for _ in 0..100 {
+            let mut fds = PollSet::new();
+            server.watch(&mut fds);
+            fds.poll(Duration::from_millis(10)).unwrap();
+            for (req, mut response) in server.handle(&fds) {
+                respond(&mut response, 200, "text/plain", req.path.as_bytes()).unwrap();
+                server.send(response);
+            }
+            if cond(server) {
+                return;
+            }
+        }
+        panic!("condition never held");
+    }
+
+    #[test]
+    fn test_server() {
+        let mut server = HttpServer::bind("127.0.0.1:0").unwrap();
+        let addr = server.local_addr().unwrap();
+        let mut client = TcpStream::connect(addr).unwrap();
+        client.write_all(b"GET /hello HTTP/1.1\r\n\r\n").unwrap();
+        let mut response = Vec::new();
+        serve_until(&mut server, |server| server.conns.is_empty());
+        client.read_to_end(&mut response).unwrap();
+        assert!(response.starts_with(b"HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with(b"\r\n\r\n/hello"));
+
+        // idle connections don't lock out others
+        let idle = (0..MAX_CONNECTIONS + 10)
+            .map(|_| TcpStream::connect(addr).unwrap())
+            .collect::<Vec<_>>();
+        serve_until(&mut server, |server| server.conns.len() == MAX_CONNECTIONS);
+        let mut client = TcpStream::connect(addr).unwrap();
+        client.write_all(b"GET /again HTTP/1.1\r\n\r\n").unwrap();
+        let mut response = Vec::new();
+        serve_until(&mut server, |server| {
+            server.conns.len() == MAX_CONNECTIONS - 1
+        });
+        client.read_to_end(&mut response).unwrap();
+        assert!(response.ends_with(b"/again"));
+        assert_eq!(server.conns.len(), MAX_CONNECTIONS - 1);
+        for conn in &mut server.conns {
+            conn.deadline = Instant::now();
+        }
+        assert_eq!(server.timeout(), Some(Duration::ZERO));
+        serve_until(&mut server, |server| server.conns.is_empty());
+        drop(idle);
+    }
+
+    #[test]
+    fn test_checks() {
+        let req = parse_request(
+            b"POST /input HTTP/1.1\r\nAuthorization: Bearer abc\r\n\
+              Content-Type: text/plain\r\nContent-Length: 2\r\n\r\nls",
+        )
+        .unwrap()
+        .unwrap();
+        assert!(has_bearer_token(&req, "abc"));
+        assert!(!has_bearer_token(&req, "abd"));
+        assert!(!has_bearer_token(&req, "ab"));
+        assert!(!is_json(&req));
+        let req = parse_request(
+            b"POST /input HTTP/1.1\r\nContent-Type: application/json; charset=utf-8\r\n\
+              Content-Length: 4\r\n\r\n\"ls\"",
+        )
+        .unwrap()
+        .unwrap();
+        assert!(is_json(&req));
+        assert!(!has_bearer_token(&req, "abc"));
+        let req = parse_request(b"POST /input HTTP/1.1\r\nContent-Length: 2\r\n\r\nls")
+            .unwrap()
+            .unwrap();
+        assert!(!is_json(&req));
+    }
+}
@@ -0,0 +1,358 @@
+//! Input for the program from FIFOs and files, and the pace it's sent at.
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, Read};
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::prelude::OpenOptionsExt;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use nix::libc::O_NONBLOCK;
+
+/// A FIFO or file whose contents are sent to the terminal as input.
+pub struct InputSource<'a> {
+    path: &'a Path,
+    file: Option<File>,
+    is_fifo: bool,
+}
+
+impl<'a> InputSource<'a> {
+    /// Opens the input at the given path in non-blocking mode.
+    pub fn open(path: &'a Path) -> io::Result<InputSource<'a>> {
+        let file = open_nonblocking(path)?;
+        let is_fifo = file.metadata()?.file_type().is_fifo();
+        Ok(InputSource {
+            path,
+            file: Some(file),
+            is_fifo,
+        })
+    }
+
+    /// Returns the path the input was opened from.
+    pub fn path(&self) -> &Path {
+        self.path
+    }
+
+    /// Returns the fd to wait on unless the input was exhausted.
+    pub fn fd(&self) -> Option<i32> {
+        self.file.as_ref().map(|f| f.as_raw_fd())
+    }
+
+    /// Reads the next chunk of input.
+    ///
+    /// Once the last writer closes a FIFO every read returns end of file, so the
+    /// FIFO is reopened to wait for the next writer.  Regular files on the other
+    /// hand are done once they were read to the end.
+    pub fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let file = match self.file {
+            Some(ref mut file) => file,
+            None => return Ok(0),
+        };
+        match file.read(buf) {
+            Ok(0) => {
+                self.file = None;
+                if self.is_fifo {
+                    self.file = Some(open_nonblocking(self.path)?);
+                }
+                Ok(0)
+            }
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => Ok(0),
+            rv => rv,
+        }
+    }
+}
+
+/// Feeds injected input to the pty at a limited pace.
+///
+/// Programs that read their input in small pieces can get confused by a
+/// burst of pasted bytes.  The pacer holds input back and releases it either
+/// byte by byte or line by line with a delay in between.
+///
+/// A gated pacer additionally holds back every line until it's marked as
+/// ready, which is used to wait for a prompt before sending the next line.
+pub struct Pacer {
+    byte_delay: Option<Duration>,
+    line_delay: Option<Duration>,
+    queue: VecDeque<u8>,
+    /// The start and length of the pieces of the queue which can't be split.
+    units: VecDeque<(usize, usize)>,
+    next: Instant,
+    gated: bool,
+    ready: bool,
+    at_line_start: bool,
+    /// Whether the last piece ended with `\r`, which a `\n` still belongs to.
+    after_cr: bool,
+}
+
+impl Pacer {
+    /// Creates a pacer.  If no delay is given and the pacer is not gated, input
+    /// is passed on immediately.
+    pub fn new(byte_delay: Option<Duration>, line_delay: Option<Duration>, gated: bool) -> Pacer {
+        Pacer {
+            byte_delay,
+            line_delay,
+            queue: VecDeque::new(),
+            units: VecDeque::new(),
+            next: Instant::now(),
+            gated,
+            ready: false,
+            at_line_start: true,
+            after_cr: false,
+        }
+    }
+
+    /// Returns `true` if input is held back.
+    pub fn is_active(&self) -> bool {
+        self.byte_delay.is_some() || self.line_delay.is_some() || self.gated
+    }
+
+    /// Returns `true` if a gated pacer is waiting to be marked as ready.
+    pub fn is_ready(&self) -> bool {
+        self.ready
+    }
+
+    /// Allows a gated pacer to release the next line.
+    pub fn set_ready(&mut self) {
+        self.ready = true;
+    }
+
+    fn is_blocked(&self) -> bool {
+        self.gated && self.at_line_start && !self.ready
+    }
+
+    /// Queues up input.
+    pub fn push(&mut self, data: &[u8]) {
+        self.queue.extend(data);
+    }
+
+    /// Queues up input which has to reach the program in one piece, such as
+    /// the markers of a bracketed paste.  It's not paced but sent together with
+    /// the input next to it.
+    pub fn push_unit(&mut self, data: &[u8]) {
+        if !data.is_empty() {
+            self.units.push_back((self.queue.len(), data.len()));
+            self.queue.extend(data);
+        }
+    }
+
+    /// Moves the unit at the front of the queue into `out`, if there is one.
+    fn pop_unit(&mut self, out: &mut Vec<u8>) {
+        if let Some(&(0, len)) = self.units.front() {
+            self.units.pop_front();
+            self.drain(len, out);
+        }
+    }
+
+    fn drain(&mut self, len: usize, out: &mut Vec<u8>) {
+        out.extend(self.queue.drain(..len));
+        for unit in self.units.iter_mut() {
+            unit.0 -= len;
+        }
+    }
+
+    /// Checks if the queue starts with the `\n` of a `\r\n` whose `\r` was
+    /// already sent, which happens when they were queued up separately.
+    fn is_lf_due(&self) -> bool {
+        self.after_cr
+            && self.queue.front() == Some(&b'\n')
+            && self.units.front().is_none_or(|x| x.0 > 0)
+    }
+
+    /// Returns how long to wait until the next piece of input is due.
+    pub fn timeout(&self) -> Option<Duration> {
+        if self.is_lf_due() {
+            Some(Duration::ZERO)
+        } else if self.queue.is_empty() || self.is_blocked() {
+            None
+        } else {
+            Some(self.next.saturating_duration_since(Instant::now()))
+        }
+    }
+
+    /// Moves the next piece of input into `out` if it's due.
+    pub fn pop_due(&mut self, out: &mut Vec<u8>) -> bool {
+        if self.is_lf_due() {
+            self.after_cr = false;
+            self.drain(1, out);
+            return true;
+        }
+        if self.queue.is_empty() || self.is_blocked() || Instant::now() < self.next {
+            return false;
+        }
+        if self.gated && self.at_line_start {
+            self.ready = false;
+        }
+        self.pop_unit(out);
+        // a piece never reaches into the next unit
+        let end = self.units.front().map_or(self.queue.len(), |x| x.0);
+        let mut len = if self.byte_delay.is_some() {
+            end.min(1)
+        } else {
+            self.queue
+                .range(..end)
+                .position(|&c| c == b'\n' || c == b'\r')
+                .map_or(end, |pos| pos + 1)
+        };
+        // `\r\n` ends a single line
+        if len > 0 && len < end && self.queue[len - 1] == b'\r' && self.queue[len] == b'\n' {
+            len += 1;
+        }
+        self.drain(len, out);
+        if len > 0 {
+            self.at_line_start = matches!(out.last(), Some(b'\n' | b'\r'));
+            self.after_cr = out.last() == Some(&b'\r');
+        }
+        self.pop_unit(out);
+        let delay = if self.at_line_start {
+            self.line_delay.or(self.byte_delay)
+        } else {
+            self.byte_delay
+        };
+        self.next = Instant::now() + delay.unwrap_or_default();
+        true
+    }
+}
+
+/// Translates newlines into carriage returns.
+///
+/// This is what the terminal sends when the user presses enter, many programs
+/// in raw mode don't treat a bare newline as enter.
+pub fn translate_newlines(buf: &mut [u8]) {
+    for c in buf.iter_mut().filter(|c| **c == b'\n') {
+        *c = b'\r';
+    }
+}
+
+/// Linux and FreeBSD don't report a FIFO as readable before its first writer
+/// connected.  Elsewhere `select` keeps reporting the end of file of a FIFO
+/// without writers, so the FIFO is opened for writing as well which keeps a
+/// writer around and the input waits for the next writer either way.
+fn open_nonblocking(path: &Path) -> io::Result<File> {
+    let mut options = File::options();
+    options.read(true).custom_flags(O_NONBLOCK);
+    #[cfg(not(any(target_os = "linux", target_os = "android", target_os = "freebsd")))]
+    if std::fs::metadata(path)?.file_type().is_fifo() {
+        options.write(true);
+    }
+    options.open(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs::{remove_file, OpenOptions};
+    use std::io::Write;
+
+    use nix::sys::stat::Mode;
+    use nix::unistd::mkfifo;
+
+    /// Pops all pieces which are due without waiting.
+    fn pop_all(pacer: &mut Pacer) -> Vec<Vec<u8>> {
+        let mut pieces = Vec::new();
+        let mut piece = Vec::new();
+        while pacer.pop_due(&mut piece) {
+            pieces.push(std::mem::take(&mut piece));
+        }
+        pieces
+    }
+
+    #[test]
+    fn test_input_source_reopens_fifo() {
+        let path = std::env::temp_dir().join(format!("teetty-input-{}.fifo", std::process::id()));
+        remove_file(&path).ok();
+        mkfifo(&path, Mode::S_IRUSR | Mode::S_IWUSR).unwrap();
+        let mut source = InputSource::open(&path).unwrap();
+        let mut buf = [0; 16];
+        for data in [&b"ls\r"[..], b"pwd\r"] {
+            let mut writer = OpenOptions::new().write(true).open(&path).unwrap();
+            writer.write_all(data).unwrap();
+            assert_eq!(source.read(&mut buf).unwrap(), data.len());
+            assert_eq!(&buf[..data.len()], data);
+            drop(writer);
+            // the end of file of the last writer only reopens the FIFO
+            assert_eq!(source.read(&mut buf).unwrap(), 0);
+            assert!(source.fd().is_some());
+        }
+        remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_input_source_file() {
+        let path = std::env::temp_dir().join(format!("teetty-input-{}.txt", std::process::id()));
+        std::fs::write(&path, b"ls\r").unwrap();
+        let mut source = InputSource::open(&path).unwrap();
+        let mut buf = [0; 16];
+        assert_eq!(source.read(&mut buf).unwrap(), 3);
+        assert_eq!(source.read(&mut buf).unwrap(), 0);
+        assert!(source.fd().is_none());
+        assert_eq!(source.read(&mut buf).unwrap(), 0);
+        remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_pacer_byte_delay() {
+        let delay = Duration::from_millis(50);
+        let mut pacer = Pacer::new(Some(delay), None, false);
+        assert!(pacer.is_active());
+        assert_eq!(pacer.timeout(), None);
+        pacer.push(b"ab");
+        assert_eq!(pop_all(&mut pacer), [b"a"]);
+        let timeout = pacer.timeout().unwrap();
+        assert!(timeout > Duration::ZERO && timeout <= delay);
+        let started = Instant::now();
+        std::thread::sleep(timeout);
+        assert_eq!(pop_all(&mut pacer), [b"b"]);
+        assert!(started.elapsed() >= timeout);
+        assert_eq!(pacer.timeout(), None);
+    }
+
+    #[test]
+    fn test_pacer_line_delay() {
+        let delay = Duration::from_millis(50);
+        let mut pacer = Pacer::new(None, Some(delay), false);
+        pacer.push(b"ls\r\npwd\rid");
+        assert_eq!(pop_all(&mut pacer), [b"ls\r\n"]);
+        assert!(pacer.timeout().unwrap() > Duration::ZERO);
+        std::thread::sleep(delay);
+        assert_eq!(pop_all(&mut pacer), [b"pwd\r"]);
+        std::thread::sleep(delay);
+        assert_eq!(pop_all(&mut pacer), [b"id"]);
+
+        // the \n of \r\n follows right away even if it's queued up later
+        let mut pacer = Pacer::new(None, Some(delay), false);
+        pacer.push(b"ls\r");
+        assert_eq!(pop_all(&mut pacer), [b"ls\r"]);
+        pacer.push(b"\npwd\r");
+        assert_eq!(pacer.timeout(), Some(Duration::ZERO));
+        assert_eq!(pop_all(&mut pacer), [b"\n"]);
+        assert!(pacer.timeout().unwrap() > Duration::ZERO);
+    }
+
+    #[test]
+    fn test_pacer_gated() {
+        let mut pacer = Pacer::new(None, None, true);
+        assert!(pacer.is_active());
+        pacer.push(b"ls\r\npwd\r");
+        assert_eq!(pacer.timeout(), None);
+        assert!(pop_all(&mut pacer).is_empty());
+        pacer.set_ready();
+        assert_eq!(pop_all(&mut pacer), [b"ls\r\n"]);
+        assert!(!pacer.is_ready());
+        pacer.set_ready();
+        assert_eq!(pop_all(&mut pacer), [b"pwd\r"]);
+    }
+
+    #[test]
+    fn test_pacer_inactive() {
+        assert!(!Pacer::new(None, None, false).is_active());
+    }
+
+    #[test]
+    fn test_translate_newlines() {
+        let mut buf = *b"ls\n\npwd\r";
+        translate_newlines(&mut buf);
+        assert_eq!(&buf, b"ls\r\rpwd\r");
+    }
+}
@@ -0,0 +1,127 @@
+//! Sends the output line by line to the systemd journal.
+//!
+//! This speaks the native journal protocol: every entry is a datagram with one
+//! `FIELD=value` pair per line.
+use std::io;
+use std::os::unix::net::UnixDatagram;
+use std::time::Duration;
+
+use crate::sink::Sink;
+
+/// Where journald listens for native protocol messages.
+const JOURNAL_SOCKET: &str = "/run/systemd/journal/socket";
+
+/// Lines longer than this are split into multiple entries.
+const MAX_LINE_LEN: usize = 16 * 1024;
+
+/// Forwards output to the journal.
+pub struct JournalSink {
+    socket: UnixDatagram,
+    fields: Vec<u8>,
+    line: Vec<u8>,
+}
+
+impl JournalSink {
+    /// Connects to journald.
+    ///
+    /// The given fields are attached to every entry in addition to `MESSAGE`.
+    pub fn connect(fields: &[(&str, &str)]) -> io::Result<JournalSink> {
+        let socket = UnixDatagram::unbound()?;
+        socket.connect(JOURNAL_SOCKET)?;
+        Ok(JournalSink::with_socket(socket, fields))
+    }
+
+    fn with_socket(socket: UnixDatagram, fields: &[(&str, &str)]) -> JournalSink {
+        let mut encoded = Vec::new();
+        for (key, value) in fields {
+            encode_field(&mut encoded, key, value.as_bytes());
+        }
+        JournalSink {
+            socket,
+            fields: encoded,
+            line: Vec::new(),
+        }
+    }
+
+    /// Feeds output to the sink.  Complete lines are sent right away.
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        for &c in data {
+            if c == b'\n' {
+                self.send_line()?;
+            } else {
+                self.line.push(c);
+                if self.line.len() >= MAX_LINE_LEN {
+                    self.send_line()?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn send_line(&mut self) -> io::Result<()> {
+        // the pty translates newlines into CRLF
+        if self.line.last() == Some(&b'\r') {
+            self.line.pop();
+        }
+        let mut msg = self.fields.clone();
+        encode_field(&mut msg, "MESSAGE", &self.line);
+        self.line.clear();
+        match self.socket.send(&msg) {
+            Ok(_) => Ok(()),
+            // nothing we can do if journald can't keep up
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => Ok(()),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+impl Sink for JournalSink {
+    fn write_chunk(&mut self, _ts: Duration, bytes: &[u8]) -> io::Result<()> {
+        self.write(bytes)
+    }
+
+    /// Sends the last line if it wasn't terminated.
+    fn finish(&mut self) -> io::Result<()> {
+        if self.line.is_empty() {
+            Ok(())
+        } else {
+            self.send_line()
+        }
+    }
+}
+
+/// Encodes a single field.  Values with newlines use the binary encoding.
+fn encode_field(out: &mut Vec<u8>, key: &str, value: &[u8]) {
+    out.extend_from_slice(key.as_bytes());
+    if value.contains(&b'\n') {
+        out.push(b'\n');
+        out.extend_from_slice(&(value.len() as u64).to_le_bytes());
+    } else {
+        out.push(b'=');
+    }
+    out.extend_from_slice(value);
+    out.push(b'\n');
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_line_splitting() {
+        let (a, b) = UnixDatagram::pair().unwrap();
+        let mut sink = JournalSink::with_socket(a, &[("TEETTY_STREAM", "pty")]);
+        sink.write(b"hello\r\nwor").unwrap();
+        sink.write(b"ld\r\nrest").unwrap();
+        sink.finish().unwrap();
+
+        let mut buf = [0; 1024];
+        for expected in ["hello", "world", "rest"] {
+            let n = b.recv(&mut buf).unwrap();
+            assert_eq!(
+                &buf[..n],
+                format!("TEETTY_STREAM=pty\nMESSAGE={}\n", expected).as_bytes()
+            );
+        }
+    }
+}
@@ -0,0 +1,281 @@
+//! Helpers to emit and parse JSON.
+use anyhow::{bail, Error};
+
+/// Quotes a string for use in JSON.
+pub fn quote(s: &str) -> String {
+    let mut rv = String::with_capacity(s.len() + 2);
+    rv.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => rv.push_str("\\\""),
+            '\\' => rv.push_str("\\\\"),
+            '\n' => rv.push_str("\\n"),
+            '\r' => rv.push_str("\\r"),
+            '\t' => rv.push_str("\\t"),
+            c if (c as u32) < 0x20 => rv.push_str(&format!("\\u{:04x}", c as u32)),
+            c => rv.push(c),
+        }
+    }
+    rv.push('"');
+    rv
+}
+
+/// Quotes an optional string for use in JSON.  `None` becomes `null`.
+pub fn quote_opt(s: Option<&str>) -> String {
+    s.map_or_else(|| "null".into(), quote)
+}
+
+/// A parsed JSON value.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Value>),
+    Object(Vec<(String, Value)>),
+}
+
+impl Value {
+    /// Looks up a key in an object.
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Value::Object(items) => items.iter().find(|x| x.0 == key).map(|x| &x.1),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            Value::Number(n) => Some(*n),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[Value]> {
+        match self {
+            Value::Array(items) => Some(items),
+            _ => None,
+        }
+    }
+}
+
+/// Parses a JSON document.
+pub fn parse(s: &str) -> Result<Value, Error> {
+    let mut parser = JsonParser {
+        s: s.as_bytes(),
+        pos: 0,
+    };
+    let value = parser.value(0)?;
+    parser.skip_ws();
+    if parser.pos != parser.s.len() {
+        bail!("trailing characters at offset {}", parser.pos);
+    }
+    Ok(value)
+}
+
+/// How deeply arrays and objects may be nested.
+const MAX_DEPTH: usize = 128;
+
+struct JsonParser<'a> {
+    s: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> JsonParser<'a> {
+    fn skip_ws(&mut self) {
+        while self
+            .s
+            .get(self.pos)
+            .is_some_and(|c| c.is_ascii_whitespace())
+        {
+            self.pos += 1;
+        }
+    }
+
+    fn eat(&mut self, c: u8) -> bool {
+        self.skip_ws();
+        if self.s.get(self.pos) == Some(&c) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, c: u8) -> Result<(), Error> {
+        if !self.eat(c) {
+            bail!("expected '{}' at offset {}", c as char, self.pos);
+        }
+        Ok(())
+    }
+
+    fn value(&mut self, depth: usize) -> Result<Value, Error> {
+        if depth > MAX_DEPTH {
+            bail!("document is nested too deeply");
+        }
+        self.skip_ws();
+        let rest = &self.s[self.pos..];
+        for (lit, value) in [
+            (&b"null"[..], Value::Null),
+            (b"true", Value::Bool(true)),
+            (b"false", Value::Bool(false)),
+        ] {
+            if rest.starts_with(lit) {
+                self.pos += lit.len();
+                return Ok(value);
+            }
+        }
+        match rest.first() {
+            Some(b'"') => Ok(Value::String(self.string()?)),
+            Some(b'[') => {
+                self.pos += 1;
+                let mut items = Vec::new();
+                if !self.eat(b']') {
+                    loop {
+                        items.push(self.value(depth + 1)?);
+                        if self.eat(b']') {
+                            break;
+                        }
+                        self.expect(b',')?;
+                    }
+                }
+                Ok(Value::Array(items))
+            }
+            Some(b'{') => {
+                self.pos += 1;
+                let mut items = Vec::new();
+                if !self.eat(b'}') {
+                    loop {
+                        self.skip_ws();
+                        let key = self.string()?;
+                        self.expect(b':')?;
+                        items.push((key, self.value(depth + 1)?));
+                        if self.eat(b'}') {
+                            break;
+                        }
+                        self.expect(b',')?;
+                    }
+                }
+                Ok(Value::Object(items))
+            }
+            Some(b'-' | b'0'..=b'9') => {
+                let start = self.pos;
+                while self
+                    .s
+                    .get(self.pos)
+                    .is_some_and(|c| matches!(c, b'-' | b'+' | b'.' | b'e' | b'E' | b'0'..=b'9'))
+                {
+                    self.pos += 1;
+                }
+                match std::str::from_utf8(&self.s[start..self.pos])
+                    .ok()
+                    .and_then(|x| x.parse().ok())
+                {
+                    Some(n) => Ok(Value::Number(n)),
+                    None => bail!("invalid number at offset {}", start),
+                }
+            }
+            _ => bail!("unexpected character at offset {}", self.pos),
+        }
+    }
+
+    fn string(&mut self) -> Result<String, Error> {
+        if self.s.get(self.pos) != Some(&b'"') {
+            bail!("expected string at offset {}", self.pos);
+        }
+        self.pos += 1;
+        let mut rv = Vec::new();
+        loop {
+            let Some(&c) = self.s.get(self.pos) else {
+                bail!("unterminated string");
+            };
+            self.pos += 1;
+            match c {
+                b'"' => break,
+                b'\\' => {
+                    let Some(&c) = self.s.get(self.pos) else {
+                        bail!("unterminated string");
+                    };
+                    self.pos += 1;
+                    match c {
+                        b'n' => rv.push(b'\n'),
+                        b'r' => rv.push(b'\r'),
+                        b't' => rv.push(b'\t'),
+                        b'b' => rv.push(0x08),
+                        b'f' => rv.push(0x0c),
+                        b'u' => {
+                            let mut code = self.hex4()?;
+                            // surrogate pairs encode characters outside the BMP
+                            if (0xd800..0xdc00).contains(&code)
+                                && self.s[self.pos..].starts_with(b"\\u")
+                            {
+                                self.pos += 2;
+                                let low = self.hex4()?;
+                                code = 0x10000
+                                    + ((code - 0xd800) << 10)
+                                    + (low.wrapping_sub(0xdc00) & 0x3ff);
+                            }
+                            let c = char::from_u32(code).unwrap_or('\u{fffd}');
+                            rv.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+                        }
+                        c => rv.push(c),
+                    }
+                }
+                c => rv.push(c),
+            }
+        }
+        String::from_utf8(rv).map_err(|_| Error::msg("invalid UTF-8 in string"))
+    }
+
+    fn hex4(&mut self) -> Result<u32, Error> {
+        let code = self
+            .s
+            .get(self.pos..self.pos + 4)
+            .and_then(|x| std::str::from_utf8(x).ok())
+            .and_then(|x| u32::from_str_radix(x, 16).ok());
+        match code {
+            Some(code) => {
+                self.pos += 4;
+                Ok(code)
+            }
+            None => bail!("invalid escape at offset {}", self.pos),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let value =
+            parse(r#"[1.5, "o", "a\"\u001b[0m\ud83d\ude00", {"x": [true, null]}]"#).unwrap();
+        assert_eq!(
+            value,
+            Value::Array(vec![
+                Value::Number(1.5),
+                Value::String("o".into()),
+                Value::String("a\"\x1b[0m\u{1f600}".into()),
+                Value::Object(vec![(
+                    "x".into(),
+                    Value::Array(vec![Value::Bool(true), Value::Null])
+                )]),
+            ])
+        );
+        assert_eq!(
+            parse(&quote("\x01\n\"")).unwrap(),
+            Value::String("\x01\n\"".into())
+        );
+        assert!(parse("[1,").is_err());
+        assert!(parse("{} x").is_err());
+    }
+}
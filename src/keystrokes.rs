@@ -0,0 +1,170 @@
+//! Recording the timing of keystrokes.
+//!
+//! With `--input-timing <path>` every read of input typed on the keyboard is
+//! appended to the file as a JSON record, one per line, with the time since the
+//! session started, the time since the previous read and the number of keys.
+//! What was typed is never recorded.  An escape sequence such as an arrow key
+//! counts as one key and so does a multibyte character.  When the session ends
+//! a summary with the total number of keys and the median and 90th percentile
+//! of the intervals between reads is appended.  A read holds all keys that
+//! arrived together, so these aren't intervals between single keys.
+//!
+//! The timing of passwords can give them away, so nothing is recorded while
+//! the pty is in canonical mode with echo turned off, which is how programs
+//! read passwords.  Programs which edit the input themselves (shells with
+//! readline, editors) turn off echo as well but also canonical mode.
+use std::fs::File;
+use std::io::{self, Write};
+use std::os::fd::RawFd;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Error};
+use nix::sys::termios::{tcgetattr, LocalFlags};
+
+use crate::ansi::{Parser, Piece};
+use crate::sink::{Event, Sink};
+
+/// Appends the timing of keystrokes to a file.
+pub struct KeystrokeTiming {
+    file: File,
+    master: RawFd,
+    started: Instant,
+    last: Option<Instant>,
+    keys: u64,
+    intervals: Vec<Duration>,
+}
+
+impl KeystrokeTiming {
+    /// Creates the file.  `master` is the pty master, which tells if the
+    /// program reads a password.
+    pub fn create(path: &Path, master: RawFd) -> Result<KeystrokeTiming, Error> {
+        let file = File::options()
+            .append(true)
+            .create(true)
+            .mode(0o600)
+            .open(path)
+            .with_context(|| format!("could not open {}", path.display()))?;
+        Ok(KeystrokeTiming {
+            file,
+            master,
+            started: Instant::now(),
+            last: None,
+            keys: 0,
+            intervals: Vec::new(),
+        })
+    }
+
+    /// Returns the interval below which `fraction` of the intervals are.
+    fn percentile(&self, fraction: f64) -> Option<f64> {
+        let mut sorted = self.intervals.clone();
+        sorted.sort();
+        let idx = ((sorted.len() as f64 - 1.0) * fraction).round() as usize;
+        sorted.get(idx).map(Duration::as_secs_f64)
+    }
+}
+
+/// Checks if the pty reads a line without echoing it, like a password prompt.
+fn reads_password(master: RawFd) -> bool {
+    tcgetattr(master).is_ok_and(|attrs| {
+        let flags = attrs.local_flags;
+        flags.contains(LocalFlags::ICANON) && !flags.contains(LocalFlags::ECHO)
+    })
+}
+
+/// Counts the keys in a read of typed input.  Terminals send the escape
+/// sequence of a key at once, so what's left of one is a key of its own such
+/// as a lone escape.
+fn count_keys(data: &[u8]) -> u64 {
+    let mut keys = 0;
+    let mut seen = 0;
+    Parser::default().feed_pieces(data, |piece| match piece {
+        // continuation bytes of UTF-8 belong to the key before
+        Piece::Byte(c) => {
+            seen += 1;
+            keys += u64::from(!(0x80..=0xbf).contains(&c));
+        }
+        Piece::Sequence(seq) => {
+            seen += seq.raw.len();
+            keys += 1;
+        }
+    });
+    keys + u64::from(seen < data.len())
+}
+
+impl Sink for KeystrokeTiming {
+    fn write_chunk(&mut self, _ts: Duration, _bytes: &[u8]) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn event(&mut self, event: &Event) -> io::Result<()> {
+        let Event::InputTyped(data) = event else {
+            return Ok(());
+        };
+        let keys = count_keys(data);
+        if keys == 0 || reads_password(self.master) {
+            return Ok(());
+        }
+        let now = Instant::now();
+        let interval = self.last.map(|x| now - x);
+        self.last = Some(now);
+        self.keys += keys;
+        self.intervals.extend(interval);
+        writeln!(
+            self.file,
+            "{{\"elapsed\":{:.6},\"interval\":{},\"keys\":{}}}",
+            (now - self.started).as_secs_f64(),
+            interval.map_or("null".into(), |x| format!("{:.6}", x.as_secs_f64())),
+            keys
+        )
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        let stat = |x: Option<f64>| x.map_or("null".into(), |x| format!("{:.6}", x));
+        writeln!(
+            self.file,
+            "{{\"summary\":true,\"keys\":{},\"reads\":{},\"median_interval\":{},\"p90_interval\":{}}}",
+            self.keys,
+            self.intervals.len() + usize::from(self.last.is_some()),
+            stat(self.percentile(0.5)),
+            stat(self.percentile(0.9)),
+        )?;
+        self.file.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_count_keys() {
+        assert_eq!(count_keys(b"ls\r"), 3);
+        assert_eq!(count_keys(b"\x1b[A\x1b[B"), 2);
+        assert_eq!(count_keys("ö".as_bytes()), 1);
+        assert_eq!(count_keys(b"\x1b"), 1);
+    }
+
+    #[test]
+    fn test_reads_password() {
+        use nix::sys::termios::{tcsetattr, SetArg};
+
+        let pty = nix::pty::openpty(None, None).unwrap();
+        let mut attrs = tcgetattr(pty.slave).unwrap();
+        attrs
+            .local_flags
+            .insert(LocalFlags::ICANON | LocalFlags::ECHO);
+        tcsetattr(pty.slave, SetArg::TCSANOW, &attrs).unwrap();
+        assert!(!reads_password(pty.master));
+        attrs.local_flags.remove(LocalFlags::ECHO);
+        tcsetattr(pty.slave, SetArg::TCSANOW, &attrs).unwrap();
+        assert!(reads_password(pty.master));
+        // raw mode of an editor
+        attrs.local_flags.remove(LocalFlags::ICANON);
+        tcsetattr(pty.slave, SetArg::TCSANOW, &attrs).unwrap();
+        assert!(!reads_password(pty.master));
+        nix::unistd::close(pty.master).ok();
+        nix::unistd::close(pty.slave).ok();
+    }
+}
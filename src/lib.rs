@@ -0,0 +1,59 @@
+//! teetty runs a program in a pty while logging its output and letting others
+//! watch and remote control it.
+//!
+//! The binary is a thin layer on top of [`spawn::spawn`].  Embedders can pass
+//! their own [`sink::Sink`]s to receive the output of the program or run it in
+//! the background with [`session::Session`].
+pub mod ansi;
+mod answerback;
+pub mod asciicast;
+pub mod audit;
+mod base64;
+mod breakpoint;
+mod broadcast;
+mod cgroup;
+mod ci;
+pub mod control;
+mod coredump;
+pub mod debug;
+mod echo;
+pub mod escape;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+mod hash;
+mod hooks;
+mod http;
+mod input;
+mod journald;
+mod json;
+mod keystrokes;
+mod metadata;
+mod metrics;
+mod namespaces;
+mod notify;
+mod outfile;
+mod passthrough;
+pub mod pattern;
+mod poll;
+pub mod process;
+mod pty;
+pub mod registry;
+pub mod script;
+mod seccomp;
+mod segment;
+mod servers;
+pub mod session;
+pub mod sink;
+pub mod spawn;
+#[cfg(target_os = "linux")]
+mod splice;
+pub mod template;
+pub mod term;
+pub mod time;
+mod traffic;
+pub mod transform;
+pub mod ttyrec;
+mod upload;
+#[cfg(all(target_os = "linux", target_env = "gnu"))]
+mod utmp;
+mod websocket;
@@ -1,11 +1,21 @@
+mod attach;
+mod bench;
+mod chain;
 mod cli;
-mod spawn;
+mod completions;
+mod config;
+mod golden;
+mod multi;
+mod play;
+mod remote;
+mod tmux;
 
 fn main() {
+    teetty::term::install_hooks();
     let code = match cli::execute() {
         Err(err) => {
             use std::io::Write;
-            writeln!(std::io::stderr(), "teetty: {}", err).ok();
+            writeln!(std::io::stderr(), "teetty: {:#}", err).ok();
             1
         }
         Ok(code) => code,
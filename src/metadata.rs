@@ -0,0 +1,209 @@
+//! Describing where a session ran.
+//!
+//! With `--metadata` a JSON sidecar (`<output>.meta.json`) is written next to
+//! the output file or the recording when the program starts.  It holds the
+//! command, the host, the user, the terminal, the working directory with the
+//! git commit checked out there and the environment of the program.  Variables
+//! which look like they hold secrets are redacted.  With `--checksum` the
+//! SHA-256 digest of the output is added when the session ended.
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::SystemTime;
+
+use anyhow::{Context, Error};
+use nix::libc::STDIN_FILENO;
+use nix::unistd::{getuid, ttyname, User};
+
+use crate::json;
+use crate::notify::write_atomically;
+use crate::pattern::Pattern;
+use crate::spawn::SpawnOptions;
+use crate::time::format_utc;
+
+/// Variables with one of these in their name are redacted.
+const SECRET_NAMES: [&str; 9] = [
+    "TOKEN",
+    "SECRET",
+    "PASSWORD",
+    "PASSWD",
+    "KEY",
+    "CREDENTIAL",
+    "AUTH",
+    "COOKIE",
+    "PRIVATE",
+];
+
+/// Returns the path of the metadata of an output file or recording.
+pub fn metadata_path(path: &Path) -> PathBuf {
+    let mut metadata = path.as_os_str().to_owned();
+    metadata.push(".meta.json");
+    metadata.into()
+}
+
+/// The metadata of a session.
+pub struct Metadata {
+    path: PathBuf,
+    /// The keys with their values as JSON.
+    fields: Vec<(&'static str, String)>,
+}
+
+impl Metadata {
+    /// Creates empty metadata for the sidecar at `path`.
+    pub fn new(path: PathBuf) -> Metadata {
+        Metadata {
+            path,
+            fields: Vec::new(),
+        }
+    }
+
+    /// Collects the metadata of the session.  `env` is the environment of the
+    /// program as `KEY=value` strings.
+    pub fn collect<I, S>(path: PathBuf, opts: &SpawnOptions, env: I) -> Metadata
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<[u8]>,
+    {
+        Metadata {
+            path,
+            fields: snapshot(opts, env),
+        }
+    }
+
+    /// Sets a field to a JSON value.
+    pub fn set(&mut self, key: &'static str, value: String) {
+        match self.fields.iter_mut().find(|x| x.0 == key) {
+            Some(field) => field.1 = value,
+            None => self.fields.push((key, value)),
+        }
+    }
+
+    /// Writes the sidecar, replacing it atomically.
+    pub fn write(&self) -> Result<(), Error> {
+        let body = self
+            .fields
+            .iter()
+            .map(|(key, value)| format!("{}:{}", json::quote(key), value))
+            .collect::<Vec<_>>()
+            .join(",");
+        write_atomically(&self.path, &format!("{{{}}}\n", body))
+            .with_context(|| format!("could not write the metadata to {}", self.path.display()))
+    }
+}
+
+fn snapshot<I, S>(opts: &SpawnOptions, env: I) -> Vec<(&'static str, String)>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<[u8]>,
+{
+    let cwd = std::env::current_dir().ok();
+    vec![
+        (
+            "command",
+            format!(
+                "[{}]",
+                opts.args
+                    .iter()
+                    .map(|x| json::quote(&x.to_string_lossy()))
+                    .collect::<Vec<_>>()
+                    .join(",")
+            ),
+        ),
+        ("started", json::quote(&format_utc(SystemTime::now()))),
+        (
+            "hostname",
+            json::quote_opt(
+                nix::unistd::gethostname()
+                    .ok()
+                    .map(|x| x.to_string_lossy().into_owned())
+                    .as_deref(),
+            ),
+        ),
+        (
+            "user",
+            json::quote_opt(
+                User::from_uid(getuid())
+                    .ok()
+                    .flatten()
+                    .map(|x| x.name)
+                    .as_deref(),
+            ),
+        ),
+        (
+            "tty",
+            json::quote_opt(
+                ttyname(STDIN_FILENO)
+                    .ok()
+                    .map(|x| x.to_string_lossy().into_owned())
+                    .as_deref(),
+            ),
+        ),
+        (
+            "cwd",
+            json::quote_opt(cwd.as_deref().map(|x| x.to_string_lossy()).as_deref()),
+        ),
+        (
+            "git_commit",
+            json::quote_opt(cwd.as_deref().and_then(git_commit).as_deref()),
+        ),
+        ("env", env_json(env, &opts.metadata_deny_env)),
+    ]
+}
+
+/// Returns the commit checked out in the directory, if it's in a git
+/// repository.
+fn git_commit(dir: &Path) -> Option<String> {
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .args(["rev-parse", "HEAD"])
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()
+        .ok()?;
+    let commit = String::from_utf8(output.stdout).ok()?;
+    (output.status.success() && !commit.trim().is_empty()).then(|| commit.trim().to_string())
+}
+
+/// Returns the environment as a JSON object with the secrets redacted.
+fn env_json<I, S>(env: I, deny: &[Pattern]) -> String
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<[u8]>,
+{
+    let entries = env
+        .into_iter()
+        .filter_map(|entry| {
+            let entry = entry.as_ref();
+            let split = entry.iter().position(|&c| c == b'=')?;
+            let (key, value) = (&entry[..split], &entry[split + 1..]);
+            let name = OsStr::from_bytes(key).to_string_lossy().into_owned();
+            let upper = name.to_ascii_uppercase();
+            let value = if SECRET_NAMES.iter().any(|x| upper.contains(x))
+                || deny.iter().any(|x| x.is_match(key))
+            {
+                "[REDACTED]".to_string()
+            } else {
+                String::from_utf8_lossy(value).into_owned()
+            };
+            Some(format!("{}:{}", json::quote(&name), json::quote(&value)))
+        })
+        .collect::<Vec<_>>();
+    format!("{{{}}}", entries.join(","))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_env_json() {
+        let deny = [Pattern::new("^INTERNAL_").unwrap()];
+        let env = ["HOME=/root", "GITHUB_TOKEN=abc", "INTERNAL_URL=x", "A=b=c"];
+        assert_eq!(
+            env_json(env, &deny),
+            r#"{"HOME":"/root","GITHUB_TOKEN":"[REDACTED]","INTERNAL_URL":"[REDACTED]","A":"b=c"}"#
+        );
+    }
+}
@@ -0,0 +1,153 @@
+//! Counters about a session in the Prometheus text format.
+use std::fmt::Write;
+use std::time::{Duration, Instant};
+
+/// What happened in a session so far.
+pub struct Metrics {
+    started: Instant,
+    last_activity: Instant,
+    output_bytes: u64,
+    injected_bytes: u64,
+    typed_bytes: u64,
+    bells: u64,
+}
+
+impl Default for Metrics {
+    fn default() -> Metrics {
+        Metrics {
+            started: Instant::now(),
+            last_activity: Instant::now(),
+            output_bytes: 0,
+            injected_bytes: 0,
+            typed_bytes: 0,
+            bells: 0,
+        }
+    }
+}
+
+impl Metrics {
+    /// Records output of the program.
+    pub fn record_output(&mut self, n: usize) {
+        self.output_bytes += n as u64;
+        self.last_activity = Instant::now();
+    }
+
+    /// Records input that was sent to the program on behalf of the user (from
+    /// the FIFOs, scripts or remote control).
+    pub fn record_injected(&mut self, n: usize) {
+        self.injected_bytes += n as u64;
+        self.last_activity = Instant::now();
+    }
+
+    /// Records input typed on the keyboard or forwarded from stdin.
+    pub fn record_typed(&mut self, n: usize) {
+        self.typed_bytes += n as u64;
+        self.last_activity = Instant::now();
+    }
+
+    /// Returns when the program last produced output or received input.
+    pub fn last_activity(&self) -> Instant {
+        self.last_activity
+    }
+
+    /// Records bells rung by the program.
+    pub fn record_bells(&mut self, n: usize) {
+        self.bells += n as u64;
+    }
+
+    /// Returns how often the program rang the bell so far.
+    pub fn bells(&self) -> u64 {
+        self.bells
+    }
+
+    /// Returns the bytes of output captured so far.
+    pub fn output_bytes(&self) -> u64 {
+        self.output_bytes
+    }
+
+    /// Returns the bytes of input injected so far.
+    pub fn injected_bytes(&self) -> u64 {
+        self.injected_bytes
+    }
+
+    /// Returns the bytes of stdin forwarded so far.
+    pub fn typed_bytes(&self) -> u64 {
+        self.typed_bytes
+    }
+
+    /// Returns how long ago the session started.
+    pub fn uptime(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    /// Renders the metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut rv = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, value: String| {
+            writeln!(rv, "# HELP {} {}", name, help).unwrap();
+            writeln!(rv, "# TYPE {} {}", name, kind).unwrap();
+            writeln!(rv, "{} {}", name, value).unwrap();
+        };
+        metric(
+            "teetty_output_bytes_total",
+            "counter",
+            "Bytes of output captured from the program.",
+            self.output_bytes.to_string(),
+        );
+        metric(
+            "teetty_injected_bytes_total",
+            "counter",
+            "Bytes of input injected into the program.",
+            self.injected_bytes.to_string(),
+        );
+        metric(
+            "teetty_uptime_seconds",
+            "gauge",
+            "Seconds since the session started.",
+            format!("{:.3}", self.started.elapsed().as_secs_f64()),
+        );
+        metric(
+            "teetty_last_activity_seconds",
+            "gauge",
+            "Seconds since the program produced output or received input.",
+            format!("{:.3}", self.last_activity.elapsed().as_secs_f64()),
+        );
+        rv
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let mut metrics = Metrics::default();
+        metrics.record_output(42);
+        metrics.record_injected(7);
+        metrics.record_typed(3);
+        let rendered = metrics.render();
+        let lines = rendered.lines().collect::<Vec<_>>();
+        assert_eq!(
+            lines[..6],
+            [
+                "# HELP teetty_output_bytes_total Bytes of output captured from the program.",
+                "# TYPE teetty_output_bytes_total counter",
+                "teetty_output_bytes_total 42",
+                "# HELP teetty_injected_bytes_total Bytes of input injected into the program.",
+                "# TYPE teetty_injected_bytes_total counter",
+                "teetty_injected_bytes_total 7",
+            ]
+        );
+        assert_eq!(lines[7], "# TYPE teetty_uptime_seconds gauge");
+        assert_eq!(lines[10], "# TYPE teetty_last_activity_seconds gauge");
+        assert_eq!(lines.len(), 12);
+        for line in [lines[8], lines[11]] {
+            let (_, value) = line.split_once(' ').unwrap();
+            let seconds: f64 = value.parse().unwrap();
+            assert!((0.0..60.0).contains(&seconds), "{}", line);
+            assert_eq!(value.split_once('.').unwrap().1.len(), 3);
+        }
+        assert!(rendered.ends_with('\n'));
+    }
+}
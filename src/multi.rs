@@ -0,0 +1,314 @@
+//! Running several programs side by side.
+//!
+//! Every program runs in a session of its own and writes its log to
+//! `<name>.log`.  The output of all of them is merged on stdout with the name
+//! of the program in front of every line, similar to foreman.  Once one of
+//! them exits the others are stopped.
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::{self, BufWriter, IsTerminal, Write};
+use std::path::Path;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use anyhow::{bail, Context, Error};
+use nix::libc::{SIGHUP, SIGINT, SIGTERM, STDOUT_FILENO};
+use nix::sys::signal::Signal;
+use signal_hook::iterator::Signals;
+use teetty::control::ShutdownMode;
+use teetty::session::Session;
+use teetty::sink::Event;
+use teetty::spawn::{get_winsize, SpawnOptions};
+
+/// The colors of the names of the programs.
+const COLORS: [u8; 6] = [36, 33, 32, 35, 34, 31];
+
+/// How long the other programs get to exit after one of them exited.
+const STOP_GRACE: Duration = Duration::from_secs(5);
+
+enum Message {
+    Event(usize, Event),
+    /// The session of the program ended.
+    Done(usize),
+    Signal,
+}
+
+/// Puts a prefix in front of every line of a stream of output.
+struct Prefixer {
+    prefix: Vec<u8>,
+    at_line_start: bool,
+}
+
+impl Prefixer {
+    fn new(prefix: String) -> Prefixer {
+        Prefixer {
+            prefix: prefix.into_bytes(),
+            at_line_start: true,
+        }
+    }
+
+    /// Appends the output to `out` with the prefix at the start of every line.
+    /// Lines can be split across chunks.
+    fn feed(&mut self, data: &[u8], out: &mut Vec<u8>) {
+        for line in data.split_inclusive(|&c| c == b'\n') {
+            if self.at_line_start {
+                out.extend_from_slice(&self.prefix);
+            }
+            out.extend_from_slice(line);
+            self.at_line_start = line.ends_with(b"\n");
+        }
+    }
+
+    /// Ends an incomplete line, eg: because another program writes output.
+    fn break_line(&mut self, out: &mut Vec<u8>) {
+        if !self.at_line_start {
+            out.extend_from_slice(b"\r\n");
+            self.at_line_start = true;
+        }
+    }
+}
+
+/// The output of a program on the terminal and in the merged log.
+struct Stream {
+    terminal: Prefixer,
+    log: Prefixer,
+}
+
+impl Stream {
+    fn feed(&mut self, data: &[u8], terminal: &mut Vec<u8>, log: &mut Vec<u8>) {
+        self.terminal.feed(data, terminal);
+        self.log.feed(data, log);
+    }
+
+    fn break_line(&mut self, terminal: &mut Vec<u8>, log: &mut Vec<u8>) {
+        self.terminal.break_line(terminal);
+        self.log.break_line(log);
+    }
+
+    /// Writes a message of teetty on a line of its own.
+    fn message(&mut self, text: &str, terminal: &mut Vec<u8>, log: &mut Vec<u8>) {
+        self.break_line(terminal, log);
+        self.feed(format!("{}\r\n", text).as_bytes(), terminal, log);
+    }
+}
+
+/// Parses `name=command`.
+pub fn parse_program(value: &str) -> Result<(String, String), String> {
+    match value.split_once('=') {
+        Some((name, _)) if !is_valid_name(name) => {
+            Err(format!("{:?} can't be used as a log file name", name))
+        }
+        Some((name, command)) if !command.trim().is_empty() => {
+            Ok((name.to_string(), command.to_string()))
+        }
+        _ => Err("expected name=command".into()),
+    }
+}
+
+/// The name becomes `<name>.log` in the log directory, so it must not leave
+/// it.
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty() && name != "." && name != ".." && !name.contains(['/', '\0'])
+}
+
+/// Runs the commands with `sh -c` and shows their merged output.  Returns the
+/// exit code of the first program which exited.
+///
+/// `{name}` in the prefix is replaced with the name of the program, padded to
+/// the longest name.  On a terminal the prefix is colored, the merged log at
+/// `log_path` gets it as plain text.
+pub fn multi(
+    programs: Vec<(String, String)>,
+    log_dir: &Path,
+    prefix: &str,
+    log_path: Option<&Path>,
+) -> Result<i32, Error> {
+    for (idx, (name, _)) in programs.iter().enumerate() {
+        if !is_valid_name(name) {
+            bail!("{:?} can't be used as a log file name", name);
+        }
+        if programs[..idx].iter().any(|x| x.0 == *name) {
+            bail!("the name {} is used twice", name);
+        }
+    }
+    let color = io::stdout().is_terminal();
+    let width = programs
+        .iter()
+        .map(|x| x.0.chars().count())
+        .max()
+        .unwrap_or(0);
+    let prefixes = programs
+        .iter()
+        .map(|x| prefix.replace("{name}", &format!("{:<width$}", x.0)))
+        .collect::<Vec<_>>();
+    let prefix_width = prefixes.iter().map(|x| x.chars().count()).max();
+    let mut log = log_path
+        .map(|path| {
+            File::create(path)
+                .map(BufWriter::new)
+                .with_context(|| format!("could not create {}", path.display()))
+        })
+        .transpose()?;
+    // the programs get the width of the terminal which is left after the prefix
+    let size = get_winsize(STDOUT_FILENO)
+        .filter(|x| x.ws_col > 0 && x.ws_row > 0)
+        .map(|x| {
+            let cols = x.ws_col.saturating_sub(prefix_width.unwrap_or(0) as u16);
+            (cols.max(20), x.ws_row)
+        });
+
+    let (tx, rx) = mpsc::channel();
+    let mut signals = Signals::new([SIGINT, SIGTERM, SIGHUP])?;
+    let handle = signals.handle();
+    {
+        let tx = tx.clone();
+        thread::spawn(move || {
+            for _ in &mut signals {
+                if tx.send(Message::Signal).is_err() {
+                    break;
+                }
+            }
+        });
+    }
+
+    let mut streams = Vec::new();
+    let mut sessions = Vec::new();
+    for (idx, ((name, command), prefix)) in programs.into_iter().zip(prefixes).enumerate() {
+        let mut opts = SpawnOptions::new([OsString::from("sh"), "-c".into(), command.into()]);
+        opts.out_path = Some(log_dir.join(format!("{}.log", name)));
+        opts.default_size = size;
+        let session = Session::start(opts)?;
+        let events = session.events();
+        let tx = tx.clone();
+        thread::spawn(move || {
+            for event in events {
+                tx.send(Message::Event(idx, event)).ok();
+            }
+            tx.send(Message::Done(idx)).ok();
+        });
+        // trailing whitespace is left uncolored
+        let colored = if color {
+            let text = prefix.trim_end();
+            let color = COLORS[idx % COLORS.len()];
+            format!("\x1b[{}m{}\x1b[0m{}", color, text, &prefix[text.len()..])
+        } else {
+            prefix.clone()
+        };
+        streams.push(Stream {
+            terminal: Prefixer::new(colored),
+            log: Prefixer::new(prefix),
+        });
+        sessions.push(Some(session));
+    }
+    drop(tx);
+
+    let mut stdout = io::stdout().lock();
+    let (mut terminal, mut merged) = (Vec::new(), Vec::new());
+    // the program which wrote the last incomplete line
+    let mut partial: Option<usize> = None;
+    let mut code = None;
+    let mut stopping = false;
+    let mut running = sessions.len();
+    while running > 0 {
+        let Ok(message) = rx.recv() else {
+            break;
+        };
+        let mut stop = false;
+        if let Message::Event(idx, Event::Output(_) | Event::ChildExited(_)) | Message::Done(idx) =
+            message
+        {
+            if let Some(other) = partial.filter(|&x| x != idx) {
+                streams[other].break_line(&mut terminal, &mut merged);
+                partial = None;
+            }
+        }
+        match message {
+            Message::Event(idx, Event::Output(data)) => {
+                let stream = &mut streams[idx];
+                stream.feed(&data, &mut terminal, &mut merged);
+                partial = (!stream.log.at_line_start).then_some(idx);
+            }
+            Message::Event(idx, Event::ChildExited(exit_code)) => {
+                let text = format!("exited with {}", exit_code);
+                streams[idx].message(&text, &mut terminal, &mut merged);
+                partial = None;
+                code.get_or_insert(exit_code);
+                stop = true;
+            }
+            Message::Event(..) => {}
+            Message::Done(idx) => {
+                running -= 1;
+                if let Some(session) = sessions[idx].take() {
+                    if let Err(err) = session.wait() {
+                        streams[idx].message(&err.to_string(), &mut terminal, &mut merged);
+                        partial = None;
+                        code.get_or_insert(1);
+                        stop = true;
+                    }
+                }
+            }
+            // a second signal kills everything right away
+            Message::Signal if stopping => {
+                for session in sessions.iter().flatten() {
+                    session.shutdown(ShutdownMode::Kill).ok();
+                }
+            }
+            Message::Signal => {
+                stop = true;
+                code.get_or_insert(130);
+            }
+        }
+        if !terminal.is_empty() {
+            stdout.write_all(&terminal)?;
+            stdout.flush()?;
+            terminal.clear();
+        }
+        if let Some(ref mut log) = log {
+            log.write_all(&merged)?;
+            log.flush()?;
+        }
+        merged.clear();
+        if stop && !stopping {
+            stopping = true;
+            for session in sessions.iter().flatten() {
+                session
+                    .shutdown(ShutdownMode::Signal(Signal::SIGTERM, STOP_GRACE))
+                    .ok();
+            }
+        }
+    }
+    handle.close();
+    Ok(code.unwrap_or_default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_program() {
+        assert_eq!(
+            parse_program("web=python -m http.server"),
+            Ok(("web".into(), "python -m http.server".into()))
+        );
+        assert!(parse_program("=ls").is_err());
+        assert!(parse_program("ls").is_err());
+        assert!(parse_program("../x=ls").is_err());
+        assert!(parse_program("a/b=ls").is_err());
+        assert!(parse_program("..=ls").is_err());
+        assert!(parse_program("..a=ls").is_ok());
+    }
+
+    #[test]
+    fn test_prefixer() {
+        let mut prefixer = Prefixer::new("[a] ".into());
+        let mut out = Vec::new();
+        prefixer.feed(b"one\r\ntw", &mut out);
+        prefixer.feed(b"o\r\n", &mut out);
+        prefixer.feed(b"three", &mut out);
+        prefixer.break_line(&mut out);
+        prefixer.break_line(&mut out);
+        assert_eq!(out, b"[a] one\r\n[a] two\r\n[a] three\r\n");
+    }
+}
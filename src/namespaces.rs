@@ -0,0 +1,263 @@
+//! Running the program in namespaces of its own.
+//!
+//! With `--unshare` the program gets new namespaces of the requested kinds.
+//! The pty keeps working as the program still inherits it: a new network
+//! namespace only has the loopback interface, which is brought up, a new mount
+//! namespace doesn't propagate mounts back to the host, and with a new pid
+//! namespace the child of teetty becomes its init process.  That init starts
+//! the program, passes on the signals teetty sends and exits with the status
+//! of the program (`128 + signal` if it was killed).  With a mount namespace as
+//! well `/proc` is mounted again so that it shows the processes of the
+//! namespace.  A user namespace maps the user to root inside, which lets
+//! unprivileged users create the other namespaces.
+//!
+//! Namespaces are only available on Linux.
+use std::os::fd::RawFd;
+#[cfg(target_os = "linux")]
+use std::sync::atomic::{AtomicI32, Ordering};
+
+use anyhow::Error;
+use nix::errno::Errno;
+#[cfg(target_os = "linux")]
+use nix::libc::{self, c_int};
+use nix::unistd::ForkResult;
+#[cfg(target_os = "linux")]
+use nix::unistd::Pid;
+
+use crate::spawn::{Namespace, SetupStep};
+
+/// The grandchild which runs the program in a new pid namespace.
+#[cfg(target_os = "linux")]
+static PROGRAM: AtomicI32 = AtomicI32::new(0);
+
+/// The namespaces for the program, prepared before forking.
+#[cfg(target_os = "linux")]
+pub struct Namespaces {
+    kinds: Vec<Namespace>,
+    uid_map: Vec<u8>,
+    gid_map: Vec<u8>,
+}
+
+#[cfg(target_os = "linux")]
+impl Namespaces {
+    pub fn new(kinds: &[Namespace]) -> Result<Namespaces, Error> {
+        Ok(Namespaces {
+            kinds: kinds.to_vec(),
+            uid_map: format!("0 {} 1", nix::unistd::getuid()).into_bytes(),
+            gid_map: format!("0 {} 1", nix::unistd::getgid()).into_bytes(),
+        })
+    }
+
+    fn has(&self, kind: Namespace) -> bool {
+        self.kinds.contains(&kind)
+    }
+
+    /// Forks the child.  The child is already in a new pid (and user)
+    /// namespace if one was requested, as a process has to be created to
+    /// enter it.
+    pub unsafe fn fork(&self) -> Result<ForkResult, Errno> {
+        if !self.has(Namespace::Pid) {
+            return nix::unistd::fork();
+        }
+        let mut flags = libc::CLONE_NEWPID | libc::SIGCHLD;
+        if self.has(Namespace::User) {
+            flags |= libc::CLONE_NEWUSER;
+        }
+        clone(flags)
+    }
+
+    /// Enters the other namespaces and sets them up.  In a new pid namespace
+    /// this process stays behind as the init of the namespace and only the
+    /// process for the program returns.  Init closes `exec_fd`, the pipe
+    /// the program reports a failure to execute through.
+    ///
+    /// This runs between forking and executing so it must not allocate.
+    pub unsafe fn enter(&self, exec_fd: RawFd) -> Result<(), (SetupStep, Errno)> {
+        let unshare = |flags| Errno::result(libc::unshare(flags)).map(drop);
+        if self.has(Namespace::User) {
+            if !self.has(Namespace::Pid) {
+                unshare(libc::CLONE_NEWUSER).map_err(|x| (SetupStep::Namespaces, x))?;
+            }
+            write_file(c"/proc/self/setgroups", b"deny")
+                .and_then(|_| write_file(c"/proc/self/uid_map", &self.uid_map))
+                .and_then(|_| write_file(c"/proc/self/gid_map", &self.gid_map))
+                .map_err(|x| (SetupStep::Namespaces, x))?;
+        }
+        let mut flags = 0;
+        for (kind, flag) in [
+            (Namespace::Mount, libc::CLONE_NEWNS),
+            (Namespace::Net, libc::CLONE_NEWNET),
+            (Namespace::Uts, libc::CLONE_NEWUTS),
+            (Namespace::Ipc, libc::CLONE_NEWIPC),
+        ] {
+            if self.has(kind) {
+                flags |= flag;
+            }
+        }
+        if flags != 0 {
+            unshare(flags).map_err(|x| (SetupStep::Namespaces, x))?;
+        }
+        if self.has(Namespace::Mount) {
+            mount_private().map_err(|x| (SetupStep::Mounts, x))?;
+            if self.has(Namespace::Pid) {
+                mount_proc().map_err(|x| (SetupStep::Mounts, x))?;
+            }
+        }
+        if self.has(Namespace::Net) {
+            loopback_up().map_err(|x| (SetupStep::Loopback, x))?;
+        }
+        if self.has(Namespace::Pid) {
+            match nix::unistd::fork().map_err(|x| (SetupStep::Namespaces, x))? {
+                ForkResult::Child => become_foreground(),
+                ForkResult::Parent { child } => {
+                    libc::close(exec_fd);
+                    run_init(child)
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub struct Namespaces;
+
+#[cfg(not(target_os = "linux"))]
+impl Namespaces {
+    pub fn new(_kinds: &[Namespace]) -> Result<Namespaces, Error> {
+        Err(Error::msg("namespaces are only supported on Linux"))
+    }
+
+    pub unsafe fn fork(&self) -> Result<ForkResult, Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    pub unsafe fn enter(&self, _exec_fd: RawFd) -> Result<(), (SetupStep, Errno)> {
+        Err((SetupStep::Namespaces, Errno::ENOSYS))
+    }
+}
+
+/// Forks like `fork` but with the flags of `clone`.
+#[cfg(target_os = "linux")]
+unsafe fn clone(flags: c_int) -> Result<ForkResult, Errno> {
+    let rv = libc::syscall(libc::SYS_clone, flags as libc::c_long, 0, 0, 0, 0);
+    match Errno::result(rv)? {
+        0 => Ok(ForkResult::Child),
+        child => Ok(ForkResult::Parent {
+            child: Pid::from_raw(child as libc::pid_t),
+        }),
+    }
+}
+
+/// Moves the program into a process group of its own which becomes the
+/// foreground process group of the pty, so that signals for the foreground
+/// don't reach init as well.
+#[cfg(target_os = "linux")]
+unsafe fn become_foreground() {
+    let mut mask: libc::sigset_t = std::mem::zeroed();
+    let mut old: libc::sigset_t = std::mem::zeroed();
+    libc::sigemptyset(&mut mask);
+    libc::sigaddset(&mut mask, libc::SIGTTOU);
+    libc::setpgid(0, 0);
+    // as a background process this would be stopped otherwise
+    libc::sigprocmask(libc::SIG_BLOCK, &mask, &mut old);
+    libc::tcsetpgrp(libc::STDOUT_FILENO, libc::getpid());
+    libc::sigprocmask(libc::SIG_SETMASK, &old, std::ptr::null_mut());
+}
+
+/// Waits for the program as the init of the pid namespace and exits with its
+/// status.  Signals sent to init are passed on to the program.
+#[cfg(target_os = "linux")]
+unsafe fn run_init(program: Pid) -> ! {
+    PROGRAM.store(program.as_raw(), Ordering::Relaxed);
+    let mut action: libc::sigaction = std::mem::zeroed();
+    action.sa_sigaction = forward_signal as *const () as usize;
+    action.sa_flags = libc::SA_SIGINFO | libc::SA_RESTART;
+    for signal in [
+        libc::SIGHUP,
+        libc::SIGINT,
+        libc::SIGQUIT,
+        libc::SIGTERM,
+        libc::SIGUSR1,
+        libc::SIGUSR2,
+    ] {
+        libc::sigaction(signal, &action, std::ptr::null_mut());
+    }
+    loop {
+        let mut status = 0;
+        let pid = libc::waitpid(-1, &mut status, 0);
+        if pid == program.as_raw() {
+            if libc::WIFSIGNALED(status) {
+                libc::_exit(128 + libc::WTERMSIG(status));
+            }
+            libc::_exit(libc::WEXITSTATUS(status));
+        }
+        if pid < 0 && Errno::last() != Errno::EINTR {
+            libc::_exit(127);
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+extern "C" fn forward_signal(signal: c_int, info: *mut libc::siginfo_t, _: *mut libc::c_void) {
+    // the kernel sends the hangup of the pty to the program as well
+    if unsafe { (*info).si_code } <= 0 {
+        unsafe { libc::kill(PROGRAM.load(Ordering::Relaxed), signal) };
+    }
+}
+
+#[cfg(target_os = "linux")]
+unsafe fn write_file(path: &std::ffi::CStr, contents: &[u8]) -> Result<(), Errno> {
+    let fd = Errno::result(libc::open(path.as_ptr(), libc::O_WRONLY | libc::O_CLOEXEC))?;
+    let rv = libc::write(fd, contents.as_ptr().cast(), contents.len());
+    libc::close(fd);
+    Errno::result(rv).map(drop)
+}
+
+/// Keeps mounts in the namespace from propagating to the host.
+#[cfg(target_os = "linux")]
+unsafe fn mount_private() -> Result<(), Errno> {
+    let rv = libc::mount(
+        std::ptr::null(),
+        c"/".as_ptr(),
+        std::ptr::null(),
+        libc::MS_REC | libc::MS_PRIVATE,
+        std::ptr::null(),
+    );
+    Errno::result(rv).map(drop)
+}
+
+/// Mounts a `/proc` for the pid namespace.
+#[cfg(target_os = "linux")]
+unsafe fn mount_proc() -> Result<(), Errno> {
+    let rv = libc::mount(
+        c"proc".as_ptr(),
+        c"/proc".as_ptr(),
+        c"proc".as_ptr(),
+        libc::MS_NOSUID | libc::MS_NODEV | libc::MS_NOEXEC,
+        std::ptr::null(),
+    );
+    Errno::result(rv).map(drop)
+}
+
+/// Brings up the loopback interface of a new network namespace.
+#[cfg(target_os = "linux")]
+unsafe fn loopback_up() -> Result<(), Errno> {
+    let socket = Errno::result(libc::socket(
+        libc::AF_INET,
+        libc::SOCK_DGRAM | libc::SOCK_CLOEXEC,
+        0,
+    ))?;
+    let mut request: libc::ifreq = std::mem::zeroed();
+    for (dst, src) in request.ifr_name.iter_mut().zip(b"lo") {
+        *dst = *src as libc::c_char;
+    }
+    let mut rv = libc::ioctl(socket, libc::SIOCGIFFLAGS, &mut request);
+    if rv == 0 {
+        request.ifr_ifru.ifru_flags |= libc::IFF_UP as libc::c_short;
+        rv = libc::ioctl(socket, libc::SIOCSIFFLAGS, &request);
+    }
+    let rv = Errno::result(rv).map(drop);
+    libc::close(socket);
+    rv
+}
@@ -0,0 +1,352 @@
+//! Notifications and reports about finished sessions.
+use std::collections::VecDeque;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use anyhow::{bail, Context, Error};
+use nix::sys::wait::WaitStatus;
+
+use crate::ansi::Parser;
+use crate::json;
+use crate::sink::Sink;
+use crate::spawn::SpawnOptions;
+use crate::time::format_utc;
+
+/// How often a notification is attempted.
+const ATTEMPTS: u32 = 4;
+
+/// How much of the end of the output goes into the reports of failed runs.
+const TAIL_SIZE: usize = 16 * 1024;
+
+/// Returns the JSON summary of a finished session.
+pub fn session_summary(opts: &SpawnOptions, code: i32, duration: Duration) -> String {
+    format!(
+        "{{\"command\":[{}],\"exit_code\":{},\"duration\":{:.3},\"out_path\":{},\"session\":{}}}",
+        opts.args
+            .iter()
+            .map(|x| json::quote(&x.to_string_lossy()))
+            .collect::<Vec<_>>()
+            .join(","),
+        code,
+        duration.as_secs_f64(),
+        json::quote_opt(
+            opts.out_path
+                .as_deref()
+                .map(|x| x.to_string_lossy())
+                .as_deref()
+        ),
+        json::quote_opt(opts.session.as_deref()),
+    )
+}
+
+/// POSTs a JSON payload to the given URL, retrying with backoff on failures.
+///
+/// The request is made with `curl` so that TLS is taken care of.
+pub fn post_json(url: &str, payload: &str) -> Result<(), Error> {
+    let mut delay = Duration::from_secs(1);
+    for attempt in 1..=ATTEMPTS {
+        match post_json_once(url, payload) {
+            Ok(()) => return Ok(()),
+            Err(err) if attempt == ATTEMPTS => return Err(err),
+            Err(_) => {}
+        }
+        thread::sleep(delay);
+        delay *= 2;
+    }
+    unreachable!();
+}
+
+fn post_json_once(url: &str, payload: &str) -> Result<(), Error> {
+    let mut child = Command::new("curl")
+        .args([
+            "--silent",
+            "--fail",
+            "--max-time",
+            "30",
+            "--header",
+            "Content-Type: application/json",
+            "--data-binary",
+            "@-",
+            "--",
+            url,
+        ])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()?;
+    child.stdin.take().unwrap().write_all(payload.as_bytes())?;
+    let status = child.wait()?;
+    if !status.success() {
+        bail!("curl failed ({})", status);
+    }
+    Ok(())
+}
+
+/// Returns the JSON description of how the program ended.
+///
+/// Exactly one of `exit_code` and `signal` is set, unlike the exit code of
+/// teetty which is `128 + signal` for programs killed by a signal.
+pub fn exit_status_json(status: WaitStatus) -> String {
+    let (code, signal, core_dumped) = match status {
+        WaitStatus::Exited(_, code) => (Some(code), None, false),
+        WaitStatus::Signaled(_, signal, core_dumped) => (None, Some(signal), core_dumped),
+        _ => (None, None, false),
+    };
+    format!(
+        "{{\"exit_code\":{},\"signal\":{},\"core_dumped\":{}}}",
+        code.map_or_else(|| "null".to_string(), |x| x.to_string()),
+        json::quote_opt(signal.map(|x| x.as_str())),
+        core_dumped,
+    )
+}
+
+/// Writes the exit status to the file.  The file is replaced atomically so
+/// that whoever watches it never sees it half written.
+pub fn write_exit_file(path: &Path, status: WaitStatus) -> Result<(), Error> {
+    write_atomically(path, &format!("{}\n", exit_status_json(status)))
+        .with_context(|| format!("could not write the exit status to {}", path.display()))
+}
+
+/// Writes a JUnit XML report with the run of the program as its only test
+/// case.  Failed runs include the end of the output.
+pub fn write_junit_report(
+    path: &Path,
+    opts: &SpawnOptions,
+    status: WaitStatus,
+    duration: Duration,
+    tail: &[u8],
+) -> Result<(), Error> {
+    let name = xml_escape(&command_line(opts));
+    let time = duration.as_secs_f64();
+    let failure = failure_message(status);
+    let mut report = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    report.push_str(&format!(
+        "<testsuites tests=\"1\" failures=\"{failures}\" time=\"{time:.3}\">\n\
+         <testsuite name=\"teetty\" tests=\"1\" failures=\"{failures}\" time=\"{time:.3}\" timestamp=\"{}\">\n\
+         <testcase classname=\"teetty\" name=\"{name}\" time=\"{time:.3}\">\n",
+        format_utc(SystemTime::now() - duration),
+        failures = failure.is_some() as u8,
+    ));
+    if let Some(message) = failure {
+        report.push_str(&format!(
+            "<failure message=\"{}\" type=\"exit status\">{}</failure>\n",
+            xml_escape(&message),
+            xml_escape(&plain_text(tail))
+        ));
+    }
+    report.push_str("</testcase>\n</testsuite>\n</testsuites>\n");
+    write_atomically(path, &report)
+        .with_context(|| format!("could not write the JUnit report to {}", path.display()))
+}
+
+/// Writes a TAP report with the run of the program as its only test.  Failed
+/// runs include the end of the output.
+pub fn write_tap_report(
+    path: &Path,
+    opts: &SpawnOptions,
+    status: WaitStatus,
+    duration: Duration,
+    tail: &[u8],
+) -> Result<(), Error> {
+    let failure = failure_message(status);
+    let mut report = format!(
+        "TAP version 13\n1..1\n{} 1 - {}\n  ---\n  duration_ms: {}\n",
+        if failure.is_some() { "not ok" } else { "ok" },
+        command_line(opts).replace('#', "\\#"),
+        duration.as_millis()
+    );
+    if let Some(message) = failure {
+        report.push_str(&format!(
+            "  message: {}\n  output: |\n",
+            json::quote(&message)
+        ));
+        for line in plain_text(tail).lines() {
+            report.push_str(&format!("    {}\n", line));
+        }
+    }
+    report.push_str("  ...\n");
+    write_atomically(path, &report)
+        .with_context(|| format!("could not write the TAP report to {}", path.display()))
+}
+
+/// Keeps the end of the output for the reports.
+#[derive(Clone, Default)]
+pub struct OutputTail(Arc<Mutex<VecDeque<u8>>>);
+
+impl OutputTail {
+    pub fn contents(&self) -> Vec<u8> {
+        self.0.lock().unwrap().iter().copied().collect()
+    }
+}
+
+impl Sink for OutputTail {
+    fn write_chunk(&mut self, _ts: Duration, bytes: &[u8]) -> io::Result<()> {
+        let mut tail = self.0.lock().unwrap();
+        let bytes = &bytes[bytes.len().saturating_sub(TAIL_SIZE)..];
+        let excess = (tail.len() + bytes.len()).saturating_sub(TAIL_SIZE);
+        tail.drain(..excess);
+        tail.extend(bytes);
+        Ok(())
+    }
+}
+
+/// Returns the summary of a session for `--stats`.
+///
+/// There are no restarts to report: the program runs once per session.
+pub fn stats_line(
+    status: WaitStatus,
+    duration: Duration,
+    output_bytes: u64,
+    stdin_bytes: u64,
+) -> String {
+    format!(
+        "{:.1}s, {}, {} bytes of output, {} bytes of stdin",
+        duration.as_secs_f64(),
+        failure_message(status).unwrap_or_else(|| "exited with 0".into()),
+        output_bytes,
+        stdin_bytes,
+    )
+}
+
+/// Returns why the program failed or `None` if it succeeded.
+fn failure_message(status: WaitStatus) -> Option<String> {
+    match status {
+        WaitStatus::Exited(_, 0) => None,
+        WaitStatus::Exited(_, code) => Some(format!("exited with {}", code)),
+        WaitStatus::Signaled(_, signal, _) => Some(format!("killed by {}", signal.as_str())),
+        status => Some(format!("ended with {:?}", status)),
+    }
+}
+
+fn command_line(opts: &SpawnOptions) -> String {
+    opts.args
+        .iter()
+        .map(|x| x.to_string_lossy())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Removes escape sequences and carriage returns from output.  The first line
+/// is dropped, it's most likely cut off.
+fn plain_text(output: &[u8]) -> String {
+    let mut text = Vec::new();
+    Parser::default().strip(output, &mut text);
+    text.retain(|&c| c != b'\r');
+    let text = String::from_utf8_lossy(&text);
+    match text.split_once('\n') {
+        Some((_, rest)) if output.len() >= TAIL_SIZE => rest.to_string(),
+        _ => text.into_owned(),
+    }
+}
+
+/// Escapes text for XML.  Control characters are not allowed at all.
+fn xml_escape(text: &str) -> String {
+    let mut rv = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => rv.push_str("&amp;"),
+            '<' => rv.push_str("&lt;"),
+            '>' => rv.push_str("&gt;"),
+            '"' => rv.push_str("&quot;"),
+            '\t' | '\n' => rv.push(c),
+            c if c.is_control() => {}
+            c => rv.push(c),
+        }
+    }
+    rv
+}
+
+/// Replaces the file without anyone seeing it half written.
+pub fn write_atomically(path: &Path, contents: &str) -> io::Result<()> {
+    // sessions of the same process must not pick the same name
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let tmp = path.with_file_name(format!(
+        ".{}.teetty-{}-{}.tmp",
+        name,
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    File::options()
+        .write(true)
+        .create_new(true)
+        .open(&tmp)
+        .and_then(|mut file| file.write_all(contents.as_bytes()))
+        .and_then(|()| fs::rename(&tmp, path))
+        .inspect_err(|_| {
+            fs::remove_file(&tmp).ok();
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exit_status_json() {
+        use nix::sys::signal::Signal;
+        use nix::unistd::Pid;
+        let pid = Pid::from_raw(1);
+        assert_eq!(
+            exit_status_json(WaitStatus::Exited(pid, 3)),
+            r#"{"exit_code":3,"signal":null,"core_dumped":false}"#
+        );
+        assert_eq!(
+            exit_status_json(WaitStatus::Signaled(pid, Signal::SIGKILL, false)),
+            r#"{"exit_code":null,"signal":"SIGKILL","core_dumped":false}"#
+        );
+        assert_eq!(
+            stats_line(
+                WaitStatus::Signaled(pid, Signal::SIGTERM, false),
+                Duration::from_millis(2500),
+                10,
+                3
+            ),
+            "2.5s, killed by SIGTERM, 10 bytes of output, 3 bytes of stdin"
+        );
+    }
+
+    #[test]
+    fn test_junit_report() {
+        let path = std::env::temp_dir().join(format!("teetty-junit-{}.xml", std::process::id()));
+        let opts = SpawnOptions::new(["make", "test"]);
+        let status = WaitStatus::Exited(nix::unistd::Pid::from_raw(1), 2);
+        write_junit_report(
+            &path,
+            &opts,
+            status,
+            Duration::from_secs(1),
+            b"\x1b[31m<fail>\x1b[0m\r\n",
+        )
+        .unwrap();
+        let report = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).ok();
+        assert!(report.contains(r#"<testcase classname="teetty" name="make test" time="1.000">"#));
+        assert!(
+            report.contains(r#"<failure message="exited with 2" type="exit status">&lt;fail&gt;"#)
+        );
+    }
+
+    #[test]
+    fn test_write_atomically_concurrent() {
+        let path = std::env::temp_dir().join(format!("teetty-exit-{}.json", std::process::id()));
+        let writers: Vec<_> = (0..8)
+            .map(|i| {
+                let path = path.clone();
+                thread::spawn(move || write_atomically(&path, &format!("{}\n", i)))
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap().unwrap();
+        }
+        let contents = fs::read_to_string(&path).unwrap();
+        assert!(contents.trim().parse::<u32>().unwrap() < 8);
+        fs::remove_file(&path).unwrap();
+    }
+}
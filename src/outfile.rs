@@ -0,0 +1,308 @@
+//! Opening and finalizing the output file.
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::os::fd::{AsRawFd, OwnedFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use anyhow::{bail, Context, Error};
+use nix::errno::Errno;
+use nix::fcntl::{flock, FlockArg};
+
+use crate::hash::{hex, Sha256};
+use crate::spawn::SpawnOptions;
+
+/// The output file of a session.
+pub struct OutFile {
+    file: File,
+    path: PathBuf,
+    /// With `--atomic` the output goes to this file until the session ended.
+    tmp: Option<PathBuf>,
+    no_clobber: bool,
+    /// With `--out-filter` the output is written to the filter which writes to
+    /// the file.
+    filter: Option<(File, Child)>,
+    /// With `--checksum` the digest of the output, where in the file it starts
+    /// and how long it is.
+    checksum: Option<(Sha256, u64, u64)>,
+}
+
+impl OutFile {
+    /// Opens the output file.
+    ///
+    /// With `--no-clobber` this fails if the file already exists and with
+    /// `--atomic` the output is written to a temporary file next to it which
+    /// only replaces the file once [`OutFile::finish`] is called.  With
+    /// `--lock` the file is locked so that other sessions can't write to it at
+    /// the same time.
+    pub fn open(path: &Path, opts: &SpawnOptions) -> Result<OutFile, Error> {
+        if opts.checksum && opts.out_filter.is_some() {
+            bail!("the output can't be checksummed with an output filter");
+        }
+        if opts.checksum && opts.echo_input_log {
+            bail!("the output can't be checksummed with the input in the log");
+        }
+        if opts.checksum && opts.segment_every.is_some() {
+            bail!("the output can't be checksummed when it's split into segments");
+        }
+        if opts.no_clobber && fs::symlink_metadata(path).is_ok() {
+            bail!("output file {} already exists", path.display());
+        }
+        let tmp = opts.atomic.then(|| {
+            // sessions of the same process must not pick the same name
+            static COUNTER: AtomicUsize = AtomicUsize::new(0);
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            path.with_file_name(format!(
+                ".{}.teetty-{}-{}.tmp",
+                name,
+                std::process::id(),
+                COUNTER.fetch_add(1, Ordering::Relaxed)
+            ))
+        });
+        let mut options = File::options();
+        if tmp.is_some() {
+            options.write(true).create_new(true);
+        } else if opts.no_clobber {
+            options.append(true).create_new(true);
+        } else {
+            // truncating only happens once the lock is held
+            options.append(true).create(true);
+        }
+        let target = tmp.as_deref().unwrap_or(path);
+        let file = match options.open(target) {
+            Ok(file) => file,
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists && tmp.is_none() => {
+                bail!("output file {} already exists", path.display());
+            }
+            Err(err) => {
+                return Err(err).with_context(|| format!("could not open {}", target.display()))
+            }
+        };
+        if opts.lock {
+            lock(&file, path, opts.lock_wait)?;
+        }
+        if opts.truncate_out && tmp.is_none() {
+            file.set_len(0)?;
+        }
+        let filter = opts
+            .out_filter
+            .as_deref()
+            .map(|cmd| spawn_filter(cmd, &file))
+            .transpose()?;
+        // when appending, the output of this session starts at the end
+        let checksum = if opts.checksum {
+            Some((Sha256::default(), file.metadata()?.len(), 0))
+        } else {
+            None
+        };
+        Ok(OutFile {
+            file,
+            path: path.to_path_buf(),
+            tmp,
+            no_clobber: opts.no_clobber,
+            filter,
+            checksum,
+        })
+    }
+
+    /// Returns the path of the file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Finishes the file and continues with a new one at `path`.
+    pub fn rotate(&mut self, path: &Path, opts: &SpawnOptions) -> Result<(), Error> {
+        let next = OutFile::open(path, opts)?;
+        std::mem::replace(self, next).finish()
+    }
+
+    /// Returns the file the output has to be written to.
+    fn file_mut(&mut self) -> &mut File {
+        match self.filter {
+            Some((ref mut stdin, _)) => stdin,
+            None => &mut self.file,
+        }
+    }
+
+    /// Returns another handle to the file the output has to be written to.
+    /// What's written through it isn't checksummed.
+    pub fn try_clone_file(&self) -> io::Result<File> {
+        match self.filter {
+            Some((ref stdin, _)) => stdin.try_clone(),
+            None => self.file.try_clone(),
+        }
+    }
+
+    /// Returns the digest of the output as JSON if requested.
+    pub fn checksum(&self) -> Option<String> {
+        let (ref hasher, offset, length) = *self.checksum.as_ref()?;
+        Some(format!(
+            "{{\"algorithm\":\"sha256\",\"digest\":\"{}\",\"offset\":{},\"length\":{}}}",
+            hex(&hasher.digest()),
+            offset,
+            length
+        ))
+    }
+
+    /// Moves the output into place after the session ended.
+    ///
+    /// If this is never called, a temporary file is left behind with the output
+    /// written so far.
+    pub fn finish(self) -> Result<(), Error> {
+        if let Some((stdin, mut child)) = self.filter {
+            // closing its input tells the filter that the output ended
+            drop(stdin);
+            let status = child
+                .wait()
+                .context("could not wait for the output filter")?;
+            if !status.success() {
+                bail!("output filter failed ({})", status);
+            }
+        }
+        let Some(tmp) = self.tmp else {
+            return Ok(());
+        };
+        self.file.sync_all()?;
+        if self.no_clobber {
+            // unlike a rename, linking never replaces a file which showed up in
+            // the meantime.
+            if let Err(err) = fs::hard_link(&tmp, &self.path) {
+                bail!(
+                    "could not move output to {}: {} (the output was kept in {})",
+                    self.path.display(),
+                    err,
+                    tmp.display()
+                );
+            }
+            fs::remove_file(&tmp).ok();
+        } else if let Err(err) = fs::rename(&tmp, &self.path) {
+            bail!(
+                "could not move output to {}: {} (the output was kept in {})",
+                self.path.display(),
+                err,
+                tmp.display()
+            );
+        }
+        Ok(())
+    }
+}
+
+impl Write for OutFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.file_mut().write(buf)?;
+        if let Some((ref mut hasher, _, ref mut length)) = self.checksum {
+            hasher.update(&buf[..n]);
+            *length += n as u64;
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file_mut().flush()
+    }
+}
+
+impl AsRawFd for OutFile {
+    fn as_raw_fd(&self) -> RawFd {
+        match self.filter {
+            Some((ref stdin, _)) => stdin.as_raw_fd(),
+            None => self.file.as_raw_fd(),
+        }
+    }
+}
+
+/// Takes an exclusive lock on the output file.  The lock is held until the file
+/// is closed.
+fn lock(file: &File, path: &Path, wait: bool) -> Result<(), Error> {
+    match flock(file.as_raw_fd(), FlockArg::LockExclusiveNonblock) {
+        Ok(()) => return Ok(()),
+        Err(Errno::EWOULDBLOCK) if wait => {}
+        Err(Errno::EWOULDBLOCK) => {
+            bail!(
+                "output file {} is locked by another session",
+                path.display()
+            )
+        }
+        Err(err) => return Err(err).context("could not lock the output file"),
+    }
+    eprintln!("teetty: waiting for the lock on {}", path.display());
+    loop {
+        match flock(file.as_raw_fd(), FlockArg::LockExclusive) {
+            Ok(()) => return Ok(()),
+            Err(Errno::EINTR) => continue,
+            Err(err) => return Err(err).context("could not lock the output file"),
+        }
+    }
+}
+
+/// Starts the output filter with its output going to the file.
+fn spawn_filter(cmd: &str, file: &File) -> Result<(File, Child), Error> {
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(cmd)
+        .stdin(Stdio::piped())
+        .stdout(file.try_clone()?)
+        .spawn()
+        .with_context(|| format!("could not start output filter '{}'", cmd))?;
+    let stdin = OwnedFd::from(child.stdin.take().unwrap());
+    Ok((File::from(stdin), child))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("teetty-outfile-{}-{}", std::process::id(), name))
+    }
+
+    #[test]
+    fn test_no_clobber_and_atomic() {
+        let path = temp_path("atomic");
+        fs::write(&path, "old").unwrap();
+        let mut opts = SpawnOptions::new(["ls"]);
+        opts.no_clobber = true;
+        assert!(OutFile::open(&path, &opts).is_err());
+
+        opts.no_clobber = false;
+        opts.atomic = true;
+        let mut out = OutFile::open(&path, &opts).unwrap();
+        out.write_all(b"new").unwrap();
+        // nothing is replaced until the session ended
+        assert_eq!(fs::read(&path).unwrap(), b"old");
+        out.finish().unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"new");
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_lock() {
+        let path = temp_path("lock");
+        let mut opts = SpawnOptions::new(["ls"]);
+        opts.lock = true;
+        let first = OutFile::open(&path, &opts).unwrap();
+        let err = OutFile::open(&path, &opts).err().unwrap();
+        assert!(err.to_string().contains("locked"));
+        drop(first);
+        OutFile::open(&path, &opts).unwrap();
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_out_filter() {
+        let path = temp_path("filter");
+        let mut opts = SpawnOptions::new(["ls"]);
+        opts.truncate_out = true;
+        opts.out_filter = Some("tr a-z A-Z".into());
+        let mut out = OutFile::open(&path, &opts).unwrap();
+        out.write_all(b"hello\n").unwrap();
+        out.finish().unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"HELLO\n");
+
+        opts.out_filter = Some("exit 3".into());
+        let out = OutFile::open(&path, &opts).unwrap();
+        assert!(out.finish().is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
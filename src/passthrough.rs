@@ -0,0 +1,165 @@
+//! Filtering what the program writes to the terminal.
+//!
+//! Unlike transformations, which change what's captured, this only affects
+//! what's shown on the terminal and sent to followers.
+use std::fs::File;
+use std::io::{self, Write};
+
+use anyhow::{Context, Error};
+
+use crate::ansi::{Parser, Piece, Sequence};
+use crate::base64;
+use crate::spawn::{Bell, Clipboard, SpawnOptions};
+
+/// Saves the current title on the terminal's title stack.
+const PUSH_TITLE: &[u8] = b"\x1b[22;0t";
+
+/// Restores the title saved with [`PUSH_TITLE`].
+pub const POP_TITLE: &[u8] = b"\x1b[23;0t";
+
+/// Removes unwanted escape sequences from the output shown on the terminal.
+pub struct Passthrough {
+    parser: Parser,
+    strip_title: bool,
+    block_clipboard: bool,
+    strip_bell: bool,
+    /// With `--clipboard capture` what the program copies goes to this file.
+    clipboard_file: Option<File>,
+    copied: Vec<Vec<u8>>,
+}
+
+impl Passthrough {
+    /// Creates the filter or returns `None` if nothing has to be filtered.
+    pub fn new(opts: &SpawnOptions) -> Result<Option<Passthrough>, Error> {
+        if !opts.strip_title
+            && matches!(opts.clipboard, Clipboard::Allow)
+            && opts.bell == Bell::default()
+        {
+            return Ok(None);
+        }
+        let clipboard_file = match opts.clipboard {
+            Clipboard::Capture(ref path) => Some(
+                File::options()
+                    .append(true)
+                    .create(true)
+                    .open(path)
+                    .with_context(|| format!("could not open {}", path.display()))?,
+            ),
+            _ => None,
+        };
+        Ok(Some(Passthrough {
+            parser: Parser::default(),
+            strip_title: opts.strip_title,
+            block_clipboard: !matches!(opts.clipboard, Clipboard::Allow),
+            strip_bell: opts.bell.strip,
+            clipboard_file,
+            copied: Vec::new(),
+        }))
+    }
+
+    /// Filters a chunk of output and appends the result to `out`.  Returns how
+    /// often the bell rang in the chunk.
+    pub fn filter(&mut self, data: &[u8], out: &mut Vec<u8>) -> io::Result<usize> {
+        let strip_title = self.strip_title;
+        let block_clipboard = self.block_clipboard;
+        let copied = &mut self.copied;
+        let mut keep = |seq: &Sequence| match seq.osc() {
+            // window and icon titles
+            Some((b"0" | b"1" | b"2", _)) => !strip_title,
+            // `52;<selection>;<base64>` copies, `52;<selection>;?` pastes
+            Some((b"52", data)) if block_clipboard => {
+                if let Some(idx) = data.iter().position(|&c| c == b';') {
+                    if let Some(text) = base64::decode(&data[idx + 1..]) {
+                        copied.push(text);
+                    }
+                }
+                false
+            }
+            _ => true,
+        };
+        // BEL also ends OSC sequences, which doesn't ring the bell
+        let mut bells = 0;
+        self.parser.feed_pieces(data, |piece| match piece {
+            Piece::Byte(0x07) => {
+                bells += 1;
+                if !self.strip_bell {
+                    out.push(0x07);
+                }
+            }
+            Piece::Byte(c) => out.push(c),
+            Piece::Sequence(seq) => {
+                if keep(&seq) {
+                    out.extend_from_slice(seq.raw);
+                }
+            }
+        });
+        if let Some(ref mut file) = self.clipboard_file {
+            for mut text in self.copied.drain(..) {
+                text.push(b'\n');
+                file.write_all(&text)?;
+            }
+            file.flush()?;
+        } else {
+            self.copied.clear();
+        }
+        Ok(bells)
+    }
+}
+
+/// Returns the escape sequences which save the current title and set a new
+/// one.
+pub fn set_title(title: &str) -> Vec<u8> {
+    let mut rv = PUSH_TITLE.to_vec();
+    rv.extend_from_slice(b"\x1b]0;");
+    // control characters would end the sequence early
+    rv.extend(title.bytes().filter(|c| !c.is_ascii_control()));
+    rv.push(0x07);
+    rv
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_filter() {
+        let mut passthrough = Passthrough {
+            parser: Parser::default(),
+            strip_title: true,
+            block_clipboard: false,
+            strip_bell: true,
+            clipboard_file: None,
+            copied: Vec::new(),
+        };
+        let mut out = Vec::new();
+        let bells = passthrough
+            .filter(b"a\x1b]0;vim\x07b\x07\x1b]2;x", &mut out)
+            .unwrap();
+        assert_eq!(bells, 1);
+        passthrough
+            .filter(b"y\x1b\\c\x1b]8;;http://x\x07d\x1b[1m", &mut out)
+            .unwrap();
+        assert_eq!(out, b"abc\x1b]8;;http://x\x07d\x1b[1m");
+
+        let path = std::env::temp_dir().join(format!("teetty-clipboard-{}", std::process::id()));
+        let mut passthrough = Passthrough {
+            parser: Parser::default(),
+            strip_title: false,
+            block_clipboard: true,
+            strip_bell: false,
+            clipboard_file: Some(File::create(&path).unwrap()),
+            copied: Vec::new(),
+        };
+        out.clear();
+        passthrough
+            .filter(
+                b"a\x1b]52;c;aGk=\x07b\x1b]52;c;?\x1b\\c\x1b]0;t\x07",
+                &mut out,
+            )
+            .unwrap();
+        let copied = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).ok();
+        assert_eq!(out, b"abc\x1b]0;t\x07");
+        assert_eq!(copied, b"hi\n");
+    }
+}
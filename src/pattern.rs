@@ -0,0 +1,439 @@
+//! A small backtracking regular expression matcher.
+//!
+//! Patterns are matched against raw output bytes.  The supported syntax is a
+//! subset of what most regular expression engines understand:
+//!
+//! * literals, `.` (any byte but newline), `^` and `$`
+//! * classes like `[a-z_]` and `[^0-9]` as well as `\d`, `\w`, `\s` and their
+//!   negated forms `\D`, `\W` and `\S`
+//! * escapes like `\n`, `\r`, `\t`, `\e` and `\x1b`
+//! * groups `(...)`, alternatives `a|b` and the repetitions `*`, `+`, `?`,
+//!   `{n}`, `{n,}` and `{n,m}` which can be made lazy with a trailing `?`
+use std::fmt;
+use std::str::FromStr;
+
+/// A compiled pattern.
+#[derive(Clone)]
+pub struct Pattern {
+    source: String,
+    alts: Vec<Vec<Node>>,
+}
+
+#[derive(Debug, Clone)]
+enum Node {
+    Byte(u8),
+    Any,
+    Class(Box<[bool; 256]>),
+    Start,
+    End,
+    Group(Vec<Vec<Node>>),
+    Repeat {
+        node: Box<Node>,
+        min: usize,
+        max: Option<usize>,
+        greedy: bool,
+    },
+}
+
+impl Pattern {
+    /// Compiles a pattern.
+    pub fn new(source: &str) -> Result<Pattern, String> {
+        let mut parser = PatternParser {
+            src: source.as_bytes(),
+            pos: 0,
+        };
+        let alts = parser.parse_alts()?;
+        if parser.pos < parser.src.len() {
+            return Err(format!("unmatched ')' at position {}", parser.pos));
+        }
+        Ok(Pattern {
+            source: source.to_string(),
+            alts,
+        })
+    }
+
+    /// Finds the leftmost match and returns its start and end offsets.
+    pub fn find(&self, input: &[u8]) -> Option<(usize, usize)> {
+        let anchored = self
+            .alts
+            .iter()
+            .all(|alt| matches!(alt.first(), Some(Node::Start)));
+        let last_start = if anchored { 0 } else { input.len() };
+        for start in 0..=last_start {
+            let mut end = None;
+            let matched = self.alts.iter().any(|alt| {
+                match_seq(alt, input, start, &mut |pos| {
+                    end = Some(pos);
+                    true
+                })
+            });
+            if matched {
+                return end.map(|end| (start, end));
+            }
+        }
+        None
+    }
+
+    /// Checks if the pattern matches anywhere in the input.
+    pub fn is_match(&self, input: &[u8]) -> bool {
+        self.find(input).is_some()
+    }
+
+    /// Returns the length of the longest possible match or `None` if the
+    /// pattern has an unbounded repetition.
+    pub fn max_len(&self) -> Option<usize> {
+        max_len(&self.alts)
+    }
+}
+
+fn max_len(alts: &[Vec<Node>]) -> Option<usize> {
+    alts.iter().try_fold(0, |longest: usize, alt| {
+        let len = alt.iter().try_fold(0, |len: usize, node| {
+            let node_len = match node {
+                Node::Byte(_) | Node::Any | Node::Class(_) => 1,
+                Node::Start | Node::End => 0,
+                Node::Group(alts) => max_len(alts)?,
+                Node::Repeat { node, max, .. } => {
+                    max_len(&[vec![(**node).clone()]])?.checked_mul((*max)?)?
+                }
+            };
+            len.checked_add(node_len)
+        })?;
+        Some(longest.max(len))
+    })
+}
+
+impl fmt::Debug for Pattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Pattern({:?})", self.source)
+    }
+}
+
+impl FromStr for Pattern {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Pattern, String> {
+        Pattern::new(s)
+    }
+}
+
+fn match_seq(nodes: &[Node], input: &[u8], pos: usize, k: &mut dyn FnMut(usize) -> bool) -> bool {
+    let (first, rest) = match nodes.split_first() {
+        Some(x) => x,
+        None => return k(pos),
+    };
+    match first {
+        Node::Byte(b) => input.get(pos) == Some(b) && match_seq(rest, input, pos + 1, k),
+        Node::Any => {
+            matches!(input.get(pos), Some(&c) if c != b'\n') && match_seq(rest, input, pos + 1, k)
+        }
+        Node::Class(set) => {
+            input.get(pos).is_some_and(|&c| set[c as usize]) && match_seq(rest, input, pos + 1, k)
+        }
+        Node::Start => pos == 0 && match_seq(rest, input, pos, k),
+        Node::End => pos == input.len() && match_seq(rest, input, pos, k),
+        Node::Group(alts) => alts
+            .iter()
+            .any(|alt| match_seq(alt, input, pos, &mut |p| match_seq(rest, input, p, k))),
+        Node::Repeat {
+            node,
+            min,
+            max,
+            greedy,
+        } => match_repeat(node, *min, *max, *greedy, 0, rest, input, pos, k),
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn match_repeat(
+    node: &Node,
+    min: usize,
+    max: Option<usize>,
+    greedy: bool,
+    count: usize,
+    rest: &[Node],
+    input: &[u8],
+    pos: usize,
+    k: &mut dyn FnMut(usize) -> bool,
+) -> bool {
+    let more = |k: &mut dyn FnMut(usize) -> bool| {
+        max.is_none_or(|max| count < max)
+            && match_seq(std::slice::from_ref(node), input, pos, &mut |p| {
+                // an empty match would repeat forever once the minimum is reached
+                (p != pos || count < min)
+                    && match_repeat(node, min, max, greedy, count + 1, rest, input, p, k)
+            })
+    };
+    if count < min {
+        return more(k);
+    }
+    if !greedy && match_seq(rest, input, pos, k) {
+        return true;
+    }
+    more(k) || (greedy && match_seq(rest, input, pos, k))
+}
+
+struct PatternParser<'a> {
+    src: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> PatternParser<'a> {
+    fn peek(&self) -> Option<u8> {
+        self.src.get(self.pos).copied()
+    }
+
+    fn next(&mut self) -> Option<u8> {
+        let rv = self.peek();
+        self.pos += 1;
+        rv
+    }
+
+    fn parse_alts(&mut self) -> Result<Vec<Vec<Node>>, String> {
+        let mut alts = vec![self.parse_seq()?];
+        while self.peek() == Some(b'|') {
+            self.pos += 1;
+            alts.push(self.parse_seq()?);
+        }
+        Ok(alts)
+    }
+
+    fn parse_seq(&mut self) -> Result<Vec<Node>, String> {
+        let mut seq = Vec::new();
+        while let Some(c) = self.peek() {
+            let node = match c {
+                b'|' | b')' => break,
+                b'(' => {
+                    self.pos += 1;
+                    let alts = self.parse_alts()?;
+                    if self.next() != Some(b')') {
+                        return Err("unclosed group".into());
+                    }
+                    Node::Group(alts)
+                }
+                b'*' | b'+' | b'?' | b'{' => {
+                    let node = match seq.pop() {
+                        Some(Node::Start | Node::End) | None => {
+                            return Err(format!("nothing to repeat at position {}", self.pos))
+                        }
+                        Some(node) => node,
+                    };
+                    self.parse_repeat(node)?
+                }
+                b'.' => {
+                    self.pos += 1;
+                    Node::Any
+                }
+                b'^' => {
+                    self.pos += 1;
+                    Node::Start
+                }
+                b'$' => {
+                    self.pos += 1;
+                    Node::End
+                }
+                b'[' => {
+                    self.pos += 1;
+                    self.parse_class()?
+                }
+                b'\\' => {
+                    self.pos += 1;
+                    self.parse_escape()?
+                }
+                c => {
+                    self.pos += 1;
+                    Node::Byte(c)
+                }
+            };
+            seq.push(node);
+        }
+        Ok(seq)
+    }
+
+    fn parse_repeat(&mut self, node: Node) -> Result<Node, String> {
+        let (min, max) = match self.next() {
+            Some(b'*') => (0, None),
+            Some(b'+') => (1, None),
+            Some(b'?') => (0, Some(1)),
+            _ => {
+                let min = self.parse_number()?;
+                let max = if self.peek() == Some(b',') {
+                    self.pos += 1;
+                    if self.peek() == Some(b'}') {
+                        None
+                    } else {
+                        Some(self.parse_number()?)
+                    }
+                } else {
+                    Some(min)
+                };
+                if self.next() != Some(b'}') {
+                    return Err("unclosed repetition".into());
+                }
+                if max.is_some_and(|max| max < min) {
+                    return Err("invalid repetition range".into());
+                }
+                (min, max)
+            }
+        };
+        let greedy = if self.peek() == Some(b'?') {
+            self.pos += 1;
+            false
+        } else {
+            true
+        };
+        Ok(Node::Repeat {
+            node: Box::new(node),
+            min,
+            max,
+            greedy,
+        })
+    }
+
+    fn parse_number(&mut self) -> Result<usize, String> {
+        let start = self.pos;
+        while self.peek().is_some_and(|c| c.is_ascii_digit()) {
+            self.pos += 1;
+        }
+        std::str::from_utf8(&self.src[start..self.pos])
+            .unwrap()
+            .parse()
+            .map_err(|_| format!("expected number at position {}", start))
+    }
+
+    fn parse_class(&mut self) -> Result<Node, String> {
+        let mut set = Box::new([false; 256]);
+        let negated = self.peek() == Some(b'^');
+        if negated {
+            self.pos += 1;
+        }
+        let mut first = true;
+        loop {
+            let lo = match self.next() {
+                None => return Err("unclosed character class".into()),
+                Some(b']') if !first => break,
+                Some(b'\\') => match self.parse_escape()? {
+                    Node::Byte(c) => c,
+                    Node::Class(other) => {
+                        for (idx, &v) in other.iter().enumerate() {
+                            set[idx] |= v;
+                        }
+                        first = false;
+                        continue;
+                    }
+                    _ => unreachable!(),
+                },
+                Some(c) => c,
+            };
+            first = false;
+            let hi = if self.peek() == Some(b'-') && self.src.get(self.pos + 1) != Some(&b']') {
+                self.pos += 1;
+                match self.next() {
+                    Some(b'\\') => match self.parse_escape()? {
+                        Node::Byte(c) => c,
+                        _ => return Err("invalid range in character class".into()),
+                    },
+                    Some(c) => c,
+                    None => return Err("unclosed character class".into()),
+                }
+            } else {
+                lo
+            };
+            if hi < lo {
+                return Err("invalid range in character class".into());
+            }
+            for c in lo..=hi {
+                set[c as usize] = true;
+            }
+        }
+        if negated {
+            for v in set.iter_mut() {
+                *v = !*v;
+            }
+        }
+        Ok(Node::Class(set))
+    }
+
+    fn parse_escape(&mut self) -> Result<Node, String> {
+        let class = |f: fn(u8) -> bool, negated: bool| {
+            let mut set = Box::new([false; 256]);
+            for (idx, v) in set.iter_mut().enumerate() {
+                *v = f(idx as u8) != negated;
+            }
+            Node::Class(set)
+        };
+        let is_word = |c: u8| c.is_ascii_alphanumeric() || c == b'_';
+        Ok(match self.next() {
+            None => return Err("trailing backslash".into()),
+            Some(b'd') => class(|c| c.is_ascii_digit(), false),
+            Some(b'D') => class(|c| c.is_ascii_digit(), true),
+            Some(b'w') => class(is_word, false),
+            Some(b'W') => class(is_word, true),
+            Some(b's') => class(|c| c.is_ascii_whitespace(), false),
+            Some(b'S') => class(|c| c.is_ascii_whitespace(), true),
+            Some(b'n') => Node::Byte(b'\n'),
+            Some(b'r') => Node::Byte(b'\r'),
+            Some(b't') => Node::Byte(b'\t'),
+            Some(b'e') => Node::Byte(0x1b),
+            Some(b'x') => {
+                let hex = self
+                    .src
+                    .get(self.pos..self.pos + 2)
+                    .and_then(|x| std::str::from_utf8(x).ok())
+                    .and_then(|x| u8::from_str_radix(x, 16).ok())
+                    .ok_or_else(|| "invalid hex escape".to_string())?;
+                self.pos += 2;
+                Node::Byte(hex)
+            }
+            Some(c) if c.is_ascii_alphanumeric() => {
+                return Err(format!("unknown escape '\\{}'", c as char))
+            }
+            Some(c) => Node::Byte(c),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn find(pattern: &str, input: &str) -> Option<(usize, usize)> {
+        Pattern::new(pattern).unwrap().find(input.as_bytes())
+    }
+
+    #[test]
+    fn test_find() {
+        assert_eq!(find(">>> $", "Python\r\n>>> "), Some((8, 12)));
+        assert_eq!(find(">>> $", ">>> x"), None);
+        assert_eq!(find("^a+", "aaab"), Some((0, 3)));
+        assert_eq!(find("^a+", "baaa"), None);
+        assert_eq!(find("a+?", "aaa"), Some((0, 1)));
+        assert_eq!(find("(foo|bar)+baz", "xbarfoobaz"), Some((1, 10)));
+        assert_eq!(find("\\d{2,3}", "a1234"), Some((1, 4)));
+        assert_eq!(find("[a-c]{2}", "xcbz"), Some((1, 3)));
+        assert_eq!(find("[^a-c ]+", "abc def"), Some((4, 7)));
+        assert_eq!(find("\\(gdb\\) $", "(gdb) "), Some((0, 6)));
+        assert_eq!(find("\\x1b\\[\\d+m", "\x1b[31m"), Some((0, 5)));
+        assert_eq!(find("a.c", "a\nc"), None);
+        assert_eq!(find("(a*)*b", "aaab"), Some((0, 4)));
+        assert_eq!(find("(a*)*b", "aaa"), None);
+    }
+
+    #[test]
+    fn test_max_len() {
+        let max_len = |pattern: &str| Pattern::new(pattern).unwrap().max_len();
+        assert_eq!(max_len("^abc$"), Some(3));
+        assert_eq!(max_len("(foo|ba)r?\\d{2,4}"), Some(8));
+        assert_eq!(max_len("token=\\w+"), None);
+    }
+
+    #[test]
+    fn test_errors() {
+        assert!(Pattern::new("(abc").is_err());
+        assert!(Pattern::new("abc)").is_err());
+        assert!(Pattern::new("*a").is_err());
+        assert!(Pattern::new("[abc").is_err());
+        assert!(Pattern::new("a{3,1}").is_err());
+        assert!(Pattern::new("\\q").is_err());
+    }
+}
@@ -0,0 +1,84 @@
+//! Playing back and exporting recordings.
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::{Context, Error};
+use nix::libc::STDOUT_FILENO;
+use teetty::ansi::Parser;
+use teetty::asciicast::Recording;
+use teetty::spawn::get_winsize;
+
+/// Plays back a recording on stdout with the original timing.
+///
+/// `speed` speeds up (or slows down) the playback and pauses longer than
+/// `idle_limit` seconds are shortened to it.  Playback starts `seek` seconds
+/// into the recording.  When the terminal was resized during the recording
+/// the terminal is asked to resize as well.
+pub fn play(path: &Path, speed: f64, idle_limit: Option<f64>, seek: f64) -> Result<i32, Error> {
+    let recording = Recording::load_range(path, seek, None)?;
+    let mut stdout = io::stdout().lock();
+    // ask the terminal to match the size of the recording like the viewer of
+    // `teetty attach` does
+    if let Some(winsize) = get_winsize(STDOUT_FILENO) {
+        if recording.height > 0
+            && recording.width > 0
+            && (winsize.ws_row, winsize.ws_col) != (recording.height, recording.width)
+        {
+            write!(stdout, "\x1b[8;{};{}t", recording.height, recording.width)?;
+        }
+    }
+    let mut last = seek;
+    for event in recording.events.iter().filter(|x| x.kind != "i") {
+        let mut delay = (event.time - last).max(0.0);
+        if let Some(limit) = idle_limit {
+            delay = delay.min(limit);
+        }
+        last = event.time;
+        if delay > 0.0 {
+            std::thread::sleep(Duration::from_secs_f64(delay / speed));
+        }
+        if event.kind != "r" {
+            stdout.write_all(event.data.as_bytes())?;
+        } else if let Some((rows, cols)) = event.size() {
+            write!(stdout, "\x1b[8;{};{}t", rows, cols)?;
+        }
+        stdout.flush()?;
+    }
+    Ok(0)
+}
+
+/// Writes the output of a recording to a file or stdout.
+///
+/// Unless `raw` is set, escape sequences and carriage returns are removed so
+/// that the result is plain text.  Only the output between `from` and `to`
+/// seconds is written.
+pub fn export(
+    path: &Path,
+    out_path: Option<&Path>,
+    raw: bool,
+    (from, to): (f64, Option<f64>),
+) -> Result<i32, Error> {
+    let recording = Recording::load_range(path, from, to)?;
+    let mut out: Box<dyn Write> = match out_path {
+        Some(p) => {
+            Box::new(File::create(p).with_context(|| format!("could not create {}", p.display()))?)
+        }
+        None => Box::new(io::stdout().lock()),
+    };
+    let mut parser = Parser::default();
+    let mut text = Vec::new();
+    for event in recording.output() {
+        if raw {
+            out.write_all(event.data.as_bytes())?;
+        } else {
+            text.clear();
+            parser.strip(event.data.as_bytes(), &mut text);
+            text.retain(|&c| c != b'\r');
+            out.write_all(&text)?;
+        }
+    }
+    out.flush()?;
+    Ok(0)
+}
@@ -0,0 +1,116 @@
+//! Waiting for fds with `poll`.
+//!
+//! Unlike the `FdSet` of `select` this works with fds of any number, so a
+//! session with many connections can't run into `FD_SETSIZE`.
+use std::os::fd::{AsRawFd, RawFd};
+use std::time::Duration;
+
+use nix::errno::Errno;
+use nix::libc::c_int;
+use nix::poll::{poll, PollFd, PollFlags};
+
+/// The fds to wait for and, after [`PollSet::poll`], which of them are ready.
+#[derive(Default)]
+pub struct PollSet {
+    fds: Vec<PollFd>,
+}
+
+impl PollSet {
+    pub fn new() -> PollSet {
+        PollSet::default()
+    }
+
+    /// Waits until the fd can be read from.
+    pub fn insert_read(&mut self, fd: RawFd) {
+        self.insert(fd, PollFlags::POLLIN);
+    }
+
+    /// Waits until the fd can be written to.
+    pub fn insert_write(&mut self, fd: RawFd) {
+        self.insert(fd, PollFlags::POLLOUT);
+    }
+
+    fn insert(&mut self, fd: RawFd, events: PollFlags) {
+        match self.fds.iter_mut().find(|x| x.as_raw_fd() == fd) {
+            Some(entry) => entry.set_events(entry.events() | events),
+            None => self.fds.push(PollFd::new(fd, events)),
+        }
+    }
+
+    /// Waits until one of the fds is ready or the timeout passed.  Returns the
+    /// number of ready fds.
+    pub fn poll(&mut self, timeout: Duration) -> Result<usize, Errno> {
+        // rounded up so that a deadline isn't missed by waking up too early
+        let timeout = timeout.as_micros().div_ceil(1000).min(c_int::MAX as u128) as c_int;
+        let n = poll(&mut self.fds, timeout)?;
+        // like select, fail for fds that aren't open
+        if self
+            .fds
+            .iter()
+            .any(|x| revents(x).contains(PollFlags::POLLNVAL))
+        {
+            return Err(Errno::EBADF);
+        }
+        Ok(n as usize)
+    }
+
+    /// Checks if reading from the fd won't block.  A hangup or an error counts
+    /// as readable since reading reports it.
+    pub fn readable(&self, fd: RawFd) -> bool {
+        self.ready(fd, PollFlags::POLLIN)
+    }
+
+    /// Checks if writing to the fd won't block.
+    pub fn writable(&self, fd: RawFd) -> bool {
+        self.ready(fd, PollFlags::POLLOUT)
+    }
+
+    fn ready(&self, fd: RawFd, events: PollFlags) -> bool {
+        self.fds
+            .iter()
+            .find(|x| x.as_raw_fd() == fd)
+            .is_some_and(|x| {
+                x.events().contains(events)
+                    && revents(x).intersects(events | PollFlags::POLLHUP | PollFlags::POLLERR)
+            })
+    }
+}
+
+/// Returns what happened to the fd.  Flags nix doesn't know are taken as an
+/// error.
+fn revents(fd: &PollFd) -> PollFlags {
+    fd.revents().unwrap_or(PollFlags::POLLERR)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use nix::unistd::{close, pipe, write};
+
+    #[test]
+    fn test_poll_set() {
+        let (r, w) = pipe().unwrap();
+        let mut fds = PollSet::new();
+        fds.insert_read(r);
+        fds.insert_write(w);
+        assert_eq!(fds.poll(Duration::from_secs(1)).unwrap(), 1);
+        assert!(!fds.readable(r));
+        assert!(fds.writable(w));
+        // only what's asked for is reported
+        assert!(!fds.readable(w));
+
+        write(w, b"x").unwrap();
+        let mut fds = PollSet::new();
+        fds.insert_read(r);
+        assert_eq!(fds.poll(Duration::ZERO).unwrap(), 1);
+        assert!(fds.readable(r));
+        close(r).unwrap();
+        close(w).unwrap();
+
+        // other tests might reuse the fds which were just closed
+        let mut fds = PollSet::new();
+        fds.insert_read(999_999);
+        assert_eq!(fds.poll(Duration::ZERO), Err(Errno::EBADF));
+    }
+}
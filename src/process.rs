@@ -0,0 +1,38 @@
+//! Helpers to inspect other processes.
+use std::collections::HashMap;
+use std::process::Command;
+
+use anyhow::{bail, Error};
+use nix::unistd::Pid;
+
+/// Returns the given process and all of its descendants.
+///
+/// Parents come before their children.
+pub fn process_tree(root: Pid) -> Result<Vec<Pid>, Error> {
+    // `ps` is used instead of `/proc` so that this also works on macOS.
+    let output = Command::new("ps")
+        .args(["-A", "-o", "pid=", "-o", "ppid="])
+        .output()?;
+    if !output.status.success() {
+        bail!("could not list processes");
+    }
+    let mut children = HashMap::<Pid, Vec<Pid>>::new();
+    for line in String::from_utf8_lossy(&output.stdout).lines() {
+        let mut parts = line.split_whitespace().map(|x| x.parse().ok());
+        if let (Some(Some(pid)), Some(Some(ppid))) = (parts.next(), parts.next()) {
+            children
+                .entry(Pid::from_raw(ppid))
+                .or_default()
+                .push(Pid::from_raw(pid));
+        }
+    }
+    let mut rv = vec![root];
+    let mut idx = 0;
+    while let Some(&pid) = rv.get(idx) {
+        if let Some(pids) = children.get(&pid) {
+            rv.extend(pids.iter().filter(|&&x| x != pid));
+        }
+        idx += 1;
+    }
+    Ok(rv)
+}
@@ -0,0 +1,105 @@
+//! Opening ptys and making them the controlling terminal.
+//!
+//! Most platforms have `openpty` and `login_tty`.  On illumos and Solaris the
+//! slave of a pty is a STREAMS device which only behaves like a terminal once
+//! the terminal modules are pushed onto it, and `login_tty` doesn't exist.
+//! Android only has `login_tty` on recent versions.
+use std::os::fd::{FromRawFd, OwnedFd};
+
+use nix::errno::Errno;
+use nix::libc::c_int;
+use nix::pty::Winsize;
+use nix::sys::termios::Termios;
+
+/// Opens a pty with the given size and terminal attributes and returns the
+/// master and the slave.
+#[cfg(not(any(target_os = "illumos", target_os = "solaris")))]
+pub fn open_pty(
+    winsize: Option<&Winsize>,
+    attrs: Option<&Termios>,
+) -> Result<(OwnedFd, OwnedFd), Errno> {
+    let pty = nix::pty::openpty(winsize, attrs)?;
+    Ok(unsafe {
+        (
+            OwnedFd::from_raw_fd(pty.master),
+            OwnedFd::from_raw_fd(pty.slave),
+        )
+    })
+}
+
+/// Opens a pty with the given size and terminal attributes and returns the
+/// master and the slave.
+#[cfg(any(target_os = "illumos", target_os = "solaris"))]
+pub fn open_pty(
+    winsize: Option<&Winsize>,
+    attrs: Option<&Termios>,
+) -> Result<(OwnedFd, OwnedFd), Errno> {
+    use std::os::fd::{AsRawFd, IntoRawFd};
+
+    use nix::fcntl::{open, OFlag};
+    use nix::libc::{ioctl, I_FIND, I_PUSH, TIOCSWINSZ};
+    use nix::pty::{grantpt, posix_openpt, ptsname, unlockpt};
+    use nix::sys::stat::Mode;
+    use nix::sys::termios::{tcsetattr, SetArg};
+
+    let master = posix_openpt(OFlag::O_RDWR | OFlag::O_NOCTTY)?;
+    grantpt(&master)?;
+    unlockpt(&master)?;
+    // `ptsname_r` doesn't exist there, teetty doesn't use `ptsname` elsewhere
+    let name = unsafe { ptsname(&master) }?;
+    let slave = open(
+        name.as_str(),
+        OFlag::O_RDWR | OFlag::O_NOCTTY,
+        Mode::empty(),
+    )?;
+    let (master, slave) = unsafe {
+        (
+            OwnedFd::from_raw_fd(master.into_raw_fd()),
+            OwnedFd::from_raw_fd(slave),
+        )
+    };
+    // the modules are already there if autopush is configured for ptys
+    if unsafe { ioctl(slave.as_raw_fd(), I_FIND, c"ldterm".as_ptr()) } == 0 {
+        for module in [c"ptem", c"ldterm", c"ttcompat"] {
+            Errno::result(unsafe { ioctl(slave.as_raw_fd(), I_PUSH, module.as_ptr()) })?;
+        }
+    }
+    if let Some(attrs) = attrs {
+        tcsetattr(slave.as_raw_fd(), SetArg::TCSAFLUSH, attrs)?;
+    }
+    if let Some(winsize) = winsize {
+        Errno::result(unsafe { ioctl(slave.as_raw_fd(), TIOCSWINSZ, winsize as *const Winsize) })?;
+    }
+    Ok((master, slave))
+}
+
+/// Makes the fd the controlling terminal of a new session and stdin, stdout
+/// and stderr of this process.
+///
+/// This runs between forking and executing so it must not allocate.
+#[cfg(not(any(target_os = "illumos", target_os = "solaris", target_os = "android")))]
+pub unsafe fn login_tty(fd: c_int) -> c_int {
+    nix::libc::login_tty(fd)
+}
+
+/// Makes the fd the controlling terminal of a new session and stdin, stdout
+/// and stderr of this process.
+///
+/// This runs between forking and executing so it must not allocate.
+#[cfg(any(target_os = "illumos", target_os = "solaris", target_os = "android"))]
+pub unsafe fn login_tty(fd: c_int) -> c_int {
+    use nix::libc::{close, dup2, ioctl, setsid, TIOCSCTTY};
+
+    if setsid() < 0 || ioctl(fd, TIOCSCTTY as _, 0) < 0 {
+        return -1;
+    }
+    for target in 0..3 {
+        if dup2(fd, target) < 0 {
+            return -1;
+        }
+    }
+    if fd > 2 {
+        close(fd);
+    }
+    0
+}
@@ -0,0 +1,272 @@
+//! A registry of named sessions.
+//!
+//! Every named session is represented by a small file in the runtime directory
+//! which records the pid of the teetty process and the pid of the program it
+//! runs.  The file is removed when the session ends.  Files left behind by
+//! sessions that didn't get to clean up are detected by checking if the teetty
+//! process is still alive.
+use std::ffi::OsString;
+use std::fs;
+use std::io::{self, Write};
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use anyhow::{bail, Context, Error};
+use nix::errno::Errno;
+use nix::sys::signal::kill;
+use nix::unistd::{getpid, getuid, Pid};
+
+use crate::notify::write_atomically;
+
+/// Information about a running session.
+#[derive(Debug, Clone)]
+pub struct SessionInfo {
+    pub name: String,
+    /// The pid of the teetty process running the session.
+    pub pid: Pid,
+    /// The pid of the program running in the session.
+    pub child: Option<Pid>,
+    /// The command line of the program.
+    pub command: String,
+    /// When the session was started (seconds since the epoch).
+    pub started: u64,
+}
+
+impl SessionInfo {
+    fn serialize(&self) -> String {
+        let mut rv = format!("pid={}\n", self.pid);
+        if let Some(child) = self.child {
+            rv.push_str(&format!("child={}\n", child));
+        }
+        rv.push_str(&format!("started={}\n", self.started));
+        rv.push_str(&format!("command={}\n", self.command.replace('\n', " ")));
+        rv
+    }
+
+    fn parse(name: &str, contents: &str) -> Option<SessionInfo> {
+        let mut info = SessionInfo {
+            name: name.to_string(),
+            pid: Pid::from_raw(0),
+            child: None,
+            command: String::new(),
+            started: 0,
+        };
+        for line in contents.lines() {
+            let (key, value) = line.split_once('=')?;
+            match key {
+                "pid" => info.pid = Pid::from_raw(value.parse().ok()?),
+                "child" => info.child = Some(Pid::from_raw(value.parse().ok()?)),
+                "started" => info.started = value.parse().ok()?,
+                "command" => info.command = value.to_string(),
+                _ => {}
+            }
+        }
+        if info.pid.as_raw() > 0 {
+            Some(info)
+        } else {
+            None
+        }
+    }
+
+    /// Checks if the teetty process of the session is still around.
+    pub fn is_alive(&self) -> bool {
+        !matches!(kill(self.pid, None), Err(Errno::ESRCH))
+    }
+}
+
+/// Returns the directory holding the session registry.
+///
+/// This is `$TEETTY_RUNTIME_DIR` if set, otherwise `teetty` in
+/// `$XDG_RUNTIME_DIR` or a per-user folder in the temp directory.
+pub fn runtime_dir() -> PathBuf {
+    if let Some(dir) = std::env::var_os("TEETTY_RUNTIME_DIR") {
+        PathBuf::from(dir)
+    } else if let Some(dir) = std::env::var_os("XDG_RUNTIME_DIR") {
+        PathBuf::from(dir).join("teetty")
+    } else {
+        std::env::temp_dir().join(format!("teetty-{}", getuid()))
+    }
+}
+
+/// Returns the path of the control socket of a session.
+pub fn socket_path(name: &str) -> Result<PathBuf, Error> {
+    Ok(session_path(name)?.with_extension("sock"))
+}
+
+fn session_path(name: &str) -> Result<PathBuf, Error> {
+    if name.is_empty()
+        || name.starts_with('.')
+        || name.contains(|c: char| c == '/' || c.is_control())
+    {
+        bail!("invalid session name '{}'", name);
+    }
+    Ok(runtime_dir().join(format!("{}.session", name)))
+}
+
+/// Looks up a running session by name.
+pub fn lookup(name: &str) -> Result<SessionInfo, Error> {
+    let path = session_path(name)?;
+    let info = fs::read_to_string(&path)
+        .ok()
+        .and_then(|contents| SessionInfo::parse(name, &contents));
+    match info {
+        Some(info) if info.is_alive() => Ok(info),
+        _ => bail!("no running session named '{}'", name),
+    }
+}
+
+/// Returns all running sessions sorted by name.
+pub fn list() -> Result<Vec<SessionInfo>, Error> {
+    let entries = match fs::read_dir(runtime_dir()) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err).context("could not read runtime directory"),
+    };
+    let mut rv = Vec::new();
+    for entry in entries {
+        let path = entry?.path();
+        if path.extension().is_none_or(|x| x != "session") {
+            continue;
+        }
+        let Some(name) = path.file_stem().and_then(|x| x.to_str()) else {
+            continue;
+        };
+        let info = fs::read_to_string(&path)
+            .ok()
+            .and_then(|contents| SessionInfo::parse(name, &contents));
+        if let Some(info) = info.filter(|x| x.is_alive()) {
+            rv.push(info);
+        }
+    }
+    rv.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(rv)
+}
+
+/// The registration of the current process as a named session.
+///
+/// The registration is removed again when this is dropped.
+pub struct Registration {
+    path: PathBuf,
+    owner: Pid,
+    info: SessionInfo,
+}
+
+impl Registration {
+    /// Registers the current process under the given name.
+    ///
+    /// Fails if another session with that name is still running.
+    pub fn claim(name: &str, args: &[OsString]) -> Result<Registration, Error> {
+        let path = session_path(name)?;
+        let dir = path.parent().unwrap();
+        fs::DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(dir)
+            .with_context(|| format!("could not create runtime directory {}", dir.display()))?;
+        let info = SessionInfo {
+            name: name.to_string(),
+            pid: getpid(),
+            child: None,
+            command: args
+                .iter()
+                .map(|x| x.to_string_lossy())
+                .collect::<Vec<_>>()
+                .join(" "),
+            started: SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map_or(0, |x| x.as_secs()),
+        };
+        for _ in 0..2 {
+            match fs::File::options()
+                .write(true)
+                .create_new(true)
+                .mode(0o600)
+                .open(&path)
+            {
+                Ok(mut f) => {
+                    let reg = Registration {
+                        path,
+                        owner: getpid(),
+                        info,
+                    };
+                    f.write_all(reg.info.serialize().as_bytes())?;
+                    return Ok(reg);
+                }
+                Err(err) if err.kind() == io::ErrorKind::AlreadyExists => {
+                    if lookup(name).is_ok() {
+                        bail!("a session named '{}' is already running", name);
+                    }
+                    // left behind by a session that is gone
+                    fs::remove_file(&path).ok();
+                }
+                Err(err) => return Err(err.into()),
+            }
+        }
+        bail!("could not register session '{}'", name);
+    }
+
+    /// Returns the path of the control socket of the session.
+    pub fn socket_path(&self) -> PathBuf {
+        self.path.with_extension("sock")
+    }
+
+    /// Records the pid of the program running in the session.
+    pub fn set_child(&mut self, child: Pid) -> Result<(), Error> {
+        self.info.child = Some(child);
+        self.write()
+    }
+
+    fn write(&self) -> Result<(), Error> {
+        // write to a temporary file first so that readers never see a partially
+        // written registration.
+        let tmp = self.path.with_extension("session.tmp");
+        fs::write(&tmp, self.info.serialize())?;
+        fs::rename(&tmp, &self.path)?;
+        Ok(())
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        // forked off children that fail to exec must not remove the registration
+        if getpid() == self.owner {
+            fs::remove_file(self.socket_path()).ok();
+            fs::remove_file(&self.path).ok();
+        }
+    }
+}
+
+/// A pid file with the pid of teetty on the first line and the pid of the
+/// program on the second one.
+///
+/// The file is removed again when this is dropped.
+pub struct PidFile {
+    path: PathBuf,
+    owner: Pid,
+}
+
+impl PidFile {
+    /// Writes the pid file for the program with the pid `child`.  The second
+    /// line is left out if teetty didn't start the program.
+    pub fn write(path: &Path, child: Option<Pid>) -> Result<PidFile, Error> {
+        let mut contents = format!("{}\n", getpid());
+        if let Some(child) = child {
+            contents.push_str(&format!("{}\n", child));
+        }
+        write_atomically(path, &contents)
+            .with_context(|| format!("could not write the pid file {}", path.display()))?;
+        Ok(PidFile {
+            path: path.to_path_buf(),
+            owner: getpid(),
+        })
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        if getpid() == self.owner {
+            fs::remove_file(&self.path).ok();
+        }
+    }
+}
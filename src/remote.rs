@@ -0,0 +1,103 @@
+//! Running commands in containers and on other hosts.
+//!
+//! `teetty docker <container> -- cmd` runs the command with `docker exec -it`
+//! and `teetty ssh <host> -- cmd` with `ssh -tt` in the pty.  The program gets
+//! a terminal of its own on the other side whose size follows the pty, and its
+//! output and input go through teetty like the ones of a local program.  EOF
+//! on stdin is sent on as `^D`, which ends the remote side the same way.
+use std::ffi::OsString;
+use std::os::unix::ffi::OsStringExt;
+
+use crate::chain::quote;
+
+/// Where the command runs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Remote {
+    /// In a running container with `docker exec` or `podman exec`.
+    Container {
+        engine: String,
+        container: String,
+        /// Extra arguments for `exec`, eg: `--user root`.
+        options: Vec<String>,
+    },
+    /// On another host with ssh.
+    Ssh {
+        destination: String,
+        /// Extra arguments for ssh, eg: `-p 2222`.
+        options: Vec<String>,
+    },
+}
+
+impl Remote {
+    /// Returns the command which runs `command` remotely.
+    pub fn wrap(&self, command: Vec<OsString>) -> Vec<OsString> {
+        match self {
+            Remote::Container {
+                engine,
+                container,
+                options,
+            } => {
+                let mut rv: Vec<OsString> = vec![engine.into(), "exec".into(), "-it".into()];
+                rv.extend(options.iter().map(OsString::from));
+                rv.push(container.into());
+                rv.extend(command);
+                rv
+            }
+            Remote::Ssh {
+                destination,
+                options,
+            } => {
+                // the escape sequences are handled by teetty, ssh would see
+                // a `~` sent with `~~` as the start of one of its own
+                let mut rv: Vec<OsString> =
+                    vec!["ssh".into(), "-tt".into(), "-e".into(), "none".into()];
+                rv.extend(options.iter().map(OsString::from));
+                rv.push(destination.into());
+                rv.push("--".into());
+                // ssh runs the command with the shell on the other side
+                let remote = command
+                    .iter()
+                    .map(|x| quote(x))
+                    .collect::<Vec<_>>()
+                    .join(&b' ');
+                rv.push(OsString::from_vec(remote));
+                rv
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wrap() {
+        let docker = Remote::Container {
+            engine: "podman".into(),
+            container: "web".into(),
+            options: vec!["--user=root".into()],
+        };
+        assert_eq!(
+            docker.wrap(vec!["ls".into()]),
+            ["podman", "exec", "-it", "--user=root", "web", "ls"]
+        );
+        let ssh = Remote::Ssh {
+            destination: "me@host".into(),
+            options: vec!["-p2222".into()],
+        };
+        assert_eq!(
+            ssh.wrap(vec!["echo".into(), "it's".into()]),
+            [
+                "ssh",
+                "-tt",
+                "-e",
+                "none",
+                "-p2222",
+                "me@host",
+                "--",
+                "'echo' 'it'\\''s'"
+            ]
+        );
+    }
+}
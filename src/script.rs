@@ -0,0 +1,239 @@
+//! Support for expect scripts.
+//!
+//! A script is a list of commands, one per line, which are executed against the
+//! session in order:
+//!
+//! ```text
+//! # wait for the python prompt and run something
+//! timeout 10
+//! expect >>> $
+//! send print(42)\r
+//! expect 42
+//! sleep 0.5
+//! signal INT
+//! ```
+//!
+//! `expect` takes a regular expression and waits until the output matches it.
+//! Output up to the end of the match is consumed.  `send` sends the rest of the
+//! line as input after resolving escapes such as `\r`, `\n`, `\t`, `\e` and
+//! `\x04`.  `sleep` pauses for the given number of seconds, `signal` sends a
+//! signal to the foreground process of the session and `timeout` changes how
+//! many seconds `expect` waits before the script fails (30 by default, 0 waits
+//! forever).
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context, Error};
+use nix::sys::signal::Signal;
+
+use crate::pattern::Pattern;
+use crate::spawn::parse_signal;
+
+/// How long `expect` waits by default.
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How much unmatched output is retained for `expect`.
+const MAX_WINDOW_SIZE: usize = 64 * 1024;
+
+#[derive(Debug, Clone)]
+enum Command {
+    Expect(Pattern),
+    Send(Vec<u8>),
+    Sleep(Duration),
+    Signal(Signal),
+    Timeout(Option<Duration>),
+}
+
+/// A parsed expect script.
+#[derive(Debug, Clone)]
+pub struct Script {
+    commands: Vec<(usize, Command)>,
+}
+
+impl Script {
+    /// Loads a script from a file.
+    pub fn load(path: &Path) -> Result<Script, Error> {
+        let source = fs::read_to_string(path)
+            .with_context(|| format!("could not read script {}", path.display()))?;
+        Script::parse(&source).with_context(|| format!("invalid script {}", path.display()))
+    }
+
+    /// Parses a script.
+    pub fn parse(source: &str) -> Result<Script, Error> {
+        let mut commands = Vec::new();
+        for (idx, line) in source.lines().enumerate() {
+            let line = line.trim_start();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (cmd, arg) = line.split_once(' ').unwrap_or((line, ""));
+            let lineno = idx + 1;
+            let command = match cmd {
+                "expect" => Command::Expect(
+                    Pattern::new(arg).map_err(|err| Error::msg(format!("line {lineno}: {err}")))?,
+                ),
+                "send" => Command::Send(unescape(arg)),
+                "sleep" => Command::Sleep(parse_seconds(arg, lineno)?),
+                "signal" => Command::Signal(
+                    parse_signal(arg.trim())
+                        .ok_or_else(|| Error::msg(format!("line {lineno}: unknown signal")))?,
+                ),
+                "timeout" => {
+                    let timeout = parse_seconds(arg, lineno)?;
+                    Command::Timeout(if timeout.is_zero() {
+                        None
+                    } else {
+                        Some(timeout)
+                    })
+                }
+                _ => bail!("line {}: unknown command '{}'", lineno, cmd),
+            };
+            commands.push((lineno, command));
+        }
+        Ok(Script { commands })
+    }
+
+    /// Creates a script which sends input at the given number of seconds
+    /// since the start, divided by `speed`.
+    pub fn replay<'a>(input: impl IntoIterator<Item = (f64, &'a [u8])>, speed: f64) -> Script {
+        let mut commands = Vec::new();
+        let mut last = 0.0;
+        for (time, data) in input {
+            let delay = (time - last).max(0.0) / speed;
+            last = time.max(last);
+            if delay > 0.0 {
+                commands.push((0, Command::Sleep(Duration::from_secs_f64(delay))));
+            }
+            commands.push((0, Command::Send(data.to_vec())));
+        }
+        Script { commands }
+    }
+}
+
+/// Something the session has to do on behalf of the script.
+pub enum Action {
+    Send(Vec<u8>),
+    Signal(Signal),
+}
+
+/// Executes a script against the output of a session.
+pub struct ScriptRunner<'a> {
+    script: &'a Script,
+    pos: usize,
+    window: Vec<u8>,
+    timeout: Option<Duration>,
+    deadline: Option<Instant>,
+}
+
+impl<'a> ScriptRunner<'a> {
+    pub fn new(script: &'a Script) -> ScriptRunner<'a> {
+        ScriptRunner {
+            script,
+            pos: 0,
+            window: Vec::new(),
+            timeout: Some(DEFAULT_TIMEOUT),
+            deadline: None,
+        }
+    }
+
+    /// Returns `true` if there is nothing left to do.
+    pub fn is_done(&self) -> bool {
+        self.pos >= self.script.commands.len()
+    }
+
+    /// Records output of the session for `expect`.
+    pub fn feed(&mut self, data: &[u8]) {
+        if self.is_done() {
+            return;
+        }
+        self.window.extend_from_slice(data);
+        if self.window.len() > MAX_WINDOW_SIZE {
+            self.window.drain(..self.window.len() - MAX_WINDOW_SIZE);
+        }
+    }
+
+    /// Returns how long until the script has to be polled again at the latest.
+    pub fn timeout(&self) -> Option<Duration> {
+        self.deadline
+            .map(|deadline| deadline.saturating_duration_since(Instant::now()))
+    }
+
+    /// Advances the script as far as possible and returns the actions to carry
+    /// out.  Fails if an `expect` timed out.
+    pub fn poll(&mut self, actions: &mut Vec<Action>) -> Result<(), Error> {
+        while let Some((lineno, command)) = self.script.commands.get(self.pos) {
+            match command {
+                Command::Expect(pattern) => {
+                    if let Some((_, end)) = pattern.find(&self.window) {
+                        self.window.drain(..end);
+                    } else if self.deadline.is_some_and(|x| x <= Instant::now()) {
+                        self.pos = self.script.commands.len();
+                        bail!("script line {}: timed out waiting for output", lineno);
+                    } else {
+                        if self.deadline.is_none() {
+                            self.deadline = self.timeout.map(|x| Instant::now() + x);
+                        }
+                        return Ok(());
+                    }
+                }
+                Command::Send(data) => actions.push(Action::Send(data.clone())),
+                Command::Sleep(duration) => match self.deadline {
+                    Some(deadline) if deadline <= Instant::now() => {}
+                    Some(_) => return Ok(()),
+                    None => {
+                        self.deadline = Some(Instant::now() + *duration);
+                        return Ok(());
+                    }
+                },
+                Command::Signal(signal) => actions.push(Action::Signal(*signal)),
+                Command::Timeout(timeout) => self.timeout = *timeout,
+            }
+            self.deadline = None;
+            self.pos += 1;
+        }
+        Ok(())
+    }
+}
+
+fn parse_seconds(arg: &str, lineno: usize) -> Result<Duration, Error> {
+    arg.trim()
+        .parse::<f64>()
+        .ok()
+        .and_then(|x| Duration::try_from_secs_f64(x).ok())
+        .ok_or_else(|| Error::msg(format!("line {lineno}: invalid number of seconds")))
+}
+
+/// Resolves backslash escapes in the argument of `send`.
+pub fn unescape(s: &str) -> Vec<u8> {
+    let mut rv = Vec::new();
+    let mut bytes = s.bytes();
+    while let Some(c) = bytes.next() {
+        if c != b'\\' {
+            rv.push(c);
+            continue;
+        }
+        match bytes.next() {
+            Some(b'n') => rv.push(b'\n'),
+            Some(b'r') => rv.push(b'\r'),
+            Some(b't') => rv.push(b'\t'),
+            Some(b'e') => rv.push(0x1b),
+            Some(b'x') => {
+                let hex = [bytes.next().unwrap_or(b'0'), bytes.next().unwrap_or(b'0')];
+                match std::str::from_utf8(&hex)
+                    .ok()
+                    .and_then(|x| u8::from_str_radix(x, 16).ok())
+                {
+                    Some(c) => rv.push(c),
+                    None => {
+                        rv.extend_from_slice(b"\\x");
+                        rv.extend_from_slice(&hex);
+                    }
+                }
+            }
+            Some(c) => rv.push(c),
+            None => rv.push(b'\\'),
+        }
+    }
+    rv
+}
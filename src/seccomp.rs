@@ -0,0 +1,953 @@
+//! Restricting the system calls of the program.
+//!
+//! `--seccomp <profile.json>` installs a seccomp-bpf filter in the child right
+//! before the program is executed.  The profile uses the format of Docker's
+//! seccomp profiles: a `defaultAction` for the system calls which aren't listed
+//! and `syscalls` rules with the `names` of system calls and their `action`.
+//!
+//! The argument conditions (`args`) of a rule are compiled into the filter.
+//! All of them have to hold for the rule to apply, unless several check the
+//! same argument: like Docker, each of those is a rule of its own then.  The
+//! `includes` and `excludes` of a rule (`caps`, `arches` and `minKernel`) are
+//! checked against the capabilities teetty has and the kernel it runs on when
+//! the profile is loaded.  System calls which are unknown on this architecture
+//! are skipped, which lets the stock Docker profile load.
+//!
+//! `SCMP_ACT_ALLOW`, `SCMP_ACT_ERRNO` (with `errnoRet` or `defaultErrnoRet`,
+//! `EPERM` by default), `SCMP_ACT_LOG`, `SCMP_ACT_TRAP`, `SCMP_ACT_KILL` and
+//! `SCMP_ACT_KILL_PROCESS` are supported.  As the filter is installed before
+//! `execve`, an allowlist has to allow it.
+//!
+//! Filters are supported on Linux on x86_64 and aarch64.
+use std::fs;
+use std::path::Path;
+
+use anyhow::{anyhow, bail, Context, Error};
+use nix::errno::Errno;
+
+use crate::debug::debug;
+use crate::json::{self, Value};
+
+/// An instruction of a classic BPF program (`struct sock_filter`).
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Instruction {
+    code: u16,
+    jt: u8,
+    jf: u8,
+    k: u32,
+}
+
+/// A BPF program (`struct sock_fprog`).
+#[repr(C)]
+struct Program {
+    len: u16,
+    filter: *const Instruction,
+}
+
+// `BPF_LD | BPF_W | BPF_ABS`, the first two are 0
+const BPF_LD_W_ABS: u16 = 0x20;
+const BPF_ALU_AND_K: u16 = 0x04 | 0x50;
+const BPF_JMP_JEQ_K: u16 = 0x05 | 0x10;
+const BPF_JMP_JGT_K: u16 = 0x05 | 0x20;
+const BPF_JMP_JGE_K: u16 = 0x05 | 0x30;
+const BPF_RET_K: u16 = 0x06;
+
+/// The offsets of the fields of `struct seccomp_data`.  The arguments are 64
+/// bits, of which the lower half comes first on the supported architectures.
+const DATA_NR: u32 = 0;
+const DATA_ARCH: u32 = 4;
+const DATA_ARGS: u32 = 16;
+
+/// Stands in for the jump to the end of a rule whose conditions don't hold
+/// until the rule is complete.
+const JUMP_FAIL: u8 = u8::MAX;
+
+const RET_KILL_PROCESS: u32 = 0x8000_0000;
+const RET_KILL_THREAD: u32 = 0x0000_0000;
+const RET_TRAP: u32 = 0x0003_0000;
+const RET_ERRNO: u32 = 0x0005_0000;
+const RET_LOG: u32 = 0x7ffc_0000;
+const RET_ALLOW: u32 = 0x7fff_0000;
+
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: Option<u32> = Some(0xc000_003e);
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: Option<u32> = Some(0xc000_00b7);
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+const AUDIT_ARCH: Option<u32> = None;
+
+/// The names of the architecture in profiles.
+#[cfg(target_arch = "x86_64")]
+const ARCH_NAMES: &[&str] = &["amd64", "x86_64", "SCMP_ARCH_X86_64"];
+#[cfg(target_arch = "aarch64")]
+const ARCH_NAMES: &[&str] = &["arm64", "aarch64", "SCMP_ARCH_AARCH64"];
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+const ARCH_NAMES: &[&str] = &[];
+
+/// The capabilities by number.
+const CAPABILITIES: &[&str] = &[
+    "CAP_CHOWN",
+    "CAP_DAC_OVERRIDE",
+    "CAP_DAC_READ_SEARCH",
+    "CAP_FOWNER",
+    "CAP_FSETID",
+    "CAP_KILL",
+    "CAP_SETGID",
+    "CAP_SETUID",
+    "CAP_SETPCAP",
+    "CAP_LINUX_IMMUTABLE",
+    "CAP_NET_BIND_SERVICE",
+    "CAP_NET_BROADCAST",
+    "CAP_NET_ADMIN",
+    "CAP_NET_RAW",
+    "CAP_IPC_LOCK",
+    "CAP_IPC_OWNER",
+    "CAP_SYS_MODULE",
+    "CAP_SYS_RAWIO",
+    "CAP_SYS_CHROOT",
+    "CAP_SYS_PTRACE",
+    "CAP_SYS_PACCT",
+    "CAP_SYS_ADMIN",
+    "CAP_SYS_BOOT",
+    "CAP_SYS_NICE",
+    "CAP_SYS_RESOURCE",
+    "CAP_SYS_TIME",
+    "CAP_SYS_TTY_CONFIG",
+    "CAP_MKNOD",
+    "CAP_LEASE",
+    "CAP_AUDIT_WRITE",
+    "CAP_AUDIT_CONTROL",
+    "CAP_SETFCAP",
+    "CAP_MAC_OVERRIDE",
+    "CAP_MAC_ADMIN",
+    "CAP_SYSLOG",
+    "CAP_WAKE_ALARM",
+    "CAP_BLOCK_SUSPEND",
+    "CAP_AUDIT_READ",
+    "CAP_PERFMON",
+    "CAP_BPF",
+    "CAP_CHECKPOINT_RESTORE",
+];
+
+/// System calls of the x32 ABI have this bit set on x86_64.
+const X32_SYSCALL_BIT: u32 = 0x4000_0000;
+
+/// The kernel rejects longer programs (`BPF_MAXINSNS`).
+const MAX_INSTRUCTIONS: usize = 4096;
+
+/// A compiled seccomp filter.
+#[derive(Debug, Clone)]
+pub struct Filter {
+    program: Vec<Instruction>,
+}
+
+impl Filter {
+    /// Loads and compiles the profile at `path`.
+    pub fn load(path: &Path) -> Result<Filter, Error> {
+        let profile = fs::read_to_string(path)
+            .with_context(|| format!("could not read the seccomp profile {}", path.display()))?;
+        Filter::compile(&profile, &Host::current())
+            .with_context(|| format!("invalid seccomp profile {}", path.display()))
+    }
+
+    fn compile(profile: &str, host: &Host) -> Result<Filter, Error> {
+        let Some(arch) = AUDIT_ARCH.filter(|_| cfg!(target_os = "linux")) else {
+            bail!("seccomp filters are not supported on this platform");
+        };
+        let profile = json::parse(profile)?;
+        let default_errno = profile.get("defaultErrnoRet").and_then(Value::as_f64);
+        let default = action(
+            profile
+                .get("defaultAction")
+                .and_then(Value::as_str)
+                .ok_or_else(|| anyhow!("defaultAction is missing"))?,
+            default_errno,
+        )?;
+        let ins = |code, jt, jf, k| Instruction { code, jt, jf, k };
+        let mut program = vec![
+            ins(BPF_LD_W_ABS, 0, 0, DATA_ARCH),
+            ins(BPF_JMP_JEQ_K, 1, 0, arch),
+            ins(BPF_RET_K, 0, 0, RET_KILL_PROCESS),
+            ins(BPF_LD_W_ABS, 0, 0, DATA_NR),
+        ];
+        // the rules would not match the numbers of other ABIs
+        if cfg!(target_arch = "x86_64") {
+            program.push(ins(BPF_JMP_JGE_K, 0, 1, X32_SYSCALL_BIT));
+            program.push(ins(BPF_RET_K, 0, 0, RET_KILL_PROCESS));
+        }
+        let rules = match profile.get("syscalls") {
+            Some(rules) => rules
+                .as_array()
+                .ok_or_else(|| anyhow!("syscalls must be an array"))?,
+            None => &[],
+        };
+        for rule in rules {
+            let rule_action = action(
+                rule.get("action")
+                    .and_then(Value::as_str)
+                    .ok_or_else(|| anyhow!("a rule has no action"))?,
+                rule.get("errnoRet")
+                    .and_then(Value::as_f64)
+                    .or(default_errno),
+            )?;
+            let names = match (rule.get("names"), rule.get("name")) {
+                (Some(names), _) => names
+                    .as_array()
+                    .ok_or_else(|| anyhow!("names must be an array"))?
+                    .iter()
+                    .map(|x| x.as_str().ok_or_else(|| anyhow!("names must be strings")))
+                    .collect::<Result<Vec<_>, _>>()?,
+                (None, Some(name)) => {
+                    vec![name
+                        .as_str()
+                        .ok_or_else(|| anyhow!("name must be a string"))?]
+                }
+                (None, None) => bail!("a rule has no names"),
+            };
+            if !host.matches(rule.get("includes"), true)?
+                || host.matches(rule.get("excludes"), false)?
+            {
+                debug!("the seccomp rule for {:?} doesn't apply", names);
+                continue;
+            }
+            let conditions = match rule.get("args") {
+                None | Some(Value::Null) => Vec::new(),
+                Some(args) => args
+                    .as_array()
+                    .ok_or_else(|| anyhow!("args must be an array"))?
+                    .iter()
+                    .map(Condition::parse)
+                    .collect::<Result<Vec<_>, _>>()?,
+            };
+            let same_arg = conditions
+                .iter()
+                .enumerate()
+                .any(|(idx, x)| conditions[..idx].iter().any(|y| y.index == x.index));
+            let alternatives = if same_arg {
+                conditions.iter().map(std::slice::from_ref).collect()
+            } else {
+                vec![&conditions[..]]
+            };
+            for name in names {
+                let Some(nr) = syscall_number(name) else {
+                    debug!(
+                        "skipping the unknown system call {} of the seccomp profile",
+                        name
+                    );
+                    continue;
+                };
+                for conditions in &alternatives {
+                    push_rule(&mut program, nr, conditions, rule_action)?;
+                }
+            }
+        }
+        program.push(ins(BPF_RET_K, 0, 0, default));
+        if program.len() > MAX_INSTRUCTIONS {
+            bail!("too many rules");
+        }
+        Ok(Filter { program })
+    }
+
+    /// Installs the filter for this process.  The process can't gain
+    /// privileges afterwards, eg: with setuid programs.
+    ///
+    /// This runs between forking and executing so it must not allocate.
+    pub fn install(&self) -> Result<(), Errno> {
+        let program = Program {
+            len: self.program.len() as u16,
+            filter: self.program.as_ptr(),
+        };
+        install(&program)
+    }
+}
+
+/// Adds a rule to the program.  The system call number is in the accumulator
+/// before and after the rule.
+fn push_rule(
+    program: &mut Vec<Instruction>,
+    nr: u32,
+    conditions: &[Condition],
+    rule_action: u32,
+) -> Result<(), Error> {
+    let ins = |code, jt, jf, k| Instruction { code, jt, jf, k };
+    let mut rule = Vec::new();
+    for condition in conditions {
+        condition.compile(&mut rule);
+    }
+    rule.push(ins(BPF_RET_K, 0, 0, rule_action));
+    if !conditions.is_empty() {
+        // the conditions loaded arguments, so the number has to be loaded again
+        // for the rules that follow
+        rule.push(ins(BPF_LD_W_ABS, 0, 0, DATA_NR));
+        let fail = rule.len() - 1;
+        for (idx, x) in rule.iter_mut().enumerate() {
+            for jump in [&mut x.jt, &mut x.jf] {
+                if *jump == JUMP_FAIL {
+                    *jump = (fail - idx - 1) as u8;
+                }
+            }
+        }
+    }
+    let len = u8::try_from(rule.len()).map_err(|_| anyhow!("a rule is too long"))?;
+    program.push(ins(BPF_JMP_JEQ_K, 0, len, nr));
+    program.extend(rule);
+    Ok(())
+}
+
+/// How an argument is compared.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Op {
+    Ne,
+    Lt,
+    Le,
+    Eq,
+    Ge,
+    Gt,
+    MaskedEq,
+}
+
+/// A condition on an argument of a system call.
+#[derive(Debug)]
+struct Condition {
+    index: u32,
+    op: Op,
+    value: u64,
+    /// The value the masked argument is compared with for `SCMP_CMP_MASKED_EQ`.
+    value_two: u64,
+}
+
+impl Condition {
+    fn parse(arg: &Value) -> Result<Condition, Error> {
+        let number = |key, required| match arg.get(key).and_then(Value::as_f64) {
+            Some(x) if x >= 0.0 && x.fract() == 0.0 => Ok(x as u64),
+            Some(_) => bail!("{} of a condition must be a non-negative integer", key),
+            None if required => bail!("a condition has no {}", key),
+            None => Ok(0),
+        };
+        let index = number("index", true)?;
+        if index > 5 {
+            bail!("system calls have no argument {}", index);
+        }
+        let op = match arg.get("op").and_then(Value::as_str) {
+            Some("SCMP_CMP_NE") => Op::Ne,
+            Some("SCMP_CMP_LT") => Op::Lt,
+            Some("SCMP_CMP_LE") => Op::Le,
+            Some("SCMP_CMP_EQ") => Op::Eq,
+            Some("SCMP_CMP_GE") => Op::Ge,
+            Some("SCMP_CMP_GT") => Op::Gt,
+            Some("SCMP_CMP_MASKED_EQ") => Op::MaskedEq,
+            Some(op) => bail!("unsupported comparison {}", op),
+            None => bail!("a condition has no op"),
+        };
+        Ok(Condition {
+            index: index as u32,
+            op,
+            value: number("value", true)?,
+            value_two: number("valueTwo", false)?,
+        })
+    }
+
+    /// Adds the instructions that check the condition.  They jump to
+    /// [`JUMP_FAIL`] if the condition doesn't hold and continue after the last
+    /// one if it does.
+    ///
+    /// The arguments are compared as two 32 bit halves, the upper one first.
+    fn compile(&self, rule: &mut Vec<Instruction>) {
+        let ins = |code, jt, jf, k| Instruction { code, jt, jf, k };
+        let low = DATA_ARGS + 8 * self.index;
+        let high = low + 4;
+        let (value_high, value_low) = ((self.value >> 32) as u32, self.value as u32);
+        let load = |rule: &mut Vec<Instruction>, offset, mask: Option<u32>| {
+            rule.push(ins(BPF_LD_W_ABS, 0, 0, offset));
+            if let Some(mask) = mask {
+                rule.push(ins(BPF_ALU_AND_K, 0, 0, mask));
+            }
+        };
+        match self.op {
+            Op::Eq => {
+                load(rule, high, None);
+                rule.push(ins(BPF_JMP_JEQ_K, 0, JUMP_FAIL, value_high));
+                load(rule, low, None);
+                rule.push(ins(BPF_JMP_JEQ_K, 0, JUMP_FAIL, value_low));
+            }
+            Op::MaskedEq => {
+                load(rule, high, Some(value_high));
+                rule.push(ins(
+                    BPF_JMP_JEQ_K,
+                    0,
+                    JUMP_FAIL,
+                    (self.value_two >> 32) as u32,
+                ));
+                load(rule, low, Some(value_low));
+                rule.push(ins(BPF_JMP_JEQ_K, 0, JUMP_FAIL, self.value_two as u32));
+            }
+            Op::Ne => {
+                load(rule, high, None);
+                rule.push(ins(BPF_JMP_JEQ_K, 0, 2, value_high));
+                load(rule, low, None);
+                rule.push(ins(BPF_JMP_JEQ_K, JUMP_FAIL, 0, value_low));
+            }
+            Op::Gt | Op::Ge => {
+                let code = if self.op == Op::Gt {
+                    BPF_JMP_JGT_K
+                } else {
+                    BPF_JMP_JGE_K
+                };
+                load(rule, high, None);
+                rule.push(ins(BPF_JMP_JGT_K, 3, 0, value_high));
+                rule.push(ins(BPF_JMP_JEQ_K, 0, JUMP_FAIL, value_high));
+                load(rule, low, None);
+                rule.push(ins(code, 0, JUMP_FAIL, value_low));
+            }
+            Op::Lt | Op::Le => {
+                // the opposite of `>=` and `>`
+                let code = if self.op == Op::Lt {
+                    BPF_JMP_JGE_K
+                } else {
+                    BPF_JMP_JGT_K
+                };
+                load(rule, high, None);
+                rule.push(ins(BPF_JMP_JGT_K, JUMP_FAIL, 0, value_high));
+                rule.push(ins(BPF_JMP_JEQ_K, 0, 2, value_high));
+                load(rule, low, None);
+                rule.push(ins(code, JUMP_FAIL, 0, value_low));
+            }
+        }
+    }
+}
+
+/// What the `includes` and `excludes` of rules are checked against.
+struct Host {
+    /// The effective capabilities as a bit set.
+    caps: u64,
+    /// The major and minor version of the kernel.
+    kernel: (u32, u32),
+}
+
+impl Host {
+    fn current() -> Host {
+        let caps = fs::read_to_string("/proc/self/status")
+            .ok()
+            .and_then(|status| {
+                let line = status.lines().find(|x| x.starts_with("CapEff:"))?;
+                u64::from_str_radix(line["CapEff:".len()..].trim(), 16).ok()
+            })
+            .unwrap_or(0);
+        let kernel = fs::read_to_string("/proc/sys/kernel/osrelease")
+            .ok()
+            .and_then(|x| parse_kernel_version(&x))
+            .unwrap_or((0, 0));
+        Host { caps, kernel }
+    }
+
+    /// Checks the `includes` (`all` is `true`, everything has to match) or the
+    /// `excludes` (anything has to match) of a rule.  Without any the result
+    /// is `all`.
+    fn matches(&self, filter: Option<&Value>, all: bool) -> Result<bool, Error> {
+        let items = match filter {
+            None | Some(Value::Null) => return Ok(all),
+            Some(Value::Object(items)) => items,
+            Some(_) => bail!("includes and excludes must be objects"),
+        };
+        let mut checks = Vec::new();
+        for (key, value) in items {
+            let strings = || -> Result<Vec<&str>, Error> {
+                value
+                    .as_array()
+                    .ok_or_else(|| anyhow!("{} must be an array", key))?
+                    .iter()
+                    .map(|x| x.as_str().ok_or_else(|| anyhow!("{} must be strings", key)))
+                    .collect()
+            };
+            match key.as_str() {
+                "caps" => checks.extend(strings()?.into_iter().map(|name| {
+                    CAPABILITIES
+                        .iter()
+                        .position(|x| x.eq_ignore_ascii_case(name))
+                        .is_some_and(|bit| self.caps & (1 << bit) != 0)
+                })),
+                "arches" => {
+                    let arches = strings()?;
+                    if !arches.is_empty() {
+                        checks.push(arches.iter().any(|x| ARCH_NAMES.contains(x)));
+                    }
+                }
+                "minKernel" => {
+                    let min = value
+                        .as_str()
+                        .and_then(parse_kernel_version)
+                        .ok_or_else(|| anyhow!("invalid minKernel"))?;
+                    checks.push(self.kernel >= min);
+                }
+                _ => bail!("unsupported condition {}", key),
+            }
+        }
+        Ok(if all {
+            checks.iter().all(|&x| x)
+        } else {
+            checks.iter().any(|&x| x)
+        })
+    }
+}
+
+/// Parses the major and minor version of a kernel, such as `6.1.0-13-amd64`.
+fn parse_kernel_version(s: &str) -> Option<(u32, u32)> {
+    let mut parts = s.trim().split(['.', '-']);
+    let major = parts.next()?.parse().ok()?;
+    let minor = parts.next().map_or(Some(0), |x| x.parse().ok())?;
+    Some((major, minor))
+}
+
+#[cfg(target_os = "linux")]
+fn install(program: &Program) -> Result<(), Errno> {
+    use nix::libc::{prctl, PR_SET_NO_NEW_PRIVS, PR_SET_SECCOMP, SECCOMP_MODE_FILTER};
+
+    unsafe {
+        Errno::result(prctl(PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0))?;
+        Errno::result(prctl(
+            PR_SET_SECCOMP,
+            SECCOMP_MODE_FILTER,
+            program as *const Program,
+        ))?;
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn install(_program: &Program) -> Result<(), Errno> {
+    Err(Errno::ENOSYS)
+}
+
+/// Returns the value a filter returns for an action of a profile.
+fn action(name: &str, errno: Option<f64>) -> Result<u32, Error> {
+    Ok(match name {
+        "SCMP_ACT_ALLOW" => RET_ALLOW,
+        "SCMP_ACT_ERRNO" => {
+            RET_ERRNO | (errno.unwrap_or(Errno::EPERM as i32 as f64) as u32 & 0xffff)
+        }
+        "SCMP_ACT_LOG" => RET_LOG,
+        "SCMP_ACT_TRAP" => RET_TRAP,
+        "SCMP_ACT_KILL" | "SCMP_ACT_KILL_THREAD" => RET_KILL_THREAD,
+        "SCMP_ACT_KILL_PROCESS" => RET_KILL_PROCESS,
+        _ => bail!("unsupported action {}", name),
+    })
+}
+
+/// The system calls by name with their numbers, from the kernel headers.
+#[cfg(target_arch = "x86_64")]
+#[rustfmt::skip]
+const SYSCALLS: &[(&str, u32)] = &[
+    ("read", 0), ("write", 1), ("open", 2), ("close", 3), ("stat", 4), ("fstat", 5),
+    ("lstat", 6), ("poll", 7), ("lseek", 8), ("mmap", 9), ("mprotect", 10), ("munmap", 11),
+    ("brk", 12), ("rt_sigaction", 13), ("rt_sigprocmask", 14), ("rt_sigreturn", 15),
+    ("ioctl", 16), ("pread64", 17), ("pwrite64", 18), ("readv", 19), ("writev", 20),
+    ("access", 21), ("pipe", 22), ("select", 23), ("sched_yield", 24), ("mremap", 25),
+    ("msync", 26), ("mincore", 27), ("madvise", 28), ("shmget", 29), ("shmat", 30),
+    ("shmctl", 31), ("dup", 32), ("dup2", 33), ("pause", 34), ("nanosleep", 35),
+    ("getitimer", 36), ("alarm", 37), ("setitimer", 38), ("getpid", 39), ("sendfile", 40),
+    ("socket", 41), ("connect", 42), ("accept", 43), ("sendto", 44), ("recvfrom", 45),
+    ("sendmsg", 46), ("recvmsg", 47), ("shutdown", 48), ("bind", 49), ("listen", 50),
+    ("getsockname", 51), ("getpeername", 52), ("socketpair", 53), ("setsockopt", 54),
+    ("getsockopt", 55), ("clone", 56), ("fork", 57), ("vfork", 58), ("execve", 59),
+    ("exit", 60), ("wait4", 61), ("kill", 62), ("uname", 63), ("semget", 64), ("semop",
+    65), ("semctl", 66), ("shmdt", 67), ("msgget", 68), ("msgsnd", 69), ("msgrcv", 70),
+    ("msgctl", 71), ("fcntl", 72), ("flock", 73), ("fsync", 74), ("fdatasync", 75),
+    ("truncate", 76), ("ftruncate", 77), ("getdents", 78), ("getcwd", 79), ("chdir", 80),
+    ("fchdir", 81), ("rename", 82), ("mkdir", 83), ("rmdir", 84), ("creat", 85), ("link",
+    86), ("unlink", 87), ("symlink", 88), ("readlink", 89), ("chmod", 90), ("fchmod", 91),
+    ("chown", 92), ("fchown", 93), ("lchown", 94), ("umask", 95), ("gettimeofday", 96),
+    ("getrlimit", 97), ("getrusage", 98), ("sysinfo", 99), ("times", 100), ("ptrace", 101),
+    ("getuid", 102), ("syslog", 103), ("getgid", 104), ("setuid", 105), ("setgid", 106),
+    ("geteuid", 107), ("getegid", 108), ("setpgid", 109), ("getppid", 110), ("getpgrp",
+    111), ("setsid", 112), ("setreuid", 113), ("setregid", 114), ("getgroups", 115),
+    ("setgroups", 116), ("setresuid", 117), ("getresuid", 118), ("setresgid", 119),
+    ("getresgid", 120), ("getpgid", 121), ("setfsuid", 122), ("setfsgid", 123), ("getsid",
+    124), ("capget", 125), ("capset", 126), ("rt_sigpending", 127), ("rt_sigtimedwait",
+    128), ("rt_sigqueueinfo", 129), ("rt_sigsuspend", 130), ("sigaltstack", 131), ("utime",
+    132), ("mknod", 133), ("uselib", 134), ("personality", 135), ("ustat", 136), ("statfs",
+    137), ("fstatfs", 138), ("sysfs", 139), ("getpriority", 140), ("setpriority", 141),
+    ("sched_setparam", 142), ("sched_getparam", 143), ("sched_setscheduler", 144),
+    ("sched_getscheduler", 145), ("sched_get_priority_max", 146),
+    ("sched_get_priority_min", 147), ("sched_rr_get_interval", 148), ("mlock", 149),
+    ("munlock", 150), ("mlockall", 151), ("munlockall", 152), ("vhangup", 153),
+    ("modify_ldt", 154), ("pivot_root", 155), ("_sysctl", 156), ("prctl", 157),
+    ("arch_prctl", 158), ("adjtimex", 159), ("setrlimit", 160), ("chroot", 161), ("sync",
+    162), ("acct", 163), ("settimeofday", 164), ("mount", 165), ("umount2", 166),
+    ("swapon", 167), ("swapoff", 168), ("reboot", 169), ("sethostname", 170),
+    ("setdomainname", 171), ("iopl", 172), ("ioperm", 173), ("create_module", 174),
+    ("init_module", 175), ("delete_module", 176), ("get_kernel_syms", 177),
+    ("query_module", 178), ("quotactl", 179), ("nfsservctl", 180), ("getpmsg", 181),
+    ("putpmsg", 182), ("afs_syscall", 183), ("tuxcall", 184), ("security", 185), ("gettid",
+    186), ("readahead", 187), ("setxattr", 188), ("lsetxattr", 189), ("fsetxattr", 190),
+    ("getxattr", 191), ("lgetxattr", 192), ("fgetxattr", 193), ("listxattr", 194),
+    ("llistxattr", 195), ("flistxattr", 196), ("removexattr", 197), ("lremovexattr", 198),
+    ("fremovexattr", 199), ("tkill", 200), ("time", 201), ("futex", 202),
+    ("sched_setaffinity", 203), ("sched_getaffinity", 204), ("set_thread_area", 205),
+    ("io_setup", 206), ("io_destroy", 207), ("io_getevents", 208), ("io_submit", 209),
+    ("io_cancel", 210), ("get_thread_area", 211), ("lookup_dcookie", 212), ("epoll_create",
+    213), ("epoll_ctl_old", 214), ("epoll_wait_old", 215), ("remap_file_pages", 216),
+    ("getdents64", 217), ("set_tid_address", 218), ("restart_syscall", 219), ("semtimedop",
+    220), ("fadvise64", 221), ("timer_create", 222), ("timer_settime", 223),
+    ("timer_gettime", 224), ("timer_getoverrun", 225), ("timer_delete", 226),
+    ("clock_settime", 227), ("clock_gettime", 228), ("clock_getres", 229),
+    ("clock_nanosleep", 230), ("exit_group", 231), ("epoll_wait", 232), ("epoll_ctl", 233),
+    ("tgkill", 234), ("utimes", 235), ("vserver", 236), ("mbind", 237), ("set_mempolicy",
+    238), ("get_mempolicy", 239), ("mq_open", 240), ("mq_unlink", 241), ("mq_timedsend",
+    242), ("mq_timedreceive", 243), ("mq_notify", 244), ("mq_getsetattr", 245),
+    ("kexec_load", 246), ("waitid", 247), ("add_key", 248), ("request_key", 249),
+    ("keyctl", 250), ("ioprio_set", 251), ("ioprio_get", 252), ("inotify_init", 253),
+    ("inotify_add_watch", 254), ("inotify_rm_watch", 255), ("migrate_pages", 256),
+    ("openat", 257), ("mkdirat", 258), ("mknodat", 259), ("fchownat", 260), ("futimesat",
+    261), ("newfstatat", 262), ("unlinkat", 263), ("renameat", 264), ("linkat", 265),
+    ("symlinkat", 266), ("readlinkat", 267), ("fchmodat", 268), ("faccessat", 269),
+    ("pselect6", 270), ("ppoll", 271), ("unshare", 272), ("set_robust_list", 273),
+    ("get_robust_list", 274), ("splice", 275), ("tee", 276), ("sync_file_range", 277),
+    ("vmsplice", 278), ("move_pages", 279), ("utimensat", 280), ("epoll_pwait", 281),
+    ("signalfd", 282), ("timerfd_create", 283), ("eventfd", 284), ("fallocate", 285),
+    ("timerfd_settime", 286), ("timerfd_gettime", 287), ("accept4", 288), ("signalfd4",
+    289), ("eventfd2", 290), ("epoll_create1", 291), ("dup3", 292), ("pipe2", 293),
+    ("inotify_init1", 294), ("preadv", 295), ("pwritev", 296), ("rt_tgsigqueueinfo", 297),
+    ("perf_event_open", 298), ("recvmmsg", 299), ("fanotify_init", 300), ("fanotify_mark",
+    301), ("prlimit64", 302), ("name_to_handle_at", 303), ("open_by_handle_at", 304),
+    ("clock_adjtime", 305), ("syncfs", 306), ("sendmmsg", 307), ("setns", 308), ("getcpu",
+    309), ("process_vm_readv", 310), ("process_vm_writev", 311), ("kcmp", 312),
+    ("finit_module", 313), ("sched_setattr", 314), ("sched_getattr", 315), ("renameat2",
+    316), ("seccomp", 317), ("getrandom", 318), ("memfd_create", 319), ("kexec_file_load",
+    320), ("bpf", 321), ("execveat", 322), ("userfaultfd", 323), ("membarrier", 324),
+    ("mlock2", 325), ("copy_file_range", 326), ("preadv2", 327), ("pwritev2", 328),
+    ("pkey_mprotect", 329), ("pkey_alloc", 330), ("pkey_free", 331), ("statx", 332),
+    ("rseq", 334), ("pidfd_send_signal", 424), ("io_uring_setup", 425), ("io_uring_enter",
+    426), ("io_uring_register", 427), ("open_tree", 428), ("move_mount", 429), ("fsopen",
+    430), ("fsconfig", 431), ("fsmount", 432), ("fspick", 433), ("pidfd_open", 434),
+    ("clone3", 435), ("close_range", 436), ("openat2", 437), ("pidfd_getfd", 438),
+    ("faccessat2", 439), ("process_madvise", 440), ("epoll_pwait2", 441), ("mount_setattr",
+    442), ("quotactl_fd", 443), ("landlock_create_ruleset", 444), ("landlock_add_rule",
+    445), ("landlock_restrict_self", 446), ("memfd_secret", 447), ("process_mrelease",
+    448), ("futex_waitv", 449), ("set_mempolicy_home_node", 450),
+];
+
+/// The system calls by name with their numbers, from the kernel headers.
+#[cfg(target_arch = "aarch64")]
+#[rustfmt::skip]
+const SYSCALLS: &[(&str, u32)] = &[
+    ("io_setup", 0), ("io_destroy", 1), ("io_submit", 2), ("io_cancel", 3),
+    ("io_getevents", 4), ("setxattr", 5), ("lsetxattr", 6), ("fsetxattr", 7), ("getxattr",
+    8), ("lgetxattr", 9), ("fgetxattr", 10), ("listxattr", 11), ("llistxattr", 12),
+    ("flistxattr", 13), ("removexattr", 14), ("lremovexattr", 15), ("fremovexattr", 16),
+    ("getcwd", 17), ("lookup_dcookie", 18), ("eventfd2", 19), ("epoll_create1", 20),
+    ("epoll_ctl", 21), ("epoll_pwait", 22), ("dup", 23), ("dup3", 24), ("fcntl", 25),
+    ("inotify_init1", 26), ("inotify_add_watch", 27), ("inotify_rm_watch", 28), ("ioctl",
+    29), ("ioprio_set", 30), ("ioprio_get", 31), ("flock", 32), ("mknodat", 33),
+    ("mkdirat", 34), ("unlinkat", 35), ("symlinkat", 36), ("linkat", 37), ("umount2", 39),
+    ("mount", 40), ("pivot_root", 41), ("nfsservctl", 42), ("statfs", 43), ("fstatfs", 44),
+    ("truncate", 45), ("ftruncate", 46), ("fallocate", 47), ("faccessat", 48), ("chdir",
+    49), ("fchdir", 50), ("chroot", 51), ("fchmod", 52), ("fchmodat", 53), ("fchownat",
+    54), ("fchown", 55), ("openat", 56), ("close", 57), ("vhangup", 58), ("pipe2", 59),
+    ("quotactl", 60), ("getdents64", 61), ("lseek", 62), ("read", 63), ("write", 64),
+    ("readv", 65), ("writev", 66), ("pread64", 67), ("pwrite64", 68), ("preadv", 69),
+    ("pwritev", 70), ("pselect6", 72), ("ppoll", 73), ("signalfd4", 74), ("vmsplice", 75),
+    ("splice", 76), ("tee", 77), ("readlinkat", 78), ("newfstatat", 79), ("fstat", 80),
+    ("sync", 81), ("fsync", 82), ("fdatasync", 83), ("timerfd_create", 85),
+    ("timerfd_settime", 86), ("timerfd_gettime", 87), ("utimensat", 88), ("acct", 89),
+    ("capget", 90), ("capset", 91), ("personality", 92), ("exit", 93), ("exit_group", 94),
+    ("waitid", 95), ("set_tid_address", 96), ("unshare", 97), ("futex", 98),
+    ("set_robust_list", 99), ("get_robust_list", 100), ("nanosleep", 101), ("getitimer",
+    102), ("setitimer", 103), ("kexec_load", 104), ("init_module", 105), ("delete_module",
+    106), ("timer_create", 107), ("timer_gettime", 108), ("timer_getoverrun", 109),
+    ("timer_settime", 110), ("timer_delete", 111), ("clock_settime", 112),
+    ("clock_gettime", 113), ("clock_getres", 114), ("clock_nanosleep", 115), ("syslog",
+    116), ("ptrace", 117), ("sched_setparam", 118), ("sched_setscheduler", 119),
+    ("sched_getscheduler", 120), ("sched_getparam", 121), ("sched_setaffinity", 122),
+    ("sched_getaffinity", 123), ("sched_yield", 124), ("sched_get_priority_max", 125),
+    ("sched_get_priority_min", 126), ("sched_rr_get_interval", 127), ("restart_syscall",
+    128), ("kill", 129), ("tkill", 130), ("tgkill", 131), ("sigaltstack", 132),
+    ("rt_sigsuspend", 133), ("rt_sigaction", 134), ("rt_sigprocmask", 135),
+    ("rt_sigpending", 136), ("rt_sigtimedwait", 137), ("rt_sigqueueinfo", 138),
+    ("rt_sigreturn", 139), ("setpriority", 140), ("getpriority", 141), ("reboot", 142),
+    ("setregid", 143), ("setgid", 144), ("setreuid", 145), ("setuid", 146), ("setresuid",
+    147), ("getresuid", 148), ("setresgid", 149), ("getresgid", 150), ("setfsuid", 151),
+    ("setfsgid", 152), ("times", 153), ("setpgid", 154), ("getpgid", 155), ("getsid", 156),
+    ("setsid", 157), ("getgroups", 158), ("setgroups", 159), ("uname", 160),
+    ("sethostname", 161), ("setdomainname", 162), ("getrusage", 165), ("umask", 166),
+    ("prctl", 167), ("getcpu", 168), ("gettimeofday", 169), ("settimeofday", 170),
+    ("adjtimex", 171), ("getpid", 172), ("getppid", 173), ("getuid", 174), ("geteuid",
+    175), ("getgid", 176), ("getegid", 177), ("gettid", 178), ("sysinfo", 179), ("mq_open",
+    180), ("mq_unlink", 181), ("mq_timedsend", 182), ("mq_timedreceive", 183),
+    ("mq_notify", 184), ("mq_getsetattr", 185), ("msgget", 186), ("msgctl", 187),
+    ("msgrcv", 188), ("msgsnd", 189), ("semget", 190), ("semctl", 191), ("semtimedop",
+    192), ("semop", 193), ("shmget", 194), ("shmctl", 195), ("shmat", 196), ("shmdt", 197),
+    ("socket", 198), ("socketpair", 199), ("bind", 200), ("listen", 201), ("accept", 202),
+    ("connect", 203), ("getsockname", 204), ("getpeername", 205), ("sendto", 206),
+    ("recvfrom", 207), ("setsockopt", 208), ("getsockopt", 209), ("shutdown", 210),
+    ("sendmsg", 211), ("recvmsg", 212), ("readahead", 213), ("brk", 214), ("munmap", 215),
+    ("mremap", 216), ("add_key", 217), ("request_key", 218), ("keyctl", 219), ("clone",
+    220), ("execve", 221), ("mmap", 222), ("swapon", 224), ("swapoff", 225), ("mprotect",
+    226), ("msync", 227), ("mlock", 228), ("munlock", 229), ("mlockall", 230),
+    ("munlockall", 231), ("mincore", 232), ("madvise", 233), ("remap_file_pages", 234),
+    ("mbind", 235), ("get_mempolicy", 236), ("set_mempolicy", 237), ("migrate_pages", 238),
+    ("move_pages", 239), ("rt_tgsigqueueinfo", 240), ("perf_event_open", 241), ("accept4",
+    242), ("recvmmsg", 243), ("wait4", 260), ("prlimit64", 261), ("fanotify_init", 262),
+    ("fanotify_mark", 263), ("name_to_handle_at", 264), ("open_by_handle_at", 265),
+    ("clock_adjtime", 266), ("syncfs", 267), ("setns", 268), ("sendmmsg", 269),
+    ("process_vm_readv", 270), ("process_vm_writev", 271), ("kcmp", 272), ("finit_module",
+    273), ("sched_setattr", 274), ("sched_getattr", 275), ("renameat2", 276), ("seccomp",
+    277), ("getrandom", 278), ("memfd_create", 279), ("bpf", 280), ("execveat", 281),
+    ("userfaultfd", 282), ("membarrier", 283), ("mlock2", 284), ("copy_file_range", 285),
+    ("preadv2", 286), ("pwritev2", 287), ("pkey_mprotect", 288), ("pkey_alloc", 289),
+    ("pkey_free", 290), ("statx", 291), ("rseq", 293), ("kexec_file_load", 294),
+    ("pidfd_send_signal", 424), ("io_uring_setup", 425), ("io_uring_enter", 426),
+    ("io_uring_register", 427), ("open_tree", 428), ("move_mount", 429), ("fsopen", 430),
+    ("fsconfig", 431), ("fsmount", 432), ("fspick", 433), ("pidfd_open", 434), ("clone3",
+    435), ("close_range", 436), ("openat2", 437), ("pidfd_getfd", 438), ("faccessat2",
+    439), ("process_madvise", 440), ("epoll_pwait2", 441), ("mount_setattr", 442),
+    ("quotactl_fd", 443), ("landlock_create_ruleset", 444), ("landlock_add_rule", 445),
+    ("landlock_restrict_self", 446), ("memfd_secret", 447), ("process_mrelease", 448),
+    ("futex_waitv", 449), ("set_mempolicy_home_node", 450),
+];
+
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+const SYSCALLS: &[(&str, u32)] = &[];
+
+/// Returns the number of a system call.
+fn syscall_number(name: &str) -> Option<u32> {
+    SYSCALLS.iter().find(|x| x.0 == name).map(|x| x.1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A host without capabilities.
+    const HOST: Host = Host {
+        caps: 0,
+        kernel: (6, 1),
+    };
+
+    /// Runs the program for a system call and returns what it returns.
+    fn run(filter: &Filter, nr: u32, args: [u64; 6]) -> u32 {
+        let mut data = [0; 64];
+        data[0..4].copy_from_slice(&nr.to_le_bytes());
+        data[4..8].copy_from_slice(&AUDIT_ARCH.unwrap().to_le_bytes());
+        for (idx, arg) in args.iter().enumerate() {
+            let offset = DATA_ARGS as usize + 8 * idx;
+            data[offset..offset + 8].copy_from_slice(&arg.to_le_bytes());
+        }
+        let mut acc = 0;
+        let mut pc = 0;
+        loop {
+            let x = filter.program[pc];
+            pc += 1;
+            let jump = |cond: bool| if cond { x.jt } else { x.jf } as usize;
+            match x.code {
+                BPF_LD_W_ABS => {
+                    let k = x.k as usize;
+                    acc = u32::from_le_bytes(data[k..k + 4].try_into().unwrap());
+                }
+                BPF_ALU_AND_K => acc &= x.k,
+                BPF_JMP_JEQ_K => pc += jump(acc == x.k),
+                BPF_JMP_JGT_K => pc += jump(acc > x.k),
+                BPF_JMP_JGE_K => pc += jump(acc >= x.k),
+                BPF_RET_K => return x.k,
+                code => panic!("unknown instruction {:#x}", code),
+            }
+        }
+    }
+
+    #[test]
+    fn test_compile() {
+        let filter = Filter::compile(
+            r#"{"defaultAction": "SCMP_ACT_ERRNO", "defaultErrnoRet": 38,
+                "syscalls": [{"names": ["read", "write"], "action": "SCMP_ACT_ALLOW"}]}"#,
+            &HOST,
+        )
+        .unwrap();
+        let write = nix::libc::SYS_write as u32;
+        assert_eq!(run(&filter, write, [0; 6]), RET_ALLOW);
+        assert_eq!(
+            run(&filter, nix::libc::SYS_open as u32, [0; 6]),
+            RET_ERRNO | 38
+        );
+        // unknown names are skipped like in Docker's default profile
+        let filter = Filter::compile(
+            r#"{"defaultAction": "SCMP_ACT_ERRNO",
+            "syscalls": [{"names": ["nope", "write"], "action": "SCMP_ACT_ALLOW"}]}"#,
+            &HOST,
+        )
+        .unwrap();
+        assert_eq!(run(&filter, write, [0; 6]), RET_ALLOW);
+        let names = vec![r#""read""#; MAX_INSTRUCTIONS].join(",");
+        assert!(Filter::compile(
+            &format!(
+                r#"{{"defaultAction": "SCMP_ACT_ALLOW",
+                "syscalls": [{{"names": [{}], "action": "SCMP_ACT_ERRNO"}}]}}"#,
+                names
+            ),
+            &HOST
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_conditions() {
+        let nr = nix::libc::SYS_personality as u32;
+        let rule = |op: &str, value: u64, value_two: u64| {
+            let profile = format!(
+                r#"{{"defaultAction": "SCMP_ACT_ERRNO",
+                "syscalls": [{{"names": ["personality"], "action": "SCMP_ACT_ALLOW",
+                    "args": [{{"index": 1, "value": {}, "valueTwo": {}, "op": "{}"}}]}}]}}"#,
+                value, value_two, op
+            );
+            Filter::compile(&profile, &HOST).unwrap()
+        };
+        let big = 5 << 32 | 7;
+        for (op, value, value_two, arg, allowed) in [
+            ("SCMP_CMP_EQ", big, 0, big, true),
+            ("SCMP_CMP_EQ", big, 0, 7, false),
+            ("SCMP_CMP_EQ", big, 0, 5 << 32, false),
+            ("SCMP_CMP_NE", big, 0, big, false),
+            ("SCMP_CMP_NE", big, 0, 7, true),
+            ("SCMP_CMP_NE", big, 0, 5 << 32, true),
+            ("SCMP_CMP_MASKED_EQ", 0xff00, 0x1200, 0x1234, true),
+            (
+                "SCMP_CMP_MASKED_EQ",
+                0xff00,
+                0x1200,
+                1 << 32 | 0x1334,
+                false,
+            ),
+            ("SCMP_CMP_MASKED_EQ", 0xff << 32, 0, 1 << 32, false),
+            ("SCMP_CMP_GT", big, 0, big + 1, true),
+            ("SCMP_CMP_GT", big, 0, big, false),
+            ("SCMP_CMP_GT", big, 0, 6 << 32, true),
+            ("SCMP_CMP_GT", big, 0, 8, false),
+            ("SCMP_CMP_GE", big, 0, big, true),
+            ("SCMP_CMP_GE", big, 0, big - 1, false),
+            ("SCMP_CMP_LT", big, 0, big - 1, true),
+            ("SCMP_CMP_LT", big, 0, big, false),
+            ("SCMP_CMP_LT", big, 0, 4 << 32 | 9, true),
+            ("SCMP_CMP_LT", big, 0, 6 << 32, false),
+            ("SCMP_CMP_LE", big, 0, big, true),
+            ("SCMP_CMP_LE", big, 0, big + 1, false),
+        ] {
+            let filter = rule(op, value, value_two);
+            let rv = run(&filter, nr, [0, arg, 0, 0, 0, 0]);
+            assert_eq!(rv == RET_ALLOW, allowed, "{} {:#x} {:#x}", op, value, arg);
+            // the rules after a conditional one still see the number
+            assert_eq!(run(&filter, nr + 1, [0, arg, 0, 0, 0, 0]), RET_ERRNO | 1);
+        }
+
+        // conditions on different arguments all have to hold, conditions on
+        // the same argument are alternatives
+        let filter = Filter::compile(
+            r#"{"defaultAction": "SCMP_ACT_ERRNO",
+            "syscalls": [{"names": ["personality"], "action": "SCMP_ACT_ALLOW", "args": [
+                {"index": 0, "value": 1, "op": "SCMP_CMP_EQ"},
+                {"index": 1, "value": 2, "op": "SCMP_CMP_EQ"}]},
+                {"names": ["write"], "action": "SCMP_ACT_ALLOW", "args": [
+                {"index": 0, "value": 1, "op": "SCMP_CMP_EQ"},
+                {"index": 0, "value": 2, "op": "SCMP_CMP_EQ"}]}]}"#,
+            &HOST,
+        )
+        .unwrap();
+        assert_eq!(run(&filter, nr, [1, 2, 0, 0, 0, 0]), RET_ALLOW);
+        assert_ne!(run(&filter, nr, [1, 3, 0, 0, 0, 0]), RET_ALLOW);
+        let write = nix::libc::SYS_write as u32;
+        assert_eq!(run(&filter, write, [2, 0, 0, 0, 0, 0]), RET_ALLOW);
+        assert_ne!(run(&filter, write, [3, 0, 0, 0, 0, 0]), RET_ALLOW);
+
+        assert!(Filter::compile(
+            r#"{"defaultAction": "SCMP_ACT_ERRNO", "syscalls": [{"names": ["write"],
+            "action": "SCMP_ACT_ALLOW", "args": [{"index": 0, "value": 1, "op": "LIKE"}]}]}"#,
+            &HOST
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_includes_and_excludes() {
+        let profile = r#"{"defaultAction": "SCMP_ACT_ERRNO", "syscalls": [
+            {"names": ["read"], "action": "SCMP_ACT_ALLOW",
+                "includes": {"caps": ["CAP_SYS_ADMIN"]}},
+            {"names": ["write"], "action": "SCMP_ACT_ALLOW",
+                "excludes": {"caps": ["CAP_SYS_ADMIN"], "arches": ["s390x"]}},
+            {"names": ["open"], "action": "SCMP_ACT_ALLOW",
+                "includes": {"arches": ["amd64", "arm64"], "minKernel": "5.8"}}]}"#;
+        let allowed = |host: &Host, name: &str| {
+            let filter = Filter::compile(profile, host).unwrap();
+            run(&filter, syscall_number(name).unwrap(), [0; 6]) == RET_ALLOW
+        };
+        let admin = Host {
+            caps: 1 << 21,
+            kernel: (5, 4),
+        };
+        assert!(!allowed(&HOST, "read"));
+        assert!(allowed(&admin, "read"));
+        assert!(allowed(&HOST, "write"));
+        assert!(!allowed(&admin, "write"));
+        assert!(allowed(&HOST, "open"));
+        assert!(!allowed(&admin, "open"));
+        assert_eq!(parse_kernel_version("6.18.44-fc-v50\n"), Some((6, 18)));
+    }
+
+    /// Forks under a profile shaped like Docker's default, which only allows
+    /// `clone` without the flags for new namespaces.
+    #[test]
+    fn test_fork_with_clone_conditions() {
+        use nix::libc;
+        use nix::sys::wait::{waitpid, WaitStatus};
+        use nix::unistd::{fork, ForkResult};
+
+        let names = SYSCALLS
+            .iter()
+            .filter(|x| x.0 != "clone" && x.0 != "clone3")
+            .map(|x| format!("{:?}", x.0))
+            .collect::<Vec<_>>()
+            .join(",");
+        let profile = format!(
+            r#"{{"defaultAction": "SCMP_ACT_ERRNO", "syscalls": [
+            {{"names": [{}], "action": "SCMP_ACT_ALLOW"}},
+            {{"names": ["clone"], "action": "SCMP_ACT_ALLOW",
+                "args": [{{"index": 0, "value": 2114060288, "valueTwo": 0,
+                    "op": "SCMP_CMP_MASKED_EQ"}}]}},
+            {{"names": ["clone3"], "action": "SCMP_ACT_ERRNO", "errnoRet": 38}}]}}"#,
+            names
+        );
+        let filter = Filter::compile(&profile, &HOST).unwrap();
+        match unsafe { fork() }.unwrap() {
+            ForkResult::Child => unsafe {
+                if filter.install().is_err() {
+                    libc::_exit(1);
+                }
+                let code = match fork() {
+                    Ok(ForkResult::Child) => libc::_exit(0),
+                    Ok(ForkResult::Parent { child }) => match waitpid(child, None) {
+                        Ok(WaitStatus::Exited(_, 0)) => 0,
+                        _ => 2,
+                    },
+                    Err(_) => 3,
+                };
+                // a clone into a new user namespace is denied
+                let rv = libc::syscall(
+                    libc::SYS_clone,
+                    libc::CLONE_NEWUSER | libc::SIGCHLD,
+                    0,
+                    0,
+                    0,
+                    0,
+                );
+                if rv == 0 {
+                    libc::_exit(0);
+                }
+                libc::_exit(if rv < 0 && Errno::last() == Errno::EPERM {
+                    code
+                } else {
+                    4
+                });
+            },
+            ForkResult::Parent { child } => {
+                assert_eq!(waitpid(child, None).unwrap(), WaitStatus::Exited(child, 0));
+            }
+        }
+    }
+}
@@ -0,0 +1,212 @@
+//! Splitting the output into commands with shell integration markers.
+//!
+//! Shells with shell integration mark their prompt and every command with
+//! OSC 133 sequences: `A` starts the prompt, `B` ends it, `C` marks the start
+//! of the output of the command and `D;<exit code>` its end.  With
+//! `--segment-per-command` every command gets a record of its own.
+use std::fs::File;
+use std::io::{self, Write};
+use std::mem::take;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use anyhow::{Context, Error};
+
+use crate::ansi::{Parser, Piece};
+use crate::json;
+use crate::time::format_utc;
+
+/// Where the segments go.
+enum Target {
+    /// One JSON record per line.
+    Records(File),
+    /// A file per command.  `{n}` in the path is the number of the command.
+    Files(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Idle,
+    /// The command is typed after the prompt.
+    Input,
+    /// The command runs.
+    Output,
+}
+
+/// Cuts the output into commands.
+pub struct Segmenter {
+    parser: Parser,
+    target: Target,
+    state: State,
+    /// The number of commands so far.
+    count: usize,
+    command: Vec<u8>,
+    /// The output of the current command without escape sequences.
+    output: Vec<u8>,
+    /// The file of the current command with [`Target::Files`].
+    file: Option<File>,
+    started: SystemTime,
+}
+
+impl Segmenter {
+    /// Creates the segmenter for the given path.
+    ///
+    /// If the path contains `{n}` the output of every command goes to a file
+    /// of its own, otherwise a JSON record per command is appended to the file.
+    pub fn create(path: &Path) -> Result<Segmenter, Error> {
+        let template = path.to_string_lossy();
+        let target = if template.contains("{n}") {
+            Target::Files(template.into_owned())
+        } else {
+            Target::Records(
+                File::options()
+                    .append(true)
+                    .create(true)
+                    .open(path)
+                    .with_context(|| format!("could not open {}", path.display()))?,
+            )
+        };
+        Ok(Segmenter {
+            parser: Parser::default(),
+            target,
+            state: State::Idle,
+            count: 0,
+            command: Vec::new(),
+            output: Vec::new(),
+            file: None,
+            started: SystemTime::now(),
+        })
+    }
+
+    /// Feeds output of the program.
+    pub fn feed(&mut self, data: &[u8]) -> io::Result<()> {
+        // writing can fail so the output is split at the markers first
+        let mut pieces = Vec::new();
+        let mut raw = Vec::new();
+        let mut text = Vec::new();
+        self.parser.feed_pieces(data, |piece| match piece {
+            Piece::Byte(c) => {
+                raw.push(c);
+                text.push(c);
+            }
+            Piece::Sequence(seq) => match seq.osc() {
+                Some((b"133", arg)) => {
+                    let (&kind, rest) = arg.split_first().unwrap_or((&0, &[]));
+                    let marker = (kind, parse_exit_code(rest));
+                    pieces.push((take(&mut raw), take(&mut text), Some(marker)));
+                }
+                _ => raw.extend_from_slice(seq.raw),
+            },
+        });
+        pieces.push((raw, text, None));
+        for (raw, text, marker) in pieces {
+            match self.state {
+                State::Idle => {}
+                State::Input => self.command.extend_from_slice(&text),
+                State::Output => {
+                    self.output.extend_from_slice(&text);
+                    if let Some(ref mut file) = self.file {
+                        file.write_all(&raw)?;
+                    }
+                }
+            }
+            if let Some((kind, code)) = marker {
+                self.marker(kind, code)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes the record of a command which was still running when the
+    /// session ended.
+    pub fn finish(&mut self) -> io::Result<()> {
+        if self.state == State::Output {
+            self.finish_command(None)?;
+            self.state = State::Idle;
+        }
+        Ok(())
+    }
+
+    fn marker(&mut self, kind: u8, code: Option<i32>) -> io::Result<()> {
+        match kind {
+            b'A' => {
+                if self.state == State::Output {
+                    self.finish_command(None)?;
+                }
+                self.state = State::Idle;
+            }
+            b'B' => {
+                self.command.clear();
+                self.state = State::Input;
+            }
+            b'C' => {
+                self.count += 1;
+                self.output.clear();
+                self.started = SystemTime::now();
+                if let Target::Files(ref template) = self.target {
+                    let path = PathBuf::from(template.replace("{n}", &self.count.to_string()));
+                    self.file = Some(File::create(path)?);
+                }
+                self.state = State::Output;
+            }
+            b'D' => {
+                if self.state == State::Output {
+                    self.finish_command(code)?;
+                }
+                self.state = State::Idle;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Writes the record of the command which just finished.
+    fn finish_command(&mut self, code: Option<i32>) -> io::Result<()> {
+        self.file = None;
+        let Target::Records(ref mut file) = self.target else {
+            return Ok(());
+        };
+        let duration = self.started.elapsed().unwrap_or_default();
+        writeln!(
+            file,
+            "{{\"index\":{},\"command\":{},\"exit_code\":{},\"started\":{},\"duration\":{:.3},\"output\":{}}}",
+            self.count,
+            json::quote(String::from_utf8_lossy(&self.command).trim()),
+            code.map_or_else(|| "null".to_string(), |x| x.to_string()),
+            json::quote(&format_utc(self.started)),
+            duration.as_secs_f64(),
+            json::quote(&String::from_utf8_lossy(&self.output)),
+        )?;
+        file.flush()
+    }
+}
+
+/// Parses the exit code of a `D` marker (`;<code>`).
+fn parse_exit_code(rest: &[u8]) -> Option<i32> {
+    let rest = std::str::from_utf8(rest.strip_prefix(b";")?).ok()?;
+    rest.split(';').next()?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_records() {
+        let path =
+            std::env::temp_dir().join(format!("teetty-segments-{}.jsonl", std::process::id()));
+        let mut segmenter = Segmenter::create(&path).unwrap();
+        segmenter
+            .feed(b"\x1b]133;A\x07$ \x1b]133;B\x07ls\r\n\x1b]13")
+            .unwrap();
+        segmenter
+            .feed(b"3;C\x07\x1b[1ma\x1b[0m\r\n\x1b]133;D;2\x07\x1b]133;A\x07$ ")
+            .unwrap();
+        let records = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).ok();
+        let record = json::parse(records.trim_end()).unwrap();
+        assert_eq!(record.get("command").and_then(|x| x.as_str()), Some("ls"));
+        assert_eq!(record.get("output").and_then(|x| x.as_str()), Some("a\r\n"));
+        assert_eq!(record.get("exit_code").and_then(|x| x.as_f64()), Some(2.0));
+    }
+}
@@ -0,0 +1,258 @@
+//! The sockets a session serves.
+use std::io::Write;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use crate::broadcast::{Broadcaster, Format};
+use crate::control::{ControlCommand, ControlServer};
+use crate::http::constant_time_eq;
+use crate::http::{has_bearer_token, is_json, respond, HttpServer, Request, Response};
+use crate::json;
+use crate::metrics::Metrics;
+use crate::poll::PollSet;
+use crate::session::Remote;
+use crate::websocket::{handshake_response, is_allowed_origin, ViewerAssets, VIEWER_HTML};
+
+/// The control socket and network endpoints of a session together with the
+/// followers of its output.
+#[derive(Default)]
+pub struct Servers {
+    pub control: Option<ControlServer>,
+    pub metrics: Option<HttpServer>,
+    pub websocket: Option<HttpServer>,
+    pub api: Option<HttpServer>,
+    /// The token clients of the HTTP API and the WebSocket have to present.
+    /// Without one no request is allowed.
+    pub token: Option<String>,
+    /// xterm.js for the viewer page.
+    pub viewer: Option<ViewerAssets>,
+    /// The commands of a [`Session`](crate::session::Session) handle.
+    pub remote: Option<Remote>,
+    pub followers: Broadcaster,
+}
+
+impl Servers {
+    /// Returns `true` if the session serves nothing.
+    pub fn is_empty(&self) -> bool {
+        self.control.is_none()
+            && self.metrics.is_none()
+            && self.websocket.is_none()
+            && self.api.is_none()
+            && self.remote.is_none()
+    }
+
+    /// Closes connections which ran out of time and adds the fds that need
+    /// watching to the set.
+    pub fn watch(&mut self, fds: &mut PollSet) {
+        if let Some(ref control) = self.control {
+            control.watch(fds);
+        }
+        for server in [&mut self.metrics, &mut self.websocket, &mut self.api]
+            .into_iter()
+            .flatten()
+        {
+            server.watch(fds);
+        }
+        if let Some(ref remote) = self.remote {
+            remote.watch(fds);
+        }
+        self.followers.watch(fds);
+    }
+
+    /// Returns how long until a connection runs out of time.
+    pub fn timeout(&self) -> Option<Duration> {
+        [&self.metrics, &self.websocket, &self.api]
+            .into_iter()
+            .flatten()
+            .filter_map(|server| server.timeout())
+            .min()
+    }
+
+    /// Serves whatever is ready.  Commands that need to be carried out by the
+    /// session are added to `commands`.
+    ///
+    /// Returns `true` if a new follower attached.
+    pub fn handle(
+        &mut self,
+        fds: &PollSet,
+        metrics: &Metrics,
+        status: &dyn Fn() -> String,
+        commands: &mut Vec<ControlCommand>,
+    ) -> bool {
+        self.followers.handle(fds);
+        let mut attached = false;
+
+        if let Some(ref mut control) = self.control {
+            attached |= control.handle(fds, &mut self.followers, status, commands);
+        }
+
+        if let Some(ref remote) = self.remote {
+            remote.handle(fds, commands);
+        }
+
+        if let Some(ref mut server) = self.api {
+            for (req, mut response) in server.handle(fds) {
+                api_request(&req, &mut response, self.token.as_deref(), status, commands);
+                server.send(response);
+            }
+        }
+
+        if let Some(ref mut server) = self.metrics {
+            for (req, mut response) in server.handle(fds) {
+                match (req.method.as_str(), req.path.as_str()) {
+                    ("GET", "/metrics") => {
+                        let body = metrics.render();
+                        respond(
+                            &mut response,
+                            200,
+                            "text/plain; version=0.0.4",
+                            body.as_bytes(),
+                        )
+                    }
+                    _ => respond(&mut response, 404, "text/plain", b"not found\n"),
+                }
+                .ok();
+                server.send(response);
+            }
+        }
+
+        if let Some(ref mut server) = self.websocket {
+            let bound = server.local_addr().ok();
+            for (req, mut response) in server.handle(fds) {
+                let upgraded = websocket_request(
+                    &req,
+                    &mut response,
+                    bound,
+                    self.token.as_deref(),
+                    self.viewer.as_ref(),
+                );
+                if upgraded {
+                    let (stream, head) = response.into_inner();
+                    attached |= self
+                        .followers
+                        .add_with_head(stream, Format::WebSocket, &head)
+                        .is_ok();
+                } else {
+                    server.send(response);
+                }
+            }
+        }
+
+        attached
+    }
+}
+
+/// Serves a request to the HTTP API.
+fn api_request(
+    req: &Request,
+    response: &mut Response,
+    token: Option<&str>,
+    status: &dyn Fn() -> String,
+    commands: &mut Vec<ControlCommand>,
+) {
+    // web pages can make requests to local addresses as well
+    if req.header("origin").is_some() {
+        respond(response, 403, "text/plain", b"cross-origin request\n").ok();
+        return;
+    }
+    if !token.is_some_and(|token| has_bearer_token(req, token)) {
+        respond(response, 401, "text/plain", b"unauthorized\n").ok();
+        return;
+    }
+    if !is_json(req) {
+        respond(response, 415, "text/plain", b"expected JSON\n").ok();
+        return;
+    }
+    let body = match parse_argument(&req.body) {
+        Ok(body) => body,
+        Err(err) => {
+            respond(response, 400, "text/plain", format!("{}\n", err).as_bytes()).ok();
+            return;
+        }
+    };
+    let rv = match (req.method.as_str(), req.path.as_str()) {
+        ("GET", "/status") => {
+            respond(response, 200, "application/json", status().as_bytes()).ok();
+            return;
+        }
+        ("POST", "/signal") => ControlCommand::signal(&body),
+        ("POST", "/input") => Ok(ControlCommand::Input(body.into_bytes())),
+        ("POST", "/resize") => ControlCommand::resize(&body),
+        ("POST", "/shutdown") => ControlCommand::shutdown(&body),
+        (_, "/status" | "/signal" | "/input" | "/resize" | "/shutdown") => {
+            respond(response, 405, "text/plain", b"method not allowed\n").ok();
+            return;
+        }
+        _ => {
+            respond(response, 404, "text/plain", b"not found\n").ok();
+            return;
+        }
+    };
+    match rv {
+        Ok(command) => {
+            commands.push(command);
+            respond(response, 204, "text/plain", b"")
+        }
+        Err(err) => respond(response, 400, "text/plain", format!("{}\n", err).as_bytes()),
+    }
+    .ok();
+}
+
+/// Serves a request to the WebSocket server.  Returns `true` if the handshake
+/// was accepted and the connection becomes a follower.
+fn websocket_request(
+    req: &Request,
+    response: &mut Response,
+    bound: Option<SocketAddr>,
+    token: Option<&str>,
+    viewer: Option<&ViewerAssets>,
+) -> bool {
+    if let Some(handshake) = handshake_response(req) {
+        if !bound.is_some_and(|bound| is_allowed_origin(req, bound)) {
+            respond(response, 403, "text/plain", b"cross-origin request\n").ok();
+            return false;
+        }
+        // browsers can't set headers for WebSockets
+        let authorized = token.is_some_and(|token| {
+            has_bearer_token(req, token)
+                || req
+                    .query_param("token")
+                    .is_some_and(|x| constant_time_eq(x.as_bytes(), token.as_bytes()))
+        });
+        if !authorized {
+            respond(response, 401, "text/plain", b"unauthorized\n").ok();
+            return false;
+        }
+        response.write_all(handshake.as_bytes()).ok();
+        return true;
+    }
+    match (req.method.as_str(), req.path.as_str(), viewer) {
+        ("GET", "/", _) => respond(
+            response,
+            200,
+            "text/html; charset=utf-8",
+            VIEWER_HTML.as_bytes(),
+        ),
+        ("GET", "/xterm.js", Some(viewer)) => {
+            respond(response, 200, "text/javascript", &viewer.script)
+        }
+        ("GET", "/xterm.css", Some(viewer)) => respond(response, 200, "text/css", &viewer.style),
+        _ => respond(response, 404, "text/plain", b"not found\n"),
+    }
+    .ok();
+    false
+}
+
+/// Parses the body of an API request, a JSON string with the argument of the
+/// command.  An empty body is an empty argument.
+fn parse_argument(body: &[u8]) -> Result<String, String> {
+    if body.is_empty() {
+        return Ok(String::new());
+    }
+    let body = std::str::from_utf8(body).map_err(|_| "the body is not UTF-8".to_string())?;
+    match json::parse(body) {
+        Ok(json::Value::String(arg)) => Ok(arg),
+        Ok(_) => Err("expected a JSON string".into()),
+        Err(err) => Err(format!("invalid JSON: {}", err)),
+    }
+}
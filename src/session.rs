@@ -0,0 +1,475 @@
+//! Running a program in the background.
+//!
+//! [`spawn`] blocks until the program exited.  A [`Session`] runs the program
+//! on a thread instead and reports what happens as a stream of [`Event`]s so
+//! that embedders can react to the output without parsing log files.  The
+//! handle also controls the program while it's running.
+//!
+//! For existing I/O code the output is also available as [`Read`] through
+//! [`Session::output`] and the input as [`Write`] through [`Session::input`].
+use std::fs::File;
+use std::io::{self, BufRead, Read, Write};
+use std::iter;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use nix::fcntl::{fcntl, FcntlArg, OFlag};
+use nix::sys::signal::Signal;
+use nix::unistd::read;
+
+use crate::control::{ControlCommand, ShutdownMode};
+use crate::poll::PollSet;
+use crate::sink::{Event, Sink};
+#[cfg(doc)]
+use crate::spawn::spawn;
+use crate::spawn::{cloexec_pipe, spawn_with_remote, SpawnError, SpawnOptions};
+
+/// A program running in a pty on a background thread.
+pub struct Session {
+    thread: Option<JoinHandle<Result<i32, SpawnError>>>,
+    result: Option<Result<i32, SpawnError>>,
+    events: Mutex<Option<Receiver<Event>>>,
+    input: Input,
+}
+
+impl Session {
+    /// Starts running a program.
+    ///
+    /// The session is headless: it never reads from stdin or writes to stdout
+    /// of this process.  Problems with the options are reported by
+    /// [`Session::wait`].
+    pub fn start(mut opts: SpawnOptions) -> Result<Session, SpawnError> {
+        if opts.detachable || opts.daemon {
+            return Err(SpawnError::InvalidOptions(
+                "background sessions cannot be detachable or daemons".into(),
+            ));
+        }
+        let (tx, rx) = mpsc::channel();
+        opts.headless = true;
+        opts.sinks.push(Box::new(EventSink(tx)));
+        let (remote, commands, wakeup) =
+            Remote::new().map_err(|source| SpawnError::Io { source })?;
+        let thread = thread::Builder::new()
+            .name("teetty-session".into())
+            .spawn(move || spawn_with_remote(opts, Some(remote)))
+            .map_err(|source| SpawnError::Io { source })?;
+        Ok(Session {
+            thread: Some(thread),
+            result: None,
+            events: Mutex::new(Some(rx)),
+            input: Input {
+                commands,
+                wakeup: Arc::new(wakeup),
+            },
+        })
+    }
+
+    /// Returns the events of the session.
+    ///
+    /// The events are queued up from the start of the session until they are
+    /// read.  The iterator ends once the session ended.  Only the first call
+    /// returns the events, later ones return an empty iterator.
+    pub fn events(&self) -> impl Iterator<Item = Event> {
+        let events = self.take_events();
+        iter::from_fn(move || events.as_ref()?.recv().ok())
+    }
+
+    /// Returns a reader for the output of the session.
+    ///
+    /// The output is taken from the events so this can't be combined with
+    /// [`Session::events`].  The reader hits the end once the session ended.
+    pub fn output(&self) -> Output {
+        Output {
+            events: self.take_events(),
+            buf: Vec::new(),
+            pos: 0,
+        }
+    }
+
+    /// Returns a writer for the input of the program.
+    pub fn input(&self) -> Input {
+        self.input.clone()
+    }
+
+    /// Sends input to the program as if it was typed.
+    pub fn write_stdin(&self, data: &[u8]) -> Result<(), SpawnError> {
+        self.input.send(ControlCommand::Input(data.to_vec()))
+    }
+
+    /// Changes the size of the terminal.
+    pub fn resize(&self, cols: u16, rows: u16) -> Result<(), SpawnError> {
+        self.input.send(ControlCommand::Resize(rows, cols))
+    }
+
+    /// Sends a signal to the foreground process of the session.
+    pub fn signal(&self, signal: Signal) -> Result<(), SpawnError> {
+        self.input.send(ControlCommand::Signal(signal))
+    }
+
+    /// Shuts down the session.
+    ///
+    /// This returns right away, [`Session::wait`] waits for the session to
+    /// end.  Unlike the other methods this can also be called while another
+    /// thread reads the events.
+    pub fn shutdown(&self, mode: ShutdownMode) -> Result<(), SpawnError> {
+        self.input.send(ControlCommand::Shutdown(mode))
+    }
+
+    /// Returns the exit code of the program if the session ended.
+    ///
+    /// If the session failed, the error is only returned once.
+    pub fn try_wait(&mut self) -> Result<Option<i32>, SpawnError> {
+        if self.thread.as_ref().is_some_and(|x| x.is_finished()) {
+            self.join();
+        }
+        match self.result {
+            None => Ok(None),
+            Some(Ok(code)) => Ok(Some(code)),
+            Some(Err(_)) => {
+                let err = self.result.take().unwrap().unwrap_err();
+                self.result = Some(Err(SpawnError::Failed("the session failed".into())));
+                Err(err)
+            }
+        }
+    }
+
+    /// Waits for the session to end and returns the exit code of the program.
+    pub fn wait(mut self) -> Result<i32, SpawnError> {
+        // nobody is going to read the events anymore
+        drop(self.take_events());
+        self.join();
+        self.result.take().unwrap()
+    }
+
+    fn take_events(&self) -> Option<Receiver<Event>> {
+        self.events
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .take()
+    }
+
+    fn join(&mut self) {
+        if let Some(thread) = self.thread.take() {
+            self.result = Some(match thread.join() {
+                Ok(rv) => rv,
+                Err(payload) => std::panic::resume_unwind(payload),
+            });
+        }
+    }
+}
+
+/// Reads the output of a [`Session`].
+pub struct Output {
+    events: Option<Receiver<Event>>,
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+impl Read for Output {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        let buf = self.fill_buf()?;
+        let n = buf.len().min(out.len());
+        out[..n].copy_from_slice(&buf[..n]);
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+impl BufRead for Output {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        while self.pos == self.buf.len() {
+            match self.events.as_ref().and_then(|x| x.recv().ok()) {
+                Some(Event::Output(data)) => {
+                    self.buf = data;
+                    self.pos = 0;
+                }
+                Some(_) => {}
+                None => break,
+            }
+        }
+        Ok(&self.buf[self.pos..])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.pos = (self.pos + amt).min(self.buf.len());
+    }
+}
+
+/// Writes input to the program of a [`Session`].
+///
+/// Writing fails with [`io::ErrorKind::BrokenPipe`] once the session ended.
+#[derive(Clone)]
+pub struct Input {
+    commands: Sender<ControlCommand>,
+    wakeup: Arc<File>,
+}
+
+impl Input {
+    fn send(&self, command: ControlCommand) -> Result<(), SpawnError> {
+        self.commands.send(command).map_err(|_| SpawnError::Ended)?;
+        // a full pipe is fine, the session is going to wake up anyways
+        (&*self.wakeup).write_all(b"x").ok();
+        Ok(())
+    }
+}
+
+impl Write for Input {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.send(ControlCommand::Input(buf.to_vec()))
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The end of a [`Session`] handle inside the session.
+pub(crate) struct Remote {
+    wakeup: OwnedFd,
+    commands: Receiver<ControlCommand>,
+}
+
+impl Remote {
+    fn new() -> io::Result<(Remote, Sender<ControlCommand>, File)> {
+        let (r, w) = cloexec_pipe()?;
+        let (r, w) = unsafe { (OwnedFd::from_raw_fd(r), OwnedFd::from_raw_fd(w)) };
+        for fd in [&r, &w] {
+            fcntl(fd.as_raw_fd(), FcntlArg::F_SETFL(OFlag::O_NONBLOCK))?;
+        }
+        let (tx, rx) = mpsc::channel();
+        let remote = Remote {
+            wakeup: r,
+            commands: rx,
+        };
+        Ok((remote, tx, File::from(w)))
+    }
+
+    /// Adds the fd that needs watching to the set.
+    pub fn watch(&self, fds: &mut PollSet) {
+        fds.insert_read(self.wakeup.as_raw_fd());
+    }
+
+    /// Adds the commands sent by the handle to `commands`.
+    pub fn handle(&self, fds: &PollSet, commands: &mut Vec<ControlCommand>) {
+        if fds.readable(self.wakeup.as_raw_fd()) {
+            let mut buf = [0; 64];
+            while matches!(read(self.wakeup.as_raw_fd(), &mut buf), Ok(n) if n > 0) {}
+        }
+        commands.extend(self.commands.try_iter());
+    }
+}
+
+/// Forwards the output and the events of the session to [`Session::events`].
+struct EventSink(Sender<Event>);
+
+impl Sink for EventSink {
+    fn write_chunk(&mut self, _ts: Duration, bytes: &[u8]) -> io::Result<()> {
+        self.0.send(Event::Output(bytes.to_vec())).ok();
+        Ok(())
+    }
+
+    fn event(&mut self, event: &Event) -> io::Result<()> {
+        self.0.send(event.clone()).ok();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_events() {
+        let session = Session::start(SpawnOptions::new(["sh", "-c", "stty size; exit 3"])).unwrap();
+        let mut output = Vec::new();
+        let mut last = None;
+        for event in session.events() {
+            match event {
+                Event::Output(data) => output.extend_from_slice(&data),
+                event => last = Some(event),
+            }
+        }
+        assert_eq!(String::from_utf8_lossy(&output), "24 80\r\n");
+        assert_eq!(last, Some(Event::ChildExited(3)));
+        assert_eq!(session.wait().unwrap(), 3);
+    }
+
+    #[test]
+    fn test_control() {
+        let script = "read line; stty size; echo \"got $line\"";
+        let mut session = Session::start(SpawnOptions::new(["sh", "-c", script])).unwrap();
+        assert_eq!(session.try_wait().unwrap(), None);
+        session.resize(100, 30).unwrap();
+        session.write_stdin(b"hello\r").unwrap();
+        let mut output = Vec::new();
+        for event in session.events() {
+            match event {
+                Event::Output(data) => output.extend_from_slice(&data),
+                Event::Resized { rows, cols } => assert_eq!((rows, cols), (30, 100)),
+                _ => {}
+            }
+        }
+        let output = String::from_utf8_lossy(&output);
+        assert!(output.ends_with("30 100\r\ngot hello\r\n"), "{:?}", output);
+        let code = loop {
+            match session.try_wait().unwrap() {
+                Some(code) => break code,
+                None => thread::sleep(Duration::from_millis(10)),
+            }
+        };
+        assert_eq!(code, 0);
+        assert!(matches!(session.write_stdin(b"x"), Err(SpawnError::Ended)));
+    }
+
+    #[test]
+    fn test_streams() {
+        let script = "stty -echo; echo ready; read a; read b; echo \"$b $a\"";
+        let session = Session::start(SpawnOptions::new(["sh", "-c", script])).unwrap();
+        let mut input = session.input();
+        let mut lines = session.output().lines();
+        assert_eq!(lines.next().unwrap().unwrap(), "ready");
+        write!(input, "one\rtwo\r").unwrap();
+        assert_eq!(lines.next().unwrap().unwrap(), "two one");
+        assert!(lines.next().is_none());
+        assert_eq!(session.wait().unwrap(), 0);
+        assert_eq!(
+            input.write(b"x").unwrap_err().kind(),
+            io::ErrorKind::BrokenPipe
+        );
+    }
+
+    #[test]
+    fn test_concurrent() {
+        let mut sessions = (0..16)
+            .map(|i| {
+                let script = format!("sleep 0.1; echo session {}; exit {}", i, i);
+                Session::start(SpawnOptions::new(["sh".into(), "-c".into(), script])).unwrap()
+            })
+            .collect::<Vec<_>>();
+        for (i, session) in sessions.iter_mut().enumerate() {
+            let mut output = String::new();
+            session.output().read_to_string(&mut output).unwrap();
+            assert_eq!(output, format!("session {}\r\n", i));
+        }
+        for (i, session) in sessions.into_iter().enumerate() {
+            assert_eq!(session.wait().unwrap(), i as i32);
+        }
+    }
+
+    #[test]
+    fn test_shutdown() {
+        let session = Session::start(SpawnOptions::new(["cat"])).unwrap();
+        session.write_stdin(b"one\r").unwrap();
+        session
+            .shutdown(ShutdownMode::Eof(Duration::from_secs(5)))
+            .unwrap();
+        assert_eq!(session.wait().unwrap(), 0);
+
+        let script = "trap '' TERM; echo ready; sleep 10";
+        let session = Session::start(SpawnOptions::new(["sh", "-c", script])).unwrap();
+        let grace = Duration::from_millis(200);
+        let output = thread::scope(|s| {
+            let reader = s.spawn(|| {
+                let mut output = String::new();
+                session.output().read_to_string(&mut output).unwrap();
+                output
+            });
+            thread::sleep(Duration::from_millis(100));
+            session
+                .shutdown(ShutdownMode::Signal(Signal::SIGTERM, grace))
+                .unwrap();
+            reader.join().unwrap()
+        });
+        assert_eq!(output, "ready\r\n");
+        assert_eq!(session.wait().unwrap(), 128 + Signal::SIGKILL as i32);
+    }
+
+    #[test]
+    fn test_resize() {
+        let script = "trap 'stty size' WINCH; echo ready; while :; do sleep 0.01; done";
+        let session = Session::start(SpawnOptions::new(["sh", "-c", script])).unwrap();
+        let mut lines = session.output().lines();
+        assert_eq!(lines.next().unwrap().unwrap(), "ready");
+        session.resize(100, 30).unwrap();
+        assert_eq!(lines.next().unwrap().unwrap(), "30 100");
+        // the kernel only signals actual changes
+        session.resize(100, 30).unwrap();
+        session.resize(90, 20).unwrap();
+        assert_eq!(lines.next().unwrap().unwrap(), "20 90");
+        session.shutdown(ShutdownMode::Kill).unwrap();
+        session.wait().unwrap();
+    }
+
+    #[test]
+    fn test_drain_timeout() {
+        let mut opts = SpawnOptions::new(["sh", "-c", "seq 1 20000; exit 4"]);
+        opts.drain_timeout = Some(Duration::ZERO);
+        let sink = crate::sink::MemorySink::new();
+        opts.sinks.push(Box::new(sink.clone()));
+        assert_eq!(Session::start(opts).unwrap().wait().unwrap(), 4);
+        assert!(sink.contents().ends_with(b"\r\n19999\r\n20000\r\n"));
+    }
+
+    #[test]
+    fn test_record_for() {
+        let path =
+            std::env::temp_dir().join(format!("teetty-record-for-{}.log", std::process::id()));
+        let mut opts = SpawnOptions::new(["sh", "-c", "echo a; sleep 0.5; echo b; exit 3"]);
+        opts.out_path = Some(path.clone());
+        opts.record_for = Some(Duration::from_millis(200));
+        let sink = crate::sink::MemorySink::new();
+        opts.sinks.push(Box::new(sink.clone()));
+        let session = Session::start(opts).unwrap();
+        let events = session.events().collect::<Vec<_>>();
+        assert_eq!(session.wait().unwrap(), 3);
+        // only the recordings stop, other sinks and the events keep going
+        assert!(events.contains(&Event::ChildExited(3)));
+        assert_eq!(sink.contents(), b"a\r\nb\r\n");
+        let log = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).ok();
+        assert_eq!(log, "a\r\n[recording stopped after 0.2s]\r\n");
+    }
+
+    #[test]
+    fn test_exit_mode() {
+        let mut opts = SpawnOptions::new(["sh", "-c", "kill -TERM $$"]);
+        opts.exit_mode = crate::spawn::ExitMode::Raw;
+        let code = Session::start(opts).unwrap().wait().unwrap();
+        assert_eq!(code, Signal::SIGTERM as i32);
+    }
+
+    #[test]
+    fn test_env() {
+        let mut opts = SpawnOptions::new(["sh", "-c", "echo $TERM $COLUMNS $LINES"]);
+        opts.term = Some("vt100".into());
+        opts.default_size = Some((100, 30));
+        let session = Session::start(opts).unwrap();
+        let mut output = String::new();
+        session.output().read_to_string(&mut output).unwrap();
+        assert_eq!(output, "vt100 100 30\r\n");
+    }
+
+    #[test]
+    fn test_exec_failed() {
+        let session = Session::start(SpawnOptions::new(["/nonexistent/teetty-test"])).unwrap();
+        match session.wait() {
+            Err(SpawnError::ExecFailed { errno, .. }) => {
+                assert_eq!(errno, nix::errno::Errno::ENOENT)
+            }
+            rv => panic!("unexpected result: {:?}", rv),
+        }
+        let mut opts = SpawnOptions::new(["true"]);
+        opts.out_path = Some("/dev/null/teetty-test.log".into());
+        match Session::start(opts).unwrap().wait() {
+            Err(SpawnError::Resource { source, .. }) => {
+                assert_eq!(source.raw_os_error(), Some(nix::libc::ENOTDIR))
+            }
+            rv => panic!("unexpected result: {:?}", rv),
+        }
+    }
+}
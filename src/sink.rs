@@ -0,0 +1,86 @@
+//! Sinks receive the output of a session.
+//!
+//! Everything a session captures besides the output file goes through sinks:
+//! recordings, the journal and uploads are sinks, and embedders can add their
+//! own with [`SpawnOptions::sinks`](crate::spawn::SpawnOptions::sinks).
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Something that happened in a session.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Event {
+    /// Output of the program.  This is only reported by
+    /// [`Session::events`](crate::session::Session::events), sinks receive the
+    /// output with [`Sink::write_chunk`].
+    Output(Vec<u8>),
+    /// Input was sent to the program through the FIFOs, a script or a control
+    /// command.  Keyboard input is reported as [`Event::InputTyped`].
+    InputInjected(Vec<u8>),
+    /// Input was typed on the keyboard and sent to the program.
+    InputTyped(Vec<u8>),
+    /// The terminal of the session changed its size.
+    Resized { rows: u16, cols: u16 },
+    /// The program exited with this exit code.
+    ChildExited(i32),
+    /// There was neither output nor input for the idle threshold.
+    Idle,
+    /// There was output or input again after the session was idle.
+    Active,
+}
+
+/// Receives the output and the events of a session.
+pub trait Sink: Send {
+    /// Receives a chunk of output.  `ts` is the time since the session started.
+    fn write_chunk(&mut self, ts: Duration, bytes: &[u8]) -> io::Result<()>;
+
+    /// Receives an event.
+    fn event(&mut self, event: &Event) -> io::Result<()> {
+        let _ = event;
+        Ok(())
+    }
+
+    /// Called once after the session ended.
+    fn finish(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A sink that writes the output to a writer such as a file or a socket.
+pub struct WriteSink<W>(pub W);
+
+impl<W: Write + Send> Sink for WriteSink<W> {
+    fn write_chunk(&mut self, _ts: Duration, bytes: &[u8]) -> io::Result<()> {
+        self.0.write_all(bytes)
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+/// A sink that keeps the output in memory.
+///
+/// The sink can be cloned before it's handed to the session to read the
+/// output later.
+#[derive(Clone, Default)]
+pub struct MemorySink(Arc<Mutex<Vec<u8>>>);
+
+impl MemorySink {
+    pub fn new() -> MemorySink {
+        MemorySink::default()
+    }
+
+    /// Returns a copy of the output captured so far.
+    pub fn contents(&self) -> Vec<u8> {
+        self.0.lock().unwrap().clone()
+    }
+}
+
+impl Sink for MemorySink {
+    fn write_chunk(&mut self, _ts: Duration, bytes: &[u8]) -> io::Result<()> {
+        self.0.lock().unwrap().extend_from_slice(bytes);
+        Ok(())
+    }
+}
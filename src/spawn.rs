@@ -1,22 +1,77 @@
-use std::ffi::{CString, OsString};
+use std::ffi::{CString, OsStr, OsString};
+use std::fmt;
 use std::fs::File;
-use std::io::{Read, Write};
-use std::os::fd::AsRawFd;
-use std::os::unix::prelude::{OpenOptionsExt, OsStrExt};
-use std::path::Path;
+use std::io::{self, Write};
+use std::os::fd::{AsRawFd, FromRawFd, IntoRawFd, OwnedFd, RawFd};
+use std::os::unix::prelude::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant, SystemTime};
 
-use anyhow::Error;
+use anyhow::{Context, Error};
 use nix::errno::Errno;
-use nix::libc::{login_tty, O_NONBLOCK, SIGWINCH, STDIN_FILENO, STDOUT_FILENO, TIOCGWINSZ, VEOF};
-use nix::pty::{openpty, Winsize};
-use nix::sys::select::{select, FdSet};
-use nix::sys::signal::{killpg, Signal};
+use nix::fcntl::{fcntl, FcntlArg, FdFlag};
+use nix::libc::{
+    self, c_int, FIONREAD, SIGCONT, SIGTSTP, SIGWINCH, STDERR_FILENO, STDIN_FILENO, STDOUT_FILENO,
+    TIOCGWINSZ, TIOCSWINSZ, VEOF,
+};
+use nix::poll::{poll, PollFd, PollFlags};
+use nix::pty::Winsize;
+use nix::sys::signal::{kill, killpg, SigHandler, SigSet, Signal};
 use nix::sys::stat::Mode;
-use nix::sys::termios::{cfmakeraw, tcgetattr, tcsetattr, LocalFlags, SetArg, Termios};
-use nix::sys::time::TimeVal;
-use nix::sys::wait::{waitpid, WaitStatus};
-use nix::unistd::{close, execvp, fork, mkfifo, read, tcgetpgrp, write, ForkResult, Pid};
-use signal_hook::iterator::Signals;
+use nix::sys::termios::{tcgetattr, tcsendbreak, LocalFlags, Termios};
+use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
+use nix::unistd::{
+    close, dup2, fork, getpid, isatty, mkfifo, pipe, read, setsid, tcgetpgrp, write, ForkResult,
+    Pid,
+};
+use signal_hook::iterator::{Handle as SignalsHandle, Signals};
+
+use crate::ansi::Parser;
+use crate::answerback::Answerback;
+use crate::asciicast::Recorder;
+use crate::audit::AuditLog;
+use crate::breakpoint::{Breakpoint, Resume, PROMPT as BREAKPOINT_PROMPT};
+use crate::cgroup::Cgroup;
+use crate::ci::CiAnnotator;
+use crate::control::{ControlCommand, ControlServer, ShutdownMode};
+use crate::coredump;
+use crate::debug::{debug, trace};
+use crate::echo::InputEcho;
+use crate::escape::{EscapeAction, EscapeDetector};
+use crate::hooks::{notify_bell, Hooks};
+use crate::http::{generate_token, HttpServer};
+use crate::input::{translate_newlines, InputSource, Pacer};
+use crate::journald::JournalSink;
+use crate::json;
+use crate::keystrokes::KeystrokeTiming;
+use crate::metadata::{metadata_path, Metadata};
+use crate::metrics::Metrics;
+use crate::namespaces::Namespaces;
+use crate::notify::{
+    post_json, session_summary, stats_line, write_exit_file, write_junit_report, write_tap_report,
+    OutputTail,
+};
+use crate::outfile::OutFile;
+use crate::passthrough::{set_title, Passthrough, POP_TITLE};
+use crate::pattern::Pattern;
+use crate::poll::PollSet;
+use crate::pty::{login_tty, open_pty};
+use crate::registry::{PidFile, Registration};
+use crate::script::{Action, Script, ScriptRunner};
+use crate::seccomp::Filter as SeccompFilter;
+use crate::segment::Segmenter;
+use crate::servers::Servers;
+use crate::session::Remote;
+use crate::sink::{Event, Sink};
+use crate::template;
+use crate::term::{self, RestoreTerm};
+use crate::time::format_utc;
+use crate::traffic::TrafficDump;
+use crate::transform::{Pipeline, Transform};
+use crate::ttyrec;
+use crate::upload::Upload;
+use crate::websocket::ViewerAssets;
 
 macro_rules! continue_on_eintr {
     ($expr:expr) => {
@@ -28,12 +83,541 @@ macro_rules! continue_on_eintr {
     };
 }
 
-pub struct SpawnOptions<'a> {
-    pub args: &'a [OsString],
-    pub out_path: Option<&'a Path>,
+/// Markers around pasted text if bracketed paste mode is enabled.
+const BRACKETED_PASTE_START: &[u8] = b"\x1b[200~";
+const BRACKETED_PASTE_END: &[u8] = b"\x1b[201~";
+
+/// How much of the most recent output is matched against the prompt.
+const PROMPT_WINDOW_SIZE: usize = 4096;
+
+/// The terminal type of the program when `TERM` isn't set.
+const DEFAULT_TERM: &str = "xterm-256color";
+
+/// The initial size of the I/O buffer if no explicit size was provided.
+const DEFAULT_BUFFER_SIZE: usize = 4096;
+
+/// How often teetty checks if it got the terminal back while in the
+/// background.
+const BACKGROUND_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How long the output is still read after the program was killed.
+const KILL_DRAIN_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The size up to which the I/O buffer is grown automatically.
+const MAX_BUFFER_SIZE: usize = 1024 * 1024;
+
+/// Bells ringing faster than this only notify once.
+const BELL_NOTIFY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Why running a program failed.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum SpawnError {
+    /// The pty could not be opened.
+    PtyOpenFailed(Errno),
+    /// The process for the program could not be forked.
+    ForkFailed(Errno),
+    /// The program could not be executed (eg: because it does not exist).
+    ExecFailed { program: OsString, errno: Errno },
+    /// Setting up the process of the program failed before it was executed.
+    SetupFailed { step: SetupStep, errno: Errno },
+    /// The options can't be used, eg: no command was given or a feature isn't
+    /// supported on this platform.
+    InvalidOptions(String),
+    /// A file, socket or server of the session could not be set up, eg: the
+    /// output file could not be created.  `what` says which one.
+    Resource { what: String, source: io::Error },
+    /// Reading or writing failed while the program was running.
+    Io { source: io::Error },
+    /// Anything else that went wrong in the session, with a description.
+    Failed(String),
+    /// The session already ended.
+    Ended,
+}
+
+impl SpawnError {
+    /// Sorts the errors of the internals into the variants.
+    fn from_internal(err: Error) -> SpawnError {
+        let err = match err.downcast::<SpawnError>() {
+            Ok(err) => return err,
+            Err(err) => err,
+        };
+        let source = match err.root_cause().downcast_ref::<Errno>() {
+            Some(errno) => Some(io::Error::from(*errno)),
+            None => err
+                .root_cause()
+                .downcast_ref::<io::Error>()
+                .map(|x| match x.raw_os_error() {
+                    Some(code) => io::Error::from_raw_os_error(code),
+                    None => io::Error::new(x.kind(), x.to_string()),
+                }),
+        };
+        let what = err
+            .chain()
+            .take(err.chain().count() - 1)
+            .map(|x| x.to_string())
+            .collect::<Vec<_>>()
+            .join(": ");
+        match source {
+            Some(source) if what.is_empty() => SpawnError::Io { source },
+            Some(source) => SpawnError::Resource { what, source },
+            None => SpawnError::Failed(format!("{:#}", err)),
+        }
+    }
+}
+
+impl fmt::Display for SpawnError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SpawnError::PtyOpenFailed(errno) => write!(f, "could not open pty: {}", errno.desc()),
+            SpawnError::ForkFailed(errno) => write!(f, "could not fork: {}", errno.desc()),
+            SpawnError::ExecFailed { program, errno } => write!(
+                f,
+                "could not execute {}: {}",
+                program.to_string_lossy(),
+                errno.desc()
+            ),
+            SpawnError::SetupFailed { step, errno } => {
+                write!(f, "could not {}: {}", step.describe(), errno.desc())
+            }
+            SpawnError::InvalidOptions(message) | SpawnError::Failed(message) => {
+                write!(f, "{}", message)
+            }
+            SpawnError::Resource { what, .. } => write!(f, "{}", what),
+            SpawnError::Io { .. } => write!(f, "I/O error"),
+            SpawnError::Ended => write!(f, "the session ended"),
+        }
+    }
+}
+
+impl std::error::Error for SpawnError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SpawnError::Resource { source, .. } | SpawnError::Io { source } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+/// What is set up in the process of the program before it's executed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum SetupStep {
+    Nice = 1,
+    IoPriority,
+    Cgroup,
+    Namespaces,
+    Mounts,
+    Loopback,
+    Seccomp,
+    CoreLimit,
+}
+
+impl SetupStep {
+    fn describe(self) -> &'static str {
+        match self {
+            SetupStep::Nice => "set the nice value",
+            SetupStep::IoPriority => "set the I/O priority",
+            SetupStep::Cgroup => "join the cgroup",
+            SetupStep::Namespaces => "create the namespaces",
+            SetupStep::Mounts => "set up the mounts of the namespace",
+            SetupStep::Loopback => "bring up the loopback interface",
+            SetupStep::Seccomp => "install the seccomp filter",
+            SetupStep::CoreLimit => "set the core dump limit",
+        }
+    }
+
+    fn from_i32(value: i32) -> Option<SetupStep> {
+        [
+            SetupStep::Nice,
+            SetupStep::IoPriority,
+            SetupStep::Cgroup,
+            SetupStep::Namespaces,
+            SetupStep::Mounts,
+            SetupStep::Loopback,
+            SetupStep::Seccomp,
+            SetupStep::CoreLimit,
+        ]
+        .into_iter()
+        .find(|&x| x as i32 == value)
+    }
+}
+
+/// The size of the pty if there is no terminal to take it from.
+const DEFAULT_WINSIZE: Winsize = Winsize {
+    ws_row: 24,
+    ws_col: 80,
+    ws_xpixel: 0,
+    ws_ypixel: 0,
+};
+
+/// What happens when the program copies something to the clipboard of the
+/// terminal (with OSC 52).
+#[derive(Debug, Clone, Default)]
+pub enum Clipboard {
+    /// The terminal gets the request.
+    #[default]
+    Allow,
+    /// Requests are removed from what's shown on the terminal.
+    Block,
+    /// Requests are removed and what was copied is appended to this file.
+    Capture(PathBuf),
+}
+
+/// How the exit status of a program which was killed by a signal is reported.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ExitMode {
+    /// The exit code is the number of the signal.
+    Raw,
+    /// The exit code is `128 + signal` like shells report it.
+    #[default]
+    Shell,
+    /// teetty kills itself with the same signal once the session is cleaned
+    /// up.  This ends the whole process, including embedders.
+    PropagateSignal,
+}
+
+/// How the program is attached to the pty.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ControllingTerminal {
+    /// The program runs in a new session with the pty as its controlling
+    /// terminal like a login shell.
+    #[default]
+    Pty,
+    /// The program runs in a new session without a controlling terminal.  The
+    /// pty is only its stdin, stdout and stderr.
+    NewSession,
+    /// The program stays in the session of teetty and the pty is only its
+    /// stdin, stdout and stderr.
+    Unchanged,
+}
+
+/// What the program gets as stdin.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ChildStdin {
+    /// The pty like stdout and stderr.
+    #[default]
+    Pty,
+    /// `/dev/null` so that reads return EOF right away.
+    Null,
+    /// Nothing, stdin is closed.
+    Closed,
+}
+
+/// The I/O scheduling class of the program and its level from 0 (highest)
+/// to 7, like with ionice(1).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IoPriority {
+    /// Gets disk access before everybody else.  This usually requires root.
+    Realtime(u8),
+    /// The default class.
+    BestEffort(u8),
+    /// Only gets disk access when nobody else needs it.
+    Idle,
+}
+
+impl IoPriority {
+    /// Returns the value for `ioprio_set`.
+    fn value(self) -> c_int {
+        let (class, level) = match self {
+            IoPriority::Realtime(level) => (1, level),
+            IoPriority::BestEffort(level) => (2, level),
+            IoPriority::Idle => (3, 0),
+        };
+        (class << 13) | c_int::from(level)
+    }
+}
+
+/// A kind of namespace the program can get a new one of (Linux only).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Namespace {
+    /// Maps the user to root, which allows unprivileged users to create the
+    /// others.
+    User,
+    /// The program runs under an init of its own and only sees its own
+    /// processes.
+    Pid,
+    /// Mounts don't propagate back to the host.
+    Mount,
+    /// Only the loopback interface is available.
+    Net,
+    /// The hostname can be changed.
+    Uts,
+    /// System V IPC and POSIX message queues.
+    Ipc,
+}
+
+/// Whether the program dumps core when it crashes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CorePolicy {
+    /// No core dumps, whatever the limit of teetty is.
+    Disable,
+    /// Core dumps up to the hard limit, which are moved to this directory when
+    /// the session ends.
+    Dir(PathBuf),
+}
+
+/// What happens when the program rings the bell (BEL outside of escape
+/// sequences).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Bell {
+    /// Counts the bells and reports the count when the session ends.
+    pub count: bool,
+    /// Runs `on_bell` or shows a desktop notification if there is none, at
+    /// most once a second.  The bells in between don't notify, so of a burst
+    /// only the first one does.
+    pub notify: bool,
+    /// Removes the bells from what's shown on the terminal and sent to
+    /// followers.
+    pub strip: bool,
+}
+
+/// What happens when stdin of teetty reaches EOF.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum StdinEof {
+    /// EOF is sent to the program as if `^D` was typed.
+    #[default]
+    Veof,
+    /// The program is hung up on as if the terminal was closed.
+    Close,
+    /// Nothing happens.
+    Ignore,
+    /// EOF is sent to the program and it's killed if it didn't exit after
+    /// this long.
+    ExitAfter(Duration),
+}
+
+/// The format of the recording.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RecordFormat {
+    /// asciicast v2 as used by asciinema.
+    #[default]
+    Asciicast,
+    /// ttyrec as used by ttyplay and IPBT.
+    Ttyrec,
+}
+
+/// The CI system whose markers are added to the output on stdout.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CiSystem {
+    /// GitHub Actions: `::group::` and `::error::` workflow commands.
+    Github,
+    /// GitLab CI: collapsible `section_start` and `section_end` markers.
+    Gitlab,
+}
+
+/// The options for running a program.
+#[derive(Default)]
+pub struct SpawnOptions {
+    pub args: Vec<OsString>,
+    /// Passes this as `argv[0]` to the program instead of its name.
+    pub argv0: Option<OsString>,
+    /// Starts the program as a login shell by prefixing `argv[0]` with `-`.
+    pub login: bool,
+    /// Sets `TERM` for the program.  By default it's inherited, or
+    /// `xterm-256color` if it isn't set.
+    pub term: Option<String>,
+    pub out_path: Option<PathBuf>,
     pub truncate_out: bool,
+    pub no_clobber: bool,
+    pub atomic: bool,
+    pub lock: bool,
+    pub lock_wait: bool,
+    pub out_filter: Option<String>,
+    pub record_path: Option<PathBuf>,
+    pub record_format: RecordFormat,
+    /// Also records the input of the program.  Only asciicast recordings can
+    /// hold input.
+    pub record_input: bool,
+    /// Writes a JSON sidecar with the environment, host, user, terminal and
+    /// git commit of the session next to the output file (or the recording).
+    pub metadata: bool,
+    /// Redacts the variables whose names match one of these in the metadata,
+    /// in addition to the ones which look like secrets.
+    pub metadata_deny_env: Vec<Pattern>,
+    /// Computes the SHA-256 digest of what's written to the output file and
+    /// adds it to the metadata when the session ends.
+    pub checksum: bool,
+    /// Appends the output and the input to this hash-chained audit log.
+    pub audit_path: Option<PathBuf>,
+    /// Appends the head of the audit chain to this file when the session ends.
+    pub audit_head: Option<PathBuf>,
+    /// Appends the timing of the reads of typed keys, but not the keys, to
+    /// this file.  Nothing is recorded while a password is read.
+    pub input_timing_path: Option<PathBuf>,
+    /// Appends a hex dump of every read and write of the pty master, stdin
+    /// and the inputs to this file.
+    pub traffic_path: Option<PathBuf>,
+    pub heartbeat: Option<Duration>,
+    /// Reports the session as idle to the sinks and followers once there was
+    /// neither output nor input for this long, and as active again on the
+    /// next output or input.
+    pub idle_after: Option<Duration>,
+    /// Prints a line with the duration, the exit status and the bytes of
+    /// output and stdin to stderr when the session ends.
+    pub stats: bool,
+    /// Stops writing to the output file, the upload, the recording, the audit
+    /// log and the segments this long after the session started.  The program
+    /// keeps running and its output is still shown and sent to followers and
+    /// the other sinks.
+    pub record_for: Option<Duration>,
+    /// Continues the output in a new file this often.  The path of every file
+    /// is expanded from `out_template`.
+    pub segment_every: Option<Duration>,
+    /// The template `out_path` was expanded from.
+    pub out_template: Option<PathBuf>,
+    /// Stops reading the output once nothing is queued up in the pty this long
+    /// after the program exited, even if other processes still keep the pty
+    /// open.  By default the output is read until every process closed the
+    /// pty.
+    pub drain_timeout: Option<Duration>,
+    pub notify_url: Option<String>,
+    /// Writes the exit status of the program to this file as JSON when the
+    /// session ends.
+    pub exit_file: Option<PathBuf>,
+    /// Writes the pid of teetty and the pid of the program to this file while
+    /// the session runs.
+    pub pid_file: Option<PathBuf>,
+    /// Writes a JUnit XML report with the run of the program as a test case to
+    /// this file when the session ends.
+    pub junit_path: Option<PathBuf>,
+    /// Writes a TAP report with the run of the program as a test to this file
+    /// when the session ends.
+    pub tap_path: Option<PathBuf>,
+    /// How the exit status is reported if the program is killed by a signal.
+    pub exit_mode: ExitMode,
+    pub on_start: Option<String>,
+    pub on_exit: Option<String>,
+    pub on_failure: Option<String>,
+    /// A shell command to run when the program rings the bell with
+    /// `bell.notify`.
+    pub on_bell: Option<String>,
+    /// What happens when the program rings the bell.
+    pub bell: Bell,
+    pub metrics_addr: Option<String>,
+    pub serve_ws: Option<String>,
+    /// An installed `xterm` package for the viewer page of `serve_ws`.
+    pub viewer_assets: Option<PathBuf>,
+    pub serve_http: Option<String>,
+    pub http_token: Option<String>,
+    pub journald: bool,
+    pub out_url: Option<String>,
+    pub out_url_headers: Vec<String>,
     pub no_flush: bool,
-    pub in_path: Option<&'a Path>,
+    pub no_splice: bool,
+    pub buffer_size: Option<usize>,
+    /// Checks the size of the terminal this often and applies changes to the
+    /// pty for environments which don't deliver SIGWINCH.
+    pub poll_winsize: Option<Duration>,
+    /// The size of the pty as `(cols, rows)` when there is no terminal to take
+    /// it from.  Defaults to 80x24.
+    pub default_size: Option<(u16, u16)>,
+    pub in_paths: Vec<PathBuf>,
+    pub in_crlf: bool,
+    pub in_delay: Option<u64>,
+    pub in_line_delay: Option<u64>,
+    pub in_bracketed_paste: bool,
+    /// Shows the injected input on the terminal.
+    pub echo_input: bool,
+    /// Writes the injected input to the output file.
+    pub echo_input_log: bool,
+    pub prompt: Option<Pattern>,
+    /// Stops the program when its output matches and asks on the terminal
+    /// whether to continue, step to the next output or kill it.
+    pub break_on: Option<Pattern>,
+    /// Groups the commands marked by shell integration on stdout for the CI
+    /// system and annotates lines matching `ci_errors` as errors.
+    pub ci: Option<CiSystem>,
+    pub ci_errors: Vec<Pattern>,
+    pub script: Option<Script>,
+    pub escape_char: Option<u8>,
+    pub detachable: bool,
+    /// Leaves the terminal of this process in cooked mode: input is sent to
+    /// the program a line at a time and `^C` goes to teetty itself.
+    pub no_raw: bool,
+    /// Whether the pty becomes the controlling terminal of the program.
+    pub ctty: ControllingTerminal,
+    /// What the program gets as stdin.  Input sent to the pty can still be
+    /// read from `/dev/tty`.
+    pub stdin: ChildStdin,
+    /// Adds this to the nice value of the program, without changing the one
+    /// of teetty.
+    pub nice: Option<i32>,
+    /// Sets the I/O priority of the program (Linux only).
+    pub ionice: Option<IoPriority>,
+    /// Runs the program and everything it starts in this cgroup, which is
+    /// created if needed (Linux only).  Relative paths are relative to the
+    /// root of the cgroup2 hierarchy.
+    pub cgroup: Option<PathBuf>,
+    /// Restricts the system calls of the program with the seccomp profile at
+    /// this path (Linux only).
+    pub seccomp: Option<PathBuf>,
+    /// Runs the program in new namespaces of these kinds (Linux only).
+    pub unshare: Vec<Namespace>,
+    /// Whether the program may dump core and where the dumps go.
+    pub core: Option<CorePolicy>,
+    /// What happens when stdin of teetty reaches EOF.
+    pub on_stdin_eof: StdinEof,
+    /// Sets the title of the terminal while the session runs.
+    pub title: Option<String>,
+    /// Keeps the program from changing the title of the terminal.
+    pub strip_title: bool,
+    /// What happens when the program copies to the clipboard of the terminal.
+    pub clipboard: Clipboard,
+    /// Answers the queries of the program for the capabilities of the terminal
+    /// and the cursor position while there is no terminal to answer them.
+    pub answerback: bool,
+    /// Writes a record for every command the shell marks with OSC 133
+    /// sequences to this file.  If the path contains `{n}` every command gets a
+    /// file with its output instead.  The segments get the output after the
+    /// `transforms`, which have to keep the markers.
+    pub segment_path: Option<PathBuf>,
+    /// Records the session as a login in utmp and wtmp so that `who` and
+    /// `last` list it.
+    pub utmp: bool,
+    /// Runs the program without the terminal of this process: nothing is read
+    /// from stdin and the output is not written to stdout.
+    pub headless: bool,
+    /// Runs the session as a daemon: teetty forks twice, detaches from the
+    /// terminal and points its stdio to `/dev/null`.  The input only comes
+    /// from `in_paths` and the control socket.  [`spawn`] returns in the
+    /// original process once the program started.
+    pub daemon: bool,
+    /// Adopts this pty master instead of opening a pty and starting `args`.
+    /// The program is expected to run on the pty already, teetty only
+    /// captures its output and injects input until the pty is closed.
+    pub master_fd: Option<RawFd>,
+    pub session: Option<String>,
+    /// Transformations of the output before it's written to the output file
+    /// and handed to the sinks.
+    pub transforms: Vec<Box<dyn Transform>>,
+    /// Also applies the transformations to what's shown on the terminal and
+    /// sent to followers.
+    pub transform_terminal: bool,
+    /// Extra sinks which receive the output and the events of the session.
+    pub sinks: Vec<Box<dyn Sink>>,
+}
+
+impl SpawnOptions {
+    /// Creates the options for running the given command.
+    pub fn new<I, S>(args: I) -> SpawnOptions
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<OsString>,
+    {
+        SpawnOptions {
+            args: args.into_iter().map(Into::into).collect(),
+            ..SpawnOptions::default()
+        }
+    }
+
+    /// Returns the name of the program for output paths and logs.  An adopted
+    /// pty has no command, so it's `teetty` then.
+    fn command_name(&self) -> &OsStr {
+        self.args
+            .first()
+            .map_or("teetty".as_ref(), |x| x.as_os_str())
+    }
 }
 
 /// Spawns a process in a PTY in a manor similar to `script`
@@ -42,197 +626,1920 @@ pub struct SpawnOptions<'a> {
 /// It leaves stdin/stdout/stderr connected but also writes events into the
 /// optional `out` log file.  Additionally it can retrieve instructions from
 /// the given control socket.
-pub fn spawn(opts: &SpawnOptions) -> Result<i32, Error> {
+pub fn spawn(opts: SpawnOptions) -> Result<i32, SpawnError> {
+    spawn_with_remote(opts, None)
+}
+
+/// Like [`spawn`] but also takes commands from a
+/// [`Session`](crate::session::Session) handle.
+pub(crate) fn spawn_with_remote(
+    opts: SpawnOptions,
+    remote: Option<Remote>,
+) -> Result<i32, SpawnError> {
+    run_session(opts, remote).map_err(SpawnError::from_internal)
+}
+
+fn run_session(mut opts: SpawnOptions, remote: Option<Remote>) -> Result<i32, Error> {
+    // reported before a daemon loses stderr
+    if (opts.serve_http.is_some() || opts.serve_ws.is_some()) && opts.http_token.is_none() {
+        let token = generate_token().context("could not generate a token")?;
+        eprintln!(
+            "teetty: the token for the HTTP API and WebSocket is {}",
+            token
+        );
+        opts.http_token = Some(token);
+    }
+    // connected here so that a daemon can still report if it fails, the sink
+    // comes with the other sinks of the session afterwards
+    if opts.journald {
+        let journal = connect_journal(&opts)?;
+        opts.sinks.push(Box::new(journal));
+    }
+    if !opts.daemon {
+        return run_program(opts, remote, &mut None);
+    }
+    let Some(ready) = daemonize()? else {
+        return Ok(0);
+    };
+    opts.headless = true;
+    let mut ready = Some(ready);
+    run_program(opts, remote, &mut ready).inspect_err(|err| {
+        // the original process reports why the daemon could not start
+        if let Some(fd) = ready {
+            write_all_fd(fd, format!("{:#}", err).as_bytes()).ok();
+        }
+    })
+}
+
+/// Connects to journald with the fields describing the session.
+fn connect_journal(opts: &SpawnOptions) -> Result<JournalSink, Error> {
+    let command = opts
+        .args
+        .iter()
+        .map(|x| x.to_string_lossy())
+        .collect::<Vec<_>>()
+        .join(" ");
+    let identifier = Path::new(opts.command_name())
+        .file_name()
+        .map_or_else(|| "teetty".into(), |x| x.to_string_lossy());
+    let mut fields = vec![
+        ("PRIORITY", "6"),
+        ("SYSLOG_IDENTIFIER", &*identifier),
+        ("TEETTY_STREAM", "pty"),
+        ("TEETTY_COMMAND", &command),
+    ];
+    if let Some(session) = opts.session.as_deref() {
+        fields.push(("TEETTY_SESSION", session));
+    }
+    JournalSink::connect(&fields).context("could not connect to journald")
+}
+
+/// Runs the program in the session.  `ready` is notified once it started.
+fn run_program(
+    mut opts: SpawnOptions,
+    remote: Option<Remote>,
+    ready: &mut Option<i32>,
+) -> Result<i32, Error> {
+    let started = Instant::now();
+    let mut sinks = std::mem::take(&mut opts.sinks);
+    let tail = (opts.junit_path.is_some() || opts.tap_path.is_some()).then(OutputTail::default);
+    if let Some(ref tail) = tail {
+        sinks.push(Box::new(tail.clone()));
+    }
+    let pipeline = Pipeline::new(std::mem::take(&mut opts.transforms));
+    let opts = &opts;
+
+    // the shell only gets control back once the process it started exits, so
+    // detachable sessions run in a forked off process.
+    let detach_notify = if opts.detachable {
+        match fork_detachable()? {
+            DetachableFork::Front(status) => return Ok(finish_status(status, opts.exit_mode)),
+            DetachableFork::Session(fd) => Some(fd),
+        }
+    } else {
+        None
+    };
+
+    let mut registration = opts
+        .session
+        .as_deref()
+        .map(|name| Registration::claim(name, &opts.args))
+        .transpose()?;
+
     // if we can't retrieve the terminal atts we're not directly connected
     // to a pty in which case we won't do any of the terminal related
     // operations.
-    let term_attrs = tcgetattr(STDIN_FILENO).ok();
-    let winsize = term_attrs.as_ref().and_then(|_| get_winsize(STDIN_FILENO));
-
-    // Create the outer pty for stdout
-    let pty = openpty(&winsize, &term_attrs)?;
-
-    // This switches the terminal to raw mode and restores it on Drop.  Unfortunately
-    // due to all our shenanigans here we have no real guarantee that `Drop` is called
-    // so there will be cases where the term is left in raw state and requires a reset :(
-    let _restore_term = term_attrs.as_ref().map(|term_attrs| {
-        let mut raw_attrs = term_attrs.clone();
-        cfmakeraw(&mut raw_attrs);
-        raw_attrs.local_flags.remove(LocalFlags::ECHO);
-        tcsetattr(STDIN_FILENO, SetArg::TCSAFLUSH, &raw_attrs).ok();
-        RestoreTerm(term_attrs.clone())
+    let term_attrs = if opts.headless {
+        None
+    } else {
+        tcgetattr(STDIN_FILENO).ok()
+    };
+    if opts.break_on.is_some() && term_attrs.is_none() {
+        return Err(Error::msg("breakpoints need a terminal"));
+    }
+    let winsize = match term_attrs {
+        Some(_) => get_winsize(STDIN_FILENO),
+        None => Some(
+            opts.default_size
+                .map_or(DEFAULT_WINSIZE, |(cols, rows)| Winsize {
+                    ws_row: rows,
+                    ws_col: cols,
+                    ws_xpixel: 0,
+                    ws_ypixel: 0,
+                }),
+        ),
+    };
+
+    let pty_attrs = term_attrs.clone().map(|mut attrs| {
+        // in cooked mode the terminal already echoes what's typed
+        if opts.no_raw {
+            attrs.local_flags.remove(LocalFlags::ECHO);
+        }
+        attrs
     });
 
-    // crate a fifo if stdin is pointed to a non existing file
-    if let Some(ref path) = opts.in_path {
-        mkfifo_atomic(&path)?;
+    // Create the outer pty for stdout.  hooks, filters and the programs of
+    // other sessions must not keep the pty open.  The program gets its own
+    // copies as stdin/stdout/stderr.
+    let (master, slave) = match opts.master_fd {
+        // the program is already running on the pty we were given
+        Some(fd) => {
+            check_master_fd(fd)?;
+            let master = unsafe { OwnedFd::from_raw_fd(fd) };
+            fcntl(fd, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC))?;
+            if let (Some(_), Some(winsize)) = (&term_attrs, winsize) {
+                set_winsize(fd, winsize).ok();
+            }
+            (master, None)
+        }
+        None => {
+            let _guard = lock_fds();
+            let fds = open_pty(winsize.as_ref(), pty_attrs.as_ref())
+                .map_err(SpawnError::PtyOpenFailed)?;
+            for fd in [&fds.0, &fds.1] {
+                fcntl(fd.as_raw_fd(), FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC))?;
+            }
+            (fds.0, Some(fds.1))
+        }
+    };
+
+    // the output file is opened before the program starts so that problems with
+    // it are reported right away, and before raw mode so that waiting for its
+    // lock can be interrupted and is reported on a line of its own.
+    let mut out_file = opts
+        .out_path
+        .as_deref()
+        .map(|p| OutFile::open(p, opts))
+        .transpose()?;
+
+    // This switches the terminal to raw mode and restores it on Drop.  When
+    // `Drop` doesn't get to run, the hooks of `term::install_hooks` restore it.
+    let restore_term = term_attrs
+        .as_ref()
+        .filter(|_| !opts.no_raw)
+        .map(RestoreTerm::raw);
+
+    // crate fifos for input paths pointing to non existing files
+    for path in &opts.in_paths {
+        mkfifo_atomic(path)?;
+    }
+
+    let sidecar = opts.out_path.as_deref().or(opts.record_path.as_deref());
+    let mut metadata = match sidecar.map(metadata_path) {
+        Some(path) if opts.metadata => {
+            let env = child_env(opts, winsize);
+            let metadata = Metadata::collect(path, opts, env.iter().map(|x| x.as_bytes()));
+            metadata.write()?;
+            Some(metadata)
+        }
+        Some(path) if opts.checksum => Some(Metadata::new(path)),
+        None if opts.metadata => {
+            return Err(Error::msg("metadata needs an output file or a recording"))
+        }
+        _ => None,
+    };
+
+    // network endpoints are set up before the program starts so that a busy
+    // address is reported right away.
+    let mut servers = Servers {
+        metrics: opts.metrics_addr.as_deref().map(bind_http).transpose()?,
+        websocket: opts.serve_ws.as_deref().map(bind_http).transpose()?,
+        api: opts.serve_http.as_deref().map(bind_http).transpose()?,
+        token: opts.http_token.as_deref().map(|x| x.to_string()),
+        viewer: opts
+            .viewer_assets
+            .as_deref()
+            .map(ViewerAssets::load)
+            .transpose()?,
+        remote,
+        ..Servers::default()
+    };
+
+    // the login is recorded before the program starts so that sessions
+    // which can't be audited don't run at all
+    let login = if opts.utmp {
+        let slave = slave
+            .as_ref()
+            .ok_or_else(|| Error::msg("utmp records need a pty opened by teetty"))?;
+        Some(record_login(slave.as_raw_fd())?)
+    } else {
+        None
+    };
+
+    let cgroup = match (&opts.cgroup, &slave) {
+        (Some(path), Some(_)) => Some(Cgroup::open(path)?),
+        (Some(_), None) => return Err(Error::msg("cgroups need a program started by teetty")),
+        (None, _) => None,
+    };
+    let seccomp = opts
+        .seccomp
+        .as_deref()
+        .map(SeccompFilter::load)
+        .transpose()?;
+
+    let child = match slave {
+        Some(slave) => Some(start_program(
+            opts,
+            &master,
+            slave,
+            winsize,
+            cgroup.as_ref().map(Cgroup::procs_fd),
+            seccomp.as_ref(),
+        )?),
+        None => None,
+    };
+    if let Some(ref mut registration) = registration {
+        if let Some(child) = child {
+            registration.set_child(child)?;
+        }
+        servers.control = Some(ControlServer::bind(&registration.socket_path())?);
+    }
+    let pid_file = opts
+        .pid_file
+        .as_deref()
+        .map(|path| PidFile::write(path, child))
+        .transpose()?;
+    if let Some(fd) = ready.take() {
+        write_all_fd(fd, b"+")?;
+        close(fd)?;
+    }
+    let signals = if term_attrs.is_some() {
+        Some(signal_passthrough(master.as_raw_fd(), child)?)
+    } else {
+        None
+    };
+    let mut inputs = opts
+        .in_paths
+        .iter()
+        .map(|p| InputSource::open(p))
+        .collect::<Result<Vec<_>, _>>()?;
+    let hooks = Hooks::new(opts, child);
+    if let Some(ref hooks) = hooks {
+        hooks.run_start_hook();
+    }
+    let (status, metrics) = communication_loop(
+        master.as_raw_fd(),
+        child,
+        term_attrs.as_ref(),
+        out_file.as_mut(),
+        &mut inputs,
+        opts,
+        detach_notify,
+        servers,
+        sinks,
+        pipeline,
+        started,
+    )?;
+    let code = exit_code(status, opts.exit_mode);
+    drop(pid_file);
+    drop(signals);
+    drop(master);
+    drop(login);
+    // the terminal is restored first so that hooks can use it normally
+    drop(restore_term);
+    if let Some(out_file) = out_file {
+        let checksum = out_file.checksum();
+        if let Err(err) = out_file.finish() {
+            eprintln!("teetty: {:#}", err);
+        }
+        if let (Some(checksum), Some(ref mut metadata)) = (checksum, &mut metadata) {
+            metadata.set("checksum", checksum);
+            if let Err(err) = metadata.write() {
+                eprintln!("teetty: {:#}", err);
+            }
+        }
+    }
+    if let (Some(cgroup), Some(ref mut metadata)) = (cgroup, &mut metadata) {
+        metadata.set("cgroup", cgroup.stats());
+        if let Err(err) = metadata.write() {
+            eprintln!("teetty: {:#}", err);
+        }
+    }
+    if let Some(CorePolicy::Dir(ref dir)) = opts.core {
+        let since = SystemTime::now() - started.elapsed();
+        match coredump::collect(dir, since) {
+            Ok(cores) => {
+                for path in &cores {
+                    eprintln!("teetty: the program dumped core to {}", path.display());
+                }
+                if let (false, Some(ref mut metadata)) = (cores.is_empty(), &mut metadata) {
+                    let paths = cores.iter().map(|x| json::quote(&x.to_string_lossy()));
+                    metadata.set(
+                        "cores",
+                        format!("[{}]", paths.collect::<Vec<_>>().join(",")),
+                    );
+                    if let Err(err) = metadata.write() {
+                        eprintln!("teetty: {:#}", err);
+                    }
+                }
+            }
+            Err(err) => eprintln!("teetty: {:#}", err),
+        }
+    }
+    if let Some(path) = opts.exit_file.as_deref() {
+        if let Err(err) = write_exit_file(path, status) {
+            eprintln!("teetty: {:#}", err);
+        }
+    }
+    let tail = tail.map(|x| x.contents()).unwrap_or_default();
+    if let Some(path) = opts.junit_path.as_deref() {
+        if let Err(err) = write_junit_report(path, opts, status, started.elapsed(), &tail) {
+            eprintln!("teetty: {:#}", err);
+        }
+    }
+    if let Some(path) = opts.tap_path.as_deref() {
+        if let Err(err) = write_tap_report(path, opts, status, started.elapsed(), &tail) {
+            eprintln!("teetty: {:#}", err);
+        }
+    }
+    if let Some(url) = opts.notify_url.as_deref() {
+        if let Err(err) = post_json(url, &session_summary(opts, code, started.elapsed())) {
+            eprintln!("teetty: could not notify {}: {}", url, err);
+        }
+    }
+    if let Some(hooks) = hooks {
+        hooks.run_exit_hooks(code, started.elapsed());
+    }
+    if opts.bell.count {
+        eprintln!("teetty: the bell rang {} times", metrics.bells());
+        if let Some(ref mut metadata) = metadata {
+            metadata.set("bells", metrics.bells().to_string());
+            if let Err(err) = metadata.write() {
+                eprintln!("teetty: {:#}", err);
+            }
+        }
+    }
+    if opts.stats {
+        eprintln!(
+            "teetty: {}",
+            stats_line(
+                status,
+                started.elapsed(),
+                metrics.output_bytes(),
+                metrics.typed_bytes()
+            )
+        );
+    }
+    Ok(finish_status(Some(status), opts.exit_mode))
+}
+
+/// Starts the program on the slave of the pty and returns its pid once it was
+/// executed.
+fn start_program(
+    opts: &SpawnOptions,
+    master: &OwnedFd,
+    slave: OwnedFd,
+    winsize: Option<Winsize>,
+    cgroup: Option<RawFd>,
+    seccomp: Option<&SeccompFilter>,
+) -> Result<Pid, Error> {
+    // nothing must be allocated between forking and executing the program
+    let mut args = opts
+        .args
+        .iter()
+        .filter_map(|x| CString::new(x.as_os_str().as_bytes()).ok())
+        .collect::<Vec<_>>();
+    if args.is_empty() {
+        return Err(SpawnError::InvalidOptions("no command given to run".into()).into());
     }
+    let program = args[0].clone();
+    if opts.argv0.is_some() || opts.login {
+        args[0] = program_name(opts)?;
+    }
+    let env = child_env(opts, winsize);
+    let arg_ptrs = null_terminated(&args);
+    let env_ptrs = null_terminated(&env);
+
+    // the child reports a failure to execute the program through this pipe.
+    // The write end is closed on a successful exec.
+    let (exec_r, exec_w) = cloexec_pipe()?;
+    let (exec_r, exec_w) = unsafe { (OwnedFd::from_raw_fd(exec_r), OwnedFd::from_raw_fd(exec_w)) };
+    let namespaces = if opts.unshare.is_empty() {
+        None
+    } else {
+        Some(Namespaces::new(&opts.unshare)?)
+    };
 
     // Fork and establish the communication loop in the parent.  This unfortunately
     // has to merge stdout/stderr since the pseudo terminal only has one stream for
     // both.
-    if let ForkResult::Parent { child } = unsafe { fork()? } {
-        close(pty.slave)?;
-        if term_attrs.is_some() {
-            sigwinch_passthrough(pty.master)?;
-        }
-        let mut out_file = match opts.out_path {
-            Some(p) => Some(
-                File::options()
-                    .append(true)
-                    .create(true)
-                    .truncate(opts.truncate_out)
-                    .open(p)?,
-            ),
-            None => None,
-        };
-        let mut in_file = match opts.in_path {
-            Some(p) => Some(
-                File::options()
-                    .read(true)
-                    .custom_flags(O_NONBLOCK)
-                    .open(p)?,
-            ),
-            None => None,
-        };
-        return Ok(communication_loop(
-            pty.master,
-            child,
-            term_attrs.is_some(),
-            out_file.as_mut(),
-            in_file.as_mut(),
-            !opts.no_flush,
-        )?);
+    let fork_result = {
+        let _guard = lock_fds();
+        match namespaces {
+            Some(ref namespaces) => unsafe { namespaces.fork() },
+            None => unsafe { fork() },
+        }
+        .map_err(SpawnError::ForkFailed)?
+    };
+    if let ForkResult::Parent { child } = fork_result {
+        drop(slave);
+        drop(exec_w);
+        let exec_error = read_exec_error(exec_r.as_raw_fd());
+        drop(exec_r);
+        if let Some((step, errno)) = exec_error {
+            waitpid(child, None).ok();
+            return Err(match step {
+                Some(step) => SpawnError::SetupFailed { step, errno },
+                None => SpawnError::ExecFailed {
+                    program: opts.args[0].clone(),
+                    errno,
+                },
+            }
+            .into());
+        }
+        debug!("started {:?} as {}", opts.args, child);
+        return Ok(child);
     }
 
     // If we reach this point we're the child and we want to turn into the
     // target executable after having set up the tty with `login_tty` which
-    // rebinds stdin/stdout/stderr to the pty.
-    let args = opts
-        .args
-        .iter()
-        .filter_map(|x| CString::new(x.as_os_str().as_bytes()).ok())
-        .collect::<Vec<_>>();
-    close(pty.master)?;
+    // rebinds stdin/stdout/stderr to the pty.  Without a controlling terminal
+    // only the fds are rebound.
     unsafe {
-        login_tty(pty.slave);
+        libc::close(master.as_raw_fd());
+        let slave = slave.into_raw_fd();
+        match opts.ctty {
+            ControllingTerminal::Pty => {
+                login_tty(slave);
+            }
+            ControllingTerminal::NewSession | ControllingTerminal::Unchanged => {
+                if opts.ctty == ControllingTerminal::NewSession {
+                    libc::setsid();
+                }
+                for fd in [STDIN_FILENO, STDOUT_FILENO, STDERR_FILENO] {
+                    libc::dup2(slave, fd);
+                }
+                if slave > STDERR_FILENO {
+                    libc::close(slave);
+                }
+            }
+        }
+        match opts.stdin {
+            ChildStdin::Pty => {}
+            ChildStdin::Null => {
+                let null = libc::open(c"/dev/null".as_ptr(), libc::O_RDONLY);
+                if null >= 0 && null != STDIN_FILENO {
+                    libc::dup2(null, STDIN_FILENO);
+                    libc::close(null);
+                }
+            }
+            ChildStdin::Closed => {
+                libc::close(STDIN_FILENO);
+            }
+        }
+        let fail = |step: i32, errno: Errno| -> ! {
+            let mut report = [0; 8];
+            report[..4].copy_from_slice(&step.to_ne_bytes());
+            report[4..].copy_from_slice(&(errno as i32).to_ne_bytes());
+            write(exec_w.as_raw_fd(), &report).ok();
+            nix::libc::_exit(127);
+        };
+        if let Some(fd) = cgroup {
+            if libc::write(fd, b"0".as_ptr().cast(), 1) < 0 {
+                fail(SetupStep::Cgroup as i32, Errno::last());
+            }
+        }
+        if let Some(nice) = opts.nice {
+            Errno::clear();
+            if libc::nice(nice) == -1 && Errno::last() != Errno::UnknownErrno {
+                fail(SetupStep::Nice as i32, Errno::last());
+            }
+        }
+        if let Some(ionice) = opts.ionice {
+            if let Err(errno) = set_io_priority(ionice) {
+                fail(SetupStep::IoPriority as i32, errno);
+            }
+        }
+        if let Some(ref core) = opts.core {
+            if let Err(errno) = set_core_limit(core) {
+                fail(SetupStep::CoreLimit as i32, errno);
+            }
+        }
+        if let Some(ref namespaces) = namespaces {
+            if let Err((step, errno)) = namespaces.enter(exec_w.as_raw_fd()) {
+                fail(step as i32, errno);
+            }
+        }
+        // last, so that the filter only has to allow what the program needs
+        if let Some(filter) = seccomp {
+            if let Err(errno) = filter.install() {
+                fail(SetupStep::Seccomp as i32, errno);
+            }
+        }
+        fail(0, exec_program(&program, &arg_ptrs, &env_ptrs));
+    }
+}
+
+/// Returns the environment of the program.
+///
+/// Unless it's given in the options, `TERM` is inherited or `xterm-256color`
+/// if it's not set.  `COLUMNS` and `LINES` match the size of the pty.
+fn child_env(opts: &SpawnOptions, winsize: Option<Winsize>) -> Vec<CString> {
+    let term = opts
+        .term
+        .clone()
+        .or_else(|| std::env::var("TERM").ok().filter(|x| !x.is_empty()))
+        .unwrap_or_else(|| DEFAULT_TERM.to_string());
+    let mut overrides = vec![("TERM", term)];
+    if let Some(winsize) = winsize {
+        overrides.push(("COLUMNS", winsize.ws_col.to_string()));
+        overrides.push(("LINES", winsize.ws_row.to_string()));
+    }
+    std::env::vars_os()
+        .filter(|(key, _)| !overrides.iter().any(|x| key == x.0))
+        .map(|(key, value)| [key.as_bytes(), b"=", value.as_bytes()].concat())
+        .chain(
+            overrides
+                .iter()
+                .map(|(key, value)| format!("{}={}", key, value).into_bytes()),
+        )
+        .filter_map(|x| CString::new(x).ok())
+        .collect()
+}
+
+/// Returns the pointers to the strings for `execve` which have to outlive it.
+fn null_terminated(strings: &[CString]) -> Vec<*const libc::c_char> {
+    strings
+        .iter()
+        .map(|x| x.as_ptr())
+        .chain(std::iter::once(std::ptr::null()))
+        .collect()
+}
+
+/// Executes the program with the given arguments and environment which are
+/// null terminated.  This only returns on errors.
+///
+/// This doesn't allocate as it runs between forking and executing.
+unsafe fn exec_program(
+    program: &CString,
+    args: &[*const libc::c_char],
+    env: &[*const libc::c_char],
+) -> Errno {
+    #[cfg(target_os = "linux")]
+    libc::execvpe(program.as_ptr(), args.as_ptr(), env.as_ptr());
+    #[cfg(not(target_os = "linux"))]
+    {
+        extern "C" {
+            static mut environ: *const *const libc::c_char;
+        }
+        environ = env.as_ptr();
+        libc::execvp(program.as_ptr(), args.as_ptr());
+    }
+    Errno::last()
+}
+
+/// Checks that an fd to adopt is the master of a pty before taking it over.
+fn check_master_fd(fd: RawFd) -> Result<(), Error> {
+    let invalid = |message| Err(SpawnError::InvalidOptions(message).into());
+    if (0..=2).contains(&fd) {
+        return invalid(format!("fd {} is stdio, not a pty master", fd));
+    }
+    if fcntl(fd, FcntlArg::F_GETFD).is_err() {
+        return invalid(format!("fd {} is not open", fd));
+    }
+    // slaves are terminals as well but have no slave of their own
+    if !isatty(fd).unwrap_or(false) || unsafe { libc::ptsname(fd) }.is_null() {
+        return invalid(format!("fd {} is not a pty master", fd));
+    }
+    Ok(())
+}
+
+/// Returns `argv[0]` for the program.  Login shells get the name of the
+/// program prefixed with `-`.
+fn program_name(opts: &SpawnOptions) -> Result<CString, Error> {
+    let name = opts.argv0.as_deref().unwrap_or(&opts.args[0]).as_bytes();
+    let name = if opts.login {
+        let base = name.rsplit(|&c| c == b'/').next().unwrap_or(name);
+        [b"-", base].concat()
+    } else {
+        name.to_vec()
+    };
+    CString::new(name).context("argv[0] must not contain NUL bytes")
+}
+
+/// Records a login on the pty for teetty which owns the session.
+#[cfg(all(target_os = "linux", target_env = "gnu"))]
+fn record_login(slave: c_int) -> Result<crate::utmp::Login, Error> {
+    let tty = nix::unistd::ttyname(slave).context("could not find the name of the pty")?;
+    crate::utmp::Login::record(&tty, getpid())
+}
+
+#[cfg(not(all(target_os = "linux", target_env = "gnu")))]
+fn record_login(_slave: c_int) -> Result<(), Error> {
+    Err(SpawnError::InvalidOptions("utmp records are not supported on this platform".into()).into())
+}
+
+/// Held while file descriptors are created that are not close-on-exec yet and
+/// while forking so that the programs of concurrent sessions never inherit
+/// them.
+static FD_LOCK: Mutex<()> = Mutex::new(());
+
+fn lock_fds() -> MutexGuard<'static, ()> {
+    FD_LOCK.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Creates a pipe which is closed on exec.
+pub(crate) fn cloexec_pipe() -> Result<(i32, i32), Errno> {
+    let _guard = lock_fds();
+    let (r, w) = pipe()?;
+    for fd in [r, w] {
+        if let Err(err) = fcntl(fd, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC)) {
+            close(r).ok();
+            close(w).ok();
+            return Err(err);
+        }
     }
-    execvp(&args[0], &args)?;
-    unreachable!();
+    Ok((r, w))
 }
 
-/// Listens to a SIGWINCH signal in a background thread and forwards it to the pty.
-fn sigwinch_passthrough(master: i32) -> Result<(), Errno> {
-    // this does not seem to work properly with vim at least.  It's probably that the
-    // killpg is going to the wrong process?
+/// Waits for the child to execute the program and returns why it failed to.
+fn read_exec_error(fd: i32) -> Option<(Option<SetupStep>, Errno)> {
+    let mut buf = [0; 8];
+    let mut len = 0;
+    while len < buf.len() {
+        match read(fd, &mut buf[len..]) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(Errno::EINTR) => continue,
+            Err(_) => break,
+        }
+    }
+    let step = i32::from_ne_bytes(buf[..4].try_into().unwrap());
+    let errno = i32::from_ne_bytes(buf[4..].try_into().unwrap());
+    (len == buf.len()).then(|| (SetupStep::from_i32(step), Errno::from_i32(errno)))
+}
+
+/// Sets the soft limit for core dumps of this process to 0 when they are
+/// disabled, or to the hard limit when they are collected.
+///
+/// This runs between forking and executing so it must not allocate.
+fn set_core_limit(core: &CorePolicy) -> Result<(), Errno> {
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    Errno::result(unsafe { libc::getrlimit(libc::RLIMIT_CORE, &mut limit) })?;
+    limit.rlim_cur = match core {
+        CorePolicy::Disable => 0,
+        CorePolicy::Dir(_) => limit.rlim_max,
+    };
+    Errno::result(unsafe { libc::setrlimit(libc::RLIMIT_CORE, &limit) }).map(drop)
+}
+
+/// Sets the I/O priority of this process.
+///
+/// This runs between forking and executing so it must not allocate.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn set_io_priority(priority: IoPriority) -> Result<(), Errno> {
+    const IOPRIO_WHO_PROCESS: c_int = 1;
+    let rv = unsafe {
+        libc::syscall(
+            libc::SYS_ioprio_set,
+            IOPRIO_WHO_PROCESS,
+            0,
+            priority.value(),
+        )
+    };
+    Errno::result(rv).map(drop)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn set_io_priority(_priority: IoPriority) -> Result<(), Errno> {
+    Err(Errno::ENOSYS)
+}
+
+/// Listens to SIGWINCH, SIGTSTP and SIGCONT in a background thread and passes
+/// them on to the session.
+///
+/// On SIGWINCH the new size is forwarded to the pty.  Setting the size on the
+/// pty makes the kernel send SIGWINCH to the foreground process group of the
+/// pty itself, so the program is not signalled here.
+///
+/// On SIGTSTP the whole session is suspended: the program is stopped, the
+/// terminal is restored from raw mode and teetty stops itself.  When it's
+/// continued the terminal goes back to raw mode (unless teetty continues in
+/// the background), picks up the size the terminal got in the meantime and the
+/// program continues.
+///
+/// The thread stops once the returned handle is closed.
+fn signal_passthrough(master: i32, child: Option<Pid>) -> Result<SignalGuard, Error> {
+    let mut signals = Signals::new([SIGWINCH, SIGTSTP, SIGCONT])?;
+    let handle = signals.handle();
     std::thread::spawn(move || {
-        for _ in &mut Signals::new(&[SIGWINCH]).unwrap() {
-            if let Some(winsize) = get_winsize(STDIN_FILENO) {
-                set_winsize(master, winsize).ok();
-                if let Ok(pgrp) = tcgetpgrp(master) {
-                    killpg(pgrp, Signal::SIGWINCH).ok();
+        for signal in &mut signals {
+            debug!(
+                "received {}",
+                Signal::try_from(signal).map_or("a signal", |x| x.as_str())
+            );
+            match signal {
+                SIGTSTP => {
+                    // the program runs in a session of its own, so to the
+                    // kernel its process group is orphaned and SIGTSTP would
+                    // be discarded.  SIGSTOP always stops it.
+                    signal_foreground(master, child, Signal::SIGSTOP);
+                    term::leave_raw();
+                    // SIGSTOP can't be caught, so this really stops until
+                    // SIGCONT arrives.
+                    nix::sys::signal::raise(Signal::SIGSTOP).ok();
+                }
+                SIGCONT => {
+                    term::enter_raw();
+                    if let Some(winsize) = get_winsize(STDIN_FILENO) {
+                        set_winsize(master, winsize).ok();
+                    }
+                    signal_foreground(master, child, Signal::SIGCONT);
+                }
+                _ => {
+                    if let Some(winsize) = get_winsize(STDIN_FILENO) {
+                        set_winsize(master, winsize).ok();
+                    }
                 }
             }
         }
     });
-    Ok(())
+    Ok(SignalGuard(handle))
 }
 
+/// Stops passing on signals on drop.
+struct SignalGuard(SignalsHandle);
+
+impl Drop for SignalGuard {
+    fn drop(&mut self) {
+        self.0.close();
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 fn communication_loop(
     master: i32,
-    child: Pid,
-    is_tty: bool,
-    mut out_file: Option<&mut File>,
-    mut in_file: Option<&mut File>,
-    flush: bool,
-) -> Result<i32, Error> {
-    let mut buf = [0; 4096];
-    let mut read_stdin = true;
+    child: Option<Pid>,
+    term_attrs: Option<&Termios>,
+    mut out_file: Option<&mut OutFile>,
+    inputs: &mut [InputSource],
+    opts: &SpawnOptions,
+    mut detach_notify: Option<i32>,
+    mut servers: Servers,
+    mut extra_sinks: Vec<Box<dyn Sink>>,
+    mut pipeline: Pipeline,
+    started: Instant,
+) -> Result<(WaitStatus, Metrics), Error> {
+    let mut is_tty = term_attrs.is_some();
+    let mut escape = opts.escape_char.filter(|_| is_tty).map(EscapeDetector::new);
+    let mut keyboard = Vec::new();
+    let flush = !opts.no_flush;
+    let mut buf = vec![0; opts.buffer_size.unwrap_or(DEFAULT_BUFFER_SIZE)];
+    let mut read_stdin = !opts.headless;
+    let mut pacer = Pacer::new(
+        opts.in_delay.map(Duration::from_millis),
+        opts.in_line_delay.map(Duration::from_millis),
+        opts.prompt.is_some(),
+    );
+    let mut prompt_window = Vec::new();
+    let mut breakpoint = opts.break_on.as_ref().map(Breakpoint::new);
+    let mut paced = Vec::new();
+
+    // to wrap injected input in bracketed paste markers we need to know if the
+    // child enabled bracketed paste mode.
+    let mut output_parser = opts.in_bracketed_paste.then(Parser::default);
+    let mut bracketed_paste = false;
+
+    let mut transformed = Vec::new();
+
+    let mut answerback = opts.answerback.then(Answerback::new);
+    let mut segmenter = opts
+        .segment_path
+        .as_deref()
+        .map(Segmenter::create)
+        .transpose()?;
+    let mut replies = Vec::new();
+
+    let mut script = opts.script.as_ref().map(ScriptRunner::new);
+    let mut script_actions = Vec::new();
+
+    let mut metrics = Metrics::default();
+    let mut control_commands = Vec::new();
+    let mut idle = false;
+    let mut last_bell: Option<Instant> = None;
+    let mut next_segment = opts
+        .segment_every
+        .zip(opts.out_template.as_deref())
+        .map(|(every, template)| (started + every, every, template));
+
+    // heartbeats go on a line of their own in the log
+    let mut last_output = Instant::now();
+    let mut log_at_line_start = true;
+
+    // the built-in sinks go first, then the ones of the embedder
+    let mut sinks: Vec<Box<dyn Sink>> = Vec::new();
+    if let Some(url) = opts.out_url.as_deref() {
+        sinks.push(Box::new(Upload::start(url, &opts.out_url_headers)?));
+    }
+    if let Some(path) = opts.record_path.as_deref() {
+        sinks.push(match opts.record_format {
+            RecordFormat::Asciicast => Box::new(Recorder::create(
+                path,
+                get_winsize(master),
+                &opts.args,
+                flush,
+                opts.record_input,
+            )?),
+            RecordFormat::Ttyrec => Box::new(ttyrec::Recorder::create(path, flush)?),
+        });
+    }
+    if let Some(path) = opts.audit_path.as_deref() {
+        sinks.push(Box::new(AuditLog::open(path, opts.audit_head.as_deref())?));
+    }
+    // `record_for` only stops these recordings of the output
+    let mut recordings = sinks.len();
+    if let Some(path) = opts.input_timing_path.as_deref() {
+        sinks.push(Box::new(KeystrokeTiming::create(path, master)?));
+    }
+    let mut traffic = opts
+        .traffic_path
+        .as_deref()
+        .map(TrafficDump::create)
+        .transpose()?;
+    if opts.echo_input || opts.echo_input_log {
+        let log = match out_file {
+            Some(ref file) if opts.echo_input_log => Some(file.try_clone_file()?),
+            _ => None,
+        };
+        sinks.push(Box::new(InputEcho::new(
+            opts.echo_input && !opts.headless,
+            log,
+        )));
+    }
+    sinks.append(&mut extra_sinks);
+    let mut last_winsize = get_winsize(master).map(|x| (x.ws_row, x.ws_col));
+
+    // only changes of the size of our terminal are applied so that polling
+    // doesn't undo resizes through the control socket.
+    let mut winsize_poll = opts.poll_winsize.filter(|_| is_tty).map(|interval| {
+        let size = get_winsize(STDIN_FILENO).map(|x| (x.ws_row, x.ws_col));
+        (interval, Instant::now() + interval, size)
+    });
+
+    let inspect_output = output_parser.is_some()
+        || answerback.is_some()
+        || segmenter.is_some()
+        || opts.prompt.is_some()
+        || opts.break_on.is_some()
+        || script.is_some()
+        || !sinks.is_empty();
+    // on Linux the output can be moved to stdout and the log file without copying
+    // it through our buffer.  If the kernel can't splice from the pty we forget
+    // about the splicer the first time it's used.
+    let mut passthrough = Passthrough::new(opts)?;
+    let mut filtered = Vec::new();
+    let mut ci = opts
+        .ci
+        .filter(|_| !opts.headless)
+        .map(|system| CiAnnotator::new(system, &opts.ci_errors));
+    let mut annotated = Vec::new();
+    #[cfg(target_os = "linux")]
+    let mut splicer = if !opts.no_splice
+        && !opts.headless
+        && pipeline.is_empty()
+        && passthrough.is_none()
+        && ci.is_none()
+        && !inspect_output
+        && !opts.checksum
+        && traffic.is_none()
+    {
+        Some(crate::splice::Splicer::new(out_file.is_some())?)
+    } else {
+        None
+    };
+
+    let title = opts.title.as_deref().filter(|_| is_tty);
+    if let Some(title) = title {
+        write_all_fd(STDOUT_FILENO, &set_title(title))?;
+    }
+
+    // after a shutdown the program is killed at the first deadline and the
+    // session stops at the second one, even if something kept the pty open.
+    let mut shutdown: Option<(Instant, bool)> = None;
+
+    // with a drain timeout the exit of the program is noticed in the loop and
+    // what's still queued up in the pty is read until the deadline.
+    let mut exited: Option<(WaitStatus, Instant)> = None;
+
+    // in the background reading stops teetty with SIGTTIN, so only the output
+    // is logged until the session is in the foreground again.  Shells don't
+    // necessarily signal a running job brought to the foreground, so this is
+    // checked frequently while in the background.
+    let mut background = false;
 
     loop {
-        let mut read_fds = FdSet::new();
-        let mut timeout = TimeVal::new(1, 0);
-        read_fds.insert(master);
+        if is_tty {
+            let was_background = background;
+            background = term::in_background();
+            if was_background != background {
+                debug!(
+                    "now in the {}",
+                    if background {
+                        "background"
+                    } else {
+                        "foreground"
+                    }
+                );
+            }
+            if was_background && !background {
+                term::enter_raw();
+            }
+        }
+        let mut fds = PollSet::new();
+        let heartbeat_deadline = opts.heartbeat.map(|x| last_output + x);
+        // checked before waiting so that activity is reported right after it
+        if let Some(threshold) = opts.idle_after {
+            if idle != (metrics.last_activity().elapsed() >= threshold) {
+                idle = !idle;
+                debug!("session is {}", if idle { "idle" } else { "active" });
+                let event = if idle { Event::Idle } else { Event::Active };
+                emit(&mut sinks, &event)?;
+                servers
+                    .followers
+                    .send_event(if idle { "idle" } else { "active" });
+            }
+        }
+        let segment_deadline = next_segment.map(|x| x.0);
+        let record_for = opts
+            .record_for
+            .filter(|_| out_file.is_some() || recordings > 0 || segmenter.is_some());
+        let record_deadline = record_for.map(|x| started + x);
+        let idle_deadline = opts
+            .idle_after
+            .filter(|_| !idle)
+            .map(|x| metrics.last_activity() + x);
+        let timeout = loop_timeout(&[
+            pacer.timeout(),
+            idle_deadline.map(|x| x.saturating_duration_since(Instant::now())),
+            record_deadline.map(|x| x.saturating_duration_since(Instant::now())),
+            segment_deadline.map(|x| x.saturating_duration_since(Instant::now())),
+            script.as_ref().and_then(|x| x.timeout()),
+            heartbeat_deadline.map(|x| x.saturating_duration_since(Instant::now())),
+            shutdown.map(|x| x.0.saturating_duration_since(Instant::now())),
+            exited.map(|x| x.1.saturating_duration_since(Instant::now())),
+            winsize_poll.map(|x| x.1.saturating_duration_since(Instant::now())),
+            background.then_some(BACKGROUND_POLL_INTERVAL),
+            servers.timeout(),
+        ]);
+        fds.insert_read(master);
         if !read_stdin && is_tty {
             read_stdin = true;
         }
-        if read_stdin {
-            read_fds.insert(STDIN_FILENO);
+        if read_stdin && !background {
+            fds.insert_read(STDIN_FILENO);
+        }
+        for fd in inputs.iter().filter_map(|x| x.fd()) {
+            fds.insert_read(fd);
+        }
+        servers.watch(&mut fds);
+        let n = continue_on_eintr!(fds.poll(timeout));
+        trace!(
+            "woke up with {} ready fds (program: {}, stdin: {})",
+            n,
+            fds.readable(master),
+            fds.readable(STDIN_FILENO)
+        );
+
+        if let Some((deadline, killed)) = shutdown {
+            if deadline <= Instant::now() {
+                if killed {
+                    break;
+                }
+                debug!("killing the program after the grace period");
+                signal_session(master, child, Signal::SIGKILL);
+                shutdown = Some((Instant::now() + KILL_DRAIN_TIMEOUT, true));
+            }
+        }
+
+        if let Some((interval, ref mut deadline, ref mut size)) = winsize_poll {
+            if is_tty && *deadline <= Instant::now() {
+                if let Some(winsize) = get_winsize(STDIN_FILENO) {
+                    if *size != Some((winsize.ws_row, winsize.ws_col)) {
+                        *size = Some((winsize.ws_row, winsize.ws_col));
+                        set_winsize(master, winsize).ok();
+                    }
+                }
+                *deadline = Instant::now() + interval;
+            }
+        }
+
+        if let Some(drain_timeout) = opts.drain_timeout {
+            match exited {
+                // what's queued up at the deadline is still read
+                Some((_, deadline)) if deadline <= Instant::now() => {
+                    if !fds.readable(master) {
+                        debug!("drain timeout reached");
+                        break;
+                    }
+                }
+                Some(_) => {}
+                None => {
+                    if let Some(Ok(status @ (WaitStatus::Exited(..) | WaitStatus::Signaled(..)))) =
+                        child.map(|x| waitpid(x, Some(WaitPidFlag::WNOHANG)))
+                    {
+                        debug!("program exited ({:?}), draining the output", status);
+                        exited = Some((status, Instant::now() + drain_timeout));
+                    }
+                }
+            }
+        }
+
+        if let Some(ref mut script) = script {
+            let sent = run_script(
+                script,
+                &mut script_actions,
+                master,
+                child,
+                &mut traffic,
+                &mut pacer,
+                is_tty,
+            )?;
+            record_injected(&mut metrics, &mut sinks, &sent)?;
+        }
+        paced.clear();
+        if pacer.pop_due(&mut paced) {
+            write_master(master, &mut traffic, &paced)?;
+        }
+        if let (Some(deadline), Some(ref mut logfile)) = (heartbeat_deadline, &mut out_file) {
+            if deadline <= Instant::now() {
+                let marker = format!(
+                    "{}[still running {}]\r\n",
+                    if log_at_line_start { "" } else { "\r\n" },
+                    format_utc(SystemTime::now())
+                );
+                logfile.write_all(marker.as_bytes())?;
+                if flush {
+                    logfile.flush()?;
+                }
+                last_output = Instant::now();
+                log_at_line_start = true;
+            }
+        }
+        if let (Some((deadline, every, template)), Some(logfile)) =
+            (&mut next_segment, &mut out_file)
+        {
+            if *deadline <= Instant::now() {
+                let path = template::expand_output_path(
+                    template,
+                    opts.command_name(),
+                    opts.session.as_deref(),
+                    SystemTime::now(),
+                )?;
+                // the template doesn't change that often
+                if path != logfile.path() {
+                    debug!("continuing the output in {}", path.display());
+                    logfile.rotate(&path, opts)?;
+                    log_at_line_start = true;
+                }
+                *deadline += *every;
+            }
+        }
+        if let Some(record_for) = record_for.filter(|&x| started + x <= Instant::now()) {
+            debug!("stopping the recording");
+            let marker = format!(
+                "{}[recording stopped after {}s]\r\n",
+                if log_at_line_start { "" } else { "\r\n" },
+                record_for.as_secs_f64()
+            );
+            let mut stopped: Vec<_> = sinks.drain(..recordings).collect();
+            write_captured(
+                &mut out_file,
+                &mut stopped,
+                marker.as_bytes(),
+                flush,
+                started,
+            )?;
+            finish_sinks(&mut stopped, is_tty);
+            recordings = 0;
+            out_file = None;
+            if let Some(mut segmenter) = segmenter.take() {
+                segmenter.finish()?;
+            }
         }
-        if let Some(ref f) = in_file {
-            read_fds.insert(f.as_raw_fd());
+        if !servers.is_empty() || !sinks.is_empty() {
+            if let Some(winsize) = get_winsize(master) {
+                let size = (winsize.ws_row, winsize.ws_col);
+                if last_winsize != Some(size) {
+                    last_winsize = Some(size);
+                    emit(
+                        &mut sinks,
+                        &Event::Resized {
+                            rows: size.0,
+                            cols: size.1,
+                        },
+                    )?;
+                }
+                servers.followers.set_winsize(size.0, size.1);
+            }
         }
-        let n = continue_on_eintr!(select(
-            None,
-            Some(&mut read_fds),
-            None,
-            None,
-            Some(&mut timeout)
-        ));
         if n == 0 {
             continue;
         }
 
-        if read_fds.contains(STDIN_FILENO) {
-            match continue_on_eintr!(read(STDIN_FILENO, &mut buf)) {
+        // followers need to see the output so it can't bypass us anymore.  A
+        // SIGWINCH makes most full screen programs redraw for the new follower.
+        let status = || session_status(opts, master, child, &metrics);
+        if servers.handle(&fds, &metrics, &status, &mut control_commands) {
+            #[cfg(target_os = "linux")]
+            {
+                splicer = None;
+            }
+            signal_foreground(master, child, Signal::SIGWINCH);
+        }
+        for command in control_commands.drain(..) {
+            debug!("control command: {:?}", command);
+            match command {
+                ControlCommand::Signal(signal) => signal_foreground(master, child, signal),
+                ControlCommand::Input(data) => {
+                    if pacer.is_active() {
+                        pacer.push(&data);
+                    } else {
+                        write_master(master, &mut traffic, &data)?;
+                    }
+                    record_injected(&mut metrics, &mut sinks, &data)?;
+                }
+                ControlCommand::Resize(rows, cols) => {
+                    set_winsize(
+                        master,
+                        Winsize {
+                            ws_row: rows,
+                            ws_col: cols,
+                            ws_xpixel: 0,
+                            ws_ypixel: 0,
+                        },
+                    )?;
+                    // recorded right away as the program might not redraw
+                    if last_winsize != Some((rows, cols)) {
+                        last_winsize = Some((rows, cols));
+                        emit(&mut sinks, &Event::Resized { rows, cols })?;
+                    }
+                }
+                // a second shutdown can only make it quicker
+                ControlCommand::Shutdown(mode) => {
+                    let grace = match mode {
+                        ShutdownMode::Eof(grace) => {
+                            let eof = tcgetattr(master).map_or(0x04, |x| x.control_chars[VEOF]);
+                            write_master(master, &mut traffic, &[eof])?;
+                            grace
+                        }
+                        ShutdownMode::Signal(signal, grace) => {
+                            signal_session(master, child, signal);
+                            grace
+                        }
+                        ShutdownMode::Kill => Duration::ZERO,
+                    };
+                    let deadline = Instant::now() + grace;
+                    if shutdown.is_none_or(|(x, killed)| !killed && deadline < x) {
+                        shutdown = Some((deadline, false));
+                    }
+                }
+            }
+        }
+
+        if fds.readable(STDIN_FILENO) {
+            let n = continue_on_eintr!(read(STDIN_FILENO, &mut buf));
+            if let Some(ref mut traffic) = traffic {
+                traffic.read("stdin", &buf[..n])?;
+            }
+            match n {
                 0 => {
-                    if let Ok(attrs) = tcgetattr(master) {
-                        if attrs.local_flags.contains(LocalFlags::ICANON) {
-                            write(master, &[attrs.control_chars[VEOF]])?;
+                    debug!("stdin reached EOF ({:?})", opts.on_stdin_eof);
+                    match opts.on_stdin_eof {
+                        StdinEof::Veof | StdinEof::ExitAfter(_) => {
+                            if let Ok(attrs) = tcgetattr(master) {
+                                if attrs.local_flags.contains(LocalFlags::ICANON) {
+                                    let eof = attrs.control_chars[VEOF];
+                                    write_master(master, &mut traffic, &[eof])?;
+                                }
+                            }
+                        }
+                        StdinEof::Close => hangup(master, child),
+                        StdinEof::Ignore => {}
+                    }
+                    if let StdinEof::ExitAfter(grace) = opts.on_stdin_eof {
+                        let deadline = Instant::now() + grace;
+                        if shutdown.is_none_or(|(x, killed)| !killed && deadline < x) {
+                            shutdown = Some((deadline, false));
                         }
                     }
                     read_stdin = false;
                 }
+                n if breakpoint.as_ref().is_some_and(|x| x.is_stopped()) => {
+                    // the keys answer the prompt instead of going to the program
+                    let breakpoint = breakpoint.as_mut().unwrap();
+                    for &c in &buf[..n] {
+                        let Some(resume) = breakpoint.key(c) else {
+                            continue;
+                        };
+                        debug!("resuming at the breakpoint: {:?}", resume);
+                        match resume {
+                            Resume::Continue | Resume::Step => {
+                                signal_foreground(master, child, Signal::SIGCONT)
+                            }
+                            Resume::Kill => signal_session(master, child, Signal::SIGKILL),
+                        }
+                        break;
+                    }
+                }
                 n => {
-                    write(master, &buf[..n])?;
+                    trace!("read {} bytes from stdin", n);
+                    match escape {
+                        Some(ref mut escape) => {
+                            keyboard.clear();
+                            for &c in &buf[..n] {
+                                let action = match escape.feed(c, &mut keyboard) {
+                                    Some(action) => action,
+                                    None => continue,
+                                };
+                                send_typed(
+                                    master,
+                                    &mut traffic,
+                                    &mut metrics,
+                                    &mut sinks,
+                                    &keyboard,
+                                )?;
+                                keyboard.clear();
+                                match action {
+                                    EscapeAction::Detach => match detach_notify.take() {
+                                        Some(notify) => {
+                                            if title.is_some() {
+                                                write_all_fd(STDOUT_FILENO, POP_TITLE)?;
+                                            }
+                                            debug!("detaching");
+                                            detach(notify)?;
+                                            is_tty = false;
+                                            read_stdin = false;
+                                            break;
+                                        }
+                                        None => hangup(master, child),
+                                    },
+                                    EscapeAction::Break => {
+                                        if tcsendbreak(master, 0).is_err() {
+                                            signal_foreground(master, child, Signal::SIGINT);
+                                        }
+                                    }
+                                    EscapeAction::Help => {
+                                        let help = escape.help(detach_notify.is_some());
+                                        write_all_fd(STDOUT_FILENO, help.as_bytes())?;
+                                    }
+                                }
+                            }
+                            send_typed(master, &mut traffic, &mut metrics, &mut sinks, &keyboard)?;
+                        }
+                        None => {
+                            send_typed(master, &mut traffic, &mut metrics, &mut sinks, &buf[..n])?
+                        }
+                    }
                 }
             };
         }
-        if let Some(ref mut f) = in_file {
-            if read_fds.contains(f.as_raw_fd()) {
-                let n = f.read(&mut buf)?;
+        // the input files are in priority order.  Only the first one with data is
+        // forwarded per iteration so that a burst of input from a higher priority
+        // source is never interleaved with input from a lower priority one.
+        for input in inputs.iter_mut() {
+            if input.fd().is_some_and(|fd| fds.readable(fd)) {
+                let n = input.read(&mut buf)?;
+                if let Some(ref mut traffic) = traffic {
+                    traffic.read(&input.path().display().to_string(), &buf[..n])?;
+                }
                 if n > 0 {
-                    write(master, &buf[..n])?;
-                };
+                    if opts.in_crlf {
+                        translate_newlines(&mut buf[..n]);
+                    }
+                    let wrapped;
+                    let data = if bracketed_paste {
+                        wrapped = wrap_paste(&buf[..n]);
+                        &wrapped[..]
+                    } else {
+                        &buf[..n]
+                    };
+                    if pacer.is_active() && bracketed_paste {
+                        push_paste(&mut pacer, data);
+                    } else if pacer.is_active() {
+                        pacer.push(data);
+                    } else {
+                        write_master(master, &mut traffic, data)?;
+                    }
+                    record_injected(&mut metrics, &mut sinks, data)?;
+                    break;
+                }
             }
         }
-        if read_fds.contains(master) {
-            match continue_on_eintr!(read(master, &mut buf)) {
-                0 => break,
+        if fds.readable(master) {
+            // unless a fixed buffer size was requested, grow the buffer so that a
+            // single read can pick up everything the pty has queued up.
+            if opts.buffer_size.is_none() && buf.len() < MAX_BUFFER_SIZE {
+                if let Some(pending) = get_pending_bytes(master) {
+                    if pending > buf.len() {
+                        buf.resize(pending.next_power_of_two().min(MAX_BUFFER_SIZE), 0);
+                    }
+                }
+            }
+            #[cfg(target_os = "linux")]
+            if let Some(ref mut s) = splicer {
+                let file = out_file.as_ref().map(|f| f.as_raw_fd());
+                match continue_on_eintr!(eio_as_eof(s.forward(
+                    master,
+                    STDOUT_FILENO,
+                    file,
+                    buf.len(),
+                    &mut buf
+                ))) {
+                    Some(0) => {
+                        debug!("the pty was closed");
+                        break;
+                    }
+                    Some(n) => {
+                        trace!("spliced {} bytes from the program", n);
+                        metrics.record_output(n);
+                        last_output = Instant::now();
+                        log_at_line_start = false;
+                        continue;
+                    }
+                    None => {}
+                }
+                splicer = None;
+            }
+            let n = continue_on_eintr!(eio_as_eof(read(master, &mut buf)));
+            if let Some(ref mut traffic) = traffic {
+                traffic.read("master", &buf[..n])?;
+            }
+            match n {
+                0 => {
+                    debug!("the pty was closed");
+                    break;
+                }
                 n => {
-                    if let Some(ref mut logfile) = out_file {
-                        logfile.write_all(&buf[..n])?;
-                        if flush {
-                            logfile.flush()?;
+                    trace!("read {} bytes from the program", n);
+                    metrics.record_output(n);
+                    last_output = Instant::now();
+                    log_at_line_start = buf[n - 1] == b'\n';
+                    if let Some(ref mut parser) = output_parser {
+                        parser.feed(&buf[..n], |seq| {
+                            if let Some((mut modes, set)) = seq.private_modes() {
+                                if modes.any(|mode| mode == 2004) {
+                                    bracketed_paste = set;
+                                }
+                            }
+                        });
+                    }
+                    // a real terminal answers itself
+                    if let Some(ref mut answerback) = answerback {
+                        let size = get_winsize(master).map_or((0, 0), |x| (x.ws_row, x.ws_col));
+                        replies.clear();
+                        answerback.feed(&buf[..n], size, &mut replies);
+                        if !is_tty && !replies.is_empty() {
+                            write_master(master, &mut traffic, &replies)?;
+                        }
+                    }
+                    if let Some(ref mut script) = script {
+                        script.feed(&buf[..n]);
+                        let sent = run_script(
+                            script,
+                            &mut script_actions,
+                            master,
+                            child,
+                            &mut traffic,
+                            &mut pacer,
+                            is_tty,
+                        )?;
+                        record_injected(&mut metrics, &mut sinks, &sent)?;
+                    }
+                    if let Some(ref prompt) = opts.prompt {
+                        if !pacer.is_ready() {
+                            prompt_window.extend_from_slice(&buf[..n]);
+                            if prompt_window.len() > PROMPT_WINDOW_SIZE {
+                                prompt_window.drain(..prompt_window.len() - PROMPT_WINDOW_SIZE);
+                            }
+                            if prompt.is_match(&prompt_window) {
+                                pacer.set_ready();
+                                prompt_window.clear();
+                            }
+                        }
+                    }
+                    let output = if pipeline.is_empty() {
+                        &buf[..n]
+                    } else {
+                        transformed.clear();
+                        pipeline.process(&buf[..n], &mut transformed);
+                        &transformed[..]
+                    };
+                    // segments are captured output and must not bypass redaction
+                    if let Some(ref mut segmenter) = segmenter {
+                        segmenter.feed(output)?;
+                    }
+                    write_captured(&mut out_file, &mut sinks, output, flush, started)?;
+                    let mut shown = if opts.transform_terminal {
+                        output
+                    } else {
+                        &buf[..n]
+                    };
+                    if let Some(ref mut passthrough) = passthrough {
+                        filtered.clear();
+                        let bells = passthrough.filter(shown, &mut filtered)?;
+                        if bells > 0 {
+                            metrics.record_bells(bells);
+                            if opts.bell.notify
+                                && last_bell.is_none_or(|x| x.elapsed() >= BELL_NOTIFY_INTERVAL)
+                            {
+                                notify_bell(opts, child);
+                                last_bell = Some(Instant::now());
+                            }
                         }
+                        shown = &filtered;
+                    }
+                    servers.followers.send_output(shown);
+                    if let Some(ref mut ci) = ci {
+                        annotated.clear();
+                        ci.filter(shown, &mut annotated);
+                        shown = &annotated;
+                    }
+                    if !opts.headless {
+                        write_all_fd(STDOUT_FILENO, shown)?;
+                    }
+                    if breakpoint.as_mut().is_some_and(|x| x.feed(&buf[..n])) {
+                        debug!("stopping the program at the breakpoint");
+                        signal_foreground(master, child, Signal::SIGSTOP);
+                        write_all_fd(STDOUT_FILENO, BREAKPOINT_PROMPT)?;
                     }
-                    write(STDOUT_FILENO, &buf[..n])?;
                 }
             };
         }
     }
 
-    let code = match waitpid(child, None)? {
+    transformed.clear();
+    pipeline.finish(&mut transformed);
+    if let Some(ref mut segmenter) = segmenter {
+        segmenter.feed(&transformed)?;
+    }
+    write_captured(&mut out_file, &mut sinks, &transformed, flush, started)?;
+    if opts.transform_terminal {
+        servers.followers.send_output(&transformed);
+        if !opts.headless {
+            write_all_fd(STDOUT_FILENO, &transformed)?;
+        }
+    }
+    if let Some(ref mut ci) = ci {
+        annotated.clear();
+        ci.finish(&mut annotated);
+        write_all_fd(STDOUT_FILENO, &annotated)?;
+    }
+    if title.is_some() && is_tty {
+        write_all_fd(STDOUT_FILENO, POP_TITLE)?;
+    }
+    if let Some(ref mut segmenter) = segmenter {
+        segmenter.finish()?;
+    }
+    // an adopted pty just ends, there is no program to wait for
+    let status = match (exited, child) {
+        (Some((status, _)), _) => status,
+        (None, Some(child)) => waitpid(child, None)?,
+        (None, None) => WaitStatus::Exited(getpid(), 0),
+    };
+    debug!("session ended ({:?})", status);
+    emit(
+        &mut sinks,
+        &Event::ChildExited(exit_code(status, opts.exit_mode)),
+    )?;
+    finish_sinks(&mut sinks, is_tty);
+    Ok((status, metrics))
+}
+
+/// Tells the sinks that they get nothing more and removes them.
+fn finish_sinks(sinks: &mut Vec<Box<dyn Sink>>, is_tty: bool) {
+    // failing sinks don't end the session, they are only reported
+    for mut sink in sinks.drain(..) {
+        if let Err(err) = sink.finish() {
+            let newline = if is_tty { "\r\n" } else { "\n" };
+            write!(std::io::stderr(), "teetty: {}{}", err, newline).ok();
+        }
+    }
+}
+
+/// Writes output to the log file and the sinks.
+fn write_captured(
+    out_file: &mut Option<&mut OutFile>,
+    sinks: &mut [Box<dyn Sink>],
+    output: &[u8],
+    flush: bool,
+    started: Instant,
+) -> Result<(), Error> {
+    if output.is_empty() {
+        return Ok(());
+    }
+    if let Some(ref mut logfile) = out_file {
+        logfile.write_all(output)?;
+        if flush {
+            logfile.flush()?;
+        }
+    }
+    let ts = started.elapsed();
+    for sink in sinks.iter_mut() {
+        sink.write_chunk(ts, output)?;
+    }
+    Ok(())
+}
+
+/// Accounts for input that was sent to the program by something other than the
+/// keyboard.
+fn record_injected(
+    metrics: &mut Metrics,
+    sinks: &mut [Box<dyn Sink>],
+    data: &[u8],
+) -> Result<(), Error> {
+    if !data.is_empty() {
+        metrics.record_injected(data.len());
+        emit(sinks, &Event::InputInjected(data.to_vec()))?;
+    }
+    Ok(())
+}
+
+/// Sends input from the keyboard to the program.
+fn send_typed(
+    master: i32,
+    traffic: &mut Option<TrafficDump>,
+    metrics: &mut Metrics,
+    sinks: &mut [Box<dyn Sink>],
+    data: &[u8],
+) -> Result<(), Error> {
+    if !data.is_empty() {
+        write_master(master, traffic, data)?;
+        metrics.record_typed(data.len());
+        emit(sinks, &Event::InputTyped(data.to_vec()))?;
+    }
+    Ok(())
+}
+
+/// Wraps input in bracketed paste markers.  End markers in the input are
+/// removed so that it can't end the paste early and be run as typed keys.
+fn wrap_paste(data: &[u8]) -> Vec<u8> {
+    let mut payload = data.to_vec();
+    // removing one can join the pieces around it into another
+    while let Some(pos) = payload
+        .windows(BRACKETED_PASTE_END.len())
+        .position(|x| x == BRACKETED_PASTE_END)
+    {
+        payload.drain(pos..pos + BRACKETED_PASTE_END.len());
+    }
+    [BRACKETED_PASTE_START, &payload, BRACKETED_PASTE_END].concat()
+}
+
+/// Queues up input wrapped by [`wrap_paste`].  Only the payload is paced, the
+/// markers are sent in one piece since the program would otherwise take their
+/// `ESC` as the escape key.
+fn push_paste(pacer: &mut Pacer, wrapped: &[u8]) {
+    let payload = &wrapped[BRACKETED_PASTE_START.len()..wrapped.len() - BRACKETED_PASTE_END.len()];
+    pacer.push_unit(BRACKETED_PASTE_START);
+    pacer.push(payload);
+    pacer.push_unit(BRACKETED_PASTE_END);
+}
+
+/// Writes data to the program and dumps it if the traffic is dumped.
+fn write_master(master: i32, traffic: &mut Option<TrafficDump>, data: &[u8]) -> Result<(), Error> {
+    write_all_fd(master, data)?;
+    if let Some(traffic) = traffic {
+        traffic.write("master", data)?;
+    }
+    Ok(())
+}
+
+/// Sends an event to all sinks.
+fn emit(sinks: &mut [Box<dyn Sink>], event: &Event) -> Result<(), Error> {
+    for sink in sinks.iter_mut() {
+        sink.event(event)?;
+    }
+    Ok(())
+}
+
+/// Returns the JSON status of the session for the control socket and the HTTP
+/// API.
+fn session_status(
+    opts: &SpawnOptions,
+    master: i32,
+    child: Option<Pid>,
+    metrics: &Metrics,
+) -> String {
+    let (rows, cols) = get_winsize(master).map_or((0, 0), |x| (x.ws_row, x.ws_col));
+    format!(
+        "{{\"session\":{},\"pid\":{},\"child\":{},\"command\":[{}],\"uptime\":{:.3},\
+         \"rows\":{},\"cols\":{},\"output_bytes\":{},\"injected_bytes\":{}}}",
+        json::quote_opt(opts.session.as_deref()),
+        getpid(),
+        child.map_or("null".to_string(), |x| x.to_string()),
+        opts.args
+            .iter()
+            .map(|x| json::quote(&x.to_string_lossy()))
+            .collect::<Vec<_>>()
+            .join(","),
+        metrics.uptime().as_secs_f64(),
+        rows,
+        cols,
+        metrics.output_bytes(),
+        metrics.injected_bytes(),
+    )
+}
+
+fn bind_http(addr: &str) -> Result<HttpServer, Error> {
+    HttpServer::bind(addr).with_context(|| format!("could not listen on {}", addr))
+}
+
+/// Linux reports `EIO` instead of EOF when reading from the master once the
+/// child closed the last fd to the slave.
+fn eio_as_eof<T: Default>(rv: Result<T, Errno>) -> Result<T, Errno> {
+    match rv {
+        Err(Errno::EIO) => Ok(T::default()),
+        rv => rv,
+    }
+}
+
+/// Advances the expect script and carries out what it asks for.  Returns the
+/// input that was sent.
+fn run_script(
+    script: &mut ScriptRunner,
+    actions: &mut Vec<Action>,
+    master: i32,
+    child: Option<Pid>,
+    traffic: &mut Option<TrafficDump>,
+    pacer: &mut Pacer,
+    is_tty: bool,
+) -> Result<Vec<u8>, Error> {
+    let mut sent = Vec::new();
+    if script.is_done() {
+        return Ok(sent);
+    }
+    let rv = script.poll(actions);
+    for action in actions.drain(..) {
+        match action {
+            Action::Send(data) => {
+                sent.extend_from_slice(&data);
+                if pacer.is_active() {
+                    pacer.push(&data);
+                } else {
+                    write_master(master, traffic, &data)?;
+                }
+            }
+            Action::Signal(signal) => signal_foreground(master, child, signal),
+        }
+    }
+    // a failed script ends the session the same way closing the terminal would
+    if let Err(err) = rv {
+        let newline = if is_tty { "\r\n" } else { "\n" };
+        write!(std::io::stderr(), "teetty: {}{}", err, newline).ok();
+        hangup(master, child);
+    }
+    Ok(sent)
+}
+
+/// Ends the session the same way closing the terminal would.
+fn hangup(master: i32, child: Option<Pid>) {
+    signal_session(master, child, Signal::SIGHUP);
+}
+
+/// Sends a signal to the foreground process group of the pty and the child.
+fn signal_session(master: i32, child: Option<Pid>, signal: Signal) {
+    signal_foreground(master, child, signal);
+    if let Some(child) = child {
+        kill(child, signal).ok();
+    }
+}
+
+/// Converts the wait status of the child into an exit code.
+fn exit_code(status: WaitStatus, mode: ExitMode) -> i32 {
+    match status {
         WaitStatus::Exited(_, status) => status,
+        WaitStatus::Signaled(_, signal, _) if mode == ExitMode::Raw => signal as i32,
         WaitStatus::Signaled(_, signal, _) => 128 + signal as i32,
         _ => 1,
+    }
+}
+
+/// Returns the exit code for the status of the session.  With
+/// [`ExitMode::PropagateSignal`] this doesn't return if the program was killed
+/// by a signal which is fatal for teetty too.
+fn finish_status(status: Option<WaitStatus>, mode: ExitMode) -> i32 {
+    let Some(status) = status else {
+        return 0;
+    };
+    if let (ExitMode::PropagateSignal, WaitStatus::Signaled(_, signal, _)) = (mode, status) {
+        // the core dump of the program is the interesting one
+        let no_core = libc::rlimit {
+            rlim_cur: 0,
+            rlim_max: 0,
+        };
+        unsafe {
+            libc::setrlimit(libc::RLIMIT_CORE, &no_core);
+            nix::sys::signal::signal(signal, SigHandler::SigDfl).ok();
+        }
+        let mut mask = SigSet::empty();
+        mask.add(signal);
+        mask.thread_unblock().ok();
+        nix::sys::signal::raise(signal).ok();
+    }
+    exit_code(status, mode)
+}
+
+enum DetachableFork {
+    /// The original process with the status of the session process, unless
+    /// the user detached.
+    Front(Option<WaitStatus>),
+    /// The process running the session with the fd to notify on detach.
+    Session(i32),
+}
+
+/// Forks off the process which runs a detachable session.
+///
+/// The original process waits until the session either ends, in which case it
+/// exits with the same status, or until the session process writes to the
+/// notification pipe because the user detached.
+fn fork_detachable() -> Result<DetachableFork, Error> {
+    let (r, w) = cloexec_pipe()?;
+    let fork_result = {
+        let _guard = lock_fds();
+        unsafe { fork() }.map_err(SpawnError::ForkFailed)?
+    };
+    let child = match fork_result {
+        ForkResult::Parent { child } => child,
+        ForkResult::Child => {
+            close(r)?;
+            return Ok(DetachableFork::Session(w));
+        }
+    };
+    close(w)?;
+    let mut buf = [0; 1];
+    let detached = loop {
+        match read(r, &mut buf) {
+            Ok(n) => break n > 0,
+            Err(Errno::EINTR) => continue,
+            Err(err) => return Err(err.into()),
+        }
+    };
+    close(r)?;
+    if detached {
+        Ok(DetachableFork::Front(None))
+    } else {
+        Ok(DetachableFork::Front(Some(waitpid(child, None)?)))
+    }
+}
+
+/// Turns this process into a daemon.
+///
+/// Returns `None` in the original process once the daemon started the program
+/// and the fd to report to that it did in the daemon.  If the daemon fails to
+/// start the program it writes the error to the fd instead.
+fn daemonize() -> Result<Option<i32>, Error> {
+    let (r, w) = cloexec_pipe()?;
+    let fork_result = {
+        let _guard = lock_fds();
+        unsafe { fork() }.map_err(SpawnError::ForkFailed)?
     };
-    close(master)?;
-    Ok(code)
+    if let ForkResult::Parent { child } = fork_result {
+        close(w)?;
+        waitpid(child, None)?;
+        let mut report = Vec::new();
+        let mut buf = [0; 1024];
+        loop {
+            match read(r, &mut buf) {
+                Ok(0) => break,
+                Ok(n) => report.extend_from_slice(&buf[..n]),
+                Err(Errno::EINTR) => continue,
+                Err(err) => return Err(err.into()),
+            }
+        }
+        close(r)?;
+        return match report.strip_prefix(b"+") {
+            Some(_) => Ok(None),
+            None if report.is_empty() => Err(Error::msg("the daemon exited before it started")),
+            None => Err(Error::msg(String::from_utf8_lossy(&report).into_owned())),
+        };
+    }
+    close(r)?;
+    // the second fork makes sure the daemon never gets a controlling terminal
+    // again and that it's not a child of the original process
+    setsid()?;
+    if let ForkResult::Parent { .. } = unsafe { fork() }.map_err(SpawnError::ForkFailed)? {
+        unsafe { libc::_exit(0) };
+    }
+    let null = File::options().read(true).write(true).open("/dev/null")?;
+    for fd in [STDIN_FILENO, STDOUT_FILENO, STDERR_FILENO] {
+        dup2(null.as_raw_fd(), fd)?;
+    }
+    Ok(Some(w))
+}
+
+/// Detaches the session from the terminal.
+///
+/// The terminal is restored and handed back to the original process, the
+/// session continues in the background with stdio pointed to `/dev/null`.
+fn detach(notify: i32) -> Result<(), Error> {
+    write_all_fd(STDOUT_FILENO, b"\r\n[detached]\r\n")?;
+    term::leave_raw();
+    let null = File::options().read(true).write(true).open("/dev/null")?;
+    for fd in [STDIN_FILENO, STDOUT_FILENO, STDERR_FILENO] {
+        dup2(null.as_raw_fd(), fd)?;
+    }
+    setsid()?;
+    write_all_fd(notify, b"d")?;
+    close(notify)?;
+    Ok(())
+}
+
+/// Sends a signal to the foreground process group of the pty.
+///
+/// If the foreground process group can't be determined the signal goes to the
+/// child instead.
+fn signal_foreground(master: i32, child: Option<Pid>, signal: Signal) {
+    match (tcgetpgrp(master), child) {
+        (Ok(pgrp), _) => killpg(pgrp, signal).ok(),
+        (Err(_), Some(child)) => kill(child, signal).ok(),
+        (Err(_), None) => None,
+    };
+}
+
+/// Parses a signal name (`INT` or `SIGINT`) or number.
+pub fn parse_signal(s: &str) -> Option<Signal> {
+    if let Ok(num) = s.parse::<i32>() {
+        return Signal::try_from(num).ok();
+    }
+    let name = s.to_ascii_uppercase();
+    if name.starts_with("SIG") {
+        name.parse().ok()
+    } else {
+        format!("SIG{}", name).parse().ok()
+    }
+}
+
+/// Returns the time to wait in `poll` for the earliest of the given timeouts
+/// but never more than a second.
+fn loop_timeout(timeouts: &[Option<Duration>]) -> Duration {
+    timeouts
+        .iter()
+        .flatten()
+        .fold(Duration::from_secs(1), |a, &b| a.min(b))
+}
+
+/// Writes the entire buffer to the given fd.
+///
+/// Unlike a plain `write` this retries on `EINTR`, continues after short writes
+/// and waits for the fd to become writable again if it's non-blocking.
+pub fn write_all_fd(fd: i32, mut buf: &[u8]) -> Result<(), Errno> {
+    while !buf.is_empty() {
+        match write(fd, buf) {
+            Ok(0) => return Err(Errno::EIO),
+            Ok(n) => buf = &buf[n..],
+            Err(Errno::EINTR) => {}
+            Err(Errno::EAGAIN) => match poll(&mut [PollFd::new(fd, PollFlags::POLLOUT)], -1) {
+                Ok(_) | Err(Errno::EINTR) => {}
+                Err(err) => return Err(err),
+            },
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(())
 }
 
 /// If possible, returns the terminal size of the given fd.
-fn get_winsize(fd: i32) -> Option<Winsize> {
+pub fn get_winsize(fd: i32) -> Option<Winsize> {
     nix::ioctl_read_bad!(_get_window_size, TIOCGWINSZ, Winsize);
     let mut size: Winsize = unsafe { std::mem::zeroed() };
     unsafe { _get_window_size(fd, &mut size).ok()? };
     Some(size)
 }
 
+/// Returns the number of bytes that can be read from the fd without blocking.
+fn get_pending_bytes(fd: i32) -> Option<usize> {
+    nix::ioctl_read_bad!(_get_pending_bytes, FIONREAD, c_int);
+    let mut pending: c_int = 0;
+    unsafe { _get_pending_bytes(fd, &mut pending).ok()? };
+    usize::try_from(pending).ok()
+}
+
 /// Sets the winsize
-fn set_winsize(fd: i32, mut winsize: Winsize) -> Result<(), Errno> {
-    nix::ioctl_write_ptr_bad!(_set_window_size, TIOCGWINSZ, Winsize);
-    unsafe { _set_window_size(fd, &mut winsize) }?;
+fn set_winsize(fd: i32, winsize: Winsize) -> Result<(), Errno> {
+    nix::ioctl_write_ptr_bad!(_set_window_size, TIOCSWINSZ, Winsize);
+    unsafe { _set_window_size(fd, &winsize) }?;
     Ok(())
 }
 
@@ -244,10 +2551,118 @@ fn mkfifo_atomic(path: &Path) -> Result<(), Errno> {
     }
 }
 
-struct RestoreTerm(Termios);
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-impl Drop for RestoreTerm {
-    fn drop(&mut self) {
-        tcsetattr(STDIN_FILENO, SetArg::TCSAFLUSH, &self.0).ok();
+    use nix::fcntl::{fcntl, FcntlArg, OFlag};
+    use nix::unistd::pipe;
+
+    /// Writes a large buffer into a small non-blocking pipe so that the writes
+    /// come back short or with `EAGAIN` while a slow reader drains it.
+    #[test]
+    fn test_write_all_fd_short_writes() {
+        let (r, w) = pipe().unwrap();
+        fcntl(w, FcntlArg::F_SETFL(OFlag::O_NONBLOCK)).unwrap();
+        #[cfg(target_os = "linux")]
+        fcntl(w, FcntlArg::F_SETPIPE_SZ(4096)).unwrap();
+
+        let data = (0..256 * 1024).map(|x| (x % 251) as u8).collect::<Vec<_>>();
+        let reader = std::thread::spawn(move || {
+            let mut rv = Vec::new();
+            let mut buf = [0; 1000];
+            loop {
+                match read(r, &mut buf).unwrap() {
+                    0 => break,
+                    n => rv.extend_from_slice(&buf[..n]),
+                }
+                std::thread::sleep(std::time::Duration::from_micros(50));
+            }
+            close(r).unwrap();
+            rv
+        });
+
+        write_all_fd(w, &data).unwrap();
+        close(w).unwrap();
+        assert!(reader.join().unwrap() == data);
+    }
+
+    #[test]
+    fn test_check_master_fd() {
+        let (master, slave) = open_pty(None, None).unwrap();
+        assert!(check_master_fd(master.as_raw_fd()).is_ok());
+        assert!(check_master_fd(slave.as_raw_fd()).is_err());
+        assert!(check_master_fd(STDIN_FILENO).is_err());
+        let (r, w) = pipe().unwrap();
+        assert!(check_master_fd(r).is_err());
+        close(r).ok();
+        close(w).ok();
+    }
+
+    #[test]
+    fn test_wrap_paste() {
+        assert_eq!(wrap_paste(b"ls\r"), b"\x1b[200~ls\r\x1b[201~");
+        assert_eq!(
+            wrap_paste(b"a\x1b[201~rm -rf ~\r\x1b[20\x1b[201~1~"),
+            b"\x1b[200~arm -rf ~\r\x1b[201~"
+        );
+    }
+
+    #[test]
+    fn test_push_paste() {
+        let pop_all = |pacer: &mut Pacer| {
+            let mut pieces = Vec::new();
+            let mut piece = Vec::new();
+            while pacer.pop_due(&mut piece) {
+                pieces.push(std::mem::take(&mut piece));
+            }
+            pieces
+        };
+
+        // with a byte delay the markers stick to the first and last byte
+        let mut pacer = Pacer::new(Some(Duration::ZERO), None, false);
+        push_paste(&mut pacer, &wrap_paste(b"ls\r"));
+        push_paste(&mut pacer, &wrap_paste(b"x"));
+        assert_eq!(
+            pop_all(&mut pacer),
+            [
+                &b"\x1b[200~l"[..],
+                b"s",
+                b"\r\x1b[201~",
+                b"\x1b[200~x\x1b[201~"
+            ]
+        );
+
+        // with a line delay a line doesn't reach into the next paste
+        let mut pacer = Pacer::new(None, Some(Duration::ZERO), false);
+        push_paste(&mut pacer, &wrap_paste(b"ls"));
+        push_paste(&mut pacer, &wrap_paste(b"a\rb"));
+        assert_eq!(
+            pop_all(&mut pacer),
+            [&b"\x1b[200~ls\x1b[201~"[..], b"\x1b[200~a\r", b"b\x1b[201~"]
+        );
+    }
+
+    /// Without a foreground process group on the pty the program itself is
+    /// stopped and continued.
+    #[test]
+    fn test_signal_foreground() {
+        let mut sleep = std::process::Command::new("sleep")
+            .arg("10")
+            .spawn()
+            .unwrap();
+        let pid = Pid::from_raw(sleep.id() as i32);
+        signal_foreground(-1, Some(pid), Signal::SIGSTOP);
+        assert_eq!(
+            waitpid(pid, Some(WaitPidFlag::WUNTRACED)).unwrap(),
+            WaitStatus::Stopped(pid, Signal::SIGSTOP)
+        );
+        signal_foreground(-1, Some(pid), Signal::SIGCONT);
+        assert_eq!(
+            waitpid(pid, Some(WaitPidFlag::WCONTINUED)).unwrap(),
+            WaitStatus::Continued(pid)
+        );
+        sleep.kill().unwrap();
+        sleep.wait().unwrap();
     }
 }
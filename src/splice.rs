@@ -0,0 +1,122 @@
+//! Zero-copy forwarding of the PTY output on Linux.
+//!
+//! The PTY master is not a pipe so it can't be passed to `tee(2)` directly.
+//! Instead the output is spliced into an intermediate pipe, duplicated with
+//! `tee(2)` into a second pipe if a log file is attached and then spliced out
+//! to stdout and the log file.  Kernels that can't splice from a tty refuse
+//! with `EINVAL` before consuming anything so we can detect this and fall back
+//! to the regular read/write loop.
+use nix::errno::Errno;
+use nix::fcntl::{splice, tee, SpliceFFlags};
+use nix::unistd::{close, read};
+
+use crate::spawn::{cloexec_pipe, write_all_fd};
+
+pub struct Splicer {
+    pipe: (i32, i32),
+    tee_pipe: Option<(i32, i32)>,
+    out_supported: bool,
+    file_supported: bool,
+}
+
+impl Splicer {
+    /// Creates a new splicer.  `tee` indicates if the output also has to go into
+    /// a log file.
+    pub fn new(tee: bool) -> Result<Splicer, Errno> {
+        Ok(Splicer {
+            pipe: cloexec_pipe()?,
+            tee_pipe: if tee { Some(cloexec_pipe()?) } else { None },
+            out_supported: true,
+            file_supported: true,
+        })
+    }
+
+    /// Moves up to `len` bytes from `master` to `out` and optionally `file`.
+    ///
+    /// Returns `None` if the kernel does not support splicing from the master.
+    /// In that case nothing was consumed and the caller has to fall back to
+    /// reading the data itself.  `buf` is used as scratch space for destinations
+    /// that can't be spliced into (eg: files opened with `O_APPEND`).
+    pub fn forward(
+        &mut self,
+        master: i32,
+        out: i32,
+        file: Option<i32>,
+        len: usize,
+        buf: &mut [u8],
+    ) -> Result<Option<usize>, Errno> {
+        let n = loop {
+            match splice(
+                master,
+                None,
+                self.pipe.1,
+                None,
+                len,
+                SpliceFFlags::SPLICE_F_MOVE,
+            ) {
+                Ok(n) => break n,
+                Err(Errno::EINTR) => continue,
+                Err(Errno::EINVAL) => return Ok(None),
+                Err(err) => return Err(err),
+            }
+        };
+        if n == 0 {
+            return Ok(Some(0));
+        }
+        if let (Some(file), Some(tee_pipe)) = (file, self.tee_pipe) {
+            let mut teed = 0;
+            while teed < n {
+                match tee(self.pipe.0, tee_pipe.1, n - teed, SpliceFFlags::empty()) {
+                    Ok(m) => teed += m,
+                    Err(Errno::EINTR) => continue,
+                    Err(err) => return Err(err),
+                }
+            }
+            drain_pipe(tee_pipe.0, file, n, &mut self.file_supported, buf)?;
+        }
+        drain_pipe(self.pipe.0, out, n, &mut self.out_supported, buf)?;
+        Ok(Some(n))
+    }
+}
+
+impl Drop for Splicer {
+    fn drop(&mut self) {
+        for (r, w) in Some(self.pipe).into_iter().chain(self.tee_pipe) {
+            close(r).ok();
+            close(w).ok();
+        }
+    }
+}
+
+/// Moves exactly `n` bytes out of the pipe into `fd`.
+///
+/// If the target rejects splicing, the data is copied through `buf` instead
+/// and `supported` is cleared so that subsequent calls don't try again.
+fn drain_pipe(
+    pipe: i32,
+    fd: i32,
+    mut n: usize,
+    supported: &mut bool,
+    buf: &mut [u8],
+) -> Result<(), Errno> {
+    while n > 0 && *supported {
+        match splice(pipe, None, fd, None, n, SpliceFFlags::SPLICE_F_MOVE) {
+            Ok(m) => n -= m,
+            Err(Errno::EINTR | Errno::EAGAIN) => continue,
+            Err(Errno::EINVAL) => *supported = false,
+            Err(err) => return Err(err),
+        }
+    }
+    while n > 0 {
+        let chunk = buf.len().min(n);
+        match read(pipe, &mut buf[..chunk]) {
+            Ok(m) => {
+                write_all_fd(fd, &buf[..m])?;
+                n -= m;
+            }
+            Err(Errno::EINTR) => continue,
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,136 @@
+//! Templates for output paths.
+//!
+//! Paths can contain `strftime(3)` formats such as `%Y-%m-%d` (in local time)
+//! and the placeholders `{cmd}` (the name of the program), `{pid}` (the pid of
+//! teetty) and `{session}` (the name of the session).  `{{` and `}}` stand for
+//! literal braces.
+use std::ffi::OsStr;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use anyhow::{bail, Context, Error};
+
+use crate::time::strftime;
+
+/// The values of the placeholders.
+pub struct Vars<'a> {
+    pub cmd: &'a str,
+    pub pid: u32,
+    pub session: Option<&'a str>,
+}
+
+/// Returns `true` if the path contains anything to expand.
+pub fn is_template(path: &Path) -> bool {
+    path.to_str().is_some_and(|x| x.contains(['%', '{', '}']))
+}
+
+/// Returns `true` if the path contains a `strftime(3)` format other than `%%`,
+/// which makes it change over time.
+pub fn has_time_format(path: &Path) -> bool {
+    let Some(mut rest) = path.to_str() else {
+        return false;
+    };
+    while let Some(idx) = rest.find('%') {
+        match rest[idx + 1..].chars().next() {
+            Some('%') => rest = &rest[idx + 2..],
+            Some(_) => return true,
+            None => return false,
+        }
+    }
+    false
+}
+
+/// Expands a path template.
+pub fn expand(path: &Path, vars: &Vars, time: SystemTime) -> Result<PathBuf, Error> {
+    let Some(template) = path.to_str().filter(|_| is_template(path)) else {
+        return Ok(path.to_path_buf());
+    };
+    let Some(formatted) = strftime(template, time) else {
+        bail!("invalid time format in '{}'", template);
+    };
+    let mut rv = String::new();
+    let mut rest = &formatted[..];
+    while let Some(idx) = rest.find(['{', '}']) {
+        rv.push_str(&rest[..idx]);
+        rest = &rest[idx..];
+        if rest.starts_with("{{") || rest.starts_with("}}") {
+            rv.push_str(&rest[..1]);
+            rest = &rest[2..];
+            continue;
+        }
+        let Some(end) = rest.find('}').filter(|_| rest.starts_with('{')) else {
+            bail!("unmatched brace in '{}'", template);
+        };
+        match &rest[1..end] {
+            "cmd" => rv.push_str(vars.cmd),
+            "pid" => rv.push_str(&vars.pid.to_string()),
+            "session" => match vars.session {
+                Some(session) => rv.push_str(session),
+                None => bail!(
+                    "'{}' uses {{session}} but no session name was given",
+                    template
+                ),
+            },
+            name => bail!("unknown placeholder {{{}}} in '{}'", name, template),
+        }
+        rest = &rest[end + 1..];
+    }
+    rv.push_str(rest);
+    Ok(PathBuf::from(rv))
+}
+
+/// Expands an output path template for a session of `command` and creates the
+/// directories leading up to it.
+pub fn expand_output_path(
+    path: &Path,
+    command: &OsStr,
+    session: Option<&str>,
+    time: SystemTime,
+) -> Result<PathBuf, Error> {
+    if !is_template(path) {
+        return Ok(path.to_path_buf());
+    }
+    let cmd = Path::new(command)
+        .file_name()
+        .map_or_else(|| "teetty".into(), |x| x.to_string_lossy());
+    let vars = Vars {
+        cmd: &cmd,
+        pid: std::process::id(),
+        session,
+    };
+    let expanded = expand(path, &vars, time)?;
+    if let Some(dir) = expanded.parent().filter(|x| !x.as_os_str().is_empty()) {
+        fs::create_dir_all(dir)
+            .with_context(|| format!("could not create directory {}", dir.display()))?;
+    }
+    Ok(expanded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expand() {
+        let vars = Vars {
+            cmd: "make",
+            pid: 42,
+            session: None,
+        };
+        let time = SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(86400 * 500);
+        let path = |x: &str| expand(Path::new(x), &vars, time);
+        assert_eq!(path("out.log").unwrap(), Path::new("out.log"));
+        assert_eq!(
+            path("logs/%Y/{cmd}-{pid}.log").unwrap(),
+            Path::new("logs/1971/make-42.log")
+        );
+        assert_eq!(path("{{x}}%%.log").unwrap(), Path::new("{x}%.log"));
+        assert!(path("{session}.log").is_err());
+        assert!(path("{nope}.log").is_err());
+        assert!(path("{cmd.log").is_err());
+        assert!(has_time_format(Path::new("{cmd}-%H%M.log")));
+        assert!(!has_time_format(Path::new("{cmd}.log")));
+        assert!(!has_time_format(Path::new("100%%.log")));
+    }
+}
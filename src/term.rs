@@ -0,0 +1,309 @@
+//! Switching the terminal to raw mode and back.
+//!
+//! While a session runs the terminal is in raw mode and the [`RestoreTerm`]
+//! guard restores it.  Programs can call [`install_hooks`] once at startup so
+//! that a panic hook, handlers for fatal signals and an `atexit` handler
+//! restore the original attributes as well and a crash doesn't leave the
+//! terminal unusable.  The library never installs these process-wide hooks
+//! itself.  If the terminal is left behind anyway `teetty --reset` (see
+//! [`reset`]) brings it back into a sane state.
+//!
+//! A process in the background of a job control shell is stopped when it
+//! changes the terminal, so raw mode is only entered while teetty is in the
+//! foreground.
+use std::fs::File;
+use std::io::Write;
+use std::os::fd::AsRawFd;
+use std::ptr::null_mut;
+use std::sync::atomic::{AtomicBool, AtomicPtr, Ordering};
+use std::sync::Once;
+
+use anyhow::{Context, Error};
+use nix::libc::{self, c_int, STDIN_FILENO};
+use nix::sys::signal::{SIGHUP, SIGINT, SIGQUIT, SIGTERM};
+use nix::sys::termios::{
+    cfmakeraw, tcgetattr, tcsetattr, ControlFlags, InputFlags, LocalFlags, OutputFlags, SetArg,
+    SpecialCharacterIndices, Termios,
+};
+use nix::unistd::{getpgrp, tcgetpgrp};
+
+/// The signals which end the process and leave the terminal behind.
+const FATAL_SIGNALS: [c_int; 4] = [
+    SIGHUP as c_int,
+    SIGINT as c_int,
+    SIGQUIT as c_int,
+    SIGTERM as c_int,
+];
+
+/// Turns off what programs commonly turn on: the alternate screen, a hidden
+/// cursor, application keys, mouse reporting, bracketed paste and colors.
+const RESET_SEQUENCE: &[u8] =
+    b"\x1b[?1049l\x1b[?25h\x1b[?1l\x1b>\x1b[?1000l\x1b[?1002l\x1b[?1003l\x1b[?1006l\x1b[?2004l\x1b[0m";
+
+/// The attributes to restore if the process goes down while the terminal is in
+/// raw mode.  This is a raw pointer so that signal handlers can read it.
+static SAVED: AtomicPtr<libc::termios> = AtomicPtr::new(null_mut());
+
+/// The allocation behind `SAVED`, reused while no [`RestoreTerm`] is active.
+static STORAGE: AtomicPtr<libc::termios> = AtomicPtr::new(null_mut());
+
+/// Whether the terminal is currently in raw mode.
+static RAW: AtomicBool = AtomicBool::new(false);
+
+/// Switches the terminal to raw mode and restores it on Drop.
+pub struct RestoreTerm(Termios);
+
+impl RestoreTerm {
+    /// Switches stdin to raw mode.  `attrs` are the attributes to restore.
+    ///
+    /// In the background the terminal is left alone until [`enter_raw`] is
+    /// called in the foreground.
+    pub fn raw(attrs: &Termios) -> RestoreTerm {
+        let attrs_c = libc::termios::from(attrs.clone());
+        let storage = STORAGE.load(Ordering::SeqCst);
+        let saved = if !storage.is_null() && SAVED.load(Ordering::SeqCst) != storage {
+            unsafe { storage.write(attrs_c) };
+            storage
+        } else {
+            // attributes which are replaced while they are in use are leaked as
+            // a signal handler might still be reading them
+            let saved = Box::into_raw(Box::new(attrs_c));
+            if storage.is_null() {
+                STORAGE.store(saved, Ordering::SeqCst);
+            }
+            saved
+        };
+        SAVED.store(saved, Ordering::SeqCst);
+        enter_raw();
+        RestoreTerm(attrs.clone())
+    }
+}
+
+impl Drop for RestoreTerm {
+    fn drop(&mut self) {
+        SAVED.store(null_mut(), Ordering::SeqCst);
+        if RAW.swap(false, Ordering::SeqCst) {
+            tcsetattr(STDIN_FILENO, SetArg::TCSAFLUSH, &self.0).ok();
+        }
+    }
+}
+
+/// Switches the terminal to raw mode if a [`RestoreTerm`] is active and the
+/// process is in the foreground.
+pub fn enter_raw() {
+    let saved = SAVED.load(Ordering::SeqCst);
+    if saved.is_null() || in_background() || RAW.load(Ordering::SeqCst) {
+        return;
+    }
+    let mut raw_attrs = Termios::from(unsafe { *saved });
+    cfmakeraw(&mut raw_attrs);
+    if tcsetattr(STDIN_FILENO, SetArg::TCSAFLUSH, &raw_attrs).is_ok() {
+        RAW.store(true, Ordering::SeqCst);
+    }
+}
+
+/// Restores the terminal from raw mode, e.g. before teetty is suspended.
+pub fn leave_raw() {
+    restore_saved();
+}
+
+/// Checks if stdin is a terminal in which another process group is in the
+/// foreground.
+pub fn in_background() -> bool {
+    tcgetpgrp(STDIN_FILENO).is_ok_and(|pgrp| pgrp != getpgrp())
+}
+
+/// Restores the saved attributes if the terminal is in raw mode.
+///
+/// This is called from signal handlers so it must only do async-signal-safe
+/// things.
+fn restore_saved() {
+    let saved = SAVED.load(Ordering::SeqCst);
+    if !saved.is_null() && RAW.swap(false, Ordering::SeqCst) {
+        unsafe {
+            libc::tcsetattr(STDIN_FILENO, libc::TCSAFLUSH, saved);
+        }
+    }
+}
+
+extern "C" fn restore_at_exit() {
+    restore_saved();
+}
+
+/// Makes sure the terminal is restored in the cases `Drop` doesn't run: on
+/// panics, fatal signals and `exit`.
+///
+/// This changes process-wide state, so it's up to the program to call it
+/// (once, early in `main`).  Later calls do nothing.
+pub fn install_hooks() {
+    static HOOKS: Once = Once::new();
+    HOOKS.call_once(|| {
+        let prev = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            // the message has to go to a usable terminal
+            restore_saved();
+            prev(info);
+        }));
+        unsafe {
+            libc::atexit(restore_at_exit);
+        }
+        for signal in FATAL_SIGNALS {
+            // signals which are ignored have to stay ignored (e.g. `nohup`)
+            if !is_default_handler(signal) {
+                continue;
+            }
+            unsafe {
+                signal_hook::low_level::register(signal, move || {
+                    restore_saved();
+                    signal_hook::low_level::emulate_default_handler(signal).ok();
+                })
+                .ok();
+            }
+        }
+    });
+}
+
+/// Checks if the signal has its default disposition.
+fn is_default_handler(signal: c_int) -> bool {
+    unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        libc::sigaction(signal, std::ptr::null(), &mut action) == 0
+            && action.sa_sigaction == libc::SIG_DFL
+    }
+}
+
+/// Puts the terminal back into a sane state.
+///
+/// This is what `teetty --reset` does: the terminal is switched back to
+/// line editing with echo and the usual control characters, and the modes
+/// programs commonly turn on are turned off again.
+pub fn reset() -> Result<(), Error> {
+    let mut tty = File::options()
+        .read(true)
+        .write(true)
+        .open("/dev/tty")
+        .context("could not open the terminal")?;
+    let mut attrs = tcgetattr(tty.as_raw_fd()).context("could not read the terminal state")?;
+    make_sane(&mut attrs);
+    tcsetattr(tty.as_raw_fd(), SetArg::TCSANOW, &attrs)
+        .context("could not restore the terminal state")?;
+    tty.write_all(RESET_SEQUENCE)?;
+    tty.write_all(b"\n")?;
+    Ok(())
+}
+
+/// Changes the attributes like `stty sane` does.
+fn make_sane(attrs: &mut Termios) {
+    attrs.input_flags.remove(
+        InputFlags::IGNBRK
+            | InputFlags::INLCR
+            | InputFlags::IGNCR
+            | InputFlags::IXOFF
+            | InputFlags::IXANY
+            | InputFlags::ISTRIP,
+    );
+    attrs
+        .input_flags
+        .insert(InputFlags::BRKINT | InputFlags::ICRNL | InputFlags::IXON | InputFlags::IMAXBEL);
+    attrs
+        .output_flags
+        .remove(OutputFlags::OCRNL | OutputFlags::ONOCR | OutputFlags::ONLRET);
+    attrs
+        .output_flags
+        .insert(OutputFlags::OPOST | OutputFlags::ONLCR);
+    attrs.control_flags.insert(ControlFlags::CREAD);
+    attrs
+        .local_flags
+        .remove(LocalFlags::ECHONL | LocalFlags::NOFLSH | LocalFlags::TOSTOP | LocalFlags::ECHOPRT);
+    attrs.local_flags.insert(
+        LocalFlags::ISIG
+            | LocalFlags::ICANON
+            | LocalFlags::IEXTEN
+            | LocalFlags::ECHO
+            | LocalFlags::ECHOE
+            | LocalFlags::ECHOK
+            | LocalFlags::ECHOCTL
+            | LocalFlags::ECHOKE,
+    );
+    for (idx, value) in [
+        (SpecialCharacterIndices::VINTR, 0x03),
+        (SpecialCharacterIndices::VQUIT, 0x1c),
+        (SpecialCharacterIndices::VERASE, 0x7f),
+        (SpecialCharacterIndices::VKILL, 0x15),
+        (SpecialCharacterIndices::VEOF, 0x04),
+        (SpecialCharacterIndices::VSTART, 0x11),
+        (SpecialCharacterIndices::VSTOP, 0x13),
+        (SpecialCharacterIndices::VSUSP, 0x1a),
+        (SpecialCharacterIndices::VREPRINT, 0x12),
+        (SpecialCharacterIndices::VWERASE, 0x17),
+        (SpecialCharacterIndices::VLNEXT, 0x16),
+        (SpecialCharacterIndices::VMIN, 1),
+        (SpecialCharacterIndices::VTIME, 0),
+    ] {
+        attrs.control_chars[idx as usize] = value;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_make_sane() {
+        let pty = nix::pty::openpty(None, None).unwrap();
+        let mut attrs = tcgetattr(pty.slave).unwrap();
+        cfmakeraw(&mut attrs);
+        attrs.control_chars[SpecialCharacterIndices::VEOF as usize] = 0;
+        make_sane(&mut attrs);
+        tcsetattr(pty.slave, SetArg::TCSANOW, &attrs).unwrap();
+        let attrs = tcgetattr(pty.slave).unwrap();
+        nix::unistd::close(pty.master).ok();
+        nix::unistd::close(pty.slave).ok();
+        assert!(attrs
+            .local_flags
+            .contains(LocalFlags::ICANON | LocalFlags::ECHO | LocalFlags::ISIG));
+        assert!(attrs
+            .output_flags
+            .contains(OutputFlags::OPOST | OutputFlags::ONLCR));
+        assert!(attrs.input_flags.contains(InputFlags::ICRNL));
+        assert_eq!(
+            attrs.control_chars[SpecialCharacterIndices::VEOF as usize],
+            0x04
+        );
+    }
+
+    /// Runs the check in a new session on a pty, once in its foreground process
+    /// group and once in another process group.
+    #[test]
+    fn test_in_background() {
+        use nix::sys::wait::{waitpid, WaitStatus};
+        use nix::unistd::{fork, setpgid, ForkResult, Pid};
+
+        let pty = nix::pty::openpty(None, None).unwrap();
+        match unsafe { fork() }.unwrap() {
+            ForkResult::Child => unsafe {
+                if crate::pty::login_tty(pty.slave) < 0 || in_background() {
+                    libc::_exit(1);
+                }
+                let code = match fork() {
+                    Ok(ForkResult::Child) => {
+                        setpgid(Pid::from_raw(0), Pid::from_raw(0)).ok();
+                        libc::_exit(if in_background() { 0 } else { 2 });
+                    }
+                    Ok(ForkResult::Parent { child }) => match waitpid(child, None) {
+                        Ok(WaitStatus::Exited(_, code)) => code,
+                        _ => 3,
+                    },
+                    Err(_) => 4,
+                };
+                libc::_exit(code);
+            },
+            ForkResult::Parent { child } => {
+                nix::unistd::close(pty.slave).ok();
+                let status = waitpid(child, None).unwrap();
+                nix::unistd::close(pty.master).ok();
+                assert_eq!(status, WaitStatus::Exited(child, 0));
+            }
+        }
+    }
+}
@@ -0,0 +1,87 @@
+//! Formatting of timestamps.
+use std::ffi::CString;
+use std::time::SystemTime;
+
+use nix::libc;
+
+/// Formats a point in time as RFC 3339 timestamp in UTC with second precision
+/// (eg: `2023-01-02T03:04:05Z`).
+pub fn format_utc(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |x| x.as_secs());
+    let (year, month, day) = civil_from_days((secs / 86400) as i64);
+    let rem = secs % 86400;
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60
+    )
+}
+
+/// Formats a point in time in the local timezone with a `strftime(3)` format
+/// string.
+pub fn strftime(format: &str, time: SystemTime) -> Option<String> {
+    let secs = time
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |x| x.as_secs()) as libc::time_t;
+    let format = CString::new(format).ok()?;
+    let mut tm = unsafe { std::mem::zeroed::<libc::tm>() };
+    if unsafe { libc::localtime_r(&secs, &mut tm) }.is_null() {
+        return None;
+    }
+    // strftime returns 0 both if the buffer is too small and if the result is
+    // empty so the buffer is only grown a few times.
+    let mut buf = vec![0u8; 256];
+    for _ in 0..4 {
+        let n = unsafe {
+            libc::strftime(
+                buf.as_mut_ptr() as *mut libc::c_char,
+                buf.len(),
+                format.as_ptr(),
+                &tm,
+            )
+        };
+        if n > 0 || format.as_bytes().is_empty() {
+            buf.truncate(n);
+            return String::from_utf8(buf).ok();
+        }
+        buf.resize(buf.len() * 4, 0);
+    }
+    Some(String::new())
+}
+
+/// Converts days since the epoch into a (year, month, day) date.
+///
+/// This is Howard Hinnant's `civil_from_days` algorithm.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    #[test]
+    fn test_format_utc() {
+        let t = |secs| format_utc(SystemTime::UNIX_EPOCH + Duration::from_secs(secs));
+        assert_eq!(t(0), "1970-01-01T00:00:00Z");
+        assert_eq!(t(951782400), "2000-02-29T00:00:00Z");
+        assert_eq!(t(1672628645), "2023-01-02T03:04:05Z");
+    }
+}
@@ -0,0 +1,110 @@
+//! Capturing a tmux pane which wasn't started under teetty.
+//!
+//! `teetty from-tmux <pane>` asks tmux with `pipe-pane` to copy the output of
+//! the pane to a FIFO.  A small shell script reads the FIFO in the pty, which
+//! is switched to raw mode so that the output arrives as the pane got it, and
+//! the output goes through the output file, the transformations, the sinks and
+//! recordings like the one of any other program.  The pipe is closed again when
+//! the capture ends.
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use anyhow::{bail, Context, Error};
+use nix::sys::stat::Mode;
+use nix::unistd::mkfifo;
+use teetty::registry::runtime_dir;
+
+use crate::chain::quote;
+
+/// The FIFO the output of the pane is copied to.  It's removed when this is
+/// dropped.
+pub struct PaneFifo {
+    path: PathBuf,
+}
+
+impl PaneFifo {
+    pub fn create() -> Result<PaneFifo, Error> {
+        let dir = runtime_dir();
+        fs::create_dir_all(&dir)
+            .with_context(|| format!("could not create runtime directory {}", dir.display()))?;
+        let path = dir.join(format!("tmux-{}.fifo", std::process::id()));
+        fs::remove_file(&path).ok();
+        mkfifo(&path, Mode::S_IRUSR | Mode::S_IWUSR)
+            .with_context(|| format!("could not create {}", path.display()))?;
+        Ok(PaneFifo { path })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for PaneFifo {
+    fn drop(&mut self) {
+        fs::remove_file(&self.path).ok();
+    }
+}
+
+/// Returns the size of the pane as `(cols, rows)`.
+pub fn pane_size(pane: &str) -> Result<(u16, u16), Error> {
+    let output = Command::new("tmux")
+        .args(["display-message", "-p", "-t", pane])
+        .arg("#{pane_width}x#{pane_height}")
+        .stdin(Stdio::null())
+        .output()
+        .context("could not run tmux")?;
+    if !output.status.success() {
+        bail!(
+            "could not find the tmux pane {}: {}",
+            pane,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    String::from_utf8_lossy(&output.stdout)
+        .trim()
+        .split_once('x')
+        .and_then(|(cols, rows)| Some((cols.parse().ok()?, rows.parse().ok()?)))
+        .with_context(|| format!("could not find the size of the tmux pane {}", pane))
+}
+
+/// Returns the command which copies the output of the pane from the FIFO
+/// until the pane closes or the capture is interrupted.
+pub fn capture_command(pane: &str, fifo: &Path, size: (u16, u16)) -> Vec<OsString> {
+    let pane = quote(OsStr::new(pane));
+    let fifo = quote(fifo.as_os_str());
+    let mut writer = b"exec cat >> ".to_vec();
+    writer.extend_from_slice(&fifo);
+    let mut script = b"pane=".to_vec();
+    script.extend_from_slice(&pane);
+    script.extend_from_slice(b"\nfifo=");
+    script.extend_from_slice(&fifo);
+    // the pane (or the server) may be gone when the pipe is closed
+    script.extend_from_slice(b"\nstop() { tmux pipe-pane -t \"$pane\" 2>/dev/null; }\n");
+    script.extend_from_slice(b"trap 'stop; exit 130' INT HUP TERM\n");
+    script.extend_from_slice(b"tmux pipe-pane -O -t \"$pane\" ");
+    script.extend(quote(OsStr::from_bytes(&writer)));
+    script.extend_from_slice(b" || exit\n");
+    script.extend_from_slice(
+        format!("stty raw isig -echo rows {} cols {}\n", size.1, size.0).as_bytes(),
+    );
+    script.extend_from_slice(b"cat \"$fifo\"\nstatus=$?\nstop\n");
+    script.extend_from_slice(b"exit \"$status\"\n");
+    vec!["sh".into(), "-c".into(), OsString::from_vec(script)]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_capture_command() {
+        let command = capture_command("%3", Path::new("/run/x.fifo"), (80, 24));
+        let script = command[2].to_string_lossy();
+        assert!(script.starts_with("pane='%3'\nfifo='/run/x.fifo'\n"));
+        assert!(script.contains(r#"pipe-pane -O -t "$pane" 'exec cat >> '\''/run/x.fifo'\''' "#));
+        assert!(script.contains("rows 24 cols 80"));
+    }
+}
@@ -0,0 +1,105 @@
+//! Dumping the traffic of the session.
+//!
+//! With `--dump-traffic <path>` every read from and write to the pty master,
+//! every read from stdin and from the input FIFOs and files is appended to the
+//! file as a hex dump in the format of `hexdump -C`, headed by the time since
+//! the session started, the direction and the number of bytes.  Comparing the
+//! dump against what a real terminal sends and receives helps finding out why
+//! a program behaves differently under teetty.  Splicing is turned off so that
+//! no output bypasses the dump.
+//!
+//! The dump holds everything that was typed, passwords included, so it's only
+//! readable by its owner.
+use std::fmt::Write as _;
+use std::fs::File;
+use std::io::{self, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::time::Instant;
+
+use anyhow::{Context, Error};
+
+/// Appends a hex dump of the traffic to a file.
+pub struct TrafficDump {
+    file: File,
+    started: Instant,
+}
+
+impl TrafficDump {
+    pub fn create(path: &Path) -> Result<TrafficDump, Error> {
+        let file = File::options()
+            .append(true)
+            .create(true)
+            .mode(0o600)
+            .open(path)
+            .with_context(|| format!("could not open {}", path.display()))?;
+        Ok(TrafficDump {
+            file,
+            started: Instant::now(),
+        })
+    }
+
+    /// Dumps data read from `source`.  No data means it reached its end.
+    pub fn read(&mut self, source: &str, data: &[u8]) -> io::Result<()> {
+        if data.is_empty() {
+            self.dump(&format!("reached the end of {}", source), data)
+        } else {
+            self.dump(&format!("read {} bytes from {}", data.len(), source), data)
+        }
+    }
+
+    /// Dumps data written to `target`.
+    pub fn write(&mut self, target: &str, data: &[u8]) -> io::Result<()> {
+        self.dump(&format!("wrote {} bytes to {}", data.len(), target), data)
+    }
+
+    fn dump(&mut self, header: &str, data: &[u8]) -> io::Result<()> {
+        let mut entry = format!(
+            "[{:12.6}] {}\n",
+            self.started.elapsed().as_secs_f64(),
+            header
+        );
+        hexdump(data, &mut entry);
+        // one write per entry so that a crash doesn't cut one in half
+        self.file.write_all(entry.as_bytes())
+    }
+}
+
+/// Formats data like `hexdump -C`: the offset, sixteen bytes in hex and the
+/// printable ones as text.
+fn hexdump(data: &[u8], out: &mut String) {
+    for (idx, line) in data.chunks(16).enumerate() {
+        write!(out, "{:08x} ", idx * 16).ok();
+        for i in 0..16 {
+            if i == 8 {
+                out.push(' ');
+            }
+            match line.get(i) {
+                Some(c) => write!(out, " {:02x}", c).ok(),
+                None => write!(out, "   ").ok(),
+            };
+        }
+        out.push_str("  |");
+        out.extend(line.iter().map(|&c| match c {
+            0x20..=0x7e => c as char,
+            _ => '.',
+        }));
+        out.push_str("|\n");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hexdump() {
+        let mut out = String::new();
+        hexdump(b"\x1b[?2004hhello world\r\n", &mut out);
+        assert_eq!(
+            out,
+            "00000000  1b 5b 3f 32 30 30 34 68  68 65 6c 6c 6f 20 77 6f  |.[?2004hhello wo|\n\
+             00000010  72 6c 64 0d 0a                                    |rld..|\n"
+        );
+    }
+}
@@ -0,0 +1,403 @@
+//! Support for transforming the output stream before it's logged.
+//!
+//! The pty hands out output in arbitrary chunks which means that a read can end
+//! in the middle of a UTF-8 sequence or an escape code.  Transformations would
+//! then see half a codepoint or half an escape sequence and either corrupt it
+//! or fail to recognize it.  The [`Pipeline`] holds such incomplete tails back
+//! until the rest arrives so that transformations only ever see whole units.
+//!
+//! Besides the built-in transformations ([`StripAnsi`], [`Redact`] and
+//! [`Timestamps`]) embedders can implement [`Transform`] themselves and add them
+//! to [`SpawnOptions::transforms`](crate::spawn::SpawnOptions::transforms).
+use std::time::SystemTime;
+
+use crate::ansi::Parser;
+use crate::pattern::Pattern;
+use crate::time::format_utc;
+
+/// A transformation of the output stream.
+pub trait Transform: Send {
+    /// Transforms a chunk of output and appends the result to `out`.
+    ///
+    /// The chunk never ends in an incomplete UTF-8 or escape sequence unless the
+    /// stream itself ended that way.  Appending nothing drops the chunk.
+    fn apply(&mut self, chunk: &[u8], out: &mut Vec<u8>);
+
+    /// Appends whatever the transformation still holds back at the end of the
+    /// stream to `out`.
+    fn finish(&mut self, _out: &mut Vec<u8>) {}
+}
+
+/// Incomplete tails larger than this are passed on regardless.  This protects
+/// against unterminated escape sequences holding back the output forever.
+const MAX_PENDING: usize = 64 * 1024;
+
+/// Runs chunks of output through a list of transformations.
+#[derive(Default)]
+pub struct Pipeline {
+    transforms: Vec<Box<dyn Transform>>,
+    pending: Vec<u8>,
+    scratch: Vec<u8>,
+}
+
+impl Pipeline {
+    /// Creates a pipeline from the given transformations.
+    pub fn new(transforms: Vec<Box<dyn Transform>>) -> Pipeline {
+        Pipeline {
+            transforms,
+            ..Default::default()
+        }
+    }
+
+    /// Returns `true` if the pipeline does not transform anything.
+    pub fn is_empty(&self) -> bool {
+        self.transforms.is_empty()
+    }
+
+    /// Feeds a chunk of output into the pipeline.
+    ///
+    /// Everything that can be transformed is appended to `out`, incomplete
+    /// sequences at the end are held back until the next call.
+    pub fn process(&mut self, chunk: &[u8], out: &mut Vec<u8>) {
+        self.pending.extend_from_slice(chunk);
+        let split = if self.pending.len() > MAX_PENDING {
+            self.pending.len()
+        } else {
+            find_safe_split(&self.pending)
+        };
+        if split > 0 {
+            let rest = self.pending.split_off(split);
+            let complete = std::mem::replace(&mut self.pending, rest);
+            self.run(&complete, out);
+        }
+    }
+
+    /// Flushes whatever is still held back at the end of the stream.
+    pub fn finish(&mut self, out: &mut Vec<u8>) {
+        let pending = std::mem::take(&mut self.pending);
+        if !pending.is_empty() {
+            self.run(&pending, out);
+        }
+        // whatever a transformation held back still has to pass the later ones
+        for idx in 0..self.transforms.len() {
+            let mut input = Vec::new();
+            self.transforms[idx].finish(&mut input);
+            if !input.is_empty() {
+                self.run_from(idx + 1, input, out);
+            }
+        }
+    }
+
+    fn run(&mut self, chunk: &[u8], out: &mut Vec<u8>) {
+        self.run_from(0, chunk.to_vec(), out);
+    }
+
+    fn run_from(&mut self, first: usize, mut input: Vec<u8>, out: &mut Vec<u8>) {
+        for transform in self.transforms[first..].iter_mut() {
+            self.scratch.clear();
+            transform.apply(&input, &mut self.scratch);
+            std::mem::swap(&mut input, &mut self.scratch);
+        }
+        out.extend_from_slice(&input);
+    }
+}
+
+/// Removes escape sequences such as colors and cursor movements.
+#[derive(Default)]
+pub struct StripAnsi {
+    parser: Parser,
+}
+
+impl StripAnsi {
+    pub fn new() -> StripAnsi {
+        StripAnsi::default()
+    }
+}
+
+impl Transform for StripAnsi {
+    fn apply(&mut self, chunk: &[u8], out: &mut Vec<u8>) {
+        self.parser.strip(chunk, out);
+    }
+}
+
+/// Patterns with unbounded repetitions are assumed to match at most this many
+/// bytes when holding back the end of a chunk.
+const MAX_REDACT_WINDOW: usize = 1024;
+
+/// Replaces everything matching one of the patterns with `[REDACTED]`.
+///
+/// A secret can be split across two reads from the pty so the last bytes of
+/// every chunk (as many as the longest pattern can match) are held back until
+/// the next one arrives.  A longer match which reaches the end of what was held
+/// back might go on in the next chunk, so the rest of its line is redacted as
+/// well.
+pub struct Redact {
+    patterns: Vec<Pattern>,
+    replacement: Vec<u8>,
+    window: usize,
+    tail: Vec<u8>,
+    /// Whether the rest of the line is dropped because it might continue a
+    /// match which was longer than the window.
+    in_long_match: bool,
+}
+
+impl Redact {
+    pub fn new(patterns: Vec<Pattern>) -> Redact {
+        Redact::with_replacement(patterns, b"[REDACTED]")
+    }
+
+    /// Replaces the matches with something else than `[REDACTED]`.
+    pub fn with_replacement(patterns: Vec<Pattern>, replacement: &[u8]) -> Redact {
+        let window = patterns
+            .iter()
+            .map(|x| {
+                x.max_len()
+                    .map_or(MAX_REDACT_WINDOW, |len| len.min(MAX_REDACT_WINDOW))
+            })
+            .max()
+            .unwrap_or(0);
+        Redact {
+            patterns,
+            replacement: replacement.to_vec(),
+            window,
+            tail: Vec::new(),
+            in_long_match: false,
+        }
+    }
+
+    /// Replaces the matches in `buf` that start before `hold` and appends
+    /// everything up to `hold` (or the end of the last match) to `out`.
+    /// Returns the offset of what was not written.
+    ///
+    /// Unless `buf` is the end of the output a match reaching the end of it
+    /// starts to drop the rest of the line.
+    fn replace(&mut self, buf: &[u8], hold: usize, out: &mut Vec<u8>, is_end: bool) -> usize {
+        let mut pos = 0;
+        // the leftmost match wins, the longest one if several start there
+        while let Some((start, end)) = self
+            .patterns
+            .iter()
+            .filter_map(|x| x.find(&buf[pos..]))
+            .map(|(start, end)| (pos + start, pos + end))
+            .min_by_key(|&(start, end)| (start, usize::MAX - end))
+        {
+            if start >= hold {
+                break;
+            }
+            if start == end {
+                // skip over empty matches
+                out.extend_from_slice(&buf[pos..=start]);
+                pos = start + 1;
+                continue;
+            }
+            out.extend_from_slice(&buf[pos..start]);
+            if end == buf.len() && !is_end {
+                self.in_long_match = true;
+            }
+            out.extend_from_slice(&self.replacement);
+            pos = end;
+        }
+        if pos < hold {
+            out.extend_from_slice(&buf[pos..hold]);
+            pos = hold;
+        }
+        pos
+    }
+}
+
+impl Transform for Redact {
+    fn apply(&mut self, mut chunk: &[u8], out: &mut Vec<u8>) {
+        if self.in_long_match {
+            // fail closed as it's unknown where the match ends
+            let end = chunk
+                .iter()
+                .position(|&c| c == b'\n')
+                .unwrap_or(chunk.len());
+            self.in_long_match = end == chunk.len();
+            chunk = &chunk[end..];
+        }
+        let mut buf = std::mem::take(&mut self.tail);
+        buf.extend_from_slice(chunk);
+        // a match that isn't complete yet has to start within the window.  The
+        // cut must not split a sequence either as later transforms expect
+        // whole ones.
+        let hold = find_safe_split(&buf[..buf.len().saturating_sub(self.window)]);
+        let pos = self.replace(&buf, hold, out, false);
+        buf.drain(..pos);
+        self.tail = buf;
+    }
+
+    fn finish(&mut self, out: &mut Vec<u8>) {
+        let buf = std::mem::take(&mut self.tail);
+        self.replace(&buf, buf.len(), out, true);
+    }
+}
+
+/// Prefixes every line with the time in UTC when it started (eg:
+/// `[2023-01-02T03:04:05Z] `).
+pub struct Timestamps {
+    at_line_start: bool,
+}
+
+impl Default for Timestamps {
+    fn default() -> Timestamps {
+        Timestamps {
+            at_line_start: true,
+        }
+    }
+}
+
+impl Timestamps {
+    pub fn new() -> Timestamps {
+        Timestamps::default()
+    }
+}
+
+impl Transform for Timestamps {
+    fn apply(&mut self, chunk: &[u8], out: &mut Vec<u8>) {
+        let mut prefix = None;
+        for line in chunk.split_inclusive(|&c| c == b'\n') {
+            if self.at_line_start {
+                let prefix =
+                    prefix.get_or_insert_with(|| format!("[{}] ", format_utc(SystemTime::now())));
+                out.extend_from_slice(prefix.as_bytes());
+            }
+            out.extend_from_slice(line);
+            self.at_line_start = line.ends_with(b"\n");
+        }
+    }
+}
+
+/// Returns the length of the prefix of `buf` that does not end in an
+/// incomplete escape or UTF-8 sequence.
+fn find_safe_split(buf: &[u8]) -> usize {
+    let mut end = buf.len();
+
+    // the last escape character is the only one that can start an incomplete
+    // sequence: every earlier one is followed by another sequence which means
+    // it was either finished or aborted.  The final ESC of a string terminator
+    // (ESC \) is itself a complete sequence.
+    if let Some(pos) = buf.iter().rposition(|&c| c == 0x1b) {
+        if !is_complete_escape(&buf[pos..]) {
+            end = pos;
+        }
+    }
+
+    // walk back to the last lead byte and check that its sequence is complete.
+    for back in 1..=end.min(4) {
+        let c = buf[end - back];
+        if c & 0xc0 == 0x80 {
+            continue;
+        }
+        let needed = match c {
+            0xc0..=0xdf => 2,
+            0xe0..=0xef => 3,
+            0xf0..=0xf7 => 4,
+            _ => 1,
+        };
+        if needed > back {
+            end -= back;
+        }
+        break;
+    }
+
+    end
+}
+
+/// Checks if the escape sequence at the start of `seq` is complete.
+fn is_complete_escape(seq: &[u8]) -> bool {
+    let mut parser = Parser::default();
+    parser.feed(seq, |_| {});
+    !parser.in_sequence()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find_safe_split() {
+        assert_eq!(find_safe_split(b"hello"), 5);
+        assert_eq!(find_safe_split(b"hello\x1b"), 5);
+        assert_eq!(find_safe_split(b"hello\x1b[1;3"), 5);
+        assert_eq!(find_safe_split(b"hello\x1b[1;31m"), 12);
+        assert_eq!(find_safe_split(b"a\x1b]0;title"), 1);
+        assert_eq!(find_safe_split(b"a\x1b]0;title\x07b"), 12);
+        assert_eq!(find_safe_split(b"a\x1b]0;title\x1b\\"), 12);
+        assert_eq!(find_safe_split("aä".as_bytes()), 3);
+        assert_eq!(find_safe_split(&"aä".as_bytes()[..2]), 1);
+        assert_eq!(find_safe_split(&"a€".as_bytes()[..3]), 1);
+        assert_eq!(find_safe_split(&"a😀".as_bytes()[..4]), 1);
+    }
+
+    #[test]
+    fn test_pipeline_reassembles() {
+        struct Upper;
+        impl Transform for Upper {
+            fn apply(&mut self, chunk: &[u8], out: &mut Vec<u8>) {
+                out.extend_from_slice(String::from_utf8_lossy(chunk).to_uppercase().as_bytes());
+            }
+        }
+
+        let mut pipeline = Pipeline::new(vec![Box::new(Upper)]);
+        let mut out = Vec::new();
+        let input = "grüße\x1b[1mbold".as_bytes();
+        for chunk in input.chunks(3) {
+            pipeline.process(chunk, &mut out);
+        }
+        pipeline.finish(&mut out);
+        assert_eq!(String::from_utf8(out).unwrap(), "GRÜSSE\x1b[1MBOLD");
+    }
+
+    #[test]
+    fn test_builtins() {
+        let run = |transform: Box<dyn Transform>, input: &[u8]| {
+            let mut pipeline = Pipeline::new(vec![transform]);
+            let mut out = Vec::new();
+            for chunk in input.chunks(4) {
+                pipeline.process(chunk, &mut out);
+            }
+            pipeline.finish(&mut out);
+            String::from_utf8(out).unwrap()
+        };
+        assert_eq!(
+            run(
+                Box::new(StripAnsi::new()),
+                b"\x1b[1;31mred\x1b[0m \x1b]0;t\x07ok"
+            ),
+            "red ok"
+        );
+        let patterns = vec!["token=\\w+".parse().unwrap(), "x*".parse().unwrap()];
+        assert_eq!(
+            run(Box::new(Redact::new(patterns)), b"a token=abc1 b"),
+            "a [REDACTED] b"
+        );
+        // a secret split across two reads
+        let mut redact = Redact::new(vec!["hunter\\d".parse().unwrap()]);
+        let mut out = Vec::new();
+        redact.apply(b"password: hun", &mut out);
+        assert_eq!(out, b"passwo");
+        redact.apply(b"ter2\r\n$ ", &mut out);
+        redact.finish(&mut out);
+        assert_eq!(out, b"password: [REDACTED]\r\n$ ");
+        // a match going on past the window is redacted up to the end of the
+        // line, a shorter one only up to where it ends
+        let input = format!(
+            "x token={} y\ntoken={} z\n",
+            "a".repeat(MAX_REDACT_WINDOW + 100),
+            "b".repeat(MAX_REDACT_WINDOW - 100)
+        );
+        assert_eq!(
+            run(
+                Box::new(Redact::new(vec!["token=\\w+".parse().unwrap()])),
+                input.as_bytes()
+            ),
+            "x [REDACTED]\n[REDACTED] z\n"
+        );
+        let out = run(Box::new(Timestamps::new()), b"one\r\ntwo\n");
+        let lines = out.split_inclusive('\n').collect::<Vec<_>>();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with('[') && lines[0].ends_with("Z] one\r\n"));
+        assert!(lines[1].ends_with("Z] two\n"));
+    }
+}
@@ -0,0 +1,107 @@
+//! Recordings in the ttyrec format.
+//!
+//! A ttyrec file is a sequence of chunks of output, each preceded by a header
+//! of three little endian 32 bit integers: the seconds and microseconds of the
+//! wall clock time the chunk was written at and the length of the chunk.
+//! Recordings can be played back with `teetty play`, ttyplay or IPBT.
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+use anyhow::{bail, Context, Error};
+
+use crate::sink::Sink;
+
+/// Writes a ttyrec recording of the output.
+pub struct Recorder {
+    file: BufWriter<File>,
+    started: SystemTime,
+    flush: bool,
+}
+
+impl Recorder {
+    /// Creates a recording.
+    pub fn create(path: &Path, flush: bool) -> Result<Recorder, Error> {
+        let file = File::create(path)
+            .with_context(|| format!("could not create recording {}", path.display()))?;
+        Ok(Recorder {
+            file: BufWriter::new(file),
+            started: SystemTime::now(),
+            flush,
+        })
+    }
+}
+
+impl Sink for Recorder {
+    fn write_chunk(&mut self, ts: Duration, data: &[u8]) -> io::Result<()> {
+        let time = (self.started + ts)
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default();
+        // the length is 32 bit so huge chunks are split up
+        for chunk in data.chunks(u32::MAX as usize) {
+            self.file
+                .write_all(&(time.as_secs() as u32).to_le_bytes())?;
+            self.file.write_all(&time.subsec_micros().to_le_bytes())?;
+            self.file.write_all(&(chunk.len() as u32).to_le_bytes())?;
+            self.file.write_all(chunk)?;
+        }
+        if self.flush {
+            self.file.flush()?;
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// Parses a ttyrec recording into the chunks of output with the seconds
+/// since the first one.
+pub fn parse(data: &[u8]) -> Result<Vec<(f64, &[u8])>, Error> {
+    let mut chunks = Vec::new();
+    let mut start = None;
+    let mut rest = data;
+    while !rest.is_empty() {
+        let offset = data.len() - rest.len();
+        let Some((header, body)) = rest.split_first_chunk::<12>() else {
+            bail!("truncated header at byte {}", offset);
+        };
+        let field =
+            |idx: usize| u32::from_le_bytes(header[idx * 4..idx * 4 + 4].try_into().unwrap());
+        let time = field(0) as f64 + field(1) as f64 / 1_000_000.0;
+        let len = field(2) as usize;
+        if body.len() < len {
+            bail!("truncated chunk at byte {}", offset);
+        }
+        let start = *start.get_or_insert(time);
+        chunks.push(((time - start).max(0.0), &body[..len]));
+        rest = &body[len..];
+    }
+    Ok(chunks)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_roundtrip() {
+        let path = std::env::temp_dir().join(format!("teetty-test-{}.ttyrec", std::process::id()));
+        let mut recorder = Recorder::create(&path, false).unwrap();
+        recorder.write_chunk(Duration::ZERO, b"hello ").unwrap();
+        recorder
+            .write_chunk(Duration::from_millis(1500), b"\xe2\x82\xac\r\n")
+            .unwrap();
+        recorder.finish().unwrap();
+        let data = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).ok();
+        assert_eq!(data.len(), 2 * 12 + 11);
+        let chunks = parse(&data).unwrap();
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(chunks[0].1, b"hello ");
+        assert!((chunks[1].0 - 1.5).abs() < 0.000002);
+        assert!(parse(&data[..20]).is_err());
+    }
+}
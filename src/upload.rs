@@ -0,0 +1,158 @@
+//! Streams the output to a remote HTTP endpoint.
+//!
+//! The output is uploaded with a single chunked `PUT` made by `curl`.  Until the
+//! upload went through, everything is also kept in a local spill file so that
+//! nothing is lost when the network or the server fails.  The spill file is
+//! created in a new directory only the user can access.
+use std::ffi::{CString, OsString};
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{sync_channel, SyncSender, TrySendError};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use anyhow::{bail, Context, Error};
+use nix::libc;
+
+use crate::sink::Sink;
+
+/// How many chunks may be waiting for the network before the upload is given
+/// up on.
+const MAX_QUEUED_CHUNKS: usize = 1024;
+
+/// A running upload.
+pub struct Upload {
+    sender: Option<SyncSender<Vec<u8>>>,
+    thread: Option<JoinHandle<Result<(), Error>>>,
+    spill: File,
+    spill_path: PathBuf,
+    /// Set when the upload fell behind and has to be aborted.
+    abort: Arc<AtomicBool>,
+}
+
+impl Upload {
+    /// Starts uploading to the given URL with the extra headers.
+    pub fn start(url: &str, headers: &[String]) -> Result<Upload, Error> {
+        let spill_path = private_dir()
+            .context("could not create a directory for the spill file")?
+            .join("upload.log");
+        let spill = File::options()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&spill_path)
+            .with_context(|| format!("could not create {}", spill_path.display()))?;
+
+        let mut cmd = Command::new("curl");
+        cmd.args(["--silent", "--show-error", "--fail", "--upload-file", "-"]);
+        for header in headers {
+            cmd.arg("--header").arg(header);
+        }
+        let mut child = cmd
+            .arg("--")
+            .arg(url)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()?;
+        let mut stdin = child.stdin.take().unwrap();
+
+        let (sender, receiver) = sync_channel::<Vec<u8>>(MAX_QUEUED_CHUNKS);
+        let abort = Arc::new(AtomicBool::new(false));
+        let aborted = abort.clone();
+        let thread = thread::spawn(move || {
+            for chunk in receiver {
+                if stdin.write_all(&chunk).is_err() {
+                    break;
+                }
+            }
+            // closing stdin would let curl finish a truncated upload
+            if aborted.load(Ordering::Relaxed) {
+                child.kill().ok();
+                child.wait()?;
+                bail!("the upload could not keep up");
+            }
+            drop(stdin);
+            let status = child.wait()?;
+            if !status.success() {
+                bail!("curl failed ({})", status);
+            }
+            Ok(())
+        });
+
+        Ok(Upload {
+            sender: Some(sender),
+            thread: Some(thread),
+            spill,
+            spill_path,
+            abort,
+        })
+    }
+}
+
+impl Sink for Upload {
+    /// Uploads a chunk of output.
+    fn write_chunk(&mut self, _ts: Duration, data: &[u8]) -> io::Result<()> {
+        self.spill.write_all(data)?;
+        if let Some(ref sender) = self.sender {
+            match sender.try_send(data.to_vec()) {
+                Ok(()) => {}
+                // if the network can't keep up the upload is abandoned rather
+                // than holding up the session.  The spill file has everything.
+                Err(TrySendError::Full(_)) => {
+                    self.abort.store(true, Ordering::Relaxed);
+                    self.sender = None;
+                }
+                Err(TrySendError::Disconnected(_)) => self.sender = None,
+            }
+        }
+        Ok(())
+    }
+
+    /// Waits for the upload to finish.  The spill file is removed if it went
+    /// through, otherwise the error mentions where the output was kept.  If
+    /// the upload fell behind, curl was killed so that the server never gets
+    /// a complete request with part of the output.
+    fn finish(&mut self) -> io::Result<()> {
+        self.sender = None;
+        let Some(thread) = self.thread.take() else {
+            return Ok(());
+        };
+        let rv = match thread.join() {
+            Ok(rv) => rv,
+            Err(_) => Err(Error::msg("the upload crashed")),
+        };
+        match rv {
+            Ok(()) => {
+                fs::remove_file(&self.spill_path).ok();
+                if let Some(dir) = self.spill_path.parent() {
+                    fs::remove_dir(dir).ok();
+                }
+                Ok(())
+            }
+            Err(err) => Err(io::Error::other(format!(
+                "upload failed: {:#}; the output was kept in {}",
+                err,
+                self.spill_path.display()
+            ))),
+        }
+    }
+}
+
+/// Creates a new directory in the temp directory that only the user can
+/// access.
+fn private_dir() -> io::Result<PathBuf> {
+    let template = std::env::temp_dir().join("teetty-upload-XXXXXX");
+    let mut path = CString::new(template.as_os_str().as_bytes())?.into_bytes_with_nul();
+    if unsafe { libc::mkdtemp(path.as_mut_ptr().cast()) }.is_null() {
+        return Err(io::Error::last_os_error());
+    }
+    path.pop();
+    Ok(PathBuf::from(OsString::from_vec(path)))
+}
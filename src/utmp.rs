@@ -0,0 +1,126 @@
+//! Login records in utmp and wtmp.
+//!
+//! Like `script` and terminal emulators do, sessions can be recorded as logins
+//! so that `who` lists them while they run and `last` shows them afterwards.
+//! Writing the records usually requires root or membership in the `utmp`
+//! group.
+use std::ffi::CString;
+use std::path::Path;
+use std::time::SystemTime;
+
+use anyhow::{bail, Error};
+use nix::libc::{self, c_char};
+use nix::unistd::{getuid, Pid, User};
+
+extern "C" {
+    /// Appends a record to wtmp.  glibc has it but the libc crate doesn't.
+    fn updwtmpx(file: *const c_char, ut: *const libc::utmpx);
+}
+
+const WTMP_PATH: &str = "/var/log/wtmp";
+
+/// A login record which is removed again on drop.
+pub struct Login {
+    entry: libc::utmpx,
+}
+
+impl Login {
+    /// Records a login of the current user on the given pty for the process.
+    pub fn record(tty: &Path, pid: Pid) -> Result<Login, Error> {
+        let mut entry: libc::utmpx = unsafe { std::mem::zeroed() };
+        let (line, id) = line_and_id(tty, entry.ut_id.len());
+        let user = User::from_uid(getuid())
+            .ok()
+            .flatten()
+            .map_or_else(|| getuid().to_string(), |x| x.name);
+        entry.ut_type = libc::USER_PROCESS;
+        entry.ut_pid = pid.as_raw();
+        copy_str(&mut entry.ut_line, &line);
+        copy_str(&mut entry.ut_id, &id);
+        copy_str(&mut entry.ut_user, &user);
+        copy_str(&mut entry.ut_host, "teetty");
+        set_time(&mut entry);
+        if !write_entry(&entry) {
+            bail!(
+                "could not write the utmp record: {}",
+                std::io::Error::last_os_error()
+            );
+        }
+        Ok(Login { entry })
+    }
+}
+
+impl Drop for Login {
+    fn drop(&mut self) {
+        self.entry.ut_type = libc::DEAD_PROCESS;
+        self.entry.ut_user = [0; libc::__UT_NAMESIZE];
+        self.entry.ut_host = [0; libc::__UT_HOSTSIZE];
+        set_time(&mut self.entry);
+        write_entry(&self.entry);
+    }
+}
+
+/// Returns the line of the pty (`pts/3` for `/dev/pts/3`) and the id of its
+/// record, which is the end of the line without `pts/`.
+fn line_and_id(tty: &Path, id_len: usize) -> (String, String) {
+    let line = tty.strip_prefix("/dev").unwrap_or(tty).to_string_lossy();
+    let id = line.strip_prefix("pts/").unwrap_or(&line);
+    let id = id[id.len().saturating_sub(id_len)..].to_string();
+    (line.into_owned(), id)
+}
+
+/// Writes the record to utmp and appends it to wtmp.
+fn write_entry(entry: &libc::utmpx) -> bool {
+    let wtmp = CString::new(WTMP_PATH).unwrap();
+    unsafe {
+        libc::setutxent();
+        let written = !libc::pututxline(entry).is_null();
+        libc::endutxent();
+        if written {
+            updwtmpx(wtmp.as_ptr(), entry);
+        }
+        written
+    }
+}
+
+fn set_time(entry: &mut libc::utmpx) {
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default();
+    entry.ut_tv.tv_sec = now.as_secs() as _;
+    entry.ut_tv.tv_usec = now.subsec_micros() as _;
+}
+
+/// Copies a string into a fixed size field.  It's only NUL terminated if
+/// there is space left.
+fn copy_str(field: &mut [c_char], value: &str) {
+    for (dst, &src) in field.iter_mut().zip(value.as_bytes()) {
+        *dst = src as c_char;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_line_and_id() {
+        assert_eq!(
+            line_and_id(Path::new("/dev/pts/3"), 4),
+            ("pts/3".into(), "3".into())
+        );
+        assert_eq!(
+            line_and_id(Path::new("/dev/ttyp12345"), 4),
+            ("ttyp12345".into(), "2345".into())
+        );
+    }
+
+    #[test]
+    fn test_copy_str() {
+        let mut field = [0 as c_char; 4];
+        copy_str(&mut field, "ab");
+        assert_eq!(field, [b'a' as c_char, b'b' as c_char, 0, 0]);
+        copy_str(&mut field, "abcdef");
+        assert_eq!(field, [b'a', b'b', b'c', b'd'].map(|x| x as c_char));
+    }
+}
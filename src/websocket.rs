@@ -0,0 +1,251 @@
+//! Serving the live output over WebSocket.
+//!
+//! The output is sent as binary messages.  Changes of the terminal size are
+//! sent as text messages with a JSON object (`{"type":"resize","rows":24,"cols":80}`).
+//! Only the server side of the protocol that's needed for this is implemented,
+//! messages from the browser are ignored.
+use std::fs;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::Path;
+
+use anyhow::{Context, Error};
+
+use crate::base64;
+use crate::hash::sha1;
+use crate::http::Request;
+
+/// The GUID from RFC 6455 that goes into the accept key.
+const WEBSOCKET_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// Opcode of text messages.
+pub const OPCODE_TEXT: u8 = 0x1;
+
+/// Opcode of binary messages.
+pub const OPCODE_BINARY: u8 = 0x2;
+
+/// A page that shows the live output.  It uses xterm.js if teetty serves it
+/// (see [`ViewerAssets`]) and shows the output as plain text otherwise, so no
+/// script is loaded from elsewhere.  The query string of the page, with the
+/// token, is passed on to the WebSocket.
+pub const VIEWER_HTML: &str = r#"<!doctype html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>teetty</title>
+<link rel="stylesheet" href="xterm.css">
+<style>
+body { margin: 0; background: #000; }
+pre { margin: 0; color: #ccc; font: 14px monospace; white-space: pre-wrap; }
+</style>
+</head>
+<body>
+<div id="terminal"></div>
+<script>
+function plainTerminal(parent) {
+  const pre = document.createElement("pre");
+  const current = document.createElement("span");
+  pre.appendChild(current);
+  parent.appendChild(pre);
+  const decoder = new TextDecoder();
+  const escapes = /\x1b(\[[0-?]*[ -\/]*[@-~]|\][^\x07\x1b]*(\x07|\x1b\\)?|[^\[\]])/g;
+  let line = "";
+  let col = 0;
+  return {
+    resize() {},
+    write(data) {
+      const text = typeof data === "string" ? data : decoder.decode(data, { stream: true });
+      for (const c of text.replace(escapes, "")) {
+        if (c === "\n") {
+          current.before(line + "\n");
+          line = "";
+          col = 0;
+        } else if (c === "\r") {
+          col = 0;
+        } else if (c === "\b") {
+          col = Math.max(col - 1, 0);
+        } else if (c >= " ") {
+          line = line.slice(0, col).padEnd(col) + c + line.slice(col + 1);
+          col += 1;
+        }
+      }
+      current.textContent = line;
+      window.scrollTo(0, document.body.scrollHeight);
+    },
+  };
+}
+
+function start(term) {
+  const ws = new WebSocket((location.protocol === "https:" ? "wss://" : "ws://") + location.host + "/ws" + location.search);
+  ws.binaryType = "arraybuffer";
+  ws.onmessage = (event) => {
+    if (typeof event.data === "string") {
+      const msg = JSON.parse(event.data);
+      if (msg.type === "resize" && msg.rows > 0 && msg.cols > 0) {
+        term.resize(msg.cols, msg.rows);
+      }
+    } else {
+      term.write(new Uint8Array(event.data));
+    }
+  };
+  ws.onclose = () => term.write("\r\n[session ended]\r\n");
+}
+
+const parent = document.getElementById("terminal");
+const script = document.createElement("script");
+script.src = "xterm.js";
+script.onload = () => {
+  const term = new Terminal({ convertEol: false, disableStdin: true });
+  term.open(parent);
+  start(term);
+};
+script.onerror = () => start(plainTerminal(parent));
+document.head.appendChild(script);
+</script>
+</body>
+</html>
+"#;
+
+/// The script and style of xterm.js for the viewer page, read from an
+/// installed copy of the `xterm` package.
+pub struct ViewerAssets {
+    pub script: Vec<u8>,
+    pub style: Vec<u8>,
+}
+
+impl ViewerAssets {
+    pub fn load(dir: &Path) -> Result<ViewerAssets, Error> {
+        let read = |path: &str| {
+            let path = dir.join(path);
+            fs::read(&path).with_context(|| format!("could not read {}", path.display()))
+        };
+        Ok(ViewerAssets {
+            script: read("lib/xterm.js")?,
+            style: read("css/xterm.css")?,
+        })
+    }
+}
+
+/// Returns the response that accepts the upgrade to WebSocket or `None` if the
+/// request isn't a WebSocket handshake.
+pub fn handshake_response(req: &Request) -> Option<String> {
+    let is_upgrade = req
+        .header("upgrade")
+        .is_some_and(|x| x.eq_ignore_ascii_case("websocket"));
+    let key = req.header("sec-websocket-key")?;
+    if req.method != "GET" || !is_upgrade {
+        return None;
+    }
+    Some(format!(
+        "HTTP/1.1 101 Switching Protocols\r\n\
+         Upgrade: websocket\r\n\
+         Connection: Upgrade\r\n\
+         Sec-WebSocket-Accept: {}\r\n\r\n",
+        accept_key(key)
+    ))
+}
+
+/// Checks that a WebSocket is opened from a page served by teetty.  Browsers
+/// send the origin of the page, which has to be the host the request went to.
+/// If the server only listens on the loopback interface that host also has to
+/// be a loopback name, so that other sites can't point their names at it.
+/// Clients that aren't browsers send no origin.
+pub fn is_allowed_origin(req: &Request, bound: SocketAddr) -> bool {
+    let Some(origin) = req.header("origin") else {
+        return true;
+    };
+    let Some(host) = req.header("host") else {
+        return false;
+    };
+    let authority = origin
+        .strip_prefix("http://")
+        .or_else(|| origin.strip_prefix("https://"));
+    if authority != Some(host) {
+        return false;
+    }
+    if !bound.ip().is_loopback() {
+        return true;
+    }
+    let name = match host.strip_prefix('[') {
+        Some(rest) => rest.split_once(']').map_or(rest, |x| x.0),
+        None => host.split_once(':').map_or(host, |x| x.0),
+    };
+    name == "localhost" || name == "::1" || name.parse::<Ipv4Addr>().is_ok_and(|x| x.is_loopback())
+}
+
+/// Computes the `Sec-WebSocket-Accept` value for a key.
+fn accept_key(key: &str) -> String {
+    base64::encode(&sha1(format!("{}{}", key, WEBSOCKET_GUID).as_bytes()))
+}
+
+/// Encodes an unmasked message in a single frame.
+pub fn encode_frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(payload.len() + 10);
+    frame.push(0x80 | opcode);
+    match payload.len() {
+        len @ 0..=125 => frame.push(len as u8),
+        len @ 126..=0xffff => {
+            frame.push(126);
+            frame.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            frame.push(127);
+            frame.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    frame.extend_from_slice(payload);
+    frame
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_accept_key() {
+        // the example from RFC 6455
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[test]
+    fn test_is_allowed_origin() {
+        let req = |origin: &str, host: &str| {
+            let head = format!(
+                "GET /ws HTTP/1.1\r\nOrigin: {}\r\nHost: {}\r\n\r\n",
+                origin, host
+            );
+            crate::http::parse_request(head.as_bytes())
+                .unwrap()
+                .unwrap()
+        };
+        let local: SocketAddr = "127.0.0.1:8080".parse().unwrap();
+        let any: SocketAddr = "0.0.0.0:8080".parse().unwrap();
+        assert!(is_allowed_origin(
+            &req("http://localhost:8080", "localhost:8080"),
+            local
+        ));
+        assert!(is_allowed_origin(
+            &req("http://[::1]:8080", "[::1]:8080"),
+            local
+        ));
+        assert!(!is_allowed_origin(
+            &req("https://evil.example", "localhost:8080"),
+            local
+        ));
+        assert!(!is_allowed_origin(
+            &req("http://evil.example:8080", "evil.example:8080"),
+            local
+        ));
+        assert!(is_allowed_origin(&req("http://box:8080", "box:8080"), any));
+    }
+
+    #[test]
+    fn test_encode_frame() {
+        assert_eq!(encode_frame(OPCODE_TEXT, b"Hello"), b"\x81\x05Hello");
+        let frame = encode_frame(OPCODE_BINARY, &[0; 256]);
+        assert_eq!(&frame[..4], b"\x82\x7e\x01\x00");
+        assert_eq!(frame.len(), 260);
+    }
+}